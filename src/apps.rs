@@ -17,9 +17,9 @@ use hyper::{Body, StatusCode};
 use hyper_reverse_proxy::ReverseProxy;
 use std::net::SocketAddr;
 
-use crate::configuration::{Config, ConfigFile, HostType};
+use crate::configuration::{Config, ConfigFile, HostType, MainSite};
 use crate::users::User;
-use crate::users::{check_authorization, Admin};
+use crate::users::{check_authorization_or_login, Admin};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct App {
@@ -106,10 +106,11 @@ lazy_static::lazy_static! {
 pub async fn proxy_handler(
     user: Option<User>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(main_site): Extension<MainSite>,
     app: HostType,
     mut req: Request<Body>,
 ) -> Response<Body> {
-    if let Some(value) = check_authorization(&app, &user) {
+    if let Some(value) = check_authorization_or_login(&app, &user, &main_site, &req) {
         return value;
     }
 
@@ -0,0 +1,479 @@
+use std::env;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use tokio::fs;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use uuid::Uuid;
+
+use crate::configuration::Config;
+use crate::davs::encrypted_streamer::{EncryptedStreamer, Header};
+use crate::davs::keys::{legacy_key, random_key, WrappedKey};
+use crate::davs::model::Dav;
+use crate::davs::names::NameCipher;
+use crate::davs::reencryption::opens;
+use crate::davs::{INTERNAL_DIR, PARTIAL_EXTENSION};
+
+/// Environment variable giving the passphrase, which is asked for otherwise
+pub const PASSPHRASE_VAR: &str = "VESTIBULE_PASSPHRASE";
+
+pub const USAGE: &str = "Offline commands on the files of encrypted davs :
+  vestibule decrypt [--dav HOST] SOURCE DESTINATION   decrypt a file or a directory tree into DESTINATION
+  vestibule encrypt [--dav HOST] [DIRECTORY]          encrypt the plain files of a directory in place
+  vestibule verify [--dav HOST] PATH                  check every chunk of a file or a directory tree
+
+With --dav, the key of the dav is unwrapped from the configuration and its file names are handled as the dav does.
+Encrypting a dav without a passphrase sets its passphrase and key in the configuration.
+Without --dav, the key is the one of the davs encrypted before the keys were wrapped.
+The passphrase is read from the VESTIBULE_PASSPHRASE environment variable or from the configuration, or else asked for.";
+
+/// Run a command line command, the server being down. False is returned if some files could not be processed.
+pub async fn run(config_file: &str, args: &[String]) -> Result<bool> {
+    let mut host = None;
+    let mut positional = vec![];
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--dav" => host = Some(args.next().ok_or_else(|| anyhow!(USAGE))?.as_str()),
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return Ok(true);
+            }
+            _ => positional.push(arg.as_str()),
+        }
+    }
+    let dav = match host {
+        Some(host) => Some(
+            Config::from_file(config_file)
+                .await?
+                .davs
+                .into_iter()
+                .find(|dav| dav.host == host)
+                .ok_or_else(|| anyhow!("no dav with host {}", host))?,
+        ),
+        None => None,
+    };
+
+    let report = match positional.as_slice() {
+        ["decrypt", source, destination] => {
+            let crypt = Crypt::new(dav.as_ref()).await?;
+            crypt
+                .decrypt(Path::new(source), Path::new(destination))
+                .await?
+        }
+        ["encrypt", rest @ ..] if rest.len() <= 1 => {
+            let directory = match (rest.first(), &dav) {
+                (Some(directory), _) => PathBuf::from(directory),
+                (None, Some(dav)) if !dav.is_home() => PathBuf::from(&dav.directory),
+                _ => bail!("the directory to encrypt is needed"),
+            };
+            let crypt = match dav {
+                Some(dav) if dav.passphrase.is_empty() => {
+                    Crypt::new_dav_key(config_file, dav).await?
+                }
+                dav => Crypt::new(dav.as_ref()).await?,
+            };
+            crypt.encrypt(&directory).await?
+        }
+        ["verify", path] => {
+            let crypt = Crypt::new(dav.as_ref()).await?;
+            crypt.verify(Path::new(path)).await?
+        }
+        _ => bail!(USAGE),
+    };
+    println!(
+        "{} files processed, {} left as they were, {} failed",
+        report.processed, report.skipped, report.failed
+    );
+    Ok(report.failed == 0)
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Report {
+    pub processed: u64,
+    /// Files already in the wanted state
+    pub skipped: u64,
+    pub failed: u64,
+}
+
+/// Key and file names cipher of the files worked on
+pub struct Crypt {
+    key: [u8; 32],
+    names: Option<NameCipher>,
+}
+
+impl Crypt {
+    pub fn from_key(key: [u8; 32], encrypt_names: bool) -> Self {
+        Self {
+            key,
+            names: encrypt_names.then(|| NameCipher::from_key(&key)),
+        }
+    }
+
+    /// Key of a dav, or the legacy key derived from the passphrase alone without a dav
+    async fn new(dav: Option<&Dav>) -> Result<Self> {
+        let passphrase = passphrase(dav).await?;
+        match dav {
+            Some(dav) => {
+                let key = match &dav.wrapped_key {
+                    Some(wrapped) => wrapped.unwrap_key(&passphrase)?,
+                    None => legacy_key(&passphrase),
+                };
+                Ok(Self::from_key(key, dav.encrypt_names))
+            }
+            None => Ok(Self::from_key(legacy_key(&passphrase), false)),
+        }
+    }
+
+    /// Draw a key for a plain dav, saved in the configuration before any file is encrypted so that an interrupted
+    /// encryption can be resumed
+    async fn new_dav_key(config_file: &str, dav: Dav) -> Result<Self> {
+        let passphrase = passphrase(None).await?;
+        if passphrase.is_empty() {
+            bail!("the passphrase cannot be empty");
+        }
+        let key = random_key();
+        let mut config = Config::from_file(config_file).await?;
+        let stored = config
+            .davs
+            .iter_mut()
+            .find(|d| d.id == dav.id)
+            .ok_or_else(|| anyhow!("no dav with id {}", dav.id))?;
+        stored.wrapped_key = Some(WrappedKey::new(&passphrase, &key)?);
+        stored.passphrase = passphrase;
+        config.to_file(config_file).await?;
+        Ok(Self::from_key(key, dav.encrypt_names))
+    }
+
+    /// Decrypt a file, or every file of a directory tree, into a destination outside of it
+    pub async fn decrypt(&self, source: &Path, destination: &Path) -> Result<Report> {
+        let mut report = Report::default();
+        if fs::metadata(source).await?.is_dir() {
+            self.decrypt_dir(source, destination, &mut report).await?;
+        } else {
+            let destination = match fs::metadata(destination).await {
+                Ok(meta) if meta.is_dir() => destination.join(self.plain_name(source)?),
+                _ => destination.to_path_buf(),
+            };
+            match self.decrypt_file(source, &destination).await {
+                Ok(_) => report.processed += 1,
+                Err(e) => {
+                    println!("{}: {}", source.display(), e);
+                    report.failed += 1;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    fn decrypt_dir<'a>(
+        &'a self,
+        dir: &'a Path,
+        destination: &'a Path,
+        report: &'a mut Report,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            fs::create_dir_all(destination).await?;
+            for (path, is_dir) in entries(dir).await? {
+                let plain = match self.plain_name(&path) {
+                    Ok(plain) => destination.join(plain),
+                    Err(e) => {
+                        println!("{}: {}", path.display(), e);
+                        report.failed += 1;
+                        continue;
+                    }
+                };
+                if is_dir {
+                    self.decrypt_dir(&path, &plain, report).await?;
+                } else {
+                    match self.decrypt_file(&path, &plain).await {
+                        Ok(_) => report.processed += 1,
+                        Err(e) => {
+                            println!("{}: {}", path.display(), e);
+                            report.failed += 1;
+                        }
+                    }
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    async fn decrypt_file(&self, source: &Path, destination: &Path) -> Result<()> {
+        let mut file = fs::File::create(destination).await?;
+        let result = EncryptedStreamer::new(fs::File::open(source).await?, self.key)
+            .copy_to(&mut file)
+            .await;
+        if result.is_err() {
+            fs::remove_file(destination).await.ok();
+        }
+        result?;
+        Ok(())
+    }
+
+    /// Encrypt the plain files of a directory tree in place, and their names if needed. The files the key decrypts
+    /// are left as they are, so that an interrupted encryption can be run again.
+    pub async fn encrypt(&self, dir: &Path) -> Result<Report> {
+        let mut report = Report::default();
+        self.encrypt_dir(dir, &mut report).await?;
+        Ok(report)
+    }
+
+    fn encrypt_dir<'a>(
+        &'a self,
+        dir: &'a Path,
+        report: &'a mut Report,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            for (path, is_dir) in entries(dir).await? {
+                if is_dir {
+                    // A directory keeps its name while some of its files are not encrypted, so that it can be resumed
+                    let failed = report.failed;
+                    self.encrypt_dir(&path, report).await?;
+                    if report.failed > failed {
+                        continue;
+                    }
+                } else {
+                    match self.encrypt_file(&path).await {
+                        Ok(true) => report.processed += 1,
+                        Ok(false) => report.skipped += 1,
+                        Err(e) => {
+                            println!("{}: {}", path.display(), e);
+                            report.failed += 1;
+                            continue;
+                        }
+                    }
+                }
+                if let Err(e) = self.encrypt_name(&path).await {
+                    println!("{}: {}", path.display(), e);
+                    report.failed += 1;
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Encrypt a plain file, false is returned for the files already encrypted
+    async fn encrypt_file(&self, path: &Path) -> Result<bool> {
+        if opens(path, self.key).await? {
+            return Ok(false);
+        }
+        // Files too short for a header, or without the magic bytes, are taken as plain
+        match Header::read(&mut fs::File::open(path).await?).await {
+            Ok(header) if header.key_id.is_some() => header.check_key(&self.key)?,
+            _ => (),
+        }
+        let partial = path.with_file_name(format!(
+            ".{}.{}.{}",
+            path.file_name().unwrap_or_default().to_string_lossy(),
+            Uuid::new_v4().simple(),
+            PARTIAL_EXTENSION
+        ));
+        let result = async {
+            let mut plain = fs::File::open(path).await?;
+            EncryptedStreamer::new(fs::File::create(&partial).await?, self.key)
+                .copy_from(&mut plain)
+                .await?;
+            fs::rename(&partial, path).await
+        }
+        .await;
+        if result.is_err() {
+            fs::remove_file(&partial).await.ok();
+        }
+        result?;
+        Ok(true)
+    }
+
+    /// Rename an entry to its encrypted name, unless it is already encrypted
+    async fn encrypt_name(&self, path: &Path) -> Result<()> {
+        let names = match &self.names {
+            Some(names) => names,
+            None => return Ok(()),
+        };
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("file name is not valid unicode"))?;
+        if names.decrypt(name).is_err() {
+            fs::rename(path, path.with_file_name(names.encrypt(name)?)).await?;
+        }
+        Ok(())
+    }
+
+    /// Decrypt every chunk of a file or of a directory tree, reporting the files that are corrupted, truncated or
+    /// encrypted with another key
+    pub async fn verify(&self, path: &Path) -> Result<Report> {
+        let mut report = Report::default();
+        if fs::metadata(path).await?.is_dir() {
+            self.verify_dir(path, &mut report).await?;
+        } else {
+            self.verify_file(path, &mut report).await;
+        }
+        Ok(report)
+    }
+
+    fn verify_dir<'a>(
+        &'a self,
+        dir: &'a Path,
+        report: &'a mut Report,
+    ) -> BoxFuture<'a, Result<()>> {
+        async move {
+            for (path, is_dir) in entries(dir).await? {
+                if is_dir {
+                    self.verify_dir(&path, report).await?;
+                } else {
+                    self.verify_file(&path, report).await;
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    async fn verify_file(&self, path: &Path, report: &mut Report) {
+        match check_file(path, self.key).await {
+            Ok(_) => report.processed += 1,
+            Err(e) => {
+                println!("corrupted {}: {}", path.display(), e);
+                report.failed += 1;
+            }
+        }
+    }
+
+    fn plain_name(&self, path: &Path) -> Result<String> {
+        let name = path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .ok_or_else(|| anyhow!("file name is not valid unicode"))?;
+        match &self.names {
+            Some(names) => Ok(names.decrypt(name)?),
+            None => Ok(name.to_owned()),
+        }
+    }
+}
+
+async fn check_file(path: &Path, key: [u8; 32]) -> Result<()> {
+    let mut file = fs::File::open(path).await?;
+    let len = file.metadata().await?.len();
+    let header = Header::read(&mut file).await?;
+    header.check_key(&key)?;
+    // The last chunk is always shorter than the others, even if empty : a file ending on a whole chunk was cut
+    if (len - header.size()) % header.encrypted_chunk_size() == 0 {
+        bail!("truncated file");
+    }
+    let mut chunks = EncryptedStreamer::new(fs::File::open(path).await?, key).into_stream();
+    while let Some(chunk) = chunks.next().await {
+        chunk?;
+    }
+    Ok(())
+}
+
+/// Entries of a directory with whether they are directories, leaving out the internal state of the davs and the
+/// partial files
+async fn entries(dir: &Path) -> Result<Vec<(PathBuf, bool)>> {
+    let mut entries = vec![];
+    let mut read_dir = fs::read_dir(dir).await?;
+    while let Some(entry) = read_dir.next_entry().await? {
+        let path = entry.path();
+        if entry.file_name() == INTERNAL_DIR
+            || path.extension().is_some_and(|ext| ext == PARTIAL_EXTENSION)
+        {
+            continue;
+        }
+        entries.push((path, entry.file_type().await?.is_dir()));
+    }
+    Ok(entries)
+}
+
+async fn passphrase(dav: Option<&Dav>) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    if let Some(dav) = dav.filter(|dav| !dav.passphrase.is_empty()) {
+        return Ok(dav.passphrase.clone());
+    }
+    eprint!("Passphrase: ");
+    let mut line = String::new();
+    match BufReader::new(tokio::io::stdin())
+        .read_line(&mut line)
+        .await
+    {
+        Ok(0) => Err(anyhow!("no passphrase given")),
+        Ok(_) => Ok(line.trim_end_matches(['\r', '\n']).to_owned()),
+        Err(e) if e.kind() == ErrorKind::Interrupted => Err(anyhow!("no passphrase given")),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{Crypt, Report};
+
+    #[tokio::test]
+    async fn test_encrypt_verify_decrypt() {
+        // Arrange
+        let base = "cli_test";
+        fs::create_dir_all(format!("{base}/dav/docs")).unwrap();
+        fs::write(format!("{base}/dav/docs/report.txt"), "secret content").unwrap();
+        fs::write(format!("{base}/dav/empty.txt"), "").unwrap();
+        let crypt = Crypt::from_key([1; 32], true);
+        let dav = Path::new(base).join("dav");
+
+        // Act : encrypt the directory, twice as after an interruption
+        let report = crypt.encrypt(&dav).await.unwrap();
+        assert_eq!(report.processed, 2);
+        let report = crypt.encrypt(&dav).await.unwrap();
+        assert_eq!(
+            report,
+            Report {
+                processed: 0,
+                skipped: 2,
+                failed: 0
+            }
+        );
+
+        // Assert that the names and the contents are encrypted, and sound
+        assert!(!dav.join("docs").exists());
+        assert!(!dav.join("empty.txt").exists());
+        assert_eq!(crypt.verify(&dav).await.unwrap().failed, 0);
+        // A file encrypted with another key is not encrypted again
+        let other = Crypt::from_key([2; 32], true);
+        assert_eq!(other.encrypt(&dav).await.unwrap().failed, 2);
+
+        // Act : decrypt the directory
+        let plain = Path::new(base).join("plain");
+        let report = crypt.decrypt(&dav, &plain).await.unwrap();
+        assert_eq!(report.processed, 2);
+        assert_eq!(
+            fs::read_to_string(plain.join("docs/report.txt")).unwrap(),
+            "secret content"
+        );
+        assert_eq!(fs::read_to_string(plain.join("empty.txt")).unwrap(), "");
+
+        // Assert that corrupted and truncated files are reported
+        let docs = fs::read_dir(&dav)
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .find(|path| path.is_dir())
+            .unwrap();
+        let report = fs::read_dir(&docs).unwrap().next().unwrap().unwrap().path();
+        let mut content = fs::read(&report).unwrap();
+        let last = content.len() - 1;
+        content[last] ^= 1;
+        fs::write(&report, &content).unwrap();
+        assert_eq!(crypt.verify(&dav).await.unwrap().failed, 1);
+        fs::write(&report, &content[..content.len() - 30]).unwrap();
+        assert_eq!(crypt.verify(&report).await.unwrap().failed, 1);
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
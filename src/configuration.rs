@@ -10,14 +10,20 @@ use axum::Extension;
 use axum::TypedHeader;
 use hyper::header;
 use hyper::StatusCode;
+use log::error;
 use serde::Deserialize;
 use serde::Serialize;
 
 use crate::apps::App;
 use crate::apps::AppWithUri;
+use crate::davs::content_store::is_on_dav_file_system;
+use crate::davs::keys::load_key;
 use crate::davs::model::Dav;
+use crate::davs::shares::Share;
+use crate::redirects::Redirect;
+use crate::streams::Stream;
 use crate::users::User;
-use sha2::{Digest, Sha256};
+use crate::utils::write_atomically;
 
 fn debug_mode() -> bool {
     false
@@ -48,13 +54,29 @@ pub struct Config {
     pub letsencrypt_email: String,
     pub apps: Vec<App>,
     pub davs: Vec<Dav>,
+    #[serde(default)]
+    pub redirects: Vec<Redirect>,
+    #[serde(default)]
+    pub streams: Vec<Stream>,
     pub users: Vec<User>,
+    #[serde(default)]
+    pub shares: Vec<Share>,
 }
 
 pub type ConfigMap = HashMap<String, HostType>;
 
 pub type ConfigFile = String;
 
+/// Public base url of the main website, where the users log in
+#[derive(Debug, Clone, PartialEq)]
+pub struct MainSite(pub String);
+
+impl MainSite {
+    pub fn scheme(&self) -> &str {
+        self.0.split("://").next().unwrap_or("https")
+    }
+}
+
 impl Config {
     pub async fn from_file(filepath: &str) -> Result<Self> {
         let data = tokio::fs::read_to_string(filepath).await?;
@@ -64,10 +86,32 @@ impl Config {
 
     pub async fn to_file(&self, filepath: &str) -> Result<()> {
         let contents = serde_yaml::to_string::<Config>(self)?;
-        tokio::fs::write(filepath, contents).await?;
+        write_atomically(filepath, contents.as_bytes()).await?;
         Ok(())
     }
 
+    /// All the domains served by vestibule : the main site and every configured service
+    pub fn domains(&self) -> Vec<String> {
+        let mut domains: Vec<String> = self
+            .apps
+            .iter()
+            .map(|app| app.host.as_str())
+            .chain(self.davs.iter().map(|dav| dav.host.as_str()))
+            .chain(self.redirects.iter().map(|redirect| redirect.host.as_str()))
+            .map(|host| format!("{}.{}", host, self.hostname))
+            .collect();
+        domains.insert(0, self.hostname.clone());
+        domains
+    }
+
+    pub fn main_site(&self) -> MainSite {
+        if self.auto_tls {
+            MainSite(format!("https://{}", self.hostname))
+        } else {
+            MainSite(format!("http://{}:{}", self.hostname, self.http_port))
+        }
+    }
+
     pub async fn to_file_or_internal_server_error(
         self,
         filepath: &str,
@@ -101,7 +145,37 @@ where
 }
 
 pub async fn load_config(config_file: &str) -> Result<(Config, Arc<ConfigMap>), anyhow::Error> {
-    let config = Config::from_file(config_file).await?;
+    let mut config = Config::from_file(config_file).await?;
+    let mut davs = config.davs.clone();
+    let mut wrapped = false;
+    for dav in davs.iter_mut() {
+        wrapped |= load_key(dav)?;
+        // The features relying on the local file system are left out on the other storages
+        if dav.storage.is_some() && dav.uses_local_features() {
+            error!(
+                "Dav {} does not store its files locally, its trash, versioning, quota and content store are disabled",
+                dav.host
+            );
+            dav.trash = None;
+            dav.versioning = None;
+            dav.quota = None;
+            dav.content_store.clear();
+        }
+        // The files are hard linked to the store
+        if !dav.content_store.is_empty() && !is_on_dav_file_system(dav)? {
+            anyhow::bail!(
+                "the content store of dav {} is not on the file system of its directory",
+                dav.host
+            );
+        }
+    }
+    // Save the keys wrapped for the davs encrypted before the keys were wrapped
+    if wrapped {
+        for (stored, dav) in config.davs.iter_mut().zip(&davs) {
+            stored.wrapped_key = dav.wrapped_key.clone();
+        }
+        config.to_file(config_file).await?;
+    }
     let hashmap = config
         .apps
         .iter()
@@ -120,19 +194,18 @@ pub async fn load_config(config_file: &str) -> Result<(Config, Arc<ConfigMap>),
                 )),
             )
         })
-        .chain(config.davs.iter().map(|dav| {
-            let mut dav = dav.clone();
-            if dav.passphrase != "" {
-                let mut hasher = Sha256::new();
-                hasher.update(&dav.passphrase);
-                let result: [u8; 32] = hasher.finalize().into();
-                dav.key = Some(result);
-            }
+        .chain(davs.into_iter().map(|dav| {
             (
                 format!("{}.{}", dav.host.to_owned(), config.hostname),
                 HostType::Dav(dav),
             )
         }))
+        .chain(config.redirects.iter().map(|redirect| {
+            (
+                format!("{}.{}", redirect.host.to_owned(), config.hostname),
+                HostType::Redirect(redirect.clone()),
+            )
+        }))
         .collect();
     Ok((config, Arc::new(hashmap)))
 }
@@ -141,6 +214,7 @@ pub async fn load_config(config_file: &str) -> Result<(Config, Arc<ConfigMap>),
 pub enum HostType {
     App(AppWithUri),
     Dav(Dav),
+    Redirect(Redirect),
 }
 
 impl HostType {
@@ -148,6 +222,7 @@ impl HostType {
         match self {
             HostType::App(app) => &app.inner.roles,
             HostType::Dav(dav) => &dav.roles,
+            HostType::Redirect(redirect) => &redirect.roles,
         }
     }
 
@@ -155,6 +230,7 @@ impl HostType {
         match self {
             HostType::App(app) => app.inner.secured,
             HostType::Dav(dav) => dav.secured,
+            HostType::Redirect(redirect) => redirect.secured,
         }
     }
 }
@@ -198,7 +274,20 @@ where
 mod tests {
     use std::fs;
 
-    use crate::{apps::App, configuration::Config, davs::model::Dav, users::User};
+    use crate::{
+        apps::App,
+        configuration::Config,
+        davs::{
+            acl::{AclRule, Permission},
+            model::Dav,
+            quota::Quota,
+            trash::TrashPolicy,
+            versions::VersioningPolicy,
+        },
+        redirects::Redirect,
+        streams::{Protocol, Stream},
+        users::User,
+    };
 
     lazy_static::lazy_static! {
         static ref APPS: Vec<App> = {
@@ -247,7 +336,17 @@ mod tests {
                     secured: true,
                     allow_symlinks: false,
                     roles: vec!["ADMINS".to_owned(),"USERS".to_owned()],
+                    acl: vec![],
+                    skeleton: "".to_owned(),
+                    quota: None,
+                    trash: None,
+                    versioning: None,
+                    content_store: "".to_owned(),
+                    storage: None,
                     passphrase: "ABCD123".to_owned(),
+                    wrapped_key: None,
+                    encrypt_names: false,
+                    migration: None,
                     key: None
                 },
                 Dav {
@@ -261,12 +360,82 @@ mod tests {
                     secured: true,
                     allow_symlinks: true,
                     roles: vec!["USERS".to_owned()],
+                    acl: vec![
+                        AclRule {
+                            path: "/shared/**".to_owned(),
+                            permission: Permission::Write,
+                            roles: vec!["USERS".to_owned()],
+                            users: vec![],
+                        },
+                        AclRule {
+                            path: "/**".to_owned(),
+                            permission: Permission::Admin,
+                            roles: vec![],
+                            users: vec!["admin".to_owned()],
+                        },
+                    ],
+                    skeleton: "".to_owned(),
+                    quota: Some(Quota {
+                        bytes: Some(1_000_000_000),
+                        files: None,
+                    }),
+                    trash: Some(TrashPolicy {
+                        retention_days: None,
+                    }),
+                    versioning: Some(VersioningPolicy {
+                        max_versions: Some(10),
+                        retention_days: None,
+                    }),
+                    content_store: "".to_owned(),
+                    storage: None,
                     passphrase: "".to_owned(),
+                    wrapped_key: None,
+                    encrypt_names: false,
+                    migration: None,
                     key: None
                 },
             ]
         };
 
+        static ref REDIRECTS: Vec<Redirect> = {
+            vec![
+                Redirect {
+                    id: 1,
+                    name: "Wiki".to_owned(),
+                    icon: "book".to_owned(),
+                    color: "#030303".to_owned(),
+                    host: "wiki".to_owned(),
+                    target: "https://wiki.example.com".to_owned(),
+                    permanent: true,
+                    preserve_path: true,
+                    preserve_query: false,
+                    secured: false,
+                    roles: vec!["USERS".to_owned()],
+                },
+            ]
+        };
+
+        static ref STREAMS: Vec<Stream> = {
+            vec![
+                Stream {
+                    id: 1,
+                    name: "SSH".to_owned(),
+                    protocol: Protocol::Tcp,
+                    listen_port: Some(2222),
+                    host: "ssh".to_owned(),
+                    forward_to: "192.168.1.8:22".to_owned(),
+                },
+                Stream {
+                    id: 2,
+                    name: "Game server".to_owned(),
+                    protocol: Protocol::Udp,
+                    listen_port: Some(27015),
+                    host: "".to_owned(),
+                    forward_to: "192.168.1.9:27015".to_owned(),
+                },
+            ]
+        };
+
         static ref USERS: Vec<User> = {
             vec![
                 User {
@@ -294,7 +463,10 @@ mod tests {
             letsencrypt_email: "foo@bar.com".to_owned(),
             apps: APPS.clone(),
             davs: DAVS.clone(),
+            redirects: REDIRECTS.clone(),
+            streams: STREAMS.clone(),
             users: USERS.clone(),
+            shares: vec![],
         };
 
         // Act
@@ -0,0 +1,356 @@
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::users::User;
+
+use super::model::Dav;
+use super::names::NameCipher;
+
+/// Rights that can be granted on a dav subtree, each one implying the previous ones
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    /// Manage the shares made by the other users within the subtree
+    Admin,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct AclRule {
+    /// Glob on the path within the dav : `*` matches inside a path segment, `**` matches any number of segments
+    pub path: String,
+    pub permission: Permission,
+    #[serde(default)]
+    pub roles: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<String>,
+}
+
+impl AclRule {
+    /// A rule without roles nor users applies to everyone, even to unlogged users on unsecured davs
+    fn applies_to(&self, user: &Option<User>) -> bool {
+        if self.roles.is_empty() && self.users.is_empty() {
+            return true;
+        }
+        match user {
+            Some(user) => {
+                self.users.contains(&user.login)
+                    || user.roles.iter().any(|role| self.roles.contains(role))
+            }
+            None => false,
+        }
+    }
+
+    fn matches(&self, path: &str) -> bool {
+        glob_match(&segments(&self.path), &segments(path))
+    }
+
+    /// Whether the rule may match a path below the given one
+    fn matches_below(&self, path: &str) -> bool {
+        glob_match_below(&segments(&self.path), &segments(path))
+    }
+}
+
+/// Access rights of a user on a dav, worked out from the dav's ACL rules
+#[derive(Debug, Clone)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+    user: Option<User>,
+    writable: bool,
+    /// The rules apply to the plain paths of the davs encrypting their file names
+    names: Option<NameCipher>,
+}
+
+impl Acl {
+    pub fn new(dav: &Dav, user: &Option<User>) -> Self {
+        Self {
+            rules: dav.acl.clone(),
+            user: user.clone(),
+            writable: dav.writable,
+            names: NameCipher::new(dav),
+        }
+    }
+
+    /// Highest permission granted on a path (relative to the dav root) by the rules matching the path and the user.
+    /// Without rules, the dav is fully open or read only according to its writable flag, the admin permission being only granted by a rule.
+    /// A non writable dav is always capped to read access.
+    pub fn permission(&self, path: &str) -> Option<Permission> {
+        let permission = if self.rules.is_empty() {
+            Some(Permission::Delete)
+        } else {
+            self.rules
+                .iter()
+                .filter(|rule| rule.applies_to(&self.user) && rule.matches(path))
+                .map(|rule| rule.permission)
+                .max()
+        };
+        if self.writable {
+            permission
+        } else {
+            permission.map(|p| p.min(Permission::Read))
+        }
+    }
+
+    /// Same as `permission`, for a directory : the ancestors of the subtrees granted to the user can be read so that they can be reached,
+    /// their listings only showing what is granted or leads to it.
+    pub fn dir_permission(&self, path: &str) -> Option<Permission> {
+        self.permission(path).or_else(|| {
+            self.rules
+                .iter()
+                .any(|rule| rule.applies_to(&self.user) && rule.matches_below(path))
+                .then_some(Permission::Read)
+        })
+    }
+
+    pub fn allows(&self, path: &str, wanted: Permission) -> bool {
+        self.permission(path).is_some_and(|p| p >= wanted)
+    }
+
+    /// Same as `permission`, but for a file system path within the dav directory.
+    /// Nothing is granted on the paths whose names cannot be decrypted.
+    pub fn permission_fs(&self, path: &Path, directory: &Path) -> Option<Permission> {
+        self.permission(&self.plain_path(path, directory)?)
+    }
+
+    /// Same as `dir_permission`, but for a file system path within the dav directory
+    pub fn dir_permission_fs(&self, path: &Path, directory: &Path) -> Option<Permission> {
+        self.dir_permission(&self.plain_path(path, directory)?)
+    }
+
+    pub fn allows_fs(&self, path: &Path, directory: &Path, wanted: Permission) -> bool {
+        self.permission_fs(path, directory)
+            .is_some_and(|p| p >= wanted)
+    }
+
+    fn plain_path(&self, path: &Path, directory: &Path) -> Option<String> {
+        let rel_path = path.strip_prefix(directory).ok()?;
+        match &self.names {
+            Some(names) => Some(
+                names
+                    .decrypt_path(rel_path)
+                    .ok()?
+                    .to_string_lossy()
+                    .into_owned(),
+            ),
+            None => Some(rel_path.to_string_lossy().into_owned()),
+        }
+    }
+}
+
+fn segments(path: &str) -> Vec<&str> {
+    path.split(['/', '\\'])
+        .filter(|s| !s.is_empty() && *s != ".")
+        .collect()
+}
+
+fn glob_match(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob_match(rest, &path[i..])),
+        Some((segment_pattern, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                segment_match(segment_pattern.as_bytes(), segment.as_bytes())
+                    && glob_match(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Whether the pattern may match a path made of the given one followed by more segments
+fn glob_match_below(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => false,
+        Some((&"**", _)) => true,
+        Some((segment_pattern, rest)) => match path.split_first() {
+            Some((segment, path_rest)) => {
+                segment_match(segment_pattern.as_bytes(), segment.as_bytes())
+                    && glob_match_below(rest, path_rest)
+            }
+            None => true,
+        },
+    }
+}
+
+fn segment_match(pattern: &[u8], segment: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => segment.is_empty(),
+        Some((b'*', rest)) => (0..=segment.len()).any(|i| segment_match(rest, &segment[i..])),
+        Some((c, rest)) => match segment.split_first() {
+            Some((s, segment_rest)) => c == s && segment_match(rest, segment_rest),
+            None => false,
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{
+        davs::{model::Dav, names::NameCipher},
+        users::User,
+    };
+
+    use super::{glob_match, glob_match_below, segments, Acl, AclRule, Permission};
+
+    fn matches(pattern: &str, path: &str) -> bool {
+        glob_match(&segments(pattern), &segments(path))
+    }
+
+    fn matches_below(pattern: &str, path: &str) -> bool {
+        glob_match_below(&segments(pattern), &segments(path))
+    }
+
+    fn user(login: &str, role: &str) -> Option<User> {
+        Some(User {
+            login: login.to_owned(),
+            password: "".to_owned(),
+            roles: vec![role.to_owned()],
+        })
+    }
+
+    fn acl(user: &Option<User>, writable: bool) -> Acl {
+        let dav = Dav {
+            writable,
+            acl: vec![
+                AclRule {
+                    path: "/**".to_owned(),
+                    permission: Permission::Admin,
+                    roles: vec!["ADMINS".to_owned()],
+                    users: vec![],
+                },
+                AclRule {
+                    path: "/shared/**".to_owned(),
+                    permission: Permission::Read,
+                    roles: vec!["USERS".to_owned()],
+                    users: vec![],
+                },
+                AclRule {
+                    path: "/shared/*.txt".to_owned(),
+                    permission: Permission::Write,
+                    roles: vec![],
+                    users: vec!["bob".to_owned()],
+                },
+                AclRule {
+                    path: "/public".to_owned(),
+                    permission: Permission::Read,
+                    roles: vec![],
+                    users: vec![],
+                },
+            ],
+            ..Default::default()
+        };
+        Acl::new(&dav, user)
+    }
+
+    #[test]
+    fn test_glob_match() {
+        assert!(matches("/", ""));
+        assert!(matches("/**", ""));
+        assert!(matches("/**", "/a/b/c"));
+        assert!(matches("/shared/**", "/shared"));
+        assert!(matches("/shared/**", "/shared/a/b"));
+        assert!(!matches("/shared/**", "/sharedx/a"));
+        assert!(matches("/shared/*", "/shared/a"));
+        assert!(!matches("/shared/*", "/shared/a/b"));
+        assert!(matches("/shared/*.txt", "shared/notes.txt"));
+        assert!(!matches("/shared/*.txt", "/shared/notes.md"));
+        assert!(matches("/**/*.txt", "/a/b/notes.txt"));
+        assert!(!matches("/public", "/public/file"));
+
+        assert!(matches_below("/shared/**", "/"));
+        assert!(matches_below("/shared/**", "/shared/a"));
+        assert!(matches_below("/*/notes.txt", "/a"));
+        assert!(!matches_below("/*/notes.txt", "/a/notes.txt"));
+        assert!(!matches_below("/shared/*", "/other"));
+        assert!(!matches_below("/public", "/public"));
+    }
+
+    #[test]
+    fn test_permission() {
+        let admin = acl(&user("admin", "ADMINS"), true);
+        assert_eq!(admin.permission("/any/file"), Some(Permission::Admin));
+
+        let user_acl = acl(&user("alice", "USERS"), true);
+        assert_eq!(user_acl.permission("/"), None);
+        assert_eq!(user_acl.permission("/shared/a.txt"), Some(Permission::Read));
+        assert!(user_acl.allows("/shared", Permission::Read));
+        assert!(!user_acl.allows("/shared/a.txt", Permission::Write));
+        assert!(user_acl.allows("/public", Permission::Read));
+
+        let bob = acl(&user("bob", "USERS"), true);
+        assert!(bob.allows("/shared/a.txt", Permission::Write));
+        assert!(!bob.allows("/shared/a.txt", Permission::Delete));
+        assert!(!bob.allows("/shared/a.md", Permission::Write));
+
+        let anonymous = acl(&None, true);
+        assert!(anonymous.allows("/public", Permission::Read));
+        assert!(!anonymous.allows("/shared", Permission::Read));
+    }
+
+    #[test]
+    fn test_ancestors_of_granted_subtrees_are_readable() {
+        let user_acl = acl(&user("alice", "USERS"), true);
+        assert_eq!(user_acl.dir_permission("/"), Some(Permission::Read));
+        assert_eq!(user_acl.dir_permission("/other"), None);
+        assert_eq!(user_acl.dir_permission("/shared"), Some(Permission::Read));
+        let bob = acl(&user("bob", "USERS"), true);
+        assert_eq!(bob.dir_permission("/shared"), Some(Permission::Read));
+        assert_eq!(bob.dir_permission("/shared/a.txt"), Some(Permission::Write));
+        let anonymous = acl(&None, true);
+        assert_eq!(anonymous.dir_permission("/"), Some(Permission::Read));
+        assert_eq!(anonymous.dir_permission("/shared"), None);
+    }
+
+    #[test]
+    fn test_read_only_dav_caps_permission() {
+        let admin = acl(&user("admin", "ADMINS"), false);
+        assert_eq!(admin.permission("/any/file"), Some(Permission::Read));
+    }
+
+    #[test]
+    fn test_no_rules_falls_back_to_writable_flag() {
+        let dav = Dav {
+            writable: true,
+            ..Default::default()
+        };
+        assert!(Acl::new(&dav, &None).allows("/file", Permission::Delete));
+        assert!(!Acl::new(&dav, &None).allows("/file", Permission::Admin));
+        let dav = Dav {
+            writable: false,
+            ..Default::default()
+        };
+        assert!(Acl::new(&dav, &None).allows("/file", Permission::Read));
+        assert!(!Acl::new(&dav, &None).allows("/file", Permission::Write));
+    }
+
+    #[test]
+    fn test_permission_fs_with_encrypted_names() {
+        let dav = Dav {
+            directory: "data".to_owned(),
+            writable: true,
+            acl: vec![AclRule {
+                path: "/public/**".to_owned(),
+                permission: Permission::Read,
+                roles: vec![],
+                users: vec![],
+            }],
+            key: Some([1; 32]),
+            encrypt_names: true,
+            ..Default::default()
+        };
+        let acl = Acl::new(&dav, &None);
+        let names = NameCipher::new(&dav).unwrap();
+        let directory = Path::new("data");
+        let public = directory.join(names.encrypt_path(Path::new("public/a.txt")).unwrap());
+        let private = directory.join(names.encrypt_path(Path::new("private/a.txt")).unwrap());
+        assert!(acl.allows_fs(&public, directory, Permission::Read));
+        assert!(!acl.allows_fs(&private, directory, Permission::Read));
+        // The rules never match the names left in clear
+        assert!(!acl.allows_fs(&directory.join("public/a.txt"), directory, Permission::Read));
+    }
+}
@@ -0,0 +1,538 @@
+use std::collections::{HashMap, HashSet};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Context, Poll};
+
+use axum::async_trait;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, ReadBuf, Take};
+use tokio::sync::RwLock;
+
+use super::content_store::{is_purge_due, temporary_path};
+use super::storage::{ChunksConfig, DirEntry, LocalStorage, Storage, StorageFile, StorageMetadata};
+use crate::utils::write_atomically;
+
+/// Bounds of the size of the chunks. Between them, the files are cut where their content tells so : inserting
+/// or removing bytes only changes the chunks around them, the next cuts being found at the same places.
+const MIN_CHUNK_SIZE: usize = 256 * 1024;
+const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+/// Cuts once every 2^20 bytes on average, beyond the minimal size
+const CUT_MASK: u64 = ((1 << 20) - 1) << 44;
+
+/// Directory of the chunks within a store
+const CHUNKS_DIR: &str = "chunks";
+/// File of a store listing the directories of the davs referencing its chunks, walked when it is purged
+const ROOTS_FILE: &str = "roots";
+
+lazy_static::lazy_static! {
+    /// Random values of the bytes, rolled into the hash that tells where to cut
+    static ref GEAR: [u64; 256] = {
+        let mut gear = [0; 256];
+        let mut state: u64 = 0x5645_5354_4942_554c;
+        for value in gear.iter_mut() {
+            // splitmix64
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            *value = z ^ (z >> 31);
+        }
+        gear
+    };
+    /// Held shared while chunks are referenced or files moved, and exclusively while a store is purged
+    static ref STORE_LOCKS: Mutex<HashMap<PathBuf, Arc<RwLock<()>>>> = Mutex::new(HashMap::new());
+    static ref REGISTERED_ROOTS: Mutex<HashSet<(PathBuf, PathBuf)>> = Mutex::new(HashSet::new());
+}
+
+/// Chunks a file is made of, saved in place of the file in the dav directory
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+struct Manifest {
+    len: u64,
+    chunks: Vec<Chunk>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct Chunk {
+    /// Hash of the stored content of the chunk, naming it in the store
+    digest: String,
+    len: u64,
+}
+
+impl Manifest {
+    /// Manifest of a file, None if the file is not a manifest
+    async fn load(path: &Path) -> io::Result<Option<Self>> {
+        Ok(serde_json::from_slice(&fs::read(path).await?).ok())
+    }
+
+    async fn load_existing(path: &Path) -> io::Result<Self> {
+        Self::load(path).await?.ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a chunked file", path.display()),
+            )
+        })
+    }
+
+    fn push(&mut self, chunk: Chunk) {
+        self.len += chunk.len;
+        self.chunks.push(chunk);
+    }
+}
+
+/// Files cut into chunks kept in a store by the hash of their content, which the davs sharing the store share.
+/// The dav directory only holds the manifests listing the chunks of the files : a copy is a copy of the manifest,
+/// and the files differing by a few bytes share most of their chunks.
+/// The contents are chunked as stored : on encrypted davs, where each file is encrypted with its own nonce,
+/// the files only share their chunks with their copies.
+pub struct ChunkStorage {
+    store: PathBuf,
+    root: PathBuf,
+}
+
+impl ChunkStorage {
+    pub fn new(config: &ChunksConfig, directory: &str) -> Self {
+        Self {
+            store: PathBuf::from(&config.store),
+            root: PathBuf::from(directory),
+        }
+    }
+
+    fn chunk_path(&self, digest: &str) -> PathBuf {
+        chunk_path(&self.store, digest)
+    }
+
+    /// Record the dav directory in the store, so that its manifests are taken into account by the purges
+    async fn register(&self) -> io::Result<()> {
+        let registered = (self.store.clone(), self.root.clone());
+        if !REGISTERED_ROOTS.lock().unwrap().insert(registered.clone()) {
+            return Ok(());
+        }
+        let lock = store_lock(&self.store);
+        let _guard = lock.write().await;
+        let mut roots = read_roots(&self.store).await?;
+        if !roots.contains(&self.root) {
+            roots.push(self.root.clone());
+            let contents: Vec<String> = roots
+                .iter()
+                .map(|root| root.to_string_lossy().into_owned())
+                .collect();
+            fs::create_dir_all(&self.store).await?;
+            let saved = write_atomically(
+                &self.store.join(ROOTS_FILE).to_string_lossy(),
+                contents.join("\n").as_bytes(),
+            )
+            .await;
+            if let Err(e) = saved {
+                REGISTERED_ROOTS.lock().unwrap().remove(&registered);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Store a chunk, unless the same content is already there
+    async fn put_chunk(&self, content: &[u8]) -> io::Result<Chunk> {
+        let digest = format!("{:x}", Sha256::digest(content));
+        let path = self.chunk_path(&digest);
+        match fs::metadata(&path).await {
+            Ok(_) => (),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::create_dir_all(path.parent().unwrap()).await?;
+                let temp = temporary_path(&path)?;
+                fs::write(&temp, content).await?;
+                if let Err(e) = fs::rename(&temp, &path).await {
+                    fs::remove_file(&temp).await.ok();
+                    return Err(e);
+                }
+            }
+            Err(e) => return Err(e),
+        }
+        Ok(Chunk {
+            digest,
+            len: content.len() as u64,
+        })
+    }
+
+    /// Purge the store in the background, if it was not done recently
+    fn purge_if_due(&self) {
+        if !is_purge_due(&self.store) {
+            return;
+        }
+        let store = self.store.clone();
+        tokio::spawn(async move {
+            if let Err(e) = purge(&store).await {
+                log::error!("could not purge chunk store {}: {}", store.display(), e);
+            }
+        });
+    }
+}
+
+#[async_trait]
+impl Storage for ChunkStorage {
+    async fn metadata(&self, path: &Path) -> io::Result<StorageMetadata> {
+        let mut metadata = LocalStorage.metadata(path).await?;
+        if !metadata.is_dir {
+            metadata.len = Manifest::load_existing(path).await?.len;
+        }
+        Ok(metadata)
+    }
+
+    async fn read_dir(&self, path: &Path) -> io::Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+        for mut entry in LocalStorage.read_dir(path).await? {
+            if !entry.metadata.is_dir {
+                // The files being written are left out, as the broken links of the local storage
+                match Manifest::load(&path.join(&entry.name)).await? {
+                    Some(manifest) => entry.metadata.len = manifest.len,
+                    None => continue,
+                }
+            }
+            entries.push(entry);
+        }
+        Ok(entries)
+    }
+
+    async fn open(&self, path: &Path) -> io::Result<Box<dyn StorageFile>> {
+        let manifest = Manifest::load_existing(path).await?;
+        let mut offsets = Vec::with_capacity(manifest.chunks.len());
+        let mut offset = 0;
+        for chunk in &manifest.chunks {
+            offsets.push(offset);
+            offset += chunk.len;
+        }
+        Ok(Box::new(ChunkedFile {
+            store: self.store.clone(),
+            manifest,
+            offsets,
+            position: 0,
+            state: ChunkedFileState::Idle,
+        }))
+    }
+
+    async fn write(
+        &self,
+        path: &Path,
+        reader: &mut (dyn AsyncRead + Unpin + Send),
+    ) -> io::Result<u64> {
+        self.register().await?;
+        let lock = store_lock(&self.store);
+        let _guard = lock.read().await;
+        let mut manifest = Manifest::default();
+        let mut chunk = Vec::with_capacity(MIN_CHUNK_SIZE);
+        let mut hash: u64 = 0;
+        let mut buffer = vec![0; 65536];
+        loop {
+            let read = reader.read(&mut buffer).await?;
+            if read == 0 {
+                break;
+            }
+            let mut start = 0;
+            for (i, byte) in buffer[..read].iter().enumerate() {
+                hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+                let len = chunk.len() + i + 1 - start;
+                if (len >= MIN_CHUNK_SIZE && hash & CUT_MASK == 0) || len >= MAX_CHUNK_SIZE {
+                    chunk.extend_from_slice(&buffer[start..=i]);
+                    manifest.push(self.put_chunk(&chunk).await?);
+                    chunk.clear();
+                    hash = 0;
+                    start = i + 1;
+                }
+            }
+            chunk.extend_from_slice(&buffer[start..read]);
+        }
+        if !chunk.is_empty() {
+            manifest.push(self.put_chunk(&chunk).await?);
+        }
+
+        // The manifest replaces the file at once, once its chunks are stored
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        let temp = temporary_path(path)?;
+        fs::write(&temp, serde_json::to_vec(&manifest)?).await?;
+        if let Err(e) = fs::rename(&temp, path).await {
+            fs::remove_file(&temp).await.ok();
+            return Err(e);
+        }
+        self.purge_if_due();
+        Ok(manifest.len)
+    }
+
+    async fn create_dir(&self, path: &Path) -> io::Result<()> {
+        LocalStorage.create_dir(path).await
+    }
+
+    async fn remove(&self, path: &Path) -> io::Result<()> {
+        LocalStorage.remove(path).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // The purges do not see a manifest being copied until it is complete
+        let lock = store_lock(&self.store);
+        let _guard = lock.read().await;
+        LocalStorage.copy(from, to).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        // Nor a manifest moved from a directory they did not walk yet to one they already walked
+        let lock = store_lock(&self.store);
+        let _guard = lock.read().await;
+        LocalStorage.rename(from, to).await
+    }
+}
+
+/// Content of a chunked file, read chunk after chunk from the position it is read from
+struct ChunkedFile {
+    store: PathBuf,
+    manifest: Manifest,
+    /// Positions of the chunks within the file
+    offsets: Vec<u64>,
+    position: u64,
+    state: ChunkedFileState,
+}
+
+enum ChunkedFileState {
+    Idle,
+    Opening(BoxFuture<'static, io::Result<Take<fs::File>>>),
+    Reading(Take<fs::File>),
+}
+
+impl AsyncRead for ChunkedFile {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = &mut *self;
+        loop {
+            match &mut this.state {
+                ChunkedFileState::Idle => {
+                    if this.position >= this.manifest.len {
+                        return Poll::Ready(Ok(()));
+                    }
+                    let index = this
+                        .offsets
+                        .partition_point(|offset| *offset <= this.position)
+                        - 1;
+                    let chunk = &this.manifest.chunks[index];
+                    let path = chunk_path(&this.store, &chunk.digest);
+                    let within = this.position - this.offsets[index];
+                    let left = chunk.len - within;
+                    this.state = ChunkedFileState::Opening(Box::pin(async move {
+                        let mut file = fs::File::open(path).await?;
+                        file.seek(SeekFrom::Start(within)).await?;
+                        Ok(file.take(left))
+                    }));
+                }
+                ChunkedFileState::Opening(opening) => {
+                    let file = ready!(opening.poll_unpin(cx));
+                    this.state = ChunkedFileState::Idle;
+                    let file = file?;
+                    this.state = ChunkedFileState::Reading(file);
+                }
+                ChunkedFileState::Reading(file) => {
+                    let filled = buf.filled().len();
+                    ready!(Pin::new(&mut *file).poll_read(cx, buf))?;
+                    let read = buf.filled().len() - filled;
+                    if read == 0 && buf.remaining() > 0 {
+                        if file.limit() > 0 {
+                            return Poll::Ready(Err(io::ErrorKind::UnexpectedEof.into()));
+                        }
+                        // The chunk is read, the next one is opened
+                        this.state = ChunkedFileState::Idle;
+                        continue;
+                    }
+                    this.position += read as u64;
+                    return Poll::Ready(Ok(()));
+                }
+            }
+        }
+    }
+}
+
+impl AsyncSeek for ChunkedFile {
+    fn start_seek(mut self: Pin<&mut Self>, position: SeekFrom) -> io::Result<()> {
+        let position = match position {
+            SeekFrom::Start(position) => Some(position),
+            SeekFrom::End(offset) => self.manifest.len.checked_add_signed(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+        }
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek position"))?;
+        // The current chunk is read on if the position does not change
+        if position != self.position {
+            self.position = position;
+            self.state = ChunkedFileState::Idle;
+        }
+        Ok(())
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<u64>> {
+        Poll::Ready(Ok(self.position))
+    }
+}
+
+fn chunk_path(store: &Path, digest: &str) -> PathBuf {
+    store.join(CHUNKS_DIR).join(&digest[..2]).join(digest)
+}
+
+fn store_lock(store: &Path) -> Arc<RwLock<()>> {
+    STORE_LOCKS
+        .lock()
+        .unwrap()
+        .entry(store.to_owned())
+        .or_default()
+        .clone()
+}
+
+async fn read_roots(store: &Path) -> io::Result<Vec<PathBuf>> {
+    match fs::read_to_string(store.join(ROOTS_FILE)).await {
+        Ok(roots) => Ok(roots.lines().map(PathBuf::from).collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
+
+/// Drop the chunks that no manifest of the davs of a store references anymore
+pub async fn purge(store: &Path) -> io::Result<()> {
+    let lock = store_lock(store);
+    let _guard = lock.write().await;
+    let mut referenced = HashSet::new();
+    for root in read_roots(store).await? {
+        let mut dirs = vec![root];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            while let Some(entry) = entries.next_entry().await? {
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    dirs.push(entry.path());
+                } else if file_type.is_file() {
+                    if let Some(manifest) = Manifest::load(&entry.path()).await? {
+                        referenced.extend(manifest.chunks.into_iter().map(|chunk| chunk.digest));
+                    }
+                }
+            }
+        }
+    }
+    let mut prefixes = match fs::read_dir(store.join(CHUNKS_DIR)).await {
+        Ok(prefixes) => prefixes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(prefix) = prefixes.next_entry().await? {
+        let mut entries = fs::read_dir(prefix.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if !referenced.contains(entry.file_name().to_string_lossy().as_ref()) {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+    use super::{purge, ChunkStorage, CHUNKS_DIR, MIN_CHUNK_SIZE};
+    use crate::davs::storage::{ChunksConfig, Storage};
+
+    fn chunk_count(store: &str) -> usize {
+        fs::read_dir(format!("{store}/{CHUNKS_DIR}"))
+            .map(|prefixes| {
+                prefixes
+                    .flat_map(|prefix| fs::read_dir(prefix.unwrap().path()).unwrap())
+                    .count()
+            })
+            .unwrap_or(0)
+    }
+
+    #[tokio::test]
+    async fn test_chunked_files() {
+        // Arrange : a content of a few chunks, that does not repeat itself
+        let base = "chunks_test";
+        let store = format!("{base}/store");
+        let storage = ChunkStorage::new(
+            &ChunksConfig {
+                store: store.clone(),
+            },
+            &format!("{base}/dav"),
+        );
+        let mut state: u32 = 1;
+        let content: Vec<u8> = (0..24 * MIN_CHUNK_SIZE)
+            .map(|_| {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (state >> 16) as u8
+            })
+            .collect();
+        let path = format!("{base}/dav/dir/file.bin");
+
+        // Act
+        let written = storage
+            .write(path.as_ref(), &mut content.as_slice())
+            .await
+            .unwrap();
+
+        // Assert that the file can be read whole and from any position
+        assert_eq!(written, content.len() as u64);
+        assert_eq!(
+            storage.metadata(path.as_ref()).await.unwrap().len,
+            content.len() as u64
+        );
+        let chunks = chunk_count(&store);
+        assert!(chunks > 1);
+        let mut read = vec![];
+        let mut file = storage.open(path.as_ref()).await.unwrap();
+        file.read_to_end(&mut read).await.unwrap();
+        assert!(read == content);
+        let position = 3 * MIN_CHUNK_SIZE + 17;
+        file.seek(std::io::SeekFrom::Start(position as u64))
+            .await
+            .unwrap();
+        let mut read = vec![0; MIN_CHUNK_SIZE];
+        file.read_exact(&mut read).await.unwrap();
+        assert!(read == content[position..position + MIN_CHUNK_SIZE]);
+
+        // Act : store the same content with a byte inserted at its beginning
+        let mut shifted = vec![0];
+        shifted.extend(&content);
+        storage
+            .write(
+                format!("{base}/dav/shifted.bin").as_ref(),
+                &mut shifted.as_slice(),
+            )
+            .await
+            .unwrap();
+
+        // Assert that only the first chunk differs
+        assert_eq!(chunk_count(&store), chunks + 1);
+
+        // Act : remove the first file, and purge the store
+        storage.remove(path.as_ref()).await.unwrap();
+        purge(store.as_ref()).await.unwrap();
+
+        // Assert that only the chunks of the other file are left
+        assert_eq!(chunk_count(&store), chunks);
+        let mut read = vec![];
+        let mut file = storage
+            .open(format!("{base}/dav/shifted.bin").as_ref())
+            .await
+            .unwrap();
+        file.read_to_end(&mut read).await.unwrap();
+        assert!(read == shifted);
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
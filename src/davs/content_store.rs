@@ -0,0 +1,257 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+
+use super::encrypted_streamer::EncryptedStreamer;
+use super::model::Dav;
+use super::PARTIAL_EXTENSION;
+
+/// Minimal delay between two purges of a store
+const PURGE_INTERVAL: Duration = Duration::from_secs(3600);
+
+lazy_static::lazy_static! {
+    static ref LAST_PURGES: Mutex<HashMap<PathBuf, Instant>> = Mutex::new(HashMap::new());
+}
+
+/// Content addressed store deduplicating the files of the davs sharing it, keeping the local features of the davs.
+/// Whole files are deduplicated : two files share their storage only if their contents are identical, which covers
+/// the same file uploaded to several davs and the copies. The chunked storage of [`super::chunks`] deduplicates
+/// the files differing by a few bytes too, without the local features.
+/// The files of the dav tree are hard links to the stored contents, so the store must be on the same file system as the davs.
+/// As the files are never modified in place (they are replaced by renaming a new file over them), the links can be shared safely.
+/// Contents are addressed by the hash of their plain text, mixed with the key on encrypted davs : only the davs sharing
+/// a key share their encrypted contents, which are linked as is.
+pub struct ContentStore {
+    dir: PathBuf,
+    key: Option<[u8; 32]>,
+}
+
+impl ContentStore {
+    /// Store of a dav, if it has one
+    pub fn new(dav: &Dav) -> Option<Self> {
+        if dav.content_store.is_empty() {
+            return None;
+        }
+        Some(Self {
+            dir: PathBuf::from(&dav.content_store),
+            key: dav.key,
+        })
+    }
+
+    /// Add a newly written file to the store, replacing it by a link to the same content if it is already stored
+    pub async fn store(&self, path: &Path) -> io::Result<()> {
+        let stored = self.content_path(&self.digest(path).await?);
+        fs::create_dir_all(stored.parent().unwrap()).await?;
+        loop {
+            match fs::hard_link(path, &stored).await {
+                Ok(_) => break,
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => (),
+                Err(e) => return Err(e),
+            }
+            // The link is renamed over the file, which is never missing, whatever a purge does meanwhile
+            let link = temporary_path(path)?;
+            match fs::hard_link(&stored, &link).await {
+                Ok(_) => (),
+                // Purged since it was found, the file is stored in its place
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            }
+            if let Err(e) = fs::rename(&link, path).await {
+                fs::remove_file(&link).await.ok();
+                return Err(e);
+            }
+            break;
+        }
+        self.purge_if_due();
+        Ok(())
+    }
+
+    /// Hash of the plain content of a file
+    async fn digest(&self, path: &Path) -> io::Result<String> {
+        let mut hasher = Sha256::new();
+        let file = fs::File::open(path).await?;
+        if let Some(key) = self.key {
+            hasher.update(key);
+            let mut stream = EncryptedStreamer::new(file, key).into_stream();
+            while let Some(chunk) = stream.next().await {
+                hasher.update(chunk?);
+            }
+        } else {
+            let mut file = file;
+            let mut buffer = vec![0; 65536];
+            loop {
+                let read = file.read(&mut buffer).await?;
+                if read == 0 {
+                    break;
+                }
+                hasher.update(&buffer[..read]);
+            }
+        }
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+
+    fn content_path(&self, digest: &str) -> PathBuf {
+        self.dir.join(&digest[..2]).join(digest)
+    }
+
+    /// Purge the store in the background, if it was not done recently
+    pub fn purge_if_due(&self) {
+        if !is_purge_due(&self.dir) {
+            return;
+        }
+        let dir = self.dir.clone();
+        tokio::spawn(async move {
+            if let Err(e) = purge(&dir).await {
+                log::error!("could not purge content store {}: {}", dir.display(), e);
+            }
+        });
+    }
+}
+
+/// Whether the store of a dav can hold hard links to its files, being on the same file system as its directory.
+/// The paths not created yet are checked through their nearest existing ancestor.
+#[cfg(unix)]
+pub fn is_on_dav_file_system(dav: &Dav) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let device = |path: &Path| -> io::Result<u64> {
+        let existing = path
+            .ancestors()
+            .find(|ancestor| ancestor.exists())
+            .unwrap_or_else(|| Path::new("."));
+        Ok(std::fs::metadata(existing)?.dev())
+    };
+    Ok(device(Path::new(&dav.content_store))? == device(Path::new(&dav.directory))?)
+}
+
+#[cfg(not(unix))]
+pub fn is_on_dav_file_system(_dav: &Dav) -> io::Result<bool> {
+    Ok(true)
+}
+
+/// Hidden path next to a file, where what replaces the file is made
+pub(super) fn temporary_path(path: &Path) -> io::Result<PathBuf> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no file name"))?;
+    Ok(path.with_file_name(format!(
+        ".{}.{}.{}",
+        file_name.to_string_lossy(),
+        Uuid::new_v4().simple(),
+        PARTIAL_EXTENSION
+    )))
+}
+
+/// Whether a store was not purged recently, the purge being then counted as done
+pub(super) fn is_purge_due(dir: &Path) -> bool {
+    let mut last_purges = LAST_PURGES.lock().unwrap();
+    if last_purges
+        .get(dir)
+        .is_some_and(|last| last.elapsed() < PURGE_INTERVAL)
+    {
+        return false;
+    }
+    last_purges.insert(dir.to_owned(), Instant::now());
+    true
+}
+
+/// Drop the contents that are not linked from any dav anymore
+#[cfg(unix)]
+pub async fn purge(dir: &Path) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let mut prefixes = match fs::read_dir(dir).await {
+        Ok(prefixes) => prefixes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    while let Some(prefix) = prefixes.next_entry().await? {
+        let mut entries = fs::read_dir(prefix.path()).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if entry.metadata().await?.nlink() == 1 {
+                fs::remove_file(entry.path()).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn purge(_dir: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::davs::model::Dav;
+
+    use super::{is_on_dav_file_system, purge, ContentStore};
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_dav_file_system() {
+        let mut dav = Dav {
+            directory: "content_store_fs_test/dav".to_owned(),
+            content_store: "content_store_fs_test/store".to_owned(),
+            ..Default::default()
+        };
+        assert!(is_on_dav_file_system(&dav).unwrap());
+        dav.content_store = "/proc/store".to_owned();
+        assert!(!is_on_dav_file_system(&dav).unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_deduplication() {
+        // Arrange
+        let base = "content_store_test";
+        fs::create_dir_all(format!("{base}/dav")).unwrap();
+        let dav = Dav {
+            directory: format!("{base}/dav"),
+            content_store: format!("{base}/store"),
+            ..Default::default()
+        };
+        let store = ContentStore::new(&dav).unwrap();
+        for (name, content) in [("a", "same"), ("b", "same"), ("c", "other")] {
+            fs::write(format!("{base}/dav/{name}"), content).unwrap();
+
+            // Act
+            store
+                .store(format!("{base}/dav/{name}").as_ref())
+                .await
+                .unwrap();
+        }
+
+        // Assert that the same contents are only stored once
+        let stored: Vec<_> = fs::read_dir(format!("{base}/store"))
+            .unwrap()
+            .flat_map(|prefix| fs::read_dir(prefix.unwrap().path()).unwrap())
+            .collect();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(fs::read_to_string(format!("{base}/dav/b")).unwrap(), "same");
+
+        // Act : remove the only file with the other content
+        fs::remove_file(format!("{base}/dav/c")).unwrap();
+        purge(format!("{base}/store").as_ref()).await.unwrap();
+
+        // Assert
+        #[cfg(unix)]
+        {
+            let stored: Vec<_> = fs::read_dir(format!("{base}/store"))
+                .unwrap()
+                .flat_map(|prefix| fs::read_dir(prefix.unwrap().path()).unwrap())
+                .collect();
+            assert_eq!(stored.len(), 1);
+        }
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -7,6 +7,7 @@ use chacha20poly1305::XChaCha20Poly1305;
 use futures::{Stream, StreamExt};
 use rand::rngs::OsRng;
 use rand::RngCore;
+use sha2::{Digest, Sha256};
 use std::io::{Error, ErrorKind};
 use std::pin::Pin;
 
@@ -14,12 +15,171 @@ use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt
 
 pub const PLAIN_CHUNK_SIZE: usize = 1_000_000; // 1 MByte
 pub const ENCRYPTION_OVERHEAD: usize = 16;
-pub const ENCRYPTED_CHUNK_SIZE: usize = PLAIN_CHUNK_SIZE + ENCRYPTION_OVERHEAD;
 pub const NONCE_SIZE: usize = 19;
+pub const KEY_ID_SIZE: usize = 8;
+
+/// Magic bytes beginning the header of the encrypted files
+const MAGIC: &[u8; 4] = b"VSTB";
+pub const FORMAT_VERSION: u8 = 1;
+/// Version of the files written before the header, which only begin with the nonce
+const LEGACY_VERSION: u8 = 0;
+/// XChaCha20Poly1305 used in the STREAM construction, with big endian 32 bits counters
+const XCHACHA20POLY1305_STREAM_BE32: u8 = 1;
+/// Upper bound of the chunk size, so that a corrupted header does not make readers allocate at will
+const MAX_CHUNK_SIZE: u32 = 64_000_000;
+pub const HEADER_SIZE: usize = MAGIC.len() + 1 + 1 + 4 + KEY_ID_SIZE + NONCE_SIZE;
+
+/// Header of the encrypted files : magic bytes, format version, cipher, plain chunk size (big endian u32), key id and nonce.
+/// The files written before the header only begin with the nonce : they are told apart by the missing magic bytes,
+/// which a random nonce begins with once in four billion times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Header {
+    pub version: u8,
+    pub cipher: u8,
+    pub chunk_size: u32,
+    /// Truncated hash of the key, missing on the legacy files
+    pub key_id: Option<[u8; KEY_ID_SIZE]>,
+    pub nonce: [u8; NONCE_SIZE],
+}
+
+impl Header {
+    /// Header of a new file encrypted with the given key
+    pub fn new(key: &[u8; 32]) -> Self {
+        let mut nonce = [0; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        Self {
+            version: FORMAT_VERSION,
+            cipher: XCHACHA20POLY1305_STREAM_BE32,
+            chunk_size: PLAIN_CHUNK_SIZE as u32,
+            key_id: Some(key_id(key)),
+            nonce,
+        }
+    }
+
+    pub async fn read<R>(reader: &mut R) -> Result<Self, Error>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut magic = [0; MAGIC.len()];
+        reader.read_exact(&mut magic).await?;
+        if &magic != MAGIC {
+            let mut nonce = [0; NONCE_SIZE];
+            nonce[..MAGIC.len()].copy_from_slice(&magic);
+            reader.read_exact(&mut nonce[MAGIC.len()..]).await?;
+            return Ok(Self {
+                version: LEGACY_VERSION,
+                cipher: XCHACHA20POLY1305_STREAM_BE32,
+                chunk_size: PLAIN_CHUNK_SIZE as u32,
+                key_id: None,
+                nonce,
+            });
+        }
+        let mut rest = [0; HEADER_SIZE - MAGIC.len()];
+        reader.read_exact(&mut rest).await?;
+        let (version, cipher) = (rest[0], rest[1]);
+        if version != FORMAT_VERSION {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported encrypted file version: {}", version),
+            ));
+        }
+        if cipher != XCHACHA20POLY1305_STREAM_BE32 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unsupported encrypted file cipher: {}", cipher),
+            ));
+        }
+        let chunk_size = u32::from_be_bytes(rest[2..6].try_into().unwrap());
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("invalid encrypted file chunk size: {}", chunk_size),
+            ));
+        }
+        Ok(Self {
+            version,
+            cipher,
+            chunk_size,
+            key_id: Some(rest[6..6 + KEY_ID_SIZE].try_into().unwrap()),
+            nonce: rest[6 + KEY_ID_SIZE..].try_into().unwrap(),
+        })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let key_id = match self.key_id {
+            Some(key_id) if self.version != LEGACY_VERSION => key_id,
+            _ => return self.nonce.to_vec(),
+        };
+        let mut bytes = Vec::with_capacity(HEADER_SIZE);
+        bytes.extend(MAGIC);
+        bytes.push(self.version);
+        bytes.push(self.cipher);
+        bytes.extend(self.chunk_size.to_be_bytes());
+        bytes.extend(key_id);
+        bytes.extend(self.nonce);
+        bytes
+    }
+
+    /// Size of the header in the file
+    pub fn size(&self) -> u64 {
+        if self.version == LEGACY_VERSION {
+            NONCE_SIZE as u64
+        } else {
+            HEADER_SIZE as u64
+        }
+    }
+
+    /// Fail early for the files encrypted with another key, rather than on the first chunk
+    pub fn check_key(&self, key: &[u8; 32]) -> Result<(), Error> {
+        match self.key_id {
+            Some(key_id) if key_id != self::key_id(key) => Err(Error::new(
+                ErrorKind::InvalidData,
+                "file encrypted with another key",
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn plain_chunk_size(&self) -> u64 {
+        self.chunk_size as u64
+    }
+
+    pub fn encrypted_chunk_size(&self) -> u64 {
+        self.chunk_size as u64 + ENCRYPTION_OVERHEAD as u64
+    }
+
+    /// Size of the plain content of a file of the given size, files too short to be valid are empty
+    pub fn decrypted_size(&self, enc_size: u64) -> u64 {
+        let enc_size_without_header = enc_size.saturating_sub(self.size());
+        let number_of_chunks = {
+            let d = enc_size_without_header / self.encrypted_chunk_size();
+            let r = enc_size_without_header % self.encrypted_chunk_size();
+            if r > 0 {
+                d + 1
+            } else {
+                d
+            }
+        };
+        enc_size_without_header.saturating_sub(ENCRYPTION_OVERHEAD as u64 * number_of_chunks)
+    }
+
+    pub fn encrypted_offset(&self, dec_offset: u64) -> u64 {
+        let number_of_chunks = dec_offset / self.plain_chunk_size() + 1;
+        dec_offset + ENCRYPTION_OVERHEAD as u64 * number_of_chunks + self.size()
+    }
+}
+
+/// Identifier of a key, stored in the header of the files it encrypts
+pub fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_SIZE] {
+    let mut hasher = Sha256::new();
+    hasher.update(b"vestibule key id");
+    hasher.update(key);
+    hasher.finalize()[..KEY_ID_SIZE].try_into().unwrap()
+}
 
 pub struct EncryptedStreamer<I>
 where
-    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    I: AsyncRead + Unpin + Send + 'static,
 {
     inner: I,
     key: [u8; 32],
@@ -27,7 +187,7 @@ where
 
 impl<I> EncryptedStreamer<I>
 where
-    I: AsyncRead + AsyncWrite + AsyncSeekExt + Unpin + Send + 'static,
+    I: AsyncRead + AsyncSeekExt + Unpin + Send + 'static,
 {
     #[inline]
     pub fn new(inner: I, key: [u8; 32]) -> Self {
@@ -36,20 +196,21 @@ where
 
     pub async fn copy_from<'a, R>(&'a mut self, mut reader: &'a mut R) -> Result<u64, Error>
     where
+        I: AsyncWrite,
         R: AsyncRead + Unpin + ?Sized,
     {
-        let mut nonce = [0; NONCE_SIZE];
-        OsRng.fill_bytes(&mut nonce);
+        let header = Header::new(&self.key);
         let aead = XChaCha20Poly1305::new(self.key.as_ref().into());
-        let mut stream_encryptor = stream::EncryptorBE32::from_aead(aead, nonce.as_ref().into());
+        let mut stream_encryptor =
+            stream::EncryptorBE32::from_aead(aead, header.nonce.as_ref().into());
 
-        // Write the nonce as stream header
-        self.inner.write_all(&nonce).await?;
+        self.inner.write_all(&header.to_bytes()).await?;
         let mut total_count = 0;
+        let chunk_size = header.chunk_size as usize;
 
         loop {
-            let mut buffer = Vec::with_capacity(PLAIN_CHUNK_SIZE);
-            let mut chunked_reader = reader.take(PLAIN_CHUNK_SIZE.try_into().unwrap());
+            let mut buffer = Vec::with_capacity(chunk_size);
+            let mut chunked_reader = reader.take(header.plain_chunk_size());
 
             let read_count = chunked_reader.read_to_end(&mut buffer).await?;
             total_count += read_count;
@@ -57,7 +218,7 @@ where
             reader = chunked_reader.into_inner();
             buffer.truncate(read_count);
 
-            if read_count == PLAIN_CHUNK_SIZE {
+            if read_count == chunk_size {
                 let ciphertext = stream_encryptor
                     .encrypt_next(buffer.as_slice())
                     .map_err(|e| {
@@ -88,16 +249,18 @@ where
     where
         W: AsyncWrite + Unpin + ?Sized,
     {
-        let nonce = self.retrieve_nonce().await?;
+        let header = self.read_header().await?;
         let aead = XChaCha20Poly1305::new(self.key.as_ref().into());
 
-        let mut stream_decryptor = stream::DecryptorBE32::from_aead(aead, nonce.as_ref().into());
+        let mut stream_decryptor =
+            stream::DecryptorBE32::from_aead(aead, header.nonce.as_ref().into());
 
         let mut total_count = 0;
+        let chunk_size = header.encrypted_chunk_size() as usize;
 
         loop {
-            let mut buffer = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE);
-            let mut reader = self.inner.take(ENCRYPTED_CHUNK_SIZE.try_into().unwrap());
+            let mut buffer = Vec::with_capacity(chunk_size);
+            let mut reader = self.inner.take(header.encrypted_chunk_size());
 
             let read_count = reader.read_to_end(&mut buffer).await?;
             total_count += read_count;
@@ -105,7 +268,7 @@ where
             self.inner = reader.into_inner();
             buffer.truncate(read_count);
 
-            if read_count == ENCRYPTED_CHUNK_SIZE {
+            if read_count == chunk_size {
                 let plaintext = stream_decryptor
                     .decrypt_next(buffer.as_slice())
                     .map_err(|e| {
@@ -138,19 +301,20 @@ where
     ) -> Pin<Box<impl ?Sized + Stream<Item = Result<Vec<u8>, Error>> + 'static>> {
         let stream = stream! {
             let aead = XChaCha20Poly1305::new(self.key.as_ref().into());
-            let nonce = self.retrieve_nonce().await?;
-            let mut stream_decryptor = stream::DecryptorBE32::from_aead(aead, nonce.as_ref().into());
+            let header = self.read_header().await?;
+            let mut stream_decryptor = stream::DecryptorBE32::from_aead(aead, header.nonce.as_ref().into());
+            let chunk_size = header.encrypted_chunk_size() as usize;
 
              loop {
-                let mut buffer = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE);
-                let mut reader = self.inner.take(ENCRYPTED_CHUNK_SIZE.try_into().unwrap());
+                let mut buffer = Vec::with_capacity(chunk_size);
+                let mut reader = self.inner.take(header.encrypted_chunk_size());
 
                 let read_count = reader.read_to_end(&mut buffer).await?;
 
                 self.inner = reader.into_inner();
                 buffer.truncate(read_count);
 
-                if read_count == ENCRYPTED_CHUNK_SIZE {
+                if read_count == chunk_size {
                     let plaintext = match stream_decryptor
                         .decrypt_next(buffer.as_slice()) {
                             Ok(plaintext) => plaintext,
@@ -182,9 +346,10 @@ where
     ) -> Pin<Box<impl ?Sized + Stream<Item = Result<Vec<u8>, Error>> + 'static>> {
         let stream = stream! {
             let aead = XChaCha20Poly1305::new(self.key.as_ref().into());
-            let nonce = self.retrieve_nonce().await?;
-            let stream_decryptor = stream::StreamBE32::from_aead(aead, nonce.as_ref().into());
-            let mut chunked_position = ChunkedPosition::new(start);
+            let header = self.read_header().await?;
+            let stream_decryptor = stream::StreamBE32::from_aead(aead, header.nonce.as_ref().into());
+            let mut chunked_position = ChunkedPosition::new(start, &header);
+            let chunk_size = header.encrypted_chunk_size() as usize;
             self.inner.seek(std::io::SeekFrom::Start(chunked_position.beginning_of_active_chunk)).await?;
 
 
@@ -193,13 +358,13 @@ where
                 if remaining == 0 {
                     break;
                 }
-                let mut buffer = Vec::with_capacity(ENCRYPTED_CHUNK_SIZE);
-                let mut reader = self.inner.take(ENCRYPTED_CHUNK_SIZE.try_into().unwrap());
+                let mut buffer = Vec::with_capacity(chunk_size);
+                let mut reader = self.inner.take(header.encrypted_chunk_size());
                 let read_count = reader.read_to_end(&mut buffer).await?;
                 self.inner = reader.into_inner();
                 buffer.truncate(read_count);
 
-                if read_count == ENCRYPTED_CHUNK_SIZE {
+                if read_count == chunk_size {
                     let mut plaintext = match stream_decryptor
                         .decrypt(chunked_position.active_chunk_counter as u32, false, buffer.as_slice()) {
                             Ok(plaintext) => plaintext,
@@ -250,30 +415,72 @@ where
         stream.boxed()
     }
 
-    pub async fn retrieve_nonce(&mut self) -> Result<[u8; NONCE_SIZE], std::io::Error> {
-        let mut nonce = [0u8; NONCE_SIZE];
-        self.inner.read_exact(&mut nonce).await?;
-        Ok(nonce)
+    async fn read_header(&mut self) -> Result<Header, Error> {
+        let header = Header::read(&mut self.inner).await?;
+        header.check_key(&self.key)?;
+        Ok(header)
     }
 }
 
-pub fn decrypted_size(enc_size: u64) -> u64 {
-    let number_of_chunks = {
-        let enc_size_without_nonce = enc_size - NONCE_SIZE as u64;
-        let d = enc_size_without_nonce / ENCRYPTED_CHUNK_SIZE as u64;
-        let r = enc_size_without_nonce % ENCRYPTED_CHUNK_SIZE as u64;
-        if r > 0 {
-            d + 1
-        } else {
-            d
+/// Encrypt a single chunk of a stream, for the uploads that are spread over several requests.
+/// Chunks must be PLAIN_CHUNK_SIZE long, but for the last one that must be shorter (or empty).
+pub fn encrypt_chunk(
+    key: &[u8; 32],
+    nonce: &[u8; NONCE_SIZE],
+    position: u32,
+    last: bool,
+    plaintext: &[u8],
+) -> Result<Vec<u8>, Error> {
+    let aead = XChaCha20Poly1305::new(key.as_ref().into());
+    stream::StreamBE32::from_aead(aead, nonce.as_ref().into())
+        .encrypt(position, last, plaintext)
+        .map_err(|e| {
+            Error::new(
+                ErrorKind::Other,
+                format!("error encrypting plaintext: {}", e),
+            )
+        })
+}
+
+/// Encrypt the content of a reader as it is read, for the storages that are given a reader rather than written to
+pub fn encrypted_stream<'a, R>(
+    mut reader: R,
+    key: [u8; 32],
+) -> impl Stream<Item = Result<Vec<u8>, Error>> + Send + 'a
+where
+    R: AsyncRead + Unpin + Send + 'a,
+{
+    let header = Header::new(&key);
+    stream! {
+        yield Ok(header.to_bytes());
+        let mut position = 0;
+        loop {
+            let mut buffer = Vec::with_capacity(PLAIN_CHUNK_SIZE);
+            (&mut reader)
+                .take(PLAIN_CHUNK_SIZE as u64)
+                .read_to_end(&mut buffer)
+                .await?;
+            let last = buffer.len() < PLAIN_CHUNK_SIZE;
+            yield encrypt_chunk(&key, &header.nonce, position, last, &buffer);
+            if last {
+                break;
+            }
+            position += 1;
         }
-    };
-    enc_size - ENCRYPTION_OVERHEAD as u64 * number_of_chunks - NONCE_SIZE as u64
+    }
 }
 
-pub fn encrypted_offset(dec_offset: u64) -> u64 {
-    let number_of_chunks = dec_offset / PLAIN_CHUNK_SIZE as u64 + 1;
-    dec_offset + ENCRYPTION_OVERHEAD as u64 * number_of_chunks + NONCE_SIZE as u64
+/// Size of the plain content of an encrypted file, given its size and a reader at its beginning.
+/// Files too short to hold a header are empty.
+pub async fn decrypted_size<R>(reader: &mut R, enc_size: u64) -> Result<u64, Error>
+where
+    R: AsyncRead + Unpin + ?Sized,
+{
+    match Header::read(reader).await {
+        Ok(header) => Ok(header.decrypted_size(enc_size)),
+        Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(0),
+        Err(e) => Err(e),
+    }
 }
 
 #[derive(PartialEq, Debug)]
@@ -284,11 +491,11 @@ pub struct ChunkedPosition {
 }
 
 impl ChunkedPosition {
-    pub fn new(plain_offset: u64) -> Self {
-        let active_chunk_counter = plain_offset / PLAIN_CHUNK_SIZE as u64;
+    pub fn new(plain_offset: u64, header: &Header) -> Self {
+        let active_chunk_counter = plain_offset / header.plain_chunk_size();
         let beginning_of_active_chunk =
-            active_chunk_counter * ENCRYPTED_CHUNK_SIZE as u64 + NONCE_SIZE as u64;
-        let start = encrypted_offset(plain_offset);
+            active_chunk_counter * header.encrypted_chunk_size() + header.size();
+        let start = header.encrypted_offset(plain_offset);
         let offset_in_active_chunk =
             start - (beginning_of_active_chunk + ENCRYPTION_OVERHEAD as u64);
         Self {
@@ -299,54 +506,130 @@ impl ChunkedPosition {
     }
 }
 
-#[test]
-fn test_decrypted_size() {
-    let nonce_size = NONCE_SIZE as u64;
-    let encryption_overhead = ENCRYPTION_OVERHEAD as u64;
-    let encrypted_chunk_size = ENCRYPTED_CHUNK_SIZE as u64;
-    let plain_chunk_size = PLAIN_CHUNK_SIZE as u64;
-
-    assert_eq!(decrypted_size(nonce_size + encryption_overhead), 0);
-    assert_eq!(
-        decrypted_size(nonce_size + 3 * encrypted_chunk_size),
-        3 * plain_chunk_size
-    );
-    assert_eq!(
-        decrypted_size(nonce_size + 3 * encrypted_chunk_size + ENCRYPTION_OVERHEAD as u64 + 150),
-        3 * plain_chunk_size + 150
-    );
-}
-
-#[test]
-fn test_chunked_position() {
-    let nonce_size = NONCE_SIZE as u64;
-    let encrypted_chunk_size = ENCRYPTED_CHUNK_SIZE as u64;
-    let plain_chunk_size = PLAIN_CHUNK_SIZE as u64;
-
-    assert_eq!(
-        ChunkedPosition::new(0),
-        ChunkedPosition {
-            beginning_of_active_chunk: nonce_size,
-            offset_in_active_chunk: 0,
-            active_chunk_counter: 0
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy_header() -> Header {
+        Header {
+            version: LEGACY_VERSION,
+            cipher: XCHACHA20POLY1305_STREAM_BE32,
+            chunk_size: PLAIN_CHUNK_SIZE as u32,
+            key_id: None,
+            nonce: [0; NONCE_SIZE],
         }
-    );
-
-    assert_eq!(
-        ChunkedPosition::new(100),
-        ChunkedPosition {
-            beginning_of_active_chunk: nonce_size,
-            offset_in_active_chunk: 100,
-            active_chunk_counter: 0
+    }
+
+    #[test]
+    fn test_decrypted_size() {
+        let encryption_overhead = ENCRYPTION_OVERHEAD as u64;
+        let encrypted_chunk_size = (PLAIN_CHUNK_SIZE + ENCRYPTION_OVERHEAD) as u64;
+        let plain_chunk_size = PLAIN_CHUNK_SIZE as u64;
+
+        for header in [legacy_header(), Header::new(&[1; 32])] {
+            let header_size = header.size();
+            assert_eq!(header.decrypted_size(header_size + encryption_overhead), 0);
+            assert_eq!(header.decrypted_size(header_size), 0);
+            assert_eq!(
+                header.decrypted_size(header_size + 3 * encrypted_chunk_size),
+                3 * plain_chunk_size
+            );
+            assert_eq!(
+                header.decrypted_size(
+                    header_size + 3 * encrypted_chunk_size + encryption_overhead + 150
+                ),
+                3 * plain_chunk_size + 150
+            );
         }
-    );
-
-    assert_eq!(
-        ChunkedPosition::new(100 + 2 * plain_chunk_size),
-        ChunkedPosition {
-            beginning_of_active_chunk: nonce_size + 2 * encrypted_chunk_size,
-            offset_in_active_chunk: 100,
-            active_chunk_counter: 2
+        assert_eq!(legacy_header().size(), NONCE_SIZE as u64);
+    }
+
+    #[test]
+    fn test_chunked_position() {
+        let encrypted_chunk_size = (PLAIN_CHUNK_SIZE + ENCRYPTION_OVERHEAD) as u64;
+        let plain_chunk_size = PLAIN_CHUNK_SIZE as u64;
+
+        for header in [legacy_header(), Header::new(&[1; 32])] {
+            let header_size = header.size();
+            assert_eq!(
+                ChunkedPosition::new(0, &header),
+                ChunkedPosition {
+                    beginning_of_active_chunk: header_size,
+                    offset_in_active_chunk: 0,
+                    active_chunk_counter: 0
+                }
+            );
+
+            assert_eq!(
+                ChunkedPosition::new(100, &header),
+                ChunkedPosition {
+                    beginning_of_active_chunk: header_size,
+                    offset_in_active_chunk: 100,
+                    active_chunk_counter: 0
+                }
+            );
+
+            assert_eq!(
+                ChunkedPosition::new(100 + 2 * plain_chunk_size, &header),
+                ChunkedPosition {
+                    beginning_of_active_chunk: header_size + 2 * encrypted_chunk_size,
+                    offset_in_active_chunk: 100,
+                    active_chunk_counter: 2
+                }
+            );
         }
-    );
+    }
+
+    #[tokio::test]
+    async fn test_header() {
+        let key = [1; 32];
+        let header = Header::new(&key);
+        let bytes = header.to_bytes();
+        assert_eq!(bytes.len(), HEADER_SIZE);
+        assert_eq!(&bytes[..4], MAGIC);
+        assert_eq!(Header::read(&mut bytes.as_slice()).await.unwrap(), header);
+        assert!(header.check_key(&key).is_ok());
+        assert!(header.check_key(&[2; 32]).is_err());
+
+        // Files without header begin with the nonce
+        let legacy = Header::read(&mut [7; NONCE_SIZE + 10].as_slice())
+            .await
+            .unwrap();
+        assert_eq!(legacy.version, LEGACY_VERSION);
+        assert_eq!(legacy.nonce, [7; NONCE_SIZE]);
+        assert!(legacy.check_key(&[2; 32]).is_ok());
+
+        // Unknown versions are refused
+        let mut future = bytes.clone();
+        future[4] = FORMAT_VERSION + 1;
+        assert_eq!(
+            Header::read(&mut future.as_slice())
+                .await
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData
+        );
+    }
+
+    #[tokio::test]
+    async fn test_legacy_file() {
+        // A file written before the header : the nonce followed by the chunks
+        let key = [3; 32];
+        let nonce = [5; NONCE_SIZE];
+        let mut legacy = nonce.to_vec();
+        legacy.extend(encrypt_chunk(&key, &nonce, 0, true, b"legacy content").unwrap());
+        assert_eq!(
+            decrypted_size(&mut legacy.as_slice(), legacy.len() as u64)
+                .await
+                .unwrap(),
+            14
+        );
+
+        let mut plain = vec![];
+        EncryptedStreamer::new(std::io::Cursor::new(legacy), key)
+            .copy_to(&mut plain)
+            .await
+            .unwrap();
+        assert_eq!(plain, b"legacy content");
+    }
 }
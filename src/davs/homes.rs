@@ -0,0 +1,101 @@
+use std::io;
+use std::path::Path;
+
+use futures_util::{future::BoxFuture, FutureExt};
+use tokio::fs;
+
+use super::encrypted_streamer::EncryptedStreamer;
+use super::names::NameCipher;
+
+/// Create a home directory on first access, filling it with the skeleton if there is one
+pub async fn create_home(
+    directory: &str,
+    skeleton: &str,
+    key: Option<[u8; 32]>,
+    names: &Option<NameCipher>,
+) -> io::Result<()> {
+    let home = Path::new(directory);
+    if fs::metadata(home).await.is_ok() {
+        return Ok(());
+    }
+    if let Some(parent) = home.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    // Only the request that actually creates the home copies the skeleton
+    match fs::create_dir(home).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => return Ok(()),
+        Err(e) => return Err(e),
+    }
+    if !skeleton.is_empty() {
+        copy_skeleton(Path::new(skeleton), home, key, names).await?;
+    }
+    Ok(())
+}
+
+fn copy_skeleton<'a>(
+    source: &'a Path,
+    dest: &'a Path,
+    key: Option<[u8; 32]>,
+    names: &'a Option<NameCipher>,
+) -> BoxFuture<'a, io::Result<()>> {
+    async move {
+        let mut entries = fs::read_dir(source).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let source = entry.path();
+            let dest = match names {
+                Some(names) => dest.join(names.encrypt_path(Path::new(&entry.file_name()))?),
+                None => dest.join(entry.file_name()),
+            };
+            let meta = fs::metadata(&source).await?;
+            if meta.is_dir() {
+                fs::create_dir(&dest).await?;
+                copy_skeleton(&source, &dest, key, names).await?;
+            } else if let Some(key) = key {
+                // Skeleton files are stored in clear, and must be encrypted as any uploaded file
+                let mut reader = fs::File::open(&source).await?;
+                let mut enc_file = EncryptedStreamer::new(fs::File::create(&dest).await?, key);
+                enc_file.copy_from(&mut reader).await?;
+            } else {
+                fs::copy(&source, &dest).await?;
+            }
+        }
+        Ok(())
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::create_home;
+
+    #[tokio::test]
+    async fn test_create_home_with_skeleton() {
+        // Arrange
+        let base = "homes_test";
+        fs::create_dir_all(format!("{base}/skeleton/documents")).unwrap();
+        fs::write(format!("{base}/skeleton/documents/readme.txt"), "welcome").unwrap();
+        let home = format!("{base}/homes/alice");
+
+        // Act
+        create_home(&home, &format!("{base}/skeleton"), None, &None)
+            .await
+            .unwrap();
+        fs::write(format!("{home}/documents/readme.txt"), "changed").unwrap();
+        // A second access must not copy the skeleton again
+        create_home(&home, &format!("{base}/skeleton"), None, &None)
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            fs::read_to_string(format!("{home}/documents/readme.txt")).unwrap(),
+            "changed"
+        );
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
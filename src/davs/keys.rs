@@ -0,0 +1,244 @@
+use std::io::{self, ErrorKind};
+
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64ct::{Base64, Encoding};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use log::error;
+use rand::rngs::OsRng;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::model::Dav;
+
+const SALT_SIZE: usize = 16;
+const WRAPPING_NONCE_SIZE: usize = 24;
+
+/// Data encryption key of an encrypted dav, wrapped with a key derived from the passphrase with argon2id.
+/// Changing the passphrase only wraps the same data key again, the files are left as they are.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct WrappedKey {
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+    pub key: String,
+    /// Key the files are being re-encrypted with, it replaces the current one once every file is migrated
+    #[serde(default)]
+    pub pending_key: Option<String>,
+}
+
+impl WrappedKey {
+    /// Wrap a data key with a passphrase, using a new salt and the default argon2 parameters
+    pub fn new(passphrase: &str, key: &[u8; 32]) -> io::Result<Self> {
+        let mut salt = [0; SALT_SIZE];
+        OsRng.fill_bytes(&mut salt);
+        let mut wrapped = Self {
+            salt: Base64::encode_string(&salt),
+            m_cost: Params::DEFAULT_M_COST,
+            t_cost: Params::DEFAULT_T_COST,
+            p_cost: Params::DEFAULT_P_COST,
+            key: String::new(),
+            pending_key: None,
+        };
+        wrapped.key = seal(&wrapped.wrapping_key(passphrase)?, key)?;
+        Ok(wrapped)
+    }
+
+    /// Data key, failing if the passphrase is not the one it was wrapped with
+    pub fn unwrap_key(&self, passphrase: &str) -> io::Result<[u8; 32]> {
+        open(&self.wrapping_key(passphrase)?, &self.key)
+    }
+
+    pub fn unwrap_pending_key(&self, passphrase: &str) -> io::Result<Option<[u8; 32]>> {
+        self.pending_key
+            .as_ref()
+            .map(|pending| open(&self.wrapping_key(passphrase)?, pending))
+            .transpose()
+    }
+
+    /// Add a key to migrate the files to
+    pub fn set_pending_key(&mut self, passphrase: &str, key: &[u8; 32]) -> io::Result<()> {
+        self.pending_key = Some(seal(&self.wrapping_key(passphrase)?, key)?);
+        Ok(())
+    }
+
+    /// Make the pending key the data key, once the files are migrated
+    pub fn promote_pending_key(&mut self) {
+        if let Some(pending) = self.pending_key.take() {
+            self.key = pending;
+        }
+    }
+
+    /// Same keys wrapped with another passphrase
+    pub fn rewrap(&self, passphrase: &str, new_passphrase: &str) -> io::Result<Self> {
+        let mut wrapped = Self::new(new_passphrase, &self.unwrap_key(passphrase)?)?;
+        if let Some(pending) = self.unwrap_pending_key(passphrase)? {
+            wrapped.set_pending_key(new_passphrase, &pending)?;
+        }
+        Ok(wrapped)
+    }
+
+    fn wrapping_key(&self, passphrase: &str) -> io::Result<[u8; 32]> {
+        let salt = Base64::decode_vec(&self.salt)
+            .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid key salt"))?;
+        let params = Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32))
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e.to_string()))?;
+        Ok(key)
+    }
+}
+
+/// Key of the davs encrypted before the keys were wrapped : a bare hash of the passphrase.
+/// It is kept as the data key of these davs until their files are re-encrypted.
+pub fn legacy_key(passphrase: &str) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    hasher.update(passphrase);
+    hasher.finalize().into()
+}
+
+pub fn random_key() -> [u8; 32] {
+    let mut key = [0; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Unwrap the data key of a dav. The davs encrypted with the legacy key get it wrapped, in which case true is returned
+/// as the configuration must be saved.
+/// With a wrong passphrase, the key derived from it is used so that nothing can be decrypted, and the dav is made read only.
+pub fn load_key(dav: &mut Dav) -> io::Result<bool> {
+    if dav.passphrase.is_empty() {
+        return Ok(false);
+    }
+    let (key, created) = match &dav.wrapped_key {
+        Some(wrapped) => (wrapped.unwrap_key(&dav.passphrase), false),
+        None => {
+            let key = legacy_key(&dav.passphrase);
+            dav.wrapped_key = Some(WrappedKey::new(&dav.passphrase, &key)?);
+            (Ok(key), true)
+        }
+    };
+    dav.key = match key {
+        Ok(key) => Some(key),
+        Err(e) => {
+            error!("Could not unwrap the key of dav {}: {}", dav.host, e);
+            dav.writable = false;
+            Some(
+                dav.wrapped_key
+                    .as_ref()
+                    .unwrap()
+                    .wrapping_key(&dav.passphrase)?,
+            )
+        }
+    };
+    Ok(created)
+}
+
+/// Wrap the keys of a dav being added or updated with its passphrase : the keys of the previous version of the dav
+/// are kept, so that a passphrase change does not make its files unreadable. New encrypted davs get a random key.
+pub fn rewrap_keys(previous: Option<&Dav>, dav: &mut Dav) -> io::Result<()> {
+    dav.wrapped_key = match previous {
+        _ if dav.passphrase.is_empty() => None,
+        Some(previous) if !previous.passphrase.is_empty() => {
+            let wrapped = match &previous.wrapped_key {
+                Some(wrapped) => wrapped.clone(),
+                None => WrappedKey::new(&previous.passphrase, &legacy_key(&previous.passphrase))?,
+            };
+            if previous.passphrase == dav.passphrase {
+                Some(wrapped)
+            } else {
+                Some(wrapped.rewrap(&previous.passphrase, &dav.passphrase)?)
+            }
+        }
+        _ => Some(WrappedKey::new(&dav.passphrase, &random_key())?),
+    };
+    Ok(())
+}
+
+fn seal(wrapping_key: &[u8; 32], key: &[u8; 32]) -> io::Result<String> {
+    let mut nonce = [0; WRAPPING_NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let mut sealed = nonce.to_vec();
+    sealed.extend(
+        XChaCha20Poly1305::new(wrapping_key.as_ref().into())
+            .encrypt(XNonce::from_slice(&nonce), key.as_ref())
+            .map_err(|_| io::Error::new(ErrorKind::Other, "could not wrap the key"))?,
+    );
+    Ok(Base64::encode_string(&sealed))
+}
+
+fn open(wrapping_key: &[u8; 32], sealed: &str) -> io::Result<[u8; 32]> {
+    let sealed = Base64::decode_vec(sealed)
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, "invalid wrapped key"))?;
+    if sealed.len() < WRAPPING_NONCE_SIZE {
+        return Err(io::Error::new(
+            ErrorKind::InvalidData,
+            "invalid wrapped key",
+        ));
+    }
+    let (nonce, ciphertext) = sealed.split_at(WRAPPING_NONCE_SIZE);
+    XChaCha20Poly1305::new(wrapping_key.as_ref().into())
+        .decrypt(XNonce::from_slice(nonce), ciphertext)
+        .ok()
+        .and_then(|key| key.try_into().ok())
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, "wrong passphrase"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{legacy_key, random_key, rewrap_keys, WrappedKey};
+    use crate::davs::model::Dav;
+
+    #[test]
+    fn test_wrapped_key() {
+        let key = random_key();
+        let mut wrapped = WrappedKey::new("passphrase", &key).unwrap();
+        assert_eq!(wrapped.unwrap_key("passphrase").unwrap(), key);
+        assert!(wrapped.unwrap_key("other").is_err());
+
+        // Rotating the passphrase keeps the keys
+        let pending = random_key();
+        wrapped.set_pending_key("passphrase", &pending).unwrap();
+        let rotated = wrapped.rewrap("passphrase", "other").unwrap();
+        assert_ne!(rotated.salt, wrapped.salt);
+        assert_eq!(rotated.unwrap_key("other").unwrap(), key);
+        assert_eq!(rotated.unwrap_pending_key("other").unwrap(), Some(pending));
+
+        wrapped.promote_pending_key();
+        assert_eq!(wrapped.unwrap_key("passphrase").unwrap(), pending);
+        assert_eq!(wrapped.unwrap_pending_key("passphrase").unwrap(), None);
+    }
+
+    #[test]
+    fn test_rewrap_keys() {
+        // Davs encrypted before the keys were wrapped keep their legacy key
+        let previous = Dav {
+            passphrase: "ABCD123".to_owned(),
+            ..Default::default()
+        };
+        let mut dav = Dav {
+            passphrase: "ABCDEFG".to_owned(),
+            ..Default::default()
+        };
+        rewrap_keys(Some(&previous), &mut dav).unwrap();
+        let wrapped = dav.wrapped_key.clone().unwrap();
+        assert_eq!(
+            wrapped.unwrap_key("ABCDEFG").unwrap(),
+            legacy_key("ABCD123")
+        );
+
+        // The same passphrase leaves the wrapped key as is
+        let mut same = dav.clone();
+        rewrap_keys(Some(&dav), &mut same).unwrap();
+        assert_eq!(same.wrapped_key, Some(wrapped));
+
+        // Removing the passphrase removes the key
+        let mut plain = Dav::default();
+        rewrap_keys(Some(&dav), &mut plain).unwrap();
+        assert_eq!(plain.wrapped_key, None);
+    }
+}
@@ -0,0 +1,237 @@
+use axum::extract;
+use axum::{Extension, Json};
+use hyper::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::Sender;
+
+use crate::configuration::{Config, ConfigFile};
+use crate::users::Admin;
+
+use super::keys::{random_key, WrappedKey};
+use super::model::Dav;
+use super::reencryption::{insert_job, remove_job, spawn_job, Job};
+
+/// Encryption a dav is being migrated to, saved before the migration starts so that it can be resumed after a crash.
+/// The dav is not served until every file is converted.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PendingMigration {
+    /// Passphrase of the dav once migrated, empty for plain files
+    pub passphrase: String,
+    pub wrapped_key: Option<WrappedKey>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MigrationRequest {
+    pub passphrase: String,
+}
+
+/// Convert the files of a dav to encrypted files with a new passphrase, or to plain files with an empty one.
+/// Without a request body, the pending migration of the dav is resumed.
+/// The progress is reported by the re-encryption status of the dav.
+pub async fn start_migration(
+    config_file: Extension<ConfigFile>,
+    Extension(reload): Extension<Sender<()>>,
+    mut config: Config,
+    _admin: Admin,
+    extract::Path(dav_id): extract::Path<(String, usize)>,
+    payload: Option<Json<MigrationRequest>>,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    let dav = match config.davs.iter_mut().find(|d| d.id == dav_id.1) {
+        Some(dav) => dav,
+        None => return Err((StatusCode::BAD_REQUEST, "dav doesn't exist")),
+    };
+    if dav.storage.is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "migration needs the local file system",
+        ));
+    }
+    // The paths of the files, the trash and the versions all derive from the names key
+    if dav.encrypt_names {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "migration of encrypted file names is not supported",
+        ));
+    }
+    let wrong_passphrase = |_| (StatusCode::BAD_REQUEST, "could not unwrap the dav key");
+    let migration = match (dav.migration.clone(), payload) {
+        (Some(migration), None) => migration,
+        (Some(migration), Some(Json(request))) if request.passphrase == migration.passphrase => {
+            migration
+        }
+        (Some(_), Some(_)) => {
+            return Err((
+                StatusCode::CONFLICT,
+                "another migration of the dav is pending",
+            ))
+        }
+        (None, None) => return Err((StatusCode::BAD_REQUEST, "no migration to resume")),
+        (None, Some(Json(request))) => {
+            if request.passphrase.is_empty() == dav.passphrase.is_empty() {
+                return Err((StatusCode::BAD_REQUEST, "the dav is already in this mode"));
+            }
+            let wrapped_key = if request.passphrase.is_empty() {
+                None
+            } else {
+                Some(
+                    WrappedKey::new(&request.passphrase, &random_key())
+                        .map_err(wrong_passphrase)?,
+                )
+            };
+            PendingMigration {
+                passphrase: request.passphrase,
+                wrapped_key,
+            }
+        }
+    };
+
+    let job = migration_job(dav, &migration)?;
+
+    let directory = dav.directory.clone();
+    insert_job(&directory)?;
+    dav.migration = Some(migration);
+    if config.to_file(&config_file).await.is_err() {
+        remove_job(&directory);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not save configuration",
+        ));
+    }
+
+    spawn_migration(config_file.0, job, reload);
+
+    Ok((StatusCode::ACCEPTED, "migration started"))
+}
+
+/// Resume the pending migration of a dav, saved by a previous run
+pub(crate) fn resume_migration(
+    config_file: ConfigFile,
+    dav: &Dav,
+    reload: Sender<()>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let migration = match &dav.migration {
+        Some(migration) => migration,
+        None => return Err((StatusCode::BAD_REQUEST, "no migration to resume")),
+    };
+    let job = migration_job(dav, migration)?;
+    insert_job(&dav.directory)?;
+    spawn_migration(config_file, job, reload);
+    Ok(())
+}
+
+/// Conversion of the files of a dav from its current encryption to the one of its migration
+fn migration_job(
+    dav: &Dav,
+    migration: &PendingMigration,
+) -> Result<Job, (StatusCode, &'static str)> {
+    let wrong_passphrase = |_| (StatusCode::BAD_REQUEST, "could not unwrap the dav key");
+    let key = match (&dav.passphrase, &dav.wrapped_key) {
+        (passphrase, _) if passphrase.is_empty() => None,
+        (passphrase, Some(wrapped)) => {
+            Some(wrapped.unwrap_key(passphrase).map_err(wrong_passphrase)?)
+        }
+        (_, None) => return Err((StatusCode::BAD_REQUEST, "could not unwrap the dav key")),
+    };
+    let new_key = match &migration.wrapped_key {
+        Some(wrapped) => Some(
+            wrapped
+                .unwrap_key(&migration.passphrase)
+                .map_err(wrong_passphrase)?,
+        ),
+        None => None,
+    };
+    let mut target = dav.clone();
+    target.passphrase = migration.passphrase.clone();
+    target.wrapped_key = migration.wrapped_key.clone();
+    target.migration = None;
+    target.key = new_key;
+
+    Ok(Job::new(target, key))
+}
+
+fn spawn_migration(config_file: ConfigFile, job: Job, reload: Sender<()>) {
+    spawn_job(job, reload, |dav| async move {
+        finish_migration(&config_file, dav.id).await
+    });
+}
+
+/// Make the migrated encryption the one of the dav, once every file is converted
+async fn finish_migration(config_file: &str, dav_id: usize) -> anyhow::Result<()> {
+    let mut config = Config::from_file(config_file).await?;
+    if let Some(dav) = config.davs.iter_mut().find(|d| d.id == dav_id) {
+        if let Some(migration) = dav.migration.take() {
+            dav.passphrase = migration.passphrase;
+            dav.wrapped_key = migration.wrapped_key;
+        }
+    }
+    config.to_file(config_file).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::davs::encrypted_streamer::EncryptedStreamer;
+    use crate::davs::model::Dav;
+    use crate::davs::reencryption::{insert_job, opens, remove_job, Job};
+
+    #[tokio::test]
+    async fn test_resumed_migration() {
+        // Arrange : a migration to encrypted files interrupted after the first file
+        let base = "migration_test";
+        fs::create_dir_all(format!("{base}/docs")).unwrap();
+        fs::write(format!("{base}/plain.txt"), "plain").unwrap();
+        let key = [1; 32];
+        let mut encrypted = EncryptedStreamer::new(
+            tokio::fs::File::create(format!("{base}/docs/encrypted.txt"))
+                .await
+                .unwrap(),
+            key,
+        );
+        encrypted
+            .copy_from(&mut "encrypted".as_bytes())
+            .await
+            .unwrap();
+        let dav = Dav {
+            directory: base.to_owned(),
+            key: Some(key),
+            ..Default::default()
+        };
+
+        // Act : resume the migration
+        insert_job(base).unwrap();
+        Job::new(dav.clone(), None).run().await.unwrap();
+
+        // Assert that every file is encrypted once
+        for (path, content) in [("plain.txt", "plain"), ("docs/encrypted.txt", "encrypted")] {
+            let path = std::path::Path::new(base).join(path);
+            assert!(opens(&path, key).await.unwrap());
+            let mut plain = vec![];
+            EncryptedStreamer::new(tokio::fs::File::open(&path).await.unwrap(), key)
+                .copy_to(&mut plain)
+                .await
+                .unwrap();
+            assert_eq!(plain, content.as_bytes());
+        }
+
+        // Act : migrate back to plain files, twice as after an interruption
+        let plain_dav = Dav { key: None, ..dav };
+        for _ in 0..2 {
+            Job::new(plain_dav.clone(), Some(key)).run().await.unwrap();
+        }
+
+        // Assert
+        assert_eq!(
+            fs::read_to_string(format!("{base}/plain.txt")).unwrap(),
+            "plain"
+        );
+        assert_eq!(
+            fs::read_to_string(format!("{base}/docs/encrypted.txt")).unwrap(),
+            "encrypted"
+        );
+
+        // Tidy
+        remove_job(base);
+        fs::remove_dir_all(base).unwrap();
+    }
+}
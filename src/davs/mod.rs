@@ -1,7 +1,24 @@
+pub mod acl;
+pub(crate) mod chunks;
+pub(crate) mod content_store;
 pub(crate) mod encrypted_streamer;
 pub(crate) mod headers;
+pub(crate) mod homes;
+pub mod keys;
+pub mod migration;
 pub mod model;
+pub mod names;
+pub mod quota;
+pub mod reencryption;
+pub mod search;
+pub mod shares;
+pub mod storage;
 pub(crate) mod streamer;
+pub mod thumbnails;
+pub mod trash;
+pub mod uploads;
+pub mod versions;
+pub mod watcher;
 pub(crate) mod webdav_server;
 
 use std::sync::Arc;
@@ -9,12 +26,33 @@ use std::sync::Arc;
 use axum::{
     extract::ConnectInfo,
     http::{Request, Response},
+    Extension,
 };
 
-use crate::users::check_authorization;
-use crate::{configuration::HostType, users::User};
+use crate::users::check_authorization_or_login;
+use crate::{
+    configuration::{HostType, MainSite},
+    users::User,
+};
 use hyper::{Body, StatusCode};
+use log::error;
+use names::NameCipher;
 use std::net::SocketAddr;
+use std::path::Path;
+
+/// Directory holding the internal state of a dav (trash...), hidden from the users
+pub const INTERNAL_DIR: &str = ".vestibule";
+
+/// Extension of the files being uploaded, hidden from the users until they replace their destination
+pub const PARTIAL_EXTENSION: &str = "vestibule-part";
+
+/// Whether a path within a dav belongs to its internal state or is a file being uploaded, hidden from the users
+pub(crate) fn is_internal(rel_path: &Path) -> bool {
+    rel_path.components().any(|c| c.as_os_str() == INTERNAL_DIR)
+        || rel_path
+            .extension()
+            .map_or(false, |ext| ext == PARTIAL_EXTENSION)
+}
 
 lazy_static::lazy_static! {
     static ref  WEBDAV_SERVER: Arc<webdav_server::WebdavServer> = {
@@ -27,19 +65,52 @@ lazy_static::lazy_static! {
 pub async fn webdav_handler(
     user: Option<User>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Extension(main_site): Extension<MainSite>,
     dav: HostType,
     req: Request<Body>,
 ) -> Response<Body> {
-    if let Some(value) = check_authorization(&dav, &user) {
+    if let Some(value) = check_authorization_or_login(&dav, &user, &main_site, &req) {
         return value;
     }
 
-    let dav = match dav {
+    let mut dav = match dav {
         HostType::Dav(app) => app,
         _ => panic!("Service is not a dav !"),
     };
 
-    match WEBDAV_SERVER.clone().call(req, addr, &dav).await {
+    if !reencryption::is_available(&dav) {
+        return Response::builder()
+            .status(StatusCode::SERVICE_UNAVAILABLE)
+            .body(Body::empty())
+            .unwrap();
+    }
+
+    // Templated davs are rooted in the home directory of the user
+    if dav.is_home() {
+        let home = match user.as_ref().and_then(|u| dav.home_directory(&u.login)) {
+            Some(home) => home,
+            None => {
+                return Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .body(Body::empty())
+                    .unwrap()
+            }
+        };
+        let created = match dav.storage {
+            None => homes::create_home(&home, &dav.skeleton, dav.key, &NameCipher::new(&dav)).await,
+            Some(_) => Ok(()),
+        };
+        if let Err(e) = created {
+            error!("Could not create home directory {}: {}", home, e);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap();
+        }
+        dav.directory = home;
+    }
+
+    match WEBDAV_SERVER.clone().call(req, addr, &dav, &user).await {
         Ok(response) => response,
         Err(_) => Response::builder()
             .status(StatusCode::INTERNAL_SERVER_ERROR)
@@ -10,6 +10,15 @@ use crate::configuration::Config;
 use crate::configuration::ConfigFile;
 use crate::users::Admin;
 
+use super::acl::AclRule;
+use super::content_store::is_on_dav_file_system;
+use super::keys::{rewrap_keys, WrappedKey};
+use super::migration::PendingMigration;
+use super::quota::Quota;
+use super::storage::StorageConfig;
+use super::trash::TrashPolicy;
+use super::versions::VersioningPolicy;
+
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Dav {
     pub id: usize,
@@ -23,11 +32,64 @@ pub struct Dav {
     #[serde(default)]
     pub allow_symlinks: bool,
     pub roles: Vec<String>,
+    #[serde(default)]
+    pub acl: Vec<AclRule>,
+    /// Directory copied into the home directories when they are created
+    #[serde(default)]
+    pub skeleton: String,
+    #[serde(default)]
+    pub quota: Option<Quota>,
+    #[serde(default)]
+    pub trash: Option<TrashPolicy>,
+    #[serde(default)]
+    pub versioning: Option<VersioningPolicy>,
+    /// Directory of a content addressed store deduplicating the identical files of the davs sharing it, as hard links
+    #[serde(default)]
+    pub content_store: String,
+    /// Storage of the files in place of the local directory, which is then only the root of their paths.
+    /// The trash, the versions, the quota, the content store, the resumable uploads, the search and the zip downloads
+    /// need the local file system, and are not available with another storage.
+    #[serde(default)]
+    pub storage: Option<StorageConfig>,
     pub passphrase: String,
+    /// Data encryption key, wrapped with a key derived from the passphrase so that the passphrase can be changed
+    #[serde(default)]
+    pub wrapped_key: Option<WrappedKey>,
+    /// Encrypt the file names along with the contents, so that the directory tree tells nothing on disk
+    #[serde(default)]
+    pub encrypt_names: bool,
+    /// Encryption the files are being migrated to
+    #[serde(default)]
+    pub migration: Option<PendingMigration>,
     #[serde(skip)]
     pub key: Option<[u8; 32]>,
 }
 
+/// Placeholder that makes a dav directory a per user home directory
+pub const LOGIN_PLACEHOLDER: &str = "{login}";
+
+impl Dav {
+    pub fn is_home(&self) -> bool {
+        self.directory.contains(LOGIN_PLACEHOLDER)
+    }
+
+    /// Whether the dav enables a feature relying on the local file system, which another storage does not allow
+    pub fn uses_local_features(&self) -> bool {
+        self.trash.is_some()
+            || self.versioning.is_some()
+            || self.quota.is_some()
+            || !self.content_store.is_empty()
+    }
+
+    /// Directory of the given user for a templated dav, refusing the logins that could escape the homes root
+    pub fn home_directory(&self, login: &str) -> Option<String> {
+        if login.is_empty() || login == "." || login == ".." || login.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        Some(self.directory.replace(LOGIN_PLACEHOLDER, login))
+    }
+}
+
 pub async fn get_davs(
     config: Config,
     _admin: Admin,
@@ -68,8 +130,49 @@ pub async fn add_dav(
     config_file: Extension<ConfigFile>,
     mut config: Config,
     _admin: Admin,
-    Json(payload): Json<Dav>,
+    Json(mut payload): Json<Dav>,
 ) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    // Keep the data key of the dav, wrapped with the new passphrase
+    let previous = config.davs.iter().find(|d| d.id == payload.id);
+    if payload.storage.is_some() && payload.uses_local_features() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "the trash, the versioning, the quota and the content store need the local storage",
+        ));
+    }
+    if !payload.content_store.is_empty() && !is_on_dav_file_system(&payload).unwrap_or(false) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "the content store must be on the file system of the dav directory",
+        ));
+    }
+    if payload.encrypt_names && payload.passphrase.is_empty() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "file names encryption needs a passphrase",
+        ));
+    }
+    if let Some(previous) = previous {
+        // The files already there would not be found anymore
+        if previous.encrypt_names != payload.encrypt_names {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "file names encryption cannot be changed on an existing dav",
+            ));
+        }
+        // Nor could they be read, they must be converted by a migration
+        if previous.migration.is_some()
+            || previous.passphrase.is_empty() != payload.passphrase.is_empty()
+        {
+            return Err((
+                StatusCode::CONFLICT,
+                "the encryption of an existing dav is changed by a migration",
+            ));
+        }
+    }
+    rewrap_keys(previous, &mut payload)
+        .map_err(|_| (StatusCode::BAD_REQUEST, "could not wrap the dav key"))?;
+
     // Find the dav
     if let Some(dav) = config.davs.iter_mut().find(|d| d.id == payload.id) {
         *dav = payload;
@@ -83,3 +186,25 @@ pub async fn add_dav(
 
     Ok((StatusCode::CREATED, "dav created or updated successfully"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Dav;
+
+    #[test]
+    fn test_home_directory() {
+        let dav = Dav {
+            directory: "/data/homes/{login}".to_owned(),
+            ..Default::default()
+        };
+        assert!(dav.is_home());
+        assert_eq!(
+            dav.home_directory("alice"),
+            Some("/data/homes/alice".to_owned())
+        );
+        assert_eq!(dav.home_directory(""), None);
+        assert_eq!(dav.home_directory(".."), None);
+        assert_eq!(dav.home_directory("../alice"), None);
+        assert_eq!(dav.home_directory("a\\b"), None);
+    }
+}
@@ -0,0 +1,170 @@
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+
+use base64ct::{Base64UrlUnpadded, Encoding};
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use super::model::Dav;
+
+const SIV_SIZE: usize = 24;
+const TAG_SIZE: usize = 16;
+/// Longest file name most file systems accept
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Deterministic encryption of the file names of a dav : each path segment is encrypted with a synthetic nonce derived
+/// from the name itself (SIV construction), so that a path always maps to the same encrypted path without any lookup.
+/// The same name gets the same encrypted name in every directory, which keeps moves and copies to simple renames.
+/// Encrypted names are longer than the plain ones : names over 151 bytes are refused.
+#[derive(Clone)]
+pub struct NameCipher {
+    key: [u8; 32],
+    siv_key: [u8; 32],
+}
+
+impl fmt::Debug for NameCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NameCipher").finish_non_exhaustive()
+    }
+}
+
+impl NameCipher {
+    /// Cipher of a dav encrypting its file names, None for the other davs
+    pub fn new(dav: &Dav) -> Option<Self> {
+        match dav.key {
+            Some(key) if dav.encrypt_names => Some(Self::from_key(&key)),
+            _ => None,
+        }
+    }
+
+    /// Cipher with keys derived from the data key of a dav, kept apart from the key of the contents
+    pub fn from_key(key: &[u8; 32]) -> Self {
+        Self {
+            key: derive(key, b"vestibule file names"),
+            siv_key: derive(key, b"vestibule file names nonce"),
+        }
+    }
+
+    pub fn encrypt(&self, name: &str) -> io::Result<String> {
+        let siv = self.siv(name);
+        let mut sealed = siv.to_vec();
+        sealed.extend(
+            XChaCha20Poly1305::new(self.key.as_ref().into())
+                .encrypt(XNonce::from_slice(&siv), name.as_bytes())
+                .map_err(|_| io::Error::new(ErrorKind::Other, "could not encrypt file name"))?,
+        );
+        let encrypted = Base64UrlUnpadded::encode_string(&sealed);
+        if encrypted.len() > MAX_NAME_LENGTH {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "file name too long",
+            ));
+        }
+        Ok(encrypted)
+    }
+
+    /// Plain name, failing for the names that were not encrypted with this cipher
+    pub fn decrypt(&self, encrypted: &str) -> io::Result<String> {
+        let invalid = || io::Error::new(ErrorKind::InvalidData, "invalid encrypted file name");
+        let sealed = Base64UrlUnpadded::decode_vec(encrypted).map_err(|_| invalid())?;
+        if sealed.len() < SIV_SIZE + TAG_SIZE {
+            return Err(invalid());
+        }
+        let (siv, ciphertext) = sealed.split_at(SIV_SIZE);
+        let name = XChaCha20Poly1305::new(self.key.as_ref().into())
+            .decrypt(XNonce::from_slice(siv), ciphertext)
+            .ok()
+            .and_then(|name| String::from_utf8(name).ok())
+            .ok_or_else(invalid)?;
+        // Only the canonical encryption of a name is accepted, so that a name has a single path on disk
+        if self.siv(&name) != siv {
+            return Err(invalid());
+        }
+        Ok(name)
+    }
+
+    /// Encrypt every segment of a path relative to the dav directory
+    pub fn encrypt_path(&self, path: &Path) -> io::Result<PathBuf> {
+        map_path(path, |name| self.encrypt(name))
+    }
+
+    pub fn decrypt_path(&self, path: &Path) -> io::Result<PathBuf> {
+        map_path(path, |name| self.decrypt(name))
+    }
+
+    fn siv(&self, name: &str) -> [u8; SIV_SIZE] {
+        let mut siv = [0; SIV_SIZE];
+        siv.copy_from_slice(&derive(&self.siv_key, name.as_bytes())[..SIV_SIZE]);
+        siv
+    }
+}
+
+fn map_path<F>(path: &Path, map: F) -> io::Result<PathBuf>
+where
+    F: Fn(&str) -> io::Result<String>,
+{
+    let mut mapped = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::Normal(name) => {
+                let name = name.to_str().ok_or_else(|| {
+                    io::Error::new(ErrorKind::InvalidData, "file name is not valid unicode")
+                })?;
+                mapped.push(map(name)?);
+            }
+            other => mapped.push(other),
+        }
+    }
+    Ok(mapped)
+}
+
+fn derive(key: &[u8], data: &[u8]) -> [u8; 32] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take keys of any size");
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::NameCipher;
+
+    #[test]
+    fn test_name_cipher() {
+        let names = NameCipher::from_key(&[1; 32]);
+        let encrypted = names.encrypt("report.pdf").unwrap();
+        assert!(!encrypted.contains("report"));
+        assert!(!encrypted.contains(['/', '\\', '.']));
+        // Deterministic, so that a path can be found again
+        assert_eq!(names.encrypt("report.pdf").unwrap(), encrypted);
+        assert_ne!(names.encrypt("report.txt").unwrap(), encrypted);
+        assert_eq!(names.decrypt(&encrypted).unwrap(), "report.pdf");
+
+        // Names that were not encrypted with the key are refused
+        assert!(names.decrypt("report.pdf").is_err());
+        assert!(NameCipher::from_key(&[2; 32]).decrypt(&encrypted).is_err());
+
+        // Names too long to be encrypted within the file systems limits
+        assert!(names.encrypt(&"a".repeat(151)).is_ok());
+        assert!(names.encrypt(&"a".repeat(152)).is_err());
+    }
+
+    #[test]
+    fn test_name_cipher_path() {
+        let names = NameCipher::from_key(&[1; 32]);
+        let encrypted = names
+            .encrypt_path(Path::new("docs/2022/report.pdf"))
+            .unwrap();
+        assert_eq!(encrypted.components().count(), 3);
+        assert!(encrypted.ends_with(names.encrypt("report.pdf").unwrap()));
+        assert_eq!(
+            names.decrypt_path(&encrypted).unwrap(),
+            Path::new("docs/2022/report.pdf")
+        );
+        assert_eq!(names.encrypt_path(Path::new("")).unwrap(), Path::new(""));
+    }
+}
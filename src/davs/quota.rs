@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use futures_util::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use super::encrypted_streamer::decrypted_size;
+use super::INTERNAL_DIR;
+
+/// Delay after which the usage of a dav is worked out again, to account for the changes made by other tools
+const USAGE_TTL: Duration = Duration::from_secs(600);
+
+lazy_static::lazy_static! {
+    /// Usage of the dav directories, worked out once then updated by the writes checked against their quota
+    static ref USAGES: Mutex<HashMap<PathBuf, CachedUsage>> = Mutex::new(HashMap::new());
+}
+
+/// Identifies each usage worked out, so that a reservation is not settled on a usage worked out after it
+static GENERATIONS: AtomicU64 = AtomicU64::new(0);
+
+struct CachedUsage {
+    usage: Usage,
+    generation: u64,
+    computed_at: Instant,
+}
+
+/// Limits on what can be stored in a dav (or in each home directory of a templated dav)
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Quota {
+    pub bytes: Option<u64>,
+    pub files: Option<u64>,
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
+pub struct Usage {
+    pub bytes: u64,
+    pub files: u64,
+}
+
+impl Quota {
+    pub fn available_bytes(&self, usage: &Usage) -> Option<u64> {
+        self.bytes.map(|bytes| bytes.saturating_sub(usage.bytes))
+    }
+
+    pub fn allows_new_file(&self, usage: &Usage) -> bool {
+        self.files.map_or(true, |files| usage.files < files)
+    }
+
+    /// Check that the given content can be added to the used space
+    pub fn allows(&self, usage: &Usage, added: &Usage) -> bool {
+        self.bytes
+            .map_or(true, |bytes| usage.bytes + added.bytes <= bytes)
+            && self
+                .files
+                .map_or(true, |files| usage.files + added.files <= files)
+    }
+
+    /// RFC 4331 properties for the PROPFIND responses
+    pub fn dav_props(&self, usage: &Usage) -> String {
+        let mut props = String::new();
+        if let Some(available) = self.available_bytes(usage) {
+            props.push_str(&format!(
+                "<D:quota-available-bytes>{}</D:quota-available-bytes>\n",
+                available
+            ));
+        }
+        props.push_str(&format!(
+            "<D:quota-used-bytes>{}</D:quota-used-bytes>\n",
+            usage.bytes
+        ));
+        props
+    }
+}
+
+/// Room reserved in the quota of a dav for a write, given back when dropped unless settled
+pub struct Reservation {
+    directory: PathBuf,
+    generation: u64,
+    pub added: Usage,
+    settled: bool,
+}
+
+impl Reservation {
+    /// Keep the reserved room as used, the write having added what was reserved
+    pub fn commit(mut self) {
+        self.settled = true;
+    }
+
+    /// Replace the reserved room by what the write actually added and removed
+    pub fn settle(mut self, added: Usage, removed: Usage) {
+        self.update(added, removed);
+        self.settled = true;
+    }
+
+    fn update(&self, added: Usage, removed: Usage) {
+        let mut usages = USAGES.lock().unwrap();
+        if let Some(cached) = usages.get_mut(&self.directory) {
+            if cached.generation == self.generation {
+                let usage = &mut cached.usage;
+                usage.bytes =
+                    (usage.bytes + added.bytes).saturating_sub(self.added.bytes + removed.bytes);
+                usage.files =
+                    (usage.files + added.files).saturating_sub(self.added.files + removed.files);
+            }
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        if !self.settled {
+            self.update(Usage::default(), Usage::default());
+        }
+    }
+}
+
+/// Reserve room in the quota of a dav for a write of a known size, or for as many bytes as are left if the size is unknown.
+/// None is returned if the write does not fit. The room is checked and taken at once, so concurrent writes cannot exceed the quota.
+pub async fn reserve(
+    directory: &Path,
+    encrypted: bool,
+    quota: &Quota,
+    bytes: Option<u64>,
+    files: u64,
+) -> io::Result<Option<Reservation>> {
+    loop {
+        {
+            let mut usages = USAGES.lock().unwrap();
+            if let Some(cached) = usages
+                .get_mut(directory)
+                .filter(|cached| cached.computed_at.elapsed() < USAGE_TTL)
+            {
+                let added = Usage {
+                    bytes: bytes.unwrap_or_else(|| {
+                        quota.available_bytes(&cached.usage).unwrap_or_default()
+                    }),
+                    files,
+                };
+                if !quota.allows(&cached.usage, &added) {
+                    return Ok(None);
+                }
+                cached.usage.bytes += added.bytes;
+                cached.usage.files += added.files;
+                return Ok(Some(Reservation {
+                    directory: directory.to_path_buf(),
+                    generation: cached.generation,
+                    added,
+                    settled: false,
+                }));
+            }
+        }
+        refresh(directory, encrypted).await?;
+    }
+}
+
+/// Space used by a dav directory, worked out again only once outdated
+pub async fn current_usage(directory: &Path, encrypted: bool) -> io::Result<Usage> {
+    if let Some(cached) = USAGES
+        .lock()
+        .unwrap()
+        .get(directory)
+        .filter(|cached| cached.computed_at.elapsed() < USAGE_TTL)
+    {
+        return Ok(cached.usage);
+    }
+    refresh(directory, encrypted).await
+}
+
+/// Forget the usage of a dav directory after a change that the reservations do not track, such as a deletion
+pub fn invalidate(directory: &Path) {
+    USAGES.lock().unwrap().remove(directory);
+}
+
+/// Work out the usage of a dav directory, unless a concurrent request just did
+async fn refresh(directory: &Path, encrypted: bool) -> io::Result<Usage> {
+    let usage = usage(directory, encrypted).await?;
+    let mut usages = USAGES.lock().unwrap();
+    match usages.get(directory) {
+        Some(cached) if cached.computed_at.elapsed() < USAGE_TTL => Ok(cached.usage),
+        _ => {
+            usages.insert(
+                directory.to_path_buf(),
+                CachedUsage {
+                    usage,
+                    generation: GENERATIONS.fetch_add(1, Ordering::Relaxed),
+                    computed_at: Instant::now(),
+                },
+            );
+            Ok(usage)
+        }
+    }
+}
+
+/// Work out the space used by a directory, counting the decrypted sizes for encrypted davs
+pub fn usage(path: &Path, encrypted: bool) -> BoxFuture<io::Result<Usage>> {
+    async move {
+        let mut total = Usage::default();
+        let meta = fs::symlink_metadata(path).await?;
+        if meta.is_file() {
+            total.files = 1;
+            total.bytes = if encrypted {
+                decrypted_size(&mut fs::File::open(path).await?, meta.len()).await?
+            } else {
+                meta.len()
+            };
+        } else if meta.is_dir() {
+            let mut entries = fs::read_dir(path).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                // The internal state (trash...) does not count in the quota
+                if entry.file_name() == INTERNAL_DIR {
+                    continue;
+                }
+                let entry_usage = usage(&entry.path(), encrypted).await?;
+                total.bytes += entry_usage.bytes;
+                total.files += entry_usage.files;
+            }
+        }
+        Ok(total)
+    }
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::{current_usage, reserve, usage, Quota, Usage};
+
+    #[test]
+    fn test_quota() {
+        let quota = Quota {
+            bytes: Some(100),
+            files: Some(2),
+        };
+        let usage = Usage {
+            bytes: 60,
+            files: 1,
+        };
+        assert_eq!(quota.available_bytes(&usage), Some(40));
+        assert!(quota.allows_new_file(&usage));
+        let usage = Usage {
+            bytes: 120,
+            files: 2,
+        };
+        assert_eq!(quota.available_bytes(&usage), Some(0));
+        assert!(!quota.allows_new_file(&usage));
+        assert!(!quota.allows(&usage, &Usage { bytes: 0, files: 1 }));
+        assert!(Quota::default().allows_new_file(&usage));
+        assert_eq!(Quota::default().available_bytes(&usage), None);
+    }
+
+    #[tokio::test]
+    async fn test_usage() {
+        // Arrange
+        let base = "quota_test";
+        fs::create_dir_all(format!("{base}/a/b")).unwrap();
+        fs::write(format!("{base}/file"), "12345").unwrap();
+        fs::write(format!("{base}/a/b/file"), "123").unwrap();
+
+        // Act
+        let usage = usage(Path::new(base), false).await.unwrap();
+
+        // Assert
+        assert_eq!(usage, Usage { bytes: 8, files: 2 });
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_reservations() {
+        // Arrange
+        let base = "quota_reservations_test";
+        fs::create_dir_all(base).unwrap();
+        fs::write(format!("{base}/file"), "12345").unwrap();
+        let base = Path::new(base);
+        let quota = Quota {
+            bytes: Some(10),
+            files: None,
+        };
+
+        // Act and Assert : the room reserved is taken until given back
+        let reserved = reserve(base, false, &quota, Some(4), 1).await.unwrap();
+        assert!(reserved.is_some());
+        assert!(reserve(base, false, &quota, Some(4), 1)
+            .await
+            .unwrap()
+            .is_none());
+        drop(reserved);
+        let reserved = reserve(base, false, &quota, None, 1)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(reserved.added.bytes, 5);
+
+        // Act and Assert : the usage is kept up to date with what was written
+        reserved.settle(Usage { bytes: 2, files: 1 }, Usage::default());
+        assert_eq!(
+            current_usage(base, false).await.unwrap(),
+            Usage { bytes: 7, files: 2 }
+        );
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::io::{self, Cursor, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::Mutex;
+
+use axum::extract;
+use axum::Extension;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use hyper::StatusCode;
+use log::{error, info};
+use serde::Serialize;
+use tokio::fs;
+use tokio::io::AsyncRead;
+use tokio::sync::broadcast::Sender;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+use crate::configuration::{Config, ConfigFile};
+use crate::users::Admin;
+
+use super::content_store::ContentStore;
+use super::encrypted_streamer::{EncryptedStreamer, Header};
+use super::keys::{load_key, random_key};
+use super::migration::resume_migration;
+use super::model::{Dav, LOGIN_PLACEHOLDER};
+use super::{INTERNAL_DIR, PARTIAL_EXTENSION};
+
+lazy_static::lazy_static! {
+    /// Re-encryptions by dav directory
+    static ref JOBS: Mutex<HashMap<String, Reencryption>> = Mutex::new(HashMap::new());
+}
+
+/// Re-encryption of the files of a dav with a new data key, for the files encrypted with a leaked or a legacy key.
+/// The new key is saved as pending before the job starts, so that an interrupted job can be resumed : the files that
+/// the current key cannot decrypt but the new one can are already migrated. The dav is not served until the job is done.
+/// The migrations of a dav between plain and encrypted files run the same way, the plain files standing for a missing key.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Reencryption {
+    pub running: bool,
+    pub reencrypted: u64,
+    /// Files that the current key cannot decrypt, left as they are
+    pub skipped: u64,
+    pub error: Option<String>,
+    /// Key the files end up encrypted with, None once migrated to plain files
+    #[serde(skip)]
+    key: Option<[u8; 32]>,
+}
+
+/// Whether the files of a dav can be served : not while they are re-encrypted or migrated, nor until the configuration
+/// is reloaded with the new key
+pub fn is_available(dav: &Dav) -> bool {
+    if dav.migration.is_some()
+        || dav
+            .wrapped_key
+            .as_ref()
+            .map_or(false, |wrapped| wrapped.pending_key.is_some())
+    {
+        return false;
+    }
+    match JOBS.lock().unwrap().get(&dav.directory) {
+        Some(job) => !job.running && job.error.is_none() && dav.key == job.key,
+        None => true,
+    }
+}
+
+pub async fn start_reencryption(
+    config_file: Extension<ConfigFile>,
+    Extension(reload): Extension<Sender<()>>,
+    mut config: Config,
+    _admin: Admin,
+    extract::Path(dav_id): extract::Path<(String, usize)>,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    let dav = match config.davs.iter_mut().find(|d| d.id == dav_id.1) {
+        Some(dav) => dav,
+        None => return Err((StatusCode::BAD_REQUEST, "dav doesn't exist")),
+    };
+    if dav.passphrase.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "dav is not encrypted"));
+    }
+    if dav.storage.is_some() {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "re-encryption needs the local file system",
+        ));
+    }
+    // The paths of the files, the trash and the versions all derive from the names key
+    if dav.encrypt_names {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "re-encryption of encrypted file names is not supported",
+        ));
+    }
+    if dav.migration.is_some() {
+        return Err((StatusCode::CONFLICT, "a migration of the dav is pending"));
+    }
+    insert_job(&dav.directory)?;
+
+    let keys = match pending_keys(dav) {
+        Ok(keys) => config
+            .to_file(&config_file)
+            .await
+            .map(|_| keys)
+            .map_err(|_| {
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "could not save configuration",
+                )
+            }),
+        Err(e) => Err(e),
+    };
+    let dav = config
+        .davs
+        .iter()
+        .find(|d| d.id == dav_id.1)
+        .unwrap()
+        .clone();
+    let keys = match keys {
+        Ok(keys) => keys,
+        Err(e) => {
+            remove_job(&dav.directory);
+            return Err(e);
+        }
+    };
+    spawn_reencryption(config_file.0, dav, keys, reload);
+
+    Ok((StatusCode::ACCEPTED, "re-encryption started"))
+}
+
+/// Resume the re-encryptions and the migrations left pending by a previous run, as their davs are not served meanwhile.
+/// The jobs running or failed since the start are left alone : the failed ones are started again by the admins.
+pub fn resume_jobs(config_file: &str, davs: &[Dav], reload: &Sender<()>) {
+    for dav in davs {
+        if JOBS
+            .lock()
+            .unwrap()
+            .get(&dav.directory)
+            .map_or(false, |job| job.running || job.error.is_some())
+        {
+            continue;
+        }
+        let resumed = if dav.migration.is_some() {
+            resume_migration(config_file.to_owned(), dav, reload.clone())
+        } else if dav
+            .wrapped_key
+            .as_ref()
+            .map_or(false, |wrapped| wrapped.pending_key.is_some())
+        {
+            resume_reencryption(config_file.to_owned(), dav, reload.clone())
+        } else {
+            continue;
+        };
+        match resumed {
+            Ok(_) => info!(
+                "Resuming the re-encryption of the files of dav {}",
+                dav.host
+            ),
+            Err((_, e)) => error!(
+                "Could not resume the re-encryption of the files of dav {}: {}",
+                dav.host, e
+            ),
+        }
+    }
+}
+
+fn resume_reencryption(
+    config_file: ConfigFile,
+    dav: &Dav,
+    reload: Sender<()>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let mut dav = dav.clone();
+    let keys = pending_keys(&mut dav)?;
+    insert_job(&dav.directory)?;
+    spawn_reencryption(config_file, dav, keys, reload);
+    Ok(())
+}
+
+/// Re-encrypt the files of a dav with its pending key, which becomes its key once done
+fn spawn_reencryption(config_file: ConfigFile, dav: Dav, (key, new_key): Keys, reload: Sender<()>) {
+    let mut target = dav;
+    target.key = Some(new_key);
+    spawn_job(Job::new(target, Some(key)), reload, |dav| async move {
+        promote_pending_key(&config_file, dav.id).await
+    });
+}
+
+/// Register a job on a dav directory, failing if one is already running there
+pub(crate) fn insert_job(directory: &str) -> Result<(), (StatusCode, &'static str)> {
+    let mut jobs = JOBS.lock().unwrap();
+    if jobs.get(directory).map_or(false, |job| job.running) {
+        return Err((StatusCode::CONFLICT, "re-encryption already running"));
+    }
+    jobs.insert(
+        directory.to_owned(),
+        Reencryption {
+            running: true,
+            ..Default::default()
+        },
+    );
+    Ok(())
+}
+
+pub(crate) fn remove_job(directory: &str) {
+    JOBS.lock().unwrap().remove(directory);
+}
+
+/// Run a job in the background, then save its outcome in the configuration with `done`.
+/// The configuration is reloaded once done, so that the dav is served again with its new key.
+pub(crate) fn spawn_job<F, Fut>(job: Job, reload: Sender<()>, done: F)
+where
+    F: FnOnce(Dav) -> Fut + Send + 'static,
+    Fut: Future<Output = anyhow::Result<()>> + Send,
+{
+    JOBS.lock()
+        .unwrap()
+        .get_mut(&job.dav.directory)
+        .unwrap()
+        .key = job.dav.key;
+    tokio::spawn(async move {
+        let dav = job.dav.clone();
+        let result = match job.run().await {
+            Ok(_) => done(dav.clone()).await,
+            Err(e) => Err(e.into()),
+        };
+        let succeeded = {
+            let mut jobs = JOBS.lock().unwrap();
+            let job = jobs.get_mut(&dav.directory).unwrap();
+            job.running = false;
+            match result {
+                Ok(_) => {
+                    info!(
+                        "Files of dav {} re-encrypted: {} done, {} skipped",
+                        dav.host, job.reencrypted, job.skipped
+                    );
+                    true
+                }
+                Err(e) => {
+                    error!("Could not re-encrypt the files of dav {}: {}", dav.host, e);
+                    job.error = Some(e.to_string());
+                    false
+                }
+            }
+        };
+        if succeeded {
+            reload.send(()).ok();
+        }
+    });
+}
+
+pub async fn get_reencryption(
+    config: Config,
+    _admin: Admin,
+    extract::Path(dav_id): extract::Path<(String, usize)>,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    let dav = config
+        .davs
+        .iter()
+        .find(|d| d.id == dav_id.1)
+        .ok_or((StatusCode::BAD_REQUEST, "dav doesn't exist".to_owned()))?;
+    let job = JOBS.lock().unwrap().get(&dav.directory).cloned().ok_or((
+        StatusCode::NOT_FOUND,
+        "no re-encryption for this dav".to_owned(),
+    ))?;
+    let encoded = serde_json::to_string(&job).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not encode re-encryption".to_owned(),
+        )
+    })?;
+    Ok((StatusCode::OK, encoded))
+}
+
+type Keys = ([u8; 32], [u8; 32]);
+
+/// Current and new keys of a dav, the new key being drawn and added as pending if the dav has none
+fn pending_keys(dav: &mut Dav) -> Result<Keys, (StatusCode, &'static str)> {
+    let wrong_passphrase = |_| (StatusCode::BAD_REQUEST, "could not unwrap the dav key");
+    load_key(dav).map_err(wrong_passphrase)?;
+    let passphrase = dav.passphrase.clone();
+    let wrapped = dav.wrapped_key.as_mut().unwrap();
+    let key = wrapped.unwrap_key(&passphrase).map_err(wrong_passphrase)?;
+    let new_key = match wrapped
+        .unwrap_pending_key(&passphrase)
+        .map_err(wrong_passphrase)?
+    {
+        Some(new_key) => new_key,
+        None => {
+            let new_key = random_key();
+            wrapped
+                .set_pending_key(&passphrase, &new_key)
+                .map_err(wrong_passphrase)?;
+            new_key
+        }
+    };
+    Ok((key, new_key))
+}
+
+async fn promote_pending_key(config_file: &str, dav_id: usize) -> anyhow::Result<()> {
+    let mut config = Config::from_file(config_file).await?;
+    if let Some(wrapped) = config
+        .davs
+        .iter_mut()
+        .find(|d| d.id == dav_id)
+        .and_then(|d| d.wrapped_key.as_mut())
+    {
+        wrapped.promote_pending_key();
+    }
+    config.to_file(config_file).await
+}
+
+/// Conversion of the files of a dav from a key to another, a missing key standing for plain files
+pub(crate) struct Job {
+    /// Dav as it is once converted
+    dav: Dav,
+    key: Option<[u8; 32]>,
+    store: Option<ContentStore>,
+}
+
+impl Job {
+    pub(crate) fn new(dav: Dav, key: Option<[u8; 32]>) -> Self {
+        Self {
+            store: ContentStore::new(&dav),
+            dav,
+            key,
+        }
+    }
+
+    pub(crate) async fn run(&self) -> io::Result<()> {
+        for root in self.roots().await? {
+            // The uploads in progress are not whole encrypted streams, they are dropped
+            match fs::remove_dir_all(root.join(INTERNAL_DIR).join("uploads")).await {
+                Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+            self.reencrypt_dir(&root, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Directories of the dav : its directory, or every home directory for the templated davs
+    async fn roots(&self) -> io::Result<Vec<PathBuf>> {
+        if !self.dav.is_home() {
+            return Ok(vec![PathBuf::from(&self.dav.directory)]);
+        }
+        let homes = match self.dav.directory.split(LOGIN_PLACEHOLDER).next() {
+            Some(homes) if !homes.is_empty() => homes,
+            _ => ".",
+        };
+        let mut roots = vec![];
+        let mut entries = match fs::read_dir(homes).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(roots),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(home) = entry
+                .file_name()
+                .to_str()
+                .and_then(|login| self.dav.home_directory(login))
+            {
+                if fs::metadata(&home).await.map_or(false, |m| m.is_dir()) {
+                    roots.push(PathBuf::from(home));
+                }
+            }
+        }
+        Ok(roots)
+    }
+
+    fn reencrypt_dir<'a>(&'a self, dir: &'a Path, internal: bool) -> BoxFuture<'a, io::Result<()>> {
+        async move {
+            let mut entries = fs::read_dir(dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let path = entry.path();
+                let file_type = entry.file_type().await?;
+                if file_type.is_dir() {
+                    self.reencrypt_dir(&path, internal || entry.file_name() == INTERNAL_DIR)
+                        .await?;
+                } else if file_type.is_file()
+                    && path
+                        .extension()
+                        .map_or(true, |ext| ext != PARTIAL_EXTENSION)
+                {
+                    let reencrypted = self.convert_file(&path).await?;
+                    // The trash and the versions are not deduplicated
+                    if let (true, false, Some(store)) = (reencrypted, internal, &self.store) {
+                        store.store(&path).await?;
+                    }
+                    let mut jobs = JOBS.lock().unwrap();
+                    let job = jobs.get_mut(&self.dav.directory).unwrap();
+                    if reencrypted {
+                        job.reencrypted += 1;
+                    } else {
+                        job.skipped += 1;
+                    }
+                }
+            }
+            Ok(())
+        }
+        .boxed()
+    }
+
+    /// Convert a file to the new key, false is returned for the files left as they are as neither key can decrypt them
+    async fn convert_file(&self, path: &Path) -> io::Result<bool> {
+        let encrypted = match self.key {
+            Some(key) => opens(path, key).await?,
+            None => false,
+        };
+        if !encrypted {
+            match (self.key, self.dav.key) {
+                // Files migrated by an interrupted job
+                (Some(_), Some(new_key)) => return opens(path, new_key).await,
+                // Files taken as plain, that an interrupted job may have left encrypted already
+                (None, Some(new_key)) if opens(path, new_key).await? => return Ok(true),
+                (None, Some(_)) if encrypted_with_another_key(path).await? => return Ok(false),
+                (None, Some(_)) => (),
+                (_, None) => return Ok(true),
+            }
+        }
+        let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+        let partial = path.with_file_name(format!(
+            ".{}.{}.{}",
+            file_name,
+            Uuid::new_v4().simple(),
+            PARTIAL_EXTENSION
+        ));
+        let result = async {
+            let mut reader: Pin<Box<dyn AsyncRead + Send>> = match self.key {
+                Some(key) if encrypted => {
+                    let plain =
+                        EncryptedStreamer::new(fs::File::open(path).await?, key).into_stream();
+                    Box::pin(StreamReader::new(plain.map_ok(Cursor::new)))
+                }
+                _ => Box::pin(fs::File::open(path).await?),
+            };
+            let mut file = fs::File::create(&partial).await?;
+            match self.dav.key {
+                Some(new_key) => {
+                    EncryptedStreamer::new(file, new_key)
+                        .copy_from(&mut reader)
+                        .await?;
+                }
+                None => {
+                    tokio::io::copy(&mut reader, &mut file).await?;
+                }
+            }
+            fs::rename(&partial, path).await
+        }
+        .await;
+        if result.is_err() {
+            fs::remove_file(&partial).await.ok();
+        }
+        result.map(|_| true)
+    }
+}
+
+/// Whether a file begins with the header of an encrypted file, which plain files hardly ever do
+async fn encrypted_with_another_key(path: &Path) -> io::Result<bool> {
+    match Header::read(&mut fs::File::open(path).await?).await {
+        Ok(header) => Ok(header.key_id.is_some()),
+        Err(e) if matches!(e.kind(), ErrorKind::InvalidData | ErrorKind::UnexpectedEof) => {
+            Ok(false)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Whether the first chunk of a file can be decrypted with a key
+pub(crate) async fn opens(path: &Path, key: [u8; 32]) -> io::Result<bool> {
+    let mut stream = EncryptedStreamer::new(fs::File::open(path).await?, key).into_stream();
+    match stream.next().await {
+        Some(Ok(_)) => Ok(true),
+        // Decryption errors, files encrypted with another key, or files too short to begin with a header
+        Some(Err(e))
+            if matches!(
+                e.kind(),
+                ErrorKind::Other | ErrorKind::InvalidData | ErrorKind::UnexpectedEof
+            ) =>
+        {
+            Ok(false)
+        }
+        Some(Err(e)) => Err(e),
+        None => Ok(false),
+    }
+}
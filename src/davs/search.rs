@@ -0,0 +1,686 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use tokio::io::AsyncReadExt;
+use uuid::Uuid;
+use xml::reader::{EventReader, XmlEvent};
+
+use super::encrypted_streamer::{decrypted_size, EncryptedStreamer, HEADER_SIZE};
+use super::model::Dav;
+use super::names::NameCipher;
+use super::webdav_server::decode_uri;
+use super::{is_internal, INTERNAL_DIR, PARTIAL_EXTENSION};
+
+/// Files above this size are indexed by name only
+const MAX_INDEXED_SIZE: u64 = 16_000_000;
+/// Distinct terms kept for a single file
+const MAX_TERMS: usize = 20_000;
+const MIN_TERM_LEN: usize = 2;
+const MAX_TERM_LEN: usize = 64;
+/// Weight of a query term found in the name of a file, against the tf-idf score of its content
+const NAME_WEIGHT: f64 = 10.0;
+
+const TEXT_EXTENSIONS: &[&str] = &[
+    "txt", "md", "markdown", "rst", "org", "tex", "csv", "tsv", "json", "xml", "html", "htm",
+    "yaml", "yml", "toml", "ini", "conf", "log", "sql", "css", "js", "ts", "rs", "py", "go", "c",
+    "h", "cpp", "java", "sh",
+];
+
+lazy_static::lazy_static! {
+    static ref INDEXES: Mutex<HashMap<PathBuf, Arc<tokio::sync::Mutex<Index>>>> = Mutex::new(HashMap::new());
+}
+
+/// Content search within a dav, parsed from the query string of a GET on a directory
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct SearchQuery {
+    /// Terms to find in the names or the contents of the files, all of them must match
+    pub terms: Vec<String>,
+    /// "dir", "file", a mime type prefix ("image", "text/plain") or an extension
+    pub kind: Option<String>,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// Modification times bounds, as milliseconds since the epoch
+    pub after: Option<u64>,
+    pub before: Option<u64>,
+    pub offset: usize,
+    pub limit: Option<usize>,
+}
+
+impl SearchQuery {
+    const PARAMS: [&'static str; 8] = [
+        "q", "type", "min_size", "max_size", "after", "before", "offset", "limit",
+    ];
+
+    /// Whether a query string asks for a search
+    pub fn is_search(query: &str) -> bool {
+        query
+            .split('&')
+            .any(|param| Self::PARAMS.contains(&param.split('=').next().unwrap_or_default()))
+    }
+
+    pub fn parse(query: &str) -> Result<Self, String> {
+        let mut search = Self::default();
+        for param in query.split('&').filter(|param| !param.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = decode_uri(&value.replace('+', " "))
+                .ok_or_else(|| format!("invalid value for {}", name))?
+                .into_owned();
+            let number = |value: &str| {
+                value
+                    .parse::<u64>()
+                    .map_err(|_| format!("{} must be a number", name))
+            };
+            match name {
+                "q" => search.terms = value.split_whitespace().map(str::to_lowercase).collect(),
+                "type" if !value.is_empty() => search.kind = Some(value.to_lowercase()),
+                "min_size" => search.min_size = Some(number(&value)?),
+                "max_size" => search.max_size = Some(number(&value)?),
+                "after" => search.after = Some(parse_date(&value)?),
+                "before" => search.before = Some(parse_date(&value)?),
+                "offset" => search.offset = number(&value)? as usize,
+                "limit" => search.limit = Some(number(&value)? as usize),
+                _ => (),
+            }
+        }
+        Ok(search)
+    }
+
+    fn matches_filters(&self, entry: &IndexEntry) -> bool {
+        self.min_size
+            .map_or(true, |min| !entry.is_dir && entry.size >= min)
+            && self
+                .max_size
+                .map_or(true, |max| !entry.is_dir && entry.size <= max)
+            && self.after.map_or(true, |after| entry.mtime >= after)
+            && self.before.map_or(true, |before| entry.mtime < before)
+            && self.kind.as_ref().map_or(true, |kind| entry.is_kind(kind))
+    }
+}
+
+/// Milliseconds since the epoch of a YYYY-MM-DD (UTC) or RFC 3339 date
+fn parse_date(value: &str) -> Result<u64, String> {
+    let time = match NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        Ok(date) => Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap()),
+        Err(_) => DateTime::parse_from_rfc3339(value)
+            .map_err(|_| format!("invalid date {}", value))?
+            .with_timezone(&Utc),
+    };
+    Ok(time.timestamp_millis().max(0) as u64)
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+struct IndexEntry {
+    /// Name of the file as the users know it
+    name: String,
+    is_dir: bool,
+    size: u64,
+    /// Size on disk, telling with the modification time whether the file changed since it was indexed
+    len: u64,
+    mtime: u64,
+    terms: HashMap<String, u32>,
+}
+
+impl IndexEntry {
+    fn is_kind(&self, kind: &str) -> bool {
+        match kind {
+            "dir" => self.is_dir,
+            "file" => !self.is_dir,
+            _ if self.is_dir => false,
+            kind if kind.contains('/') || is_mime_type(kind) => {
+                let mime = mime_guess::from_path(&self.name).first_or_octet_stream();
+                mime.essence_str() == kind || mime.type_() == kind
+            }
+            kind => Path::new(&self.name)
+                .extension()
+                .and_then(|ext| ext.to_str())
+                .map_or(false, |ext| {
+                    ext.eq_ignore_ascii_case(kind.trim_start_matches('.'))
+                }),
+        }
+    }
+}
+
+fn is_mime_type(kind: &str) -> bool {
+    matches!(
+        kind,
+        "application" | "audio" | "font" | "image" | "model" | "text" | "video"
+    )
+}
+
+/// Full-text index of a local dav : the names and the extracted text of its files, keyed by their path on disk.
+/// It is kept in the dav internal directory, encrypted on encrypted davs.
+#[derive(Default, Debug, Serialize, Deserialize)]
+struct Index {
+    entries: BTreeMap<String, IndexEntry>,
+}
+
+impl Index {
+    fn file(root: &Path) -> PathBuf {
+        root.join(INTERNAL_DIR).join("index.json")
+    }
+
+    async fn load(root: &Path, key: Option<[u8; 32]>) -> io::Result<Self> {
+        let mut file = fs::File::open(Self::file(root)).await?;
+        let mut content = vec![];
+        match key {
+            Some(key) => {
+                EncryptedStreamer::new(file, key)
+                    .copy_to(&mut content)
+                    .await?;
+            }
+            None => {
+                file.read_to_end(&mut content).await?;
+            }
+        }
+        serde_json::from_slice(&content).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    async fn save(&self, root: &Path, key: Option<[u8; 32]>) -> io::Result<()> {
+        let file = Self::file(root);
+        let partial =
+            file.with_extension(format!("{}.{}", Uuid::new_v4().simple(), PARTIAL_EXTENSION));
+        fs::create_dir_all(file.parent().unwrap()).await?;
+        let content = serde_json::to_vec(self)?;
+        match key {
+            Some(key) => {
+                EncryptedStreamer::new(fs::File::create(&partial).await?, key)
+                    .copy_from(&mut content.as_slice())
+                    .await?;
+            }
+            None => fs::write(&partial, content).await?,
+        }
+        fs::rename(partial, file).await
+    }
+
+    /// Index a file, or a directory and its whole content
+    async fn add(
+        &mut self,
+        path: &Path,
+        indexer: &Indexer,
+        previous: &BTreeMap<String, IndexEntry>,
+    ) -> io::Result<()> {
+        let mut pending = vec![path.to_path_buf()];
+        while let Some(path) = pending.pop() {
+            let rel_path = match path.strip_prefix(&indexer.root) {
+                Ok(rel_path) => rel_path.to_path_buf(),
+                Err(_) => continue,
+            };
+            if is_internal(&rel_path) {
+                continue;
+            }
+            let meta = match fs::symlink_metadata(&path).await {
+                Ok(meta) if !meta.is_symlink() || indexer.allow_symlinks => {
+                    fs::metadata(&path).await?
+                }
+                _ => continue,
+            };
+            if meta.is_dir() {
+                let mut entries = fs::read_dir(&path).await?;
+                while let Some(entry) = entries.next_entry().await? {
+                    pending.push(entry.path());
+                }
+            }
+            // The dav root is not a search result
+            if rel_path.components().next().is_none() {
+                continue;
+            }
+            let key = key_of(&rel_path);
+            // The content of the unchanged files is not extracted again
+            let entry = match previous.get(&key) {
+                Some(entry)
+                    if entry.is_dir == meta.is_dir()
+                        && entry.len == meta.len()
+                        && entry.mtime == mtime(&meta) =>
+                {
+                    Some(entry.clone())
+                }
+                _ => indexer.entry(&path, &rel_path, &meta).await,
+            };
+            if let Some(entry) = entry {
+                self.entries.insert(key, entry);
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove a file, or a directory and its whole content (everything for the dav root), returning the removed entries
+    fn remove(&mut self, rel_path: &Path) -> BTreeMap<String, IndexEntry> {
+        let key = key_of(rel_path);
+        if key.is_empty() {
+            return std::mem::take(&mut self.entries);
+        }
+        let prefix = format!("{}/", key);
+        let removed_keys: Vec<String> = std::iter::once(key.clone())
+            .chain(
+                self.entries
+                    .range(prefix.clone()..)
+                    .take_while(|(path, _)| path.starts_with(&prefix))
+                    .map(|(path, _)| path.clone()),
+            )
+            .collect();
+        let mut removed = BTreeMap::new();
+        for key in removed_keys {
+            if let Some(entry) = self.entries.remove(&key) {
+                removed.insert(key, entry);
+            }
+        }
+        removed
+    }
+
+    /// Paths on disk of the entries matching a search within a directory, the most relevant first
+    fn search(&self, root: &Path, scope: &Path, query: &SearchQuery) -> Vec<PathBuf> {
+        let scope = match scope.strip_prefix(root) {
+            Ok(scope) if scope.components().next().is_some() => format!("{}/", key_of(scope)),
+            _ => String::new(),
+        };
+        let files = self.entries.values().filter(|entry| !entry.is_dir).count() as f64;
+        let frequencies: Vec<(Vec<String>, f64)> = query
+            .terms
+            .iter()
+            .map(|term| {
+                let tokens = tokenize(term);
+                let documents = self
+                    .entries
+                    .values()
+                    .filter(|entry| tokens.iter().all(|t| entry.terms.contains_key(t)))
+                    .count() as f64;
+                (tokens, (1.0 + files / documents.max(1.0)).ln())
+            })
+            .collect();
+        let mut hits: Vec<(f64, &String)> = self
+            .entries
+            .range(scope.clone()..)
+            .take_while(|(path, _)| path.starts_with(&scope))
+            .filter(|(_, entry)| query.matches_filters(entry))
+            .filter_map(|(path, entry)| {
+                let name = entry.name.to_lowercase();
+                let mut score = 0.0;
+                for (term, (tokens, idf)) in query.terms.iter().zip(&frequencies) {
+                    let content =
+                        !tokens.is_empty() && tokens.iter().all(|t| entry.terms.contains_key(t));
+                    if name.contains(term.as_str()) {
+                        score += NAME_WEIGHT;
+                    } else if !content {
+                        return None;
+                    }
+                    if content {
+                        score += tokens
+                            .iter()
+                            .map(|t| (1.0 + (entry.terms[t] as f64).ln()) * idf)
+                            .sum::<f64>();
+                    }
+                }
+                Some((score, path))
+            })
+            .collect();
+        hits.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+        hits.into_iter().map(|(_, path)| root.join(path)).collect()
+    }
+}
+
+/// What is needed to index the files of a dav
+struct Indexer {
+    root: PathBuf,
+    key: Option<[u8; 32]>,
+    names: Option<NameCipher>,
+    allow_symlinks: bool,
+}
+
+impl Indexer {
+    fn new(dav: &Dav) -> Self {
+        Self {
+            root: PathBuf::from(&dav.directory),
+            key: dav.key,
+            names: NameCipher::new(dav),
+            allow_symlinks: dav.allow_symlinks,
+        }
+    }
+
+    async fn entry(
+        &self,
+        path: &Path,
+        rel_path: &Path,
+        meta: &std::fs::Metadata,
+    ) -> Option<IndexEntry> {
+        let name = rel_path.file_name()?.to_str()?;
+        let name = match &self.names {
+            Some(names) => names.decrypt(name).ok()?,
+            None => name.to_owned(),
+        };
+        let mut entry = IndexEntry {
+            name,
+            is_dir: meta.is_dir(),
+            size: meta.len(),
+            len: meta.len(),
+            mtime: mtime(meta),
+            terms: HashMap::new(),
+        };
+        if entry.is_dir {
+            entry.size = 0;
+            return Some(entry);
+        }
+        if let (Some(_), true) = (self.key, meta.len() > 0) {
+            let mut header = vec![0; HEADER_SIZE.min(meta.len() as usize)];
+            let mut file = fs::File::open(path).await.ok()?;
+            file.read_exact(&mut header).await.ok()?;
+            entry.size = decrypted_size(&mut header.as_slice(), meta.len())
+                .await
+                .ok()?;
+        }
+        if entry.size <= MAX_INDEXED_SIZE && has_extractor(&entry.name) {
+            if let Ok(content) = self.read(path).await {
+                let name = entry.name.clone();
+                entry.terms = tokio::task::spawn_blocking(move || {
+                    extract_text(&name, &content)
+                        .map(|text| count_terms(&text))
+                        .unwrap_or_default()
+                })
+                .await
+                // The extraction of a malformed document may panic
+                .unwrap_or_default();
+            }
+        }
+        Some(entry)
+    }
+
+    async fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        match self.key {
+            Some(key) => {
+                let mut content = vec![];
+                EncryptedStreamer::new(fs::File::open(path).await?, key)
+                    .copy_to(&mut content)
+                    .await?;
+                Ok(content)
+            }
+            None => fs::read(path).await,
+        }
+    }
+}
+
+/// Index of a dav, loaded from its internal directory or built if missing and `build` is set
+async fn index_for(
+    indexer: &Indexer,
+    build: bool,
+) -> io::Result<Option<Arc<tokio::sync::Mutex<Index>>>> {
+    if let Some(index) = INDEXES.lock().unwrap().get(&indexer.root) {
+        return Ok(Some(index.clone()));
+    }
+    let index = match Index::load(&indexer.root, indexer.key).await {
+        Ok(index) => index,
+        // Missing, or written with a previous key
+        Err(_) if build => {
+            let mut index = Index::default();
+            index.add(&indexer.root, indexer, &BTreeMap::new()).await?;
+            index.save(&indexer.root, indexer.key).await?;
+            index
+        }
+        Err(_) => return Ok(None),
+    };
+    let index = INDEXES
+        .lock()
+        .unwrap()
+        .entry(indexer.root.clone())
+        .or_insert_with(|| Arc::new(tokio::sync::Mutex::new(index)))
+        .clone();
+    Ok(Some(index))
+}
+
+/// Search the files of a local dav within a directory, returning their paths on disk, the most relevant first.
+/// The index is built on the first search.
+pub async fn search(dav: &Dav, scope: &Path, query: &SearchQuery) -> io::Result<Vec<PathBuf>> {
+    let indexer = Indexer::new(dav);
+    let index = index_for(&indexer, true).await?.unwrap();
+    let index = index.lock().await;
+    Ok(index.search(&indexer.root, scope, query))
+}
+
+/// Update the index of a local dav after some of its files (or directories, or the whole dav) were changed, created or removed
+pub async fn refresh(dav: &Dav, paths: &[PathBuf]) -> io::Result<()> {
+    let indexer = Indexer::new(dav);
+    // Nothing to keep in sync until the dav is searched
+    let index = match index_for(&indexer, false).await? {
+        Some(index) => index,
+        None => return Ok(()),
+    };
+    let mut index = index.lock().await;
+    for path in paths {
+        if let Ok(rel_path) = path.strip_prefix(&indexer.root) {
+            let previous = index.remove(rel_path);
+            index.add(path, &indexer, &previous).await?;
+        }
+    }
+    index.save(&indexer.root, indexer.key).await
+}
+
+fn mtime(meta: &std::fs::Metadata) -> u64 {
+    meta.modified()
+        .unwrap_or(SystemTime::UNIX_EPOCH)
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+fn key_of(rel_path: &Path) -> String {
+    rel_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn extension(name: &str) -> String {
+    Path::new(name)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_lowercase()
+}
+
+fn has_extractor(name: &str) -> bool {
+    let extension = extension(name);
+    TEXT_EXTENSIONS.contains(&extension.as_str())
+        || matches!(
+            extension.as_str(),
+            "pdf" | "docx" | "xlsx" | "pptx" | "odt" | "ods" | "odp"
+        )
+}
+
+/// Text of a document, from its (plain) name and content
+fn extract_text(name: &str, content: &[u8]) -> Option<String> {
+    let extension = extension(name);
+    match extension.as_str() {
+        "pdf" => pdf_extract::extract_text_from_mem(content).ok(),
+        "docx" => office_text(content, |entry| entry == "word/document.xml"),
+        "xlsx" => office_text(content, |entry| entry == "xl/sharedStrings.xml"),
+        "pptx" => office_text(content, |entry| {
+            entry.starts_with("ppt/slides/slide") && entry.ends_with(".xml")
+        }),
+        "odt" | "ods" | "odp" => office_text(content, |entry| entry == "content.xml"),
+        extension if TEXT_EXTENSIONS.contains(&extension) => {
+            Some(String::from_utf8_lossy(content).into_owned())
+        }
+        _ => None,
+    }
+}
+
+/// Text of the xml entries of a zipped office document
+fn office_text(content: &[u8], wanted: impl Fn(&str) -> bool) -> Option<String> {
+    let mut archive = zip::ZipArchive::new(Cursor::new(content)).ok()?;
+    let mut text = String::new();
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i).ok()?;
+        if !wanted(entry.name()) {
+            continue;
+        }
+        let mut xml = vec![];
+        entry
+            .by_ref()
+            .take(MAX_INDEXED_SIZE)
+            .read_to_end(&mut xml)
+            .ok()?;
+        text.push_str(&xml_text(&xml));
+        text.push(' ');
+    }
+    Some(text)
+}
+
+/// Character data of an xml document, stopping at the first error
+fn xml_text(xml: &[u8]) -> String {
+    let mut text = String::new();
+    for event in EventReader::new(xml) {
+        match event {
+            Ok(XmlEvent::Characters(chars)) | Ok(XmlEvent::CData(chars)) => {
+                text.push_str(&chars);
+            }
+            // Paragraphs, cells and runs are separate words
+            Ok(XmlEvent::EndElement { .. }) => text.push(' '),
+            Ok(_) => (),
+            Err(_) => break,
+        }
+    }
+    text
+}
+
+/// Lowercase alphanumeric words of a text
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| (MIN_TERM_LEN..=MAX_TERM_LEN).contains(&word.chars().count()))
+        .map(str::to_lowercase)
+        .collect()
+}
+
+fn count_terms(text: &str) -> HashMap<String, u32> {
+    let mut terms = HashMap::new();
+    for token in tokenize(text) {
+        if terms.len() < MAX_TERMS || terms.contains_key(&token) {
+            *terms.entry(token).or_insert(0) += 1;
+        }
+    }
+    terms
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Write;
+    use std::path::Path;
+
+    use crate::davs::model::Dav;
+
+    use super::{count_terms, extract_text, refresh, search, tokenize, xml_text, SearchQuery};
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(
+            tokenize("Hello, World! L'été 2022 a-b"),
+            vec!["hello", "world", "été", "2022"]
+        );
+        let terms = count_terms("one two one");
+        assert_eq!(terms["one"], 2);
+        assert_eq!(terms["two"], 1);
+    }
+
+    #[test]
+    fn test_office_text() {
+        let xml = br#"<w:document xmlns:w="urn:w"><w:p><w:r><w:t>Quarterly</w:t></w:r><w:r><w:t>report</w:t></w:r></w:p></w:document>"#;
+        assert_eq!(
+            xml_text(xml).split_whitespace().collect::<Vec<_>>(),
+            vec!["Quarterly", "report"]
+        );
+        let mut docx = zip::ZipWriter::new(std::io::Cursor::new(vec![]));
+        docx.start_file("[Content_Types].xml", Default::default())
+            .unwrap();
+        docx.write_all(b"<Types/>").unwrap();
+        docx.start_file("word/document.xml", Default::default())
+            .unwrap();
+        docx.write_all(b"<document><body><p>Budget forecast</p></body></document>")
+            .unwrap();
+        let docx = docx.finish().unwrap().into_inner();
+        let text = extract_text("Report.DOCX", &docx).unwrap();
+        assert_eq!(tokenize(&text), vec!["budget", "forecast"]);
+        assert_eq!(extract_text("image.png", b"budget"), None);
+    }
+
+    #[test]
+    fn test_parse_search_query() {
+        let query = SearchQuery::parse(
+            "q=Annual%20Report&type=pdf&min_size=10&after=2022-01-02&offset=5&limit=10",
+        )
+        .unwrap();
+        assert_eq!(query.terms, vec!["annual", "report"]);
+        assert_eq!(query.kind.as_deref(), Some("pdf"));
+        assert_eq!(query.min_size, Some(10));
+        assert_eq!(query.after, Some(1_641_081_600_000));
+        assert_eq!((query.offset, query.limit), (5, Some(10)));
+        assert!(SearchQuery::parse("limit=ten").is_err());
+        assert!(SearchQuery::is_search("type=dir"));
+        assert!(!SearchQuery::is_search("zip"));
+    }
+
+    #[tokio::test]
+    async fn test_search_index() {
+        // Arrange
+        let base = "search_index_test";
+        let _ = fs::remove_dir_all(base);
+        fs::create_dir_all(format!("{}/notes", base)).unwrap();
+        fs::write(
+            format!("{}/notes/shopping.md", base),
+            "apples bananas apples",
+        )
+        .unwrap();
+        fs::write(format!("{}/notes/todo.txt", base), "buy bananas").unwrap();
+        fs::write(format!("{}/apples.bin", base), "binary").unwrap();
+        let dav = Dav {
+            directory: base.to_owned(),
+            ..Default::default()
+        };
+        let root = Path::new(base);
+        let query = |q: &str| SearchQuery::parse(q).unwrap();
+
+        // Act and assert : ranked content and name matches
+        let results = search(&dav, root, &query("q=apples")).await.unwrap();
+        assert_eq!(
+            results,
+            vec![root.join("apples.bin"), root.join("notes/shopping.md")]
+        );
+        let results = search(&dav, root, &query("q=bananas&type=txt"))
+            .await
+            .unwrap();
+        assert_eq!(results, vec![root.join("notes/todo.txt")]);
+        let results = search(&dav, root, &query("type=dir")).await.unwrap();
+        assert_eq!(results, vec![root.join("notes")]);
+
+        // Act and assert : the index follows the changes
+        fs::write(format!("{}/notes/todo.txt", base), "sell apples").unwrap();
+        fs::rename(
+            format!("{}/notes/shopping.md", base),
+            format!("{}/shopping.md", base),
+        )
+        .unwrap();
+        refresh(
+            &dav,
+            &[
+                root.join("notes/todo.txt"),
+                root.join("notes/shopping.md"),
+                root.join("shopping.md"),
+            ],
+        )
+        .await
+        .unwrap();
+        let results = search(&dav, &root.join("notes"), &query("q=apples"))
+            .await
+            .unwrap();
+        assert_eq!(results, vec![root.join("notes/todo.txt")]);
+        let results = search(&dav, root, &query("q=bananas")).await.unwrap();
+        assert_eq!(results, vec![root.join("shopping.md")]);
+
+        // Cleanup
+        fs::remove_dir_all(base).unwrap();
+    }
+}
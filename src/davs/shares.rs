@@ -0,0 +1,724 @@
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
+use axum::extract::{ConnectInfo, Path as UrlPath};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use headers::authorization::Basic;
+use headers::{Authorization, HeaderMapExt};
+use hyper::header::{
+    HeaderValue, CONTENT_RANGE, IF_MATCH, IF_NONE_MATCH, LOCATION, RANGE, RETRY_AFTER,
+    WWW_AUTHENTICATE,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use log::error;
+use rand::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::configuration::{Config, ConfigFile, ConfigMap, HostType};
+use crate::users::{check_authorization, Admin, User};
+use crate::utils::{random_string, write_atomically};
+
+use super::acl::{Acl, AclRule, Permission};
+use super::model::Dav;
+use super::names::NameCipher;
+use super::storage;
+use super::webdav_server::{decode_uri, encode_uri};
+use super::{is_internal, reencryption, WEBDAV_SERVER};
+
+/// Url prefix of the shares, on the main hostname
+pub const SHARES_URL: &str = "/share/";
+
+const TOKEN_LENGTH: usize = 32;
+
+/// Number of names tried for a file dropped where another one already exists
+const MAX_RENAMINGS: usize = 1000;
+
+/// Wrong passwords accepted from a visitor of a share before the visitor is locked out
+const MAX_PASSWORD_FAILURES: u32 = 5;
+const PASSWORD_LOCKOUT: Duration = Duration::from_secs(60);
+
+lazy_static::lazy_static! {
+    /// Serializes the updates of the shares and of their download counts
+    static ref SHARES_LOCK: Mutex<()> = Mutex::new(());
+    /// Wrong passwords given for each share token by each client address, with the time of the last one
+    static ref PASSWORD_FAILURES: std::sync::Mutex<HashMap<(String, IpAddr), (u32, Instant)>> =
+        std::sync::Mutex::new(HashMap::new());
+}
+
+/// What the visitors of a share can do
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ShareMode {
+    /// Download the shared file, or browse and download the content of the shared directory
+    #[default]
+    ReadOnly,
+    /// Put new files right into the shared directory, without seeing what it holds nor creating subdirectories.
+    /// A file dropped under the name of an existing one is renamed.
+    UploadOnly,
+}
+
+/// Largest file that can be put through a request, set on the requests of the file drops
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MaxFileSize(pub u64);
+
+/// Public link to a path of a dav, reachable without an account
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Share {
+    pub token: String,
+    pub dav_id: usize,
+    /// Plain path within the dav, or within the home directory of the owner for templated davs
+    pub path: String,
+    /// Login of the user who created the share, whose rights bound the share
+    pub owner: String,
+    /// Argon2 hash of the password asked to the visitors, if any
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub password: String,
+    #[serde(default)]
+    pub mode: ShareMode,
+    /// Expiry date, in milliseconds since the epoch
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    /// Downloads allowed before the share expires. Each complete GET of a file counts,
+    /// as does each GET of a directory as a zip; listings, HEAD and ranged requests do not.
+    /// The downloads are counted apart from the configuration.
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+    /// Largest file that can be dropped into an upload only share, in bytes
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    pub created_at: u64,
+}
+
+impl Share {
+    pub fn is_expired(&self, downloads: u64, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+            || self
+                .max_downloads
+                .is_some_and(|max_downloads| downloads >= max_downloads)
+    }
+
+    fn permission(&self) -> Permission {
+        match self.mode {
+            ShareMode::ReadOnly => Permission::Read,
+            ShareMode::UploadOnly => Permission::Write,
+        }
+    }
+
+    fn redacted(&self, downloads: &Downloads) -> ListedShare {
+        let mut share = self.clone();
+        if !share.password.is_empty() {
+            share.password = "REDACTED".to_owned();
+        }
+        ListedShare {
+            downloads: downloads.of(&share.token),
+            share,
+        }
+    }
+}
+
+/// A share as listed to its owner and to the admins
+#[derive(Debug, Serialize)]
+pub struct ListedShare {
+    #[serde(flatten)]
+    pub share: Share,
+    pub downloads: u64,
+}
+
+/// Downloads counted for each share token. They are saved in their own file next to the configuration,
+/// which every counted download would otherwise rewrite.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Downloads(HashMap<String, u64>);
+
+impl Downloads {
+    fn file(config_file: &str) -> PathBuf {
+        Path::new(config_file).with_extension("downloads.yaml")
+    }
+
+    async fn load(config_file: &str) -> anyhow::Result<Self> {
+        match tokio::fs::read_to_string(Self::file(config_file)).await {
+            Ok(data) => Ok(serde_yaml::from_str(&data)?),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&self, config_file: &str) -> anyhow::Result<()> {
+        let contents = serde_yaml::to_string(self)?;
+        write_atomically(
+            &Self::file(config_file).to_string_lossy(),
+            contents.as_bytes(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    fn of(&self, token: &str) -> u64 {
+        self.0.get(token).copied().unwrap_or(0)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NewShare {
+    pub dav_id: usize,
+    pub path: String,
+    #[serde(default)]
+    pub password: String,
+    #[serde(default)]
+    pub mode: ShareMode,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+    #[serde(default)]
+    pub max_downloads: Option<u64>,
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+}
+
+/// Shares of the user, and the ones of the other users that the user manages
+pub async fn get_user_shares(
+    config: Config,
+    Extension(config_file): Extension<ConfigFile>,
+    Extension(config_map): Extension<Arc<ConfigMap>>,
+    user: User,
+) -> Result<Json<Vec<ListedShare>>, (StatusCode, &'static str)> {
+    let downloads = load_downloads(&config_file).await?;
+    Ok(Json(
+        config
+            .shares
+            .iter()
+            .filter(|share| share.owner == user.login || administers(&config_map, &user, share))
+            .map(|share| share.redacted(&downloads))
+            .collect(),
+    ))
+}
+
+pub async fn add_share(
+    config_file: Extension<ConfigFile>,
+    config_map: Extension<Arc<ConfigMap>>,
+    user: User,
+    Json(payload): Json<NewShare>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let path = normalize(&payload.path).ok_or((StatusCode::BAD_REQUEST, "invalid path"))?;
+    if payload
+        .expires_at
+        .is_some_and(|expires_at| expires_at <= now_millis())
+    {
+        return Err((StatusCode::BAD_REQUEST, "expiry date is in the past"));
+    }
+    if payload.max_downloads == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "download limit must be positive"));
+    }
+    if payload.max_file_size == Some(0) {
+        return Err((StatusCode::BAD_REQUEST, "file size limit must be positive"));
+    }
+    let dav = find_dav(&config_map, payload.dav_id)
+        .ok_or((StatusCode::BAD_REQUEST, "dav doesn't exist"))?;
+    let mut share = Share {
+        token: random_string(TOKEN_LENGTH),
+        dav_id: payload.dav_id,
+        path,
+        owner: user.login.clone(),
+        password: String::new(),
+        mode: payload.mode,
+        expires_at: payload.expires_at,
+        max_downloads: payload.max_downloads,
+        max_file_size: payload.max_file_size,
+        created_at: now_millis(),
+    };
+    let dav = owner_dav(dav, &share, &user).ok_or((StatusCode::FORBIDDEN, "access denied"))?;
+    let meta = storage::for_dav(&dav)
+        .metadata(&fs_path(&dav, &share.path).ok_or((StatusCode::BAD_REQUEST, "invalid path"))?)
+        .await
+        .map_err(|_| (StatusCode::NOT_FOUND, "path doesn't exist"))?;
+    if share.mode == ShareMode::UploadOnly && !meta.is_dir {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "upload only shares must be directories",
+        ));
+    }
+    if !payload.password.is_empty() {
+        share.password = Argon2::default()
+            .hash_password(
+                payload.password.as_bytes(),
+                &SaltString::generate(&mut OsRng),
+            )
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "password hash failed"))?
+            .to_string();
+    }
+
+    let _lock = SHARES_LOCK.lock().await;
+    let mut config = Config::from_file(&config_file).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not load configuration",
+        )
+    })?;
+    config.shares.push(share.clone());
+    config
+        .to_file_or_internal_server_error(&config_file)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        Json(share.redacted(&Downloads::default())),
+    ))
+}
+
+pub async fn delete_user_share(
+    config_file: Extension<ConfigFile>,
+    Extension(config_map): Extension<Arc<ConfigMap>>,
+    user: User,
+    UrlPath(token): UrlPath<(String, String)>,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    remove_share(&config_file, &token.1, |share| {
+        share.owner == user.login || administers(&config_map, &user, share)
+    })
+    .await
+}
+
+pub async fn get_shares(
+    config: Config,
+    Extension(config_file): Extension<ConfigFile>,
+    _admin: Admin,
+) -> Result<Json<Vec<ListedShare>>, (StatusCode, &'static str)> {
+    let downloads = load_downloads(&config_file).await?;
+    Ok(Json(
+        config
+            .shares
+            .iter()
+            .map(|share| share.redacted(&downloads))
+            .collect(),
+    ))
+}
+
+async fn load_downloads(config_file: &str) -> Result<Downloads, (StatusCode, &'static str)> {
+    Downloads::load(config_file).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not load download counts",
+        )
+    })
+}
+
+pub async fn delete_share(
+    config_file: Extension<ConfigFile>,
+    _admin: Admin,
+    UrlPath(token): UrlPath<(String, String)>,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    remove_share(&config_file, &token.1, |_| true).await
+}
+
+async fn remove_share(
+    config_file: &str,
+    token: &str,
+    allowed: impl Fn(&Share) -> bool,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    let _lock = SHARES_LOCK.lock().await;
+    let mut config = Config::from_file(config_file).await.map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not load configuration",
+        )
+    })?;
+    match config
+        .shares
+        .iter()
+        .position(|share| share.token == token && allowed(share))
+    {
+        Some(pos) => config.shares.remove(pos),
+        None => return Err((StatusCode::BAD_REQUEST, "share doesn't exist")),
+    };
+    config.to_file_or_internal_server_error(config_file).await?;
+    // A stale count is harmless, the share being gone
+    if let Ok(mut downloads) = Downloads::load(config_file).await {
+        if downloads.0.remove(token).is_some() {
+            downloads.save(config_file).await.ok();
+        }
+    }
+    Ok((StatusCode::OK, "share deleted successfully"))
+}
+
+/// Serve the content of a share to its visitors, through the webdav server of its dav
+pub async fn share_handler(
+    config: Config,
+    Extension(config_map): Extension<Arc<ConfigMap>>,
+    Extension(config_file): Extension<ConfigFile>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut req: Request<Body>,
+) -> Response<Body> {
+    let (token, rest) = match req.uri().path().strip_prefix(SHARES_URL) {
+        Some(path) => path.split_once('/').unwrap_or((path, "")),
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let token = token.to_owned();
+    let share = match config.shares.iter().find(|share| share.token == token) {
+        Some(share) => share,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    let downloads = match Downloads::load(&config_file).await {
+        Ok(downloads) => downloads.of(&token),
+        Err(e) => {
+            error!("Could not load the downloads of share {}: {}", token, e);
+            return status(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    };
+    if share.is_expired(downloads, now_millis()) {
+        return status(StatusCode::GONE);
+    }
+    if !share.password.is_empty() {
+        let visitor = (token.clone(), addr.ip());
+        if is_locked_out(&visitor) {
+            let mut res = status(StatusCode::TOO_MANY_REQUESTS);
+            res.headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(PASSWORD_LOCKOUT.as_secs()));
+            return res;
+        }
+        if !password_matches(share, &visitor, &req).await {
+            let mut res = status(StatusCode::UNAUTHORIZED);
+            res.headers_mut().insert(
+                WWW_AUTHENTICATE,
+                HeaderValue::from_static("Basic realm=\"share\""),
+            );
+            return res;
+        }
+    }
+
+    // The share lasts as long as its owner keeps the access to the shared path
+    let owner = config.users.iter().find(|user| user.login == share.owner);
+    let dav = match (find_dav(&config_map, share.dav_id), owner) {
+        (Some(dav), Some(owner)) => owner_dav(dav, share, owner),
+        _ => None,
+    };
+    let mut dav = match dav {
+        Some(dav) => dav,
+        None => return status(StatusCode::NOT_FOUND),
+    };
+    if !reencryption::is_available(&dav) {
+        return status(StatusCode::SERVICE_UNAVAILABLE);
+    }
+    // The visitors get the rights of the share, and never those kept for the users of the dav
+    dav.trash = None;
+    dav.versioning = None;
+    dav.writable = share.mode == ShareMode::UploadOnly;
+    dav.acl = vec![AclRule {
+        path: "**".to_owned(),
+        permission: share.permission(),
+        roles: vec![],
+        users: vec![],
+    }];
+
+    // The requested path is looked up below the shared path, which it cannot escape
+    let mut rest = match decode_uri(rest) {
+        Some(rest) if normalize(&rest).is_some() => normalize(&rest).unwrap_or_default(),
+        _ => return status(StatusCode::NOT_FOUND),
+    };
+    let method = req.method().clone();
+    let allowed = match share.mode {
+        ShareMode::ReadOnly => matches!(method.as_str(), "GET" | "HEAD" | "OPTIONS" | "PROPFIND"),
+        ShareMode::UploadOnly => {
+            method == Method::OPTIONS || (method == Method::PUT && !rest.is_empty())
+        }
+    };
+    if !allowed {
+        return status(StatusCode::METHOD_NOT_ALLOWED);
+    }
+    if method == Method::PUT {
+        // Nothing already there can be replaced or completed, nor can directories be created
+        if req.headers().contains_key(CONTENT_RANGE) || rest.contains('/') {
+            return status(StatusCode::FORBIDDEN);
+        }
+        rest = match free_name(&dav, &share.path, &rest).await {
+            Some(rest) => rest,
+            None => return status(StatusCode::CONFLICT),
+        };
+        // A concurrent drop taking the same name fails instead of being replaced
+        req.headers_mut().remove(IF_MATCH);
+        req.headers_mut()
+            .insert(IF_NONE_MATCH, HeaderValue::from_static("*"));
+        if let Some(max_file_size) = share.max_file_size {
+            req.extensions_mut().insert(MaxFileSize(max_file_size));
+        }
+    }
+    let counted = method == Method::GET
+        && !req.headers().contains_key(RANGE)
+        && matches!(req.uri().query(), None | Some("") | Some("zip"));
+    let target = join(&share.path, &rest);
+    let uri = match req.uri().query() {
+        Some(query) => format!("/{}?{}", encode_uri(&target), query),
+        None => format!("/{}", encode_uri(&target)),
+    };
+    *req.uri_mut() = match uri.parse() {
+        Ok(uri) => uri,
+        Err(_) => return status(StatusCode::BAD_REQUEST),
+    };
+    // The download is counted before being served, so that concurrent requests cannot exceed the limit
+    if counted {
+        match reserve_download(&config_file, &token).await {
+            Ok(true) => (),
+            Ok(false) => return status(StatusCode::GONE),
+            Err(e) => {
+                error!("Could not count the download of share {}: {}", token, e);
+                return status(StatusCode::INTERNAL_SERVER_ERROR);
+            }
+        }
+    }
+
+    let res = WEBDAV_SERVER.clone().call(req, addr, &dav, &None).await;
+    if counted && !matches!(&res, Ok(res) if res.status() == StatusCode::OK) {
+        if let Err(e) = release_download(&config_file, &token).await {
+            error!("Could not release the download of share {}: {}", token, e);
+        }
+    }
+    let mut res = match res {
+        Ok(res) => res,
+        Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    // Tell the name the dropped file was given
+    if method == Method::PUT && res.status() == StatusCode::CREATED {
+        let location = format!("{}{}/{}", SHARES_URL, token, encode_uri(&rest));
+        if let Ok(location) = HeaderValue::from_str(&location) {
+            res.headers_mut().insert(LOCATION, location);
+        }
+    }
+    if res.status() == StatusCode::MULTI_STATUS {
+        return share_hrefs(res, &share.path, &token).await;
+    }
+    res
+}
+
+/// Point the hrefs of a webdav listing, made from the dav root, to the share
+async fn share_hrefs(res: Response<Body>, path: &str, token: &str) -> Response<Body> {
+    let (parts, body) = res.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+        Ok(body) => String::from_utf8_lossy(&body).into_owned(),
+        Err(_) => return status(StatusCode::INTERNAL_SERVER_ERROR),
+    };
+    let shared = format!("<D:href>{}", encode_uri(&format!("/{}", path)));
+    let body = body.replace(
+        &shared,
+        &format!("<D:href>{}{}", SHARES_URL, encode_uri(token)),
+    );
+    let mut res = Response::from_parts(parts, Body::from(body));
+    res.headers_mut().remove(hyper::header::CONTENT_LENGTH);
+    res
+}
+
+/// Name of a dropped file within the share, numbered after the existing files of the same name
+async fn free_name(dav: &Dav, shared: &str, name: &str) -> Option<String> {
+    let storage = storage::for_dav(dav);
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    for i in 0..MAX_RENAMINGS {
+        let candidate = match i {
+            0 => name.to_owned(),
+            i => format!("{} ({}){}", stem, i, extension),
+        };
+        let path = fs_path(dav, &join(shared, &candidate))?;
+        if storage.metadata(&path).await.is_err() {
+            return Some(candidate);
+        }
+    }
+    None
+}
+
+/// Count a download, false if the share expired or was revoked meanwhile
+async fn reserve_download(config_file: &str, token: &str) -> anyhow::Result<bool> {
+    let _lock = SHARES_LOCK.lock().await;
+    let config = Config::from_file(config_file).await?;
+    let mut downloads = Downloads::load(config_file).await?;
+    match config.shares.iter().find(|share| share.token == token) {
+        Some(share) if !share.is_expired(downloads.of(token), now_millis()) => (),
+        _ => return Ok(false),
+    };
+    *downloads.0.entry(token.to_owned()).or_default() += 1;
+    downloads.save(config_file).await?;
+    Ok(true)
+}
+
+/// Give back the download reserved for a request that was not served
+async fn release_download(config_file: &str, token: &str) -> anyhow::Result<()> {
+    let _lock = SHARES_LOCK.lock().await;
+    let mut downloads = Downloads::load(config_file).await?;
+    if let Some(count) = downloads.0.get_mut(token) {
+        *count = count.saturating_sub(1);
+        downloads.save(config_file).await?;
+    }
+    Ok(())
+}
+
+/// Check the password given by a visitor, counting the wrong ones against the visitor
+async fn password_matches(share: &Share, visitor: &(String, IpAddr), req: &Request<Body>) -> bool {
+    let credentials = match req.headers().typed_get::<Authorization<Basic>>() {
+        Some(credentials) => credentials,
+        None => return false,
+    };
+    // Hashing is slow on purpose, and kept off the executor
+    let password = credentials.password().to_owned();
+    let hash = share.password.clone();
+    let matches = tokio::task::spawn_blocking(move || {
+        PasswordHash::new(&hash).is_ok_and(|hash| {
+            Argon2::default()
+                .verify_password(password.as_bytes(), &hash)
+                .is_ok()
+        })
+    })
+    .await
+    .unwrap_or(false);
+    let mut failures = PASSWORD_FAILURES.lock().unwrap();
+    if matches {
+        failures.remove(visitor);
+    } else {
+        let (count, last) = failures
+            .entry(visitor.clone())
+            .or_insert((0, Instant::now()));
+        *count += 1;
+        *last = Instant::now();
+    }
+    matches
+}
+
+/// Whether a visitor gave too many wrong passwords for a share lately, the other visitors being still let in
+fn is_locked_out(visitor: &(String, IpAddr)) -> bool {
+    let mut failures = PASSWORD_FAILURES.lock().unwrap();
+    match failures.get(visitor) {
+        Some((_, last)) if last.elapsed() >= PASSWORD_LOCKOUT => {
+            failures.remove(visitor);
+            false
+        }
+        Some((count, _)) => *count >= MAX_PASSWORD_FAILURES,
+        None => false,
+    }
+}
+
+/// The dav of a share, rooted in the home directory of the owner for templated davs,
+/// if the owner is still allowed to share the path
+fn owner_dav(mut dav: Dav, share: &Share, owner: &User) -> Option<Dav> {
+    let host = HostType::Dav(dav.clone());
+    let owner = Some(owner.clone());
+    if check_authorization(&host, &owner).is_some() {
+        return None;
+    }
+    if !Acl::new(&dav, &owner).allows(&share.path, share.permission()) {
+        return None;
+    }
+    if dav.is_home() {
+        dav.directory = dav.home_directory(&share.owner)?;
+    }
+    Some(dav)
+}
+
+/// Whether a user manages the share of another user, being granted the admin permission on its path.
+/// The homes are private : nobody manages the shares made in the home of another user.
+fn administers(config_map: &ConfigMap, user: &User, share: &Share) -> bool {
+    let dav = match find_dav(config_map, share.dav_id) {
+        Some(dav) => dav,
+        None => return false,
+    };
+    let user = Some(user.clone());
+    !dav.is_home()
+        && check_authorization(&HostType::Dav(dav.clone()), &user).is_none()
+        && Acl::new(&dav, &user).allows(&share.path, Permission::Admin)
+}
+
+fn find_dav(config_map: &ConfigMap, dav_id: usize) -> Option<Dav> {
+    config_map.values().find_map(|host| match host {
+        HostType::Dav(dav) if dav.id == dav_id => Some(dav.clone()),
+        _ => None,
+    })
+}
+
+/// File system path of a plain path within the dav
+fn fs_path(dav: &Dav, path: &str) -> Option<std::path::PathBuf> {
+    let directory = Path::new(&dav.directory);
+    match NameCipher::new(dav) {
+        Some(names) => Some(directory.join(names.encrypt_path(Path::new(path)).ok()?)),
+        None => Some(directory.join(path)),
+    }
+}
+
+/// Path relative to the dav root without empty segments, None if it escapes its root or reaches the internal state
+fn normalize(path: &str) -> Option<String> {
+    let segments: Vec<&str> = path
+        .split(['/', '\\'])
+        .filter(|segment| !segment.is_empty() && *segment != ".")
+        .collect();
+    let path = segments.join("/");
+    if segments.contains(&"..") || is_internal(Path::new(&path)) {
+        return None;
+    }
+    Some(path)
+}
+
+/// Join two paths relative to the dav root, either of them being possibly empty
+fn join(path: &str, rest: &str) -> String {
+    match (path, rest) {
+        ("", rest) => rest.to_owned(),
+        (path, "") => path.to_owned(),
+        (path, rest) => format!("{}/{}", path, rest),
+    }
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, Share, ShareMode};
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(
+            normalize("/docs//reports/").as_deref(),
+            Some("docs/reports")
+        );
+        assert_eq!(normalize("./docs").as_deref(), Some("docs"));
+        assert_eq!(normalize("").as_deref(), Some(""));
+        assert_eq!(normalize("docs/../secret"), None);
+        assert_eq!(normalize(".vestibule/trash"), None);
+        assert_eq!(normalize("docs/file.vestibule-part"), None);
+    }
+
+    #[test]
+    fn test_share_expiry() {
+        let mut share = Share {
+            token: "token".to_owned(),
+            dav_id: 1,
+            path: "docs".to_owned(),
+            owner: "user".to_owned(),
+            password: String::new(),
+            mode: ShareMode::ReadOnly,
+            expires_at: None,
+            max_downloads: None,
+            max_file_size: None,
+            created_at: 0,
+        };
+        assert!(!share.is_expired(3, 1000));
+        share.expires_at = Some(1000);
+        assert!(share.is_expired(3, 1000));
+        assert!(!share.is_expired(3, 999));
+        share.expires_at = None;
+        share.max_downloads = Some(3);
+        assert!(share.is_expired(3, 0));
+        share.max_downloads = Some(4);
+        assert!(!share.is_expired(3, 0));
+    }
+}
@@ -0,0 +1,376 @@
+use std::io::{self, Cursor};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::process::Stdio;
+use std::time::SystemTime;
+
+use futures::TryStreamExt;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::{DynamicImage, ImageDecoder, ImageReader};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use tokio::io::{AsyncRead, AsyncReadExt};
+use tokio::process::Command;
+use tokio::sync::Semaphore;
+use tokio_util::io::StreamReader;
+use uuid::Uuid;
+
+use super::encrypted_streamer::EncryptedStreamer;
+use super::model::Dav;
+use super::{INTERNAL_DIR, PARTIAL_EXTENSION};
+
+/// Largest side of a thumbnail
+const MAX_SIDE: u32 = 1024;
+/// Images above this size are not decoded, videos are streamed to ffmpeg whatever their size
+const MAX_IMAGE_SIZE: u64 = 64_000_000;
+/// Thumbnails generated at once, as decoding large images is costly
+const MAX_GENERATIONS: usize = 4;
+const JPEG_QUALITY: u8 = 80;
+
+lazy_static::lazy_static! {
+    static ref GENERATIONS: Semaphore = Semaphore::new(MAX_GENERATIONS);
+}
+
+/// Bounding box of a thumbnail, the aspect ratio of the source being kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThumbnailSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ThumbnailSize {
+    /// Parse a WxH size, such as 256x256
+    pub fn parse(value: &str) -> Option<Self> {
+        let (width, height) = value.split_once('x')?;
+        let size = Self {
+            width: width.parse().ok()?,
+            height: height.parse().ok()?,
+        };
+        ((1..=MAX_SIDE).contains(&size.width) && (1..=MAX_SIDE).contains(&size.height))
+            .then_some(size)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Thumbnail {
+    pub content: Vec<u8>,
+    pub mime: &'static str,
+}
+
+impl Thumbnail {
+    fn extension(&self) -> &'static str {
+        match self.mime {
+            "image/webp" => "webp",
+            _ => "jpg",
+        }
+    }
+}
+
+/// Thumbnails of the images and videos of a dav, cached in the dav internal directory (encrypted on encrypted davs).
+/// Each file gets a directory named after the hash of its path, holding its thumbnails for its current content.
+pub struct ThumbnailStore {
+    root: PathBuf,
+    dir: PathBuf,
+    key: Option<[u8; 32]>,
+}
+
+impl ThumbnailStore {
+    pub fn new(dav: &Dav) -> Self {
+        let root = PathBuf::from(&dav.directory);
+        Self {
+            dir: root.join(INTERNAL_DIR).join("thumbnails"),
+            root,
+            key: dav.key,
+        }
+    }
+
+    /// Thumbnail of a file given its name as the users know it, None if it is not an image or a video that can be decoded
+    pub async fn get(
+        &self,
+        path: &Path,
+        name: &str,
+        size: ThumbnailSize,
+    ) -> io::Result<Option<Thumbnail>> {
+        let mime = mime_guess::from_path(name).first_or_octet_stream();
+        if !matches!(mime.type_().as_str(), "image" | "video") {
+            return Ok(None);
+        }
+        let meta = fs::metadata(path).await?;
+        let file_dir = self.file_dir(path)?;
+        let version = format!(
+            "{}-{}-{}x{}",
+            meta.modified()
+                .unwrap_or(SystemTime::UNIX_EPOCH)
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos(),
+            meta.len(),
+            size.width,
+            size.height
+        );
+        if let Some(thumbnail) = self.cached(&file_dir, &version).await? {
+            return Ok(Some(thumbnail));
+        }
+
+        let _permit = GENERATIONS
+            .acquire()
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        // Generated meanwhile by a concurrent request
+        if let Some(thumbnail) = self.cached(&file_dir, &version).await? {
+            return Ok(Some(thumbnail));
+        }
+        let image = if mime.type_() == "video" {
+            video_frame(self.reader(path).await?).await?
+        } else if meta.len() <= MAX_IMAGE_SIZE {
+            let mut content = vec![];
+            self.reader(path).await?.read_to_end(&mut content).await?;
+            Some(content)
+        } else {
+            None
+        };
+        let image = match image {
+            Some(image) => image,
+            None => return Ok(None),
+        };
+        let thumbnail = tokio::task::spawn_blocking(move || generate(&image, size))
+            .await
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        if let Some(thumbnail) = &thumbnail {
+            self.store(&file_dir, &version, thumbnail).await?;
+        }
+        Ok(thumbnail)
+    }
+
+    async fn cached(&self, file_dir: &Path, version: &str) -> io::Result<Option<Thumbnail>> {
+        for (extension, mime) in [("jpg", "image/jpeg"), ("webp", "image/webp")] {
+            let path = file_dir.join(format!("{}.{}", version, extension));
+            let file = match fs::File::open(&path).await {
+                Ok(file) => file,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            let mut content = vec![];
+            match self.key {
+                Some(key) => {
+                    EncryptedStreamer::new(file, key)
+                        .copy_to(&mut content)
+                        .await?;
+                }
+                None => {
+                    let mut file = file;
+                    file.read_to_end(&mut content).await?;
+                }
+            }
+            return Ok(Some(Thumbnail { content, mime }));
+        }
+        Ok(None)
+    }
+
+    /// Cache a thumbnail, dropping the ones of the previous contents of the file
+    async fn store(&self, file_dir: &Path, version: &str, thumbnail: &Thumbnail) -> io::Result<()> {
+        fs::create_dir_all(file_dir).await?;
+        let content_version = version.rsplit_once('-').map_or(version, |(v, _)| v);
+        let mut entries = fs::read_dir(file_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if !name.starts_with(&format!("{}-", content_version))
+                && !name.ends_with(PARTIAL_EXTENSION)
+            {
+                match fs::remove_file(entry.path()).await {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                    _ => (),
+                }
+            }
+        }
+        let path = file_dir.join(format!("{}.{}", version, thumbnail.extension()));
+        let partial =
+            path.with_extension(format!("{}.{}", Uuid::new_v4().simple(), PARTIAL_EXTENSION));
+        match self.key {
+            Some(key) => {
+                EncryptedStreamer::new(fs::File::create(&partial).await?, key)
+                    .copy_from(&mut thumbnail.content.as_slice())
+                    .await?;
+            }
+            None => fs::write(&partial, &thumbnail.content).await?,
+        }
+        fs::rename(partial, path).await
+    }
+
+    /// Drop the thumbnails of changed or removed files.
+    /// The thumbnails of the files of a changed directory are kept, they are never served once their file changed.
+    pub async fn forget(&self, paths: &[PathBuf]) -> io::Result<()> {
+        for path in paths.iter().filter(|path| **path != self.root) {
+            match fs::remove_dir_all(self.file_dir(path)?).await {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Plain content of a file
+    async fn reader(&self, path: &Path) -> io::Result<Pin<Box<dyn AsyncRead + Send>>> {
+        let file = fs::File::open(path).await?;
+        Ok(match self.key {
+            Some(key) => {
+                let plain = EncryptedStreamer::new(file, key).into_stream();
+                Box::pin(StreamReader::new(plain.map_ok(Cursor::new)))
+            }
+            None => Box::pin(file),
+        })
+    }
+
+    fn file_dir(&self, path: &Path) -> io::Result<PathBuf> {
+        let rel_path = path
+            .strip_prefix(&self.root)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "not a file of the dav"))?;
+        let hash = Sha256::digest(rel_path.to_string_lossy().as_bytes());
+        Ok(self.dir.join(format!("{:x}", hash)))
+    }
+}
+
+/// First frame of a video as a png image, None if ffmpeg is missing or cannot decode the video
+async fn video_frame(mut video: Pin<Box<dyn AsyncRead + Send>>) -> io::Result<Option<Vec<u8>>> {
+    let child = Command::new("ffmpeg")
+        .args(["-v", "error", "-i", "pipe:0", "-frames:v", "1"])
+        .args(["-f", "image2pipe", "-vcodec", "png", "pipe:1"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let mut child = match child {
+        Ok(child) => child,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut stdin = child.stdin.take().unwrap();
+    // ffmpeg stops reading once it has the frame
+    let feed = tokio::spawn(async move {
+        let _ = tokio::io::copy(&mut video, &mut stdin).await;
+    });
+    let output = child.wait_with_output().await?;
+    feed.abort();
+    Ok((output.status.success() && !output.stdout.is_empty()).then_some(output.stdout))
+}
+
+/// Resize an image, turned upright from its EXIF orientation, to a jpeg (or a webp if it has transparency)
+fn generate(image: &[u8], size: ThumbnailSize) -> Option<Thumbnail> {
+    let mut decoder = ImageReader::new(Cursor::new(image))
+        .with_guessed_format()
+        .ok()?
+        .into_decoder()
+        .ok()?;
+    let orientation = decoder.orientation().ok()?;
+    let mut image = DynamicImage::from_decoder(decoder).ok()?;
+    image.apply_orientation(orientation);
+    let image = image.thumbnail(size.width, size.height);
+    let mut content = vec![];
+    if image.color().has_alpha() {
+        image
+            .to_rgba8()
+            .write_with_encoder(WebPEncoder::new_lossless(&mut content))
+            .ok()?;
+        Some(Thumbnail {
+            content,
+            mime: "image/webp",
+        })
+    } else {
+        image
+            .to_rgb8()
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut content, JPEG_QUALITY))
+            .ok()?;
+        Some(Thumbnail {
+            content,
+            mime: "image/jpeg",
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use image::{ImageFormat, ImageReader, Rgb, RgbImage, Rgba, RgbaImage};
+
+    use crate::davs::model::Dav;
+
+    use super::{ThumbnailSize, ThumbnailStore};
+
+    #[test]
+    fn test_parse_thumbnail_size() {
+        assert_eq!(
+            ThumbnailSize::parse("256x128"),
+            Some(ThumbnailSize {
+                width: 256,
+                height: 128
+            })
+        );
+        assert_eq!(ThumbnailSize::parse("0x128"), None);
+        assert_eq!(ThumbnailSize::parse("4096x4096"), None);
+        assert_eq!(ThumbnailSize::parse("256"), None);
+    }
+
+    #[tokio::test]
+    async fn test_thumbnails() {
+        // Arrange
+        let base = "thumbnails_test";
+        let _ = fs::remove_dir_all(base);
+        fs::create_dir_all(base).unwrap();
+        let mut png = Cursor::new(vec![]);
+        RgbImage::from_pixel(400, 200, Rgb([200, 30, 30]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        fs::write(format!("{}/photo.png", base), png.get_ref()).unwrap();
+        let mut png = Cursor::new(vec![]);
+        RgbaImage::from_pixel(100, 100, Rgba([0, 0, 0, 0]))
+            .write_to(&mut png, ImageFormat::Png)
+            .unwrap();
+        fs::write(format!("{}/icon.png", base), png.get_ref()).unwrap();
+        fs::write(format!("{}/notes.txt", base), "not an image").unwrap();
+        let store = ThumbnailStore::new(&Dav {
+            directory: base.to_owned(),
+            ..Default::default()
+        });
+        let size = ThumbnailSize {
+            width: 100,
+            height: 100,
+        };
+
+        // Act
+        let photo = Path::new(base).join("photo.png");
+        let thumbnail = store.get(&photo, "photo.png", size).await.unwrap().unwrap();
+        let cached = store.get(&photo, "photo.png", size).await.unwrap().unwrap();
+        let icon = Path::new(base).join("icon.png");
+        let transparent = store.get(&icon, "icon.png", size).await.unwrap().unwrap();
+        let notes = Path::new(base).join("notes.txt");
+        let text = store.get(&notes, "notes.txt", size).await.unwrap();
+
+        // Assert
+        assert_eq!(thumbnail.mime, "image/jpeg");
+        assert_eq!(cached, thumbnail);
+        let image = ImageReader::new(Cursor::new(&thumbnail.content))
+            .with_guessed_format()
+            .unwrap()
+            .decode()
+            .unwrap();
+        assert_eq!((image.width(), image.height()), (100, 50));
+        assert_eq!(transparent.mime, "image/webp");
+        assert_eq!(text, None);
+        assert_eq!(
+            fs::read_dir(store.file_dir(&photo).unwrap())
+                .unwrap()
+                .count(),
+            1
+        );
+        store.forget(&[photo.clone()]).await.unwrap();
+        assert!(!store.file_dir(&photo).unwrap().exists());
+
+        // Cleanup
+        fs::remove_dir_all(base).unwrap();
+    }
+}
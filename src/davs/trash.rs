@@ -0,0 +1,216 @@
+use std::cmp::Reverse;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+use uuid::Uuid;
+
+use super::model::Dav;
+use super::{quota, INTERNAL_DIR};
+
+/// Url of the trash API within a dav
+pub const TRASH_URL: &str = "/.vestibule/trash";
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashPolicy {
+    /// Days after which the trashed items are purged, they are kept forever if not set
+    pub retention_days: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TrashedItem {
+    pub id: String,
+    /// Original path within the dav
+    pub path: String,
+    pub is_dir: bool,
+    pub size: u64,
+    pub deleted_at: u64,
+}
+
+/// Trash of a dav : the items are moved as is (so they stay encrypted on encrypted davs) in the dav internal directory,
+/// along with a json file describing them
+pub struct TrashBin {
+    root: PathBuf,
+    dir: PathBuf,
+    encrypted: bool,
+}
+
+impl TrashBin {
+    pub fn new(dav: &Dav) -> Self {
+        let root = PathBuf::from(&dav.directory);
+        Self {
+            dir: root.join(INTERNAL_DIR).join("trash"),
+            root,
+            encrypted: dav.key.is_some(),
+        }
+    }
+
+    /// Move a file or a directory of the dav to the trash
+    pub async fn put(&self, path: &Path) -> io::Result<TrashedItem> {
+        let rel_path = match path.strip_prefix(&self.root) {
+            Ok(rel_path) if rel_path.components().next().is_some() => rel_path,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "only the content of the dav can be trashed",
+                ))
+            }
+        };
+        let item = TrashedItem {
+            id: Uuid::new_v4().to_string(),
+            path: format!("/{}", rel_path.to_string_lossy().replace('\\', "/")),
+            is_dir: fs::metadata(path).await?.is_dir(),
+            size: quota::usage(path, self.encrypted).await?.bytes,
+            deleted_at: now(),
+        };
+        fs::create_dir_all(&self.dir).await?;
+        fs::write(
+            self.item_path(&item.id).with_extension("json"),
+            serde_json::to_vec(&item)?,
+        )
+        .await?;
+        fs::rename(path, self.item_path(&item.id)).await?;
+        Ok(item)
+    }
+
+    pub async fn list(&self) -> io::Result<Vec<TrashedItem>> {
+        let mut items = vec![];
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(items),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == "json") {
+                if let Ok(item) = serde_json::from_slice(&fs::read(&path).await?) {
+                    items.push(item);
+                }
+            }
+        }
+        items.sort_by_key(|item: &TrashedItem| Reverse(item.deleted_at));
+        Ok(items)
+    }
+
+    pub async fn get(&self, id: &str) -> io::Result<TrashedItem> {
+        // Parsing the id as an uuid prevents from getting out of the trash directory
+        if Uuid::parse_str(id).is_err() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let content = fs::read(self.item_path(id).with_extension("json")).await?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Put back an item where it was deleted from, failing if something else took its place
+    pub async fn restore(&self, item: &TrashedItem) -> io::Result<()> {
+        let dest = self.root.join(item.path.trim_start_matches('/'));
+        if fs::symlink_metadata(&dest).await.is_ok() {
+            return Err(io::ErrorKind::AlreadyExists.into());
+        }
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent).await?;
+        }
+        fs::rename(self.item_path(&item.id), &dest).await?;
+        fs::remove_file(self.item_path(&item.id).with_extension("json")).await
+    }
+
+    pub async fn purge(&self, item: &TrashedItem) -> io::Result<()> {
+        let path = self.item_path(&item.id);
+        if item.is_dir {
+            fs::remove_dir_all(&path).await?;
+        } else {
+            fs::remove_file(&path).await?;
+        }
+        fs::remove_file(path.with_extension("json")).await
+    }
+
+    pub async fn purge_expired(&self, policy: &TrashPolicy) -> io::Result<()> {
+        if let Some(days) = policy.retention_days {
+            let limit = now().saturating_sub(days * 24 * 3600);
+            for item in self.list().await? {
+                if item.deleted_at < limit {
+                    self.purge(&item).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn item_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::davs::model::Dav;
+
+    use super::{TrashBin, TrashPolicy};
+
+    #[tokio::test]
+    async fn test_trash_put_restore_and_purge() {
+        // Arrange
+        let base = "trash_test";
+        fs::create_dir_all(format!("{base}/dir")).unwrap();
+        fs::write(format!("{base}/dir/file"), "content").unwrap();
+        let dav = Dav {
+            directory: base.to_owned(),
+            ..Default::default()
+        };
+        let bin = TrashBin::new(&dav);
+
+        // Act : trash the file and the directory
+        let file_item = bin
+            .put(Path::new(&format!("{base}/dir/file")))
+            .await
+            .unwrap();
+        let dir_item = bin.put(Path::new(&format!("{base}/dir"))).await.unwrap();
+
+        // Assert
+        assert_eq!(file_item.path, "/dir/file");
+        assert_eq!(file_item.size, 7);
+        assert!(dir_item.is_dir);
+        assert!(!Path::new(&format!("{base}/dir")).exists());
+        assert_eq!(bin.list().await.unwrap().len(), 2);
+        assert!(bin.get("../../dir").await.is_err());
+
+        // Act : restore the file, that needs its parent directory to be recreated
+        bin.restore(&bin.get(&file_item.id).await.unwrap())
+            .await
+            .unwrap();
+
+        // Assert
+        assert_eq!(
+            fs::read_to_string(format!("{base}/dir/file")).unwrap(),
+            "content"
+        );
+        // The directory cannot be restored over the recreated one
+        assert!(bin.restore(&dir_item).await.is_err());
+
+        // Act : purge the expired items
+        bin.purge_expired(&TrashPolicy {
+            retention_days: Some(1),
+        })
+        .await
+        .unwrap();
+        assert_eq!(bin.list().await.unwrap().len(), 1);
+        bin.purge(&dir_item).await.unwrap();
+
+        // Assert
+        assert!(bin.list().await.unwrap().is_empty());
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -0,0 +1,283 @@
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWriteExt};
+use uuid::Uuid;
+
+use super::encrypted_streamer::{encrypt_chunk, Header};
+use super::model::Dav;
+use super::quota;
+use super::INTERNAL_DIR;
+
+/// Url of the resumable uploads API (tus protocol) within a dav
+pub const UPLOADS_URL: &str = "/.vestibule/uploads";
+pub const TUS_VERSION: &str = "1.0.0";
+
+/// Uploads not completed within this delay are dropped
+const UPLOAD_EXPIRATION: u64 = 7 * 24 * 3600;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Upload {
+    pub id: String,
+    /// Path of the file within the dav, once the upload is complete
+    pub path: String,
+    pub length: u64,
+    /// Number of bytes of the file received and persisted so far
+    pub offset: u64,
+    pub created_at: u64,
+}
+
+impl Upload {
+    pub fn is_complete(&self) -> bool {
+        self.offset == self.length
+    }
+}
+
+/// Uploads in progress, stored in the dav internal directory.
+/// On encrypted davs, only whole encryption chunks are persisted : the offset only moves by PLAIN_CHUNK_SIZE steps until the last chunk.
+pub struct UploadStore {
+    /// Directory of the dav, in the quota of which the uploads take room
+    directory: PathBuf,
+    dir: PathBuf,
+    key: Option<[u8; 32]>,
+}
+
+impl UploadStore {
+    pub fn new(dav: &Dav) -> Self {
+        Self {
+            directory: PathBuf::from(&dav.directory),
+            dir: PathBuf::from(&dav.directory)
+                .join(INTERNAL_DIR)
+                .join("uploads"),
+            key: dav.key,
+        }
+    }
+
+    pub async fn create(&self, path: &str, length: u64) -> io::Result<Upload> {
+        self.purge_expired().await?;
+        let upload = Upload {
+            id: Uuid::new_v4().to_string(),
+            path: path.to_owned(),
+            length,
+            offset: 0,
+            created_at: now(),
+        };
+        fs::create_dir_all(&self.dir).await?;
+        // Encrypted files begin with the header of the stream
+        let mut content = vec![];
+        if let Some(key) = self.key {
+            let header = Header::new(&key);
+            content.extend(header.to_bytes());
+            if length == 0 {
+                content.extend(encrypt_chunk(&key, &header.nonce, 0, true, &[])?);
+            }
+        }
+        fs::write(self.data_path(&upload.id), content).await?;
+        self.save(&upload).await?;
+        Ok(upload)
+    }
+
+    pub async fn get(&self, id: &str) -> io::Result<Upload> {
+        // Parsing the id as an uuid prevents from getting out of the uploads directory
+        if Uuid::parse_str(id).is_err() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let content = fs::read(self.data_path(id).with_extension("json")).await?;
+        Ok(serde_json::from_slice(&content)?)
+    }
+
+    /// Append what can be read to the upload, the offset is saved as the data is persisted so that the upload can be resumed
+    pub async fn append<R>(&self, upload: &mut Upload, reader: &mut R) -> io::Result<()>
+    where
+        R: AsyncRead + Unpin + ?Sized,
+    {
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(self.data_path(&upload.id))
+            .await?;
+        let key = match self.key {
+            Some(key) => key,
+            None => {
+                let mut reader = reader.take(upload.length - upload.offset);
+                let copied = io::copy(&mut reader, &mut file).await;
+                file.flush().await?;
+                // What was written before a connection drop is kept
+                upload.offset = file.metadata().await?.len();
+                self.save(upload).await?;
+                return copied.map(|_| ());
+            }
+        };
+        let header = Header::read(&mut fs::File::open(self.data_path(&upload.id)).await?).await?;
+        let chunk_size = header.plain_chunk_size();
+        while !upload.is_complete() {
+            // The last chunk is shorter than the others
+            let chunk_len = (upload.length - upload.offset).min(chunk_size);
+            let mut buffer = Vec::with_capacity(chunk_len as usize);
+            reader.take(chunk_len).read_to_end(&mut buffer).await?;
+            if (buffer.len() as u64) < chunk_len {
+                // The chunk is incomplete, it will be sent again
+                break;
+            }
+            let position = (upload.offset / chunk_size) as u32;
+            let last = chunk_len < chunk_size;
+            let mut ciphertext = encrypt_chunk(&key, &header.nonce, position, last, &buffer)?;
+            if !last && upload.offset + chunk_len == upload.length {
+                // As with the encrypted streamer, files made of whole chunks end with an empty last chunk
+                ciphertext.extend(encrypt_chunk(&key, &header.nonce, position + 1, true, &[])?);
+            }
+            file.write_all(&ciphertext).await?;
+            file.flush().await?;
+            upload.offset += chunk_len;
+            self.save(upload).await?;
+        }
+        Ok(())
+    }
+
+    /// Take the data of a complete upload, and forget about the upload
+    pub async fn complete(&self, upload: &Upload) -> io::Result<PathBuf> {
+        fs::remove_file(self.data_path(&upload.id).with_extension("json")).await?;
+        Ok(self.data_path(&upload.id))
+    }
+
+    /// Drop an upload, giving back the room taken in the quota of the dav as it was created
+    pub async fn delete(&self, upload: &Upload) -> io::Result<()> {
+        fs::remove_file(self.data_path(&upload.id)).await?;
+        fs::remove_file(self.data_path(&upload.id).with_extension("json")).await?;
+        quota::invalidate(&self.directory);
+        Ok(())
+    }
+
+    async fn purge_expired(&self) -> io::Result<()> {
+        let mut entries = match fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let id = entry.file_name().to_string_lossy().replace(".json", "");
+            if let Ok(upload) = self.get(&id).await {
+                if upload.created_at + UPLOAD_EXPIRATION < now() {
+                    self.delete(&upload).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn save(&self, upload: &Upload) -> io::Result<()> {
+        fs::write(
+            self.data_path(&upload.id).with_extension("json"),
+            serde_json::to_vec(upload)?,
+        )
+        .await
+    }
+
+    fn data_path(&self, id: &str) -> PathBuf {
+        self.dir.join(id)
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tokio::fs::File;
+
+    use crate::davs::{
+        encrypted_streamer::{EncryptedStreamer, PLAIN_CHUNK_SIZE},
+        model::Dav,
+    };
+
+    use super::UploadStore;
+
+    #[tokio::test]
+    async fn test_resumed_encrypted_upload() {
+        // Arrange
+        let base = "uploads_test";
+        fs::create_dir_all(base).unwrap();
+        let key = [7; 32];
+        let dav = Dav {
+            directory: base.to_owned(),
+            key: Some(key),
+            ..Default::default()
+        };
+        let store = UploadStore::new(&dav);
+        let content: Vec<u8> = (0..PLAIN_CHUNK_SIZE * 2 + 10)
+            .map(|i| (i % 251) as u8)
+            .collect();
+        let mut upload = store
+            .create("/file.bin", content.len() as u64)
+            .await
+            .unwrap();
+
+        // Act : send a chunk and a half, only the whole chunk must be kept
+        let mut part = &content[..PLAIN_CHUNK_SIZE + PLAIN_CHUNK_SIZE / 2];
+        store.append(&mut upload, &mut part).await.unwrap();
+        assert_eq!(upload.offset, PLAIN_CHUNK_SIZE as u64);
+        assert_eq!(store.get(&upload.id).await.unwrap(), upload);
+
+        // Act : resume the upload
+        let mut part = &content[upload.offset as usize..];
+        store.append(&mut upload, &mut part).await.unwrap();
+        assert!(upload.is_complete());
+        let path = store.complete(&upload).await.unwrap();
+
+        // Assert that the file can be decrypted as any other
+        let mut decrypted = vec![];
+        EncryptedStreamer::new(File::open(path).await.unwrap(), key)
+            .copy_to(&mut decrypted)
+            .await
+            .unwrap();
+        assert!(decrypted == content);
+        assert!(store.get(&upload.id).await.is_err());
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_upload_of_whole_chunks() {
+        // Arrange
+        let base = "uploads_whole_chunks_test";
+        fs::create_dir_all(base).unwrap();
+        let key = [9; 32];
+        let dav = Dav {
+            directory: base.to_owned(),
+            key: Some(key),
+            ..Default::default()
+        };
+        let store = UploadStore::new(&dav);
+        let content = vec![42; PLAIN_CHUNK_SIZE];
+        let mut upload = store
+            .create("/file.bin", content.len() as u64)
+            .await
+            .unwrap();
+
+        // Act
+        store
+            .append(&mut upload, &mut content.as_slice())
+            .await
+            .unwrap();
+
+        // Assert that the empty last chunk was written
+        let path = store.complete(&upload).await.unwrap();
+        let mut decrypted = vec![];
+        EncryptedStreamer::new(File::open(path).await.unwrap(), key)
+            .copy_to(&mut decrypted)
+            .await
+            .unwrap();
+        assert!(decrypted == content);
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
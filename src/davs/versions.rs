@@ -0,0 +1,252 @@
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::fs;
+use uuid::Uuid;
+
+use super::model::Dav;
+use super::{quota, INTERNAL_DIR};
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct VersioningPolicy {
+    /// Number of previous versions kept for each file
+    pub max_versions: Option<usize>,
+    /// Days during which the previous versions are kept
+    pub retention_days: Option<u64>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Version {
+    pub id: String,
+    pub created_at: u64,
+    pub size: u64,
+}
+
+/// Previous versions of the files of a dav, kept as is (so they stay encrypted on encrypted davs) in the dav internal directory.
+/// Each file gets a directory named after the hash of its path, holding a directory per version.
+pub struct VersionStore {
+    root: PathBuf,
+    dir: PathBuf,
+    encrypted: bool,
+    policy: VersioningPolicy,
+}
+
+impl VersionStore {
+    pub fn new(dav: &Dav, policy: &VersioningPolicy) -> Self {
+        let root = PathBuf::from(&dav.directory);
+        Self {
+            dir: root.join(INTERNAL_DIR).join("versions"),
+            root,
+            encrypted: dav.key.is_some(),
+            policy: policy.clone(),
+        }
+    }
+
+    /// Move the current content of a file to its history
+    pub async fn put(&self, path: &Path) -> io::Result<Version> {
+        let version = self.snapshot(path).await?;
+        self.prune(path).await?;
+        Ok(version)
+    }
+
+    async fn snapshot(&self, path: &Path) -> io::Result<Version> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let id = format!("{}-{}", now, Uuid::new_v4().simple());
+        let version_path = self.version_path(path, &id)?;
+        fs::create_dir_all(version_path.parent().unwrap()).await?;
+        let size = quota::usage(path, self.encrypted).await?.bytes;
+        fs::rename(path, &version_path).await?;
+        Ok(Version {
+            created_at: parse_id(&id).unwrap(),
+            id,
+            size,
+        })
+    }
+
+    /// Versions of a file, the most recent first
+    pub async fn list(&self, path: &Path) -> io::Result<Vec<Version>> {
+        let mut versions = vec![];
+        let mut entries = match fs::read_dir(self.file_dir(path)?).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(versions),
+            Err(e) => return Err(e),
+        };
+        while let Some(entry) = entries.next_entry().await? {
+            let id = entry.file_name().to_string_lossy().to_string();
+            if let Some(created_at) = parse_id(&id) {
+                let size = quota::usage(&self.version_path(path, &id)?, self.encrypted)
+                    .await?
+                    .bytes;
+                versions.push(Version {
+                    id,
+                    created_at,
+                    size,
+                });
+            }
+        }
+        versions.sort_by(|a, b| b.id.cmp(&a.id));
+        Ok(versions)
+    }
+
+    /// Path of the content of a version, named as the file to keep its extension
+    pub fn version_path(&self, path: &Path, id: &str) -> io::Result<PathBuf> {
+        // Parsing the id prevents from getting out of the versions directory
+        if parse_id(id).is_none() {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let file_name = path
+            .file_name()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file"))?;
+        Ok(self.file_dir(path)?.join(id).join(file_name))
+    }
+
+    /// Make a version the current content of the file, the replaced content becoming a version itself
+    pub async fn restore(&self, path: &Path, id: &str) -> io::Result<()> {
+        let version_path = self.version_path(path, id)?;
+        fs::metadata(&version_path).await?;
+        if fs::metadata(path).await.is_ok() {
+            self.snapshot(path).await?;
+        }
+        fs::rename(&version_path, path).await?;
+        fs::remove_dir(version_path.parent().unwrap()).await?;
+        // Prune afterwards, not to drop the restored version
+        self.prune(path).await
+    }
+
+    /// Move the history of a file along with it, merging it with the history of the destination
+    pub async fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        let (from_dir, to_dir) = (self.file_dir(from)?, self.file_dir(to)?);
+        let (from_name, to_name) = match (from.file_name(), to.file_name()) {
+            (Some(from_name), Some(to_name)) => (from_name, to_name),
+            _ => return Ok(()),
+        };
+        let mut entries = match fs::read_dir(&from_dir).await {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        fs::create_dir_all(&to_dir).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            // The versions keep the name of the file they belong to
+            fs::rename(entry.path().join(from_name), entry.path().join(to_name)).await?;
+            fs::rename(entry.path(), to_dir.join(entry.file_name())).await?;
+        }
+        fs::remove_dir(from_dir).await?;
+        self.prune(to).await
+    }
+
+    /// Drop the versions exceeding the number of versions to keep or older than the retention period
+    async fn prune(&self, path: &Path) -> io::Result<()> {
+        let limit = self.policy.retention_days.map(|days| {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_millis() as u64;
+            now.saturating_sub(days * 24 * 3600 * 1000)
+        });
+        for (i, version) in self.list(path).await?.iter().enumerate() {
+            if self.policy.max_versions.map_or(false, |max| i >= max)
+                || limit.map_or(false, |limit| version.created_at < limit)
+            {
+                fs::remove_dir_all(self.version_path(path, &version.id)?.parent().unwrap()).await?;
+            }
+        }
+        Ok(())
+    }
+
+    fn file_dir(&self, path: &Path) -> io::Result<PathBuf> {
+        let rel_path = path
+            .strip_prefix(&self.root)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path is not in the dav"))?;
+        let mut hasher = Sha256::new();
+        hasher.update(rel_path.to_string_lossy().replace('\\', "/"));
+        Ok(self.dir.join(format!("{:x}", hasher.finalize())))
+    }
+}
+
+/// Version ids are made of the creation time in nanoseconds and of an uuid, the creation time is given back in milliseconds
+fn parse_id(id: &str) -> Option<u64> {
+    let (created_at, uuid) = id.split_once('-')?;
+    Uuid::try_parse(uuid).ok()?;
+    created_at
+        .parse::<u128>()
+        .ok()
+        .map(|nanos| (nanos / 1_000_000) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::davs::model::Dav;
+
+    use super::{VersionStore, VersioningPolicy};
+
+    #[tokio::test]
+    async fn test_versions() {
+        // Arrange
+        let base = "versions_test";
+        fs::create_dir_all(base).unwrap();
+        let dav = Dav {
+            directory: base.to_owned(),
+            ..Default::default()
+        };
+        let store = VersionStore::new(
+            &dav,
+            &VersioningPolicy {
+                max_versions: Some(2),
+                retention_days: None,
+            },
+        );
+        let path = Path::new(base).join("file.txt");
+
+        // Act : write the file three times, keeping the previous versions
+        for content in ["v1", "v2", "v3"] {
+            if path.exists() {
+                store.put(&path).await.unwrap();
+            }
+            fs::write(&path, content).unwrap();
+        }
+
+        // Assert that only the two last versions are kept
+        let versions = store.list(&path).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        let v2_path = store.version_path(&path, &versions[0].id).unwrap();
+        assert_eq!(fs::read_to_string(&v2_path).unwrap(), "v2");
+        assert!(v2_path.ends_with("file.txt"));
+        assert!(store.version_path(&path, "../../file.txt").is_err());
+
+        // Act : restore the second version
+        store.restore(&path, &versions[0].id).await.unwrap();
+
+        // Assert that the third version is now in the history
+        assert_eq!(fs::read_to_string(&path).unwrap(), "v2");
+        let versions = store.list(&path).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        let v3_path = store.version_path(&path, &versions[0].id).unwrap();
+        assert_eq!(fs::read_to_string(v3_path).unwrap(), "v3");
+
+        // Act : move the file with its history
+        let new_path = Path::new(base).join("moved.txt");
+        fs::rename(&path, &new_path).unwrap();
+        store.rename(&path, &new_path).await.unwrap();
+
+        // Assert
+        assert!(store.list(&path).await.unwrap().is_empty());
+        let versions = store.list(&new_path).await.unwrap();
+        assert_eq!(versions.len(), 2);
+        assert!(store
+            .version_path(&new_path, &versions[0].id)
+            .unwrap()
+            .exists());
+
+        // Tidy
+        fs::remove_dir_all(base).unwrap();
+    }
+}
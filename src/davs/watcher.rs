@@ -0,0 +1,272 @@
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+use std::time::Duration;
+
+use log::{error, info};
+use notify::{Event, RecursiveMode, Watcher};
+use tokio::sync::{broadcast, mpsc};
+
+use super::is_internal;
+use super::model::{Dav, LOGIN_PLACEHOLDER};
+use super::search;
+use super::thumbnails::ThumbnailStore;
+
+/// Delay during which the changes are gathered, so that a burst of writes updates the index once
+const DEBOUNCE_DELAY: Duration = Duration::from_millis(200);
+const CHANGES_CAPACITY: usize = 256;
+
+lazy_static::lazy_static! {
+    static ref CHANGES: broadcast::Sender<Change> = broadcast::channel(CHANGES_CAPACITY).0;
+}
+
+/// Files of a dav changed on disk, by vestibule or by another tool
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change {
+    /// Directory of the dav (the home directory of the user for templated davs)
+    pub directory: String,
+    /// Changed, created or removed paths, the whole dav if the changes could not be tracked
+    pub paths: Vec<PathBuf>,
+}
+
+/// Receive the changes of the watched davs, once their search indexes and thumbnails are up to date,
+/// so that what is told to the clients, such as push notifications, matches what they will be served
+pub fn subscribe() -> broadcast::Receiver<Change> {
+    CHANGES.subscribe()
+}
+
+/// Watch the local directories of the davs until a reload is requested, keeping their search indexes fresh,
+/// dropping the cached thumbnails of the changed files and telling the changes to the subscribers
+pub fn watch_davs(davs: &[Dav], tx: &broadcast::Sender<()>) {
+    let mut watched = HashSet::new();
+    for dav in davs.iter().filter(|dav| dav.storage.is_none()) {
+        let root = watched_root(dav);
+        if !watched.insert(root.clone()) {
+            continue;
+        }
+        // Watched through its canonical path, the one the events come with
+        let absolute_root = match std::fs::canonicalize(&root) {
+            Ok(absolute_root) => absolute_root,
+            Err(e) => {
+                error!("Could not watch {}: {}", root.display(), e);
+                continue;
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let watcher = notify::recommended_watcher(move |event| {
+            let _ = sender.send(event);
+        })
+        .and_then(|mut watcher| {
+            watcher.watch(&absolute_root, RecursiveMode::Recursive)?;
+            Ok(watcher)
+        });
+        match watcher {
+            Ok(watcher) => {
+                info!("Watching {} for changes", root.display());
+                // The watcher stops when dropped along with the task
+                let rx = tx.subscribe();
+                let dav = dav.clone();
+                tokio::spawn(async move {
+                    let _watcher = watcher;
+                    watch(dav, root, absolute_root, receiver, rx).await
+                });
+            }
+            Err(e) => error!("Could not watch {}: {}", root.display(), e),
+        }
+    }
+}
+
+async fn watch(
+    dav: Dav,
+    root: PathBuf,
+    absolute_root: PathBuf,
+    mut events: mpsc::UnboundedReceiver<notify::Result<Event>>,
+    mut rx: broadcast::Receiver<()>,
+) {
+    // The events come with absolute paths, mapped back to the configured directory
+    let gathered = || Changes {
+        root: root.clone(),
+        absolute_root: absolute_root.clone(),
+        changes: vec![],
+    };
+    loop {
+        let mut changes = gathered();
+        tokio::select! {
+            event = events.recv() => match event {
+                Some(event) => changes.add(&dav, event),
+                None => return,
+            },
+            _ = rx.recv() => return,
+        }
+        // Gather the events following the first one
+        let deadline = tokio::time::sleep(DEBOUNCE_DELAY);
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                event = events.recv() => match event {
+                    Some(event) => changes.add(&dav, event),
+                    None => break,
+                },
+                _ = &mut deadline => break,
+            }
+        }
+        for change in changes.changes {
+            let mut changed = dav.clone();
+            changed.directory = change.directory.clone();
+            if let Err(e) = search::refresh(&changed, &change.paths).await {
+                error!(
+                    "Failed to update the search index of {}, {}",
+                    change.directory, e
+                );
+            }
+            if let Err(e) = ThumbnailStore::new(&changed).forget(&change.paths).await {
+                error!(
+                    "Failed to drop the thumbnails of {}, {}",
+                    change.directory, e
+                );
+            }
+            // Nobody may be listening
+            let _ = CHANGES.send(change);
+        }
+    }
+}
+
+/// Changes gathered by dav directory
+struct Changes {
+    root: PathBuf,
+    absolute_root: PathBuf,
+    changes: Vec<Change>,
+}
+
+impl Changes {
+    fn add(&mut self, dav: &Dav, event: notify::Result<Event>) {
+        let (paths, rescan) = match event {
+            Ok(event) if event.kind.is_access() => return,
+            Ok(event) => {
+                let rescan = event.need_rescan();
+                (event.paths, rescan)
+            }
+            // Events were lost, the whole dav has to be checked again
+            Err(e) => (e.paths, true),
+        };
+        let paths: Vec<PathBuf> = match (rescan, dav.is_home()) {
+            (true, false) => vec![PathBuf::from(&dav.directory)],
+            (true, true) => self.home_directories(dav),
+            _ => paths,
+        };
+        for path in paths {
+            let path = match path.strip_prefix(&self.absolute_root) {
+                Ok(rel_path) => self.root.join(rel_path),
+                Err(_) => path,
+            };
+            let directory = match dav_directory(dav, &path) {
+                Some(directory) => directory,
+                None => continue,
+            };
+            let rel_path = path.strip_prefix(&directory).unwrap_or(&path);
+            if is_internal(rel_path) {
+                continue;
+            }
+            let path = if rescan {
+                PathBuf::from(&directory)
+            } else {
+                path
+            };
+            match self.changes.iter_mut().find(|c| c.directory == directory) {
+                Some(change) if !change.paths.contains(&path) => change.paths.push(path),
+                Some(_) => (),
+                None => self.changes.push(Change {
+                    directory,
+                    paths: vec![path],
+                }),
+            }
+        }
+    }
+
+    /// Candidate home directories of a templated dav, from the entries of the watched root
+    fn home_directories(&self, dav: &Dav) -> Vec<PathBuf> {
+        let depth = self.root.components().count() + 1;
+        let rest: PathBuf = Path::new(&dav.directory).components().skip(depth).collect();
+        match std::fs::read_dir(&self.absolute_root) {
+            Ok(entries) => entries
+                .filter_map(Result::ok)
+                .map(|entry| self.root.join(entry.file_name()).join(&rest))
+                .collect(),
+            Err(e) => {
+                error!("Could not list {}: {}", self.absolute_root.display(), e);
+                vec![]
+            }
+        }
+    }
+}
+
+/// Directory watched for a dav : its own, or the one holding the home directories of a templated dav
+fn watched_root(dav: &Dav) -> PathBuf {
+    Path::new(&dav.directory)
+        .components()
+        .take_while(|c| !c.as_os_str().to_string_lossy().contains(LOGIN_PLACEHOLDER))
+        .collect()
+}
+
+/// Directory of the dav a changed path belongs to, the home directory of its user for templated davs
+fn dav_directory(dav: &Dav, path: &Path) -> Option<String> {
+    if !dav.is_home() {
+        return path
+            .starts_with(&dav.directory)
+            .then(|| dav.directory.clone());
+    }
+    let root = watched_root(dav);
+    let template = Path::new(&dav.directory)
+        .components()
+        .nth(root.components().count())?
+        .as_os_str()
+        .to_string_lossy()
+        .into_owned();
+    let (prefix, suffix) = template.split_once(LOGIN_PLACEHOLDER)?;
+    let login = match path.strip_prefix(&root).ok()?.components().next()? {
+        Component::Normal(name) => name
+            .to_str()?
+            .strip_prefix(prefix)?
+            .strip_suffix(suffix)?
+            .to_owned(),
+        _ => return None,
+    };
+    let home = dav.home_directory(&login)?;
+    path.starts_with(&home).then_some(home)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::davs::model::Dav;
+
+    use super::{dav_directory, watched_root};
+
+    #[test]
+    fn test_home_dav_directory() {
+        let dav = Dav {
+            directory: "/data/homes/{login}/files".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(watched_root(&dav), PathBuf::from("/data/homes"));
+        assert_eq!(
+            dav_directory(&dav, Path::new("/data/homes/alice/files/doc.txt")).as_deref(),
+            Some("/data/homes/alice/files")
+        );
+        assert_eq!(
+            dav_directory(&dav, Path::new("/data/homes/alice/other.txt")),
+            None
+        );
+        assert_eq!(dav_directory(&dav, Path::new("/data/homes")), None);
+
+        let dav = Dav {
+            directory: "/data/files".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(watched_root(&dav), PathBuf::from("/data/files"));
+        assert_eq!(
+            dav_directory(&dav, Path::new("/data/files/a/b.txt")).as_deref(),
+            Some("/data/files")
+        );
+    }
+}
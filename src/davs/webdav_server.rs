@@ -22,7 +22,7 @@ LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE
 SOFTWARE.
 */
-use futures_util::{future::BoxFuture, FutureExt, StreamExt};
+use futures_util::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt};
 use log::{debug, error, info};
 use std::borrow::Cow;
 use std::io::Error;
@@ -31,11 +31,12 @@ use xml::escape::escape_str_pcdata;
 use async_walkdir::WalkDir;
 use async_zip::write::{EntryOptions, ZipFileWriter};
 use async_zip::Compression;
+use base64ct::{Base64, Encoding};
 use chrono::{TimeZone, Utc};
 use futures::TryStreamExt;
 use headers::{
-    AcceptRanges, ContentType, ETag, HeaderMap, HeaderMapExt, IfModifiedSince, IfNoneMatch,
-    IfRange, LastModified, Range,
+    AcceptRanges, ContentLength, ContentRange, ETag, HeaderMap, HeaderMapExt, IfMatch,
+    IfModifiedSince, IfNoneMatch, IfRange, LastModified, Range,
 };
 use hyper::header::{
     HeaderValue, CONTENT_DISPOSITION, CONTENT_LENGTH, CONTENT_RANGE, CONTENT_TYPE, RANGE,
@@ -43,35 +44,56 @@ use hyper::header::{
 use hyper::{Body, Method, StatusCode, Uri};
 use serde::Serialize;
 
-use std::fs::Metadata;
 use std::net::SocketAddr;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::SystemTime;
 use tokio::fs::File;
-use tokio::io::AsyncSeekExt;
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::{fs, io};
 use tokio_util::io::StreamReader;
 use uuid::Uuid;
 
 use crate::davs::encrypted_streamer::EncryptedStreamer;
+use crate::users::User;
 
-use super::encrypted_streamer::decrypted_size;
+use super::acl::{Acl, Permission};
+use super::content_store::ContentStore;
+use super::encrypted_streamer::{decrypted_size, encrypted_stream, HEADER_SIZE};
 use super::headers::Depth;
 use super::model::Dav;
+use super::names::NameCipher;
+use super::quota;
+use super::search::{self, SearchQuery};
+use super::shares::MaxFileSize;
+use super::storage::{self, LocalStorage, Storage, StorageFile, StorageMetadata};
 use super::streamer::Streamer;
+use super::thumbnails::{ThumbnailSize, ThumbnailStore};
+use super::trash::{TrashBin, TrashedItem, TRASH_URL};
+use super::uploads::{Upload, UploadStore, TUS_VERSION, UPLOADS_URL};
+use super::versions::VersionStore;
+use super::{is_internal, PARTIAL_EXTENSION};
 use crate::davs::headers::Overwrite;
 
 pub type Request = hyper::Request<Body>;
 pub type Response = hyper::Response<Body>;
 
 const BUF_SIZE: usize = 65536;
+const MAX_RANGES: usize = 64;
 
 pub type BoxResult<T> = Result<T, Box<dyn std::error::Error>>;
 
 pub struct WebdavServer {}
 
+/// The dav a request is served from, with what is worked out once per request
+struct DavContext<'a> {
+    dav: &'a Dav,
+    storage: &'a dyn Storage,
+    acl: &'a Acl,
+    names: &'a Option<NameCipher>,
+}
+
 impl WebdavServer {
     pub fn new() -> Self {
         Self {}
@@ -82,11 +104,12 @@ impl WebdavServer {
         req: Request,
         addr: SocketAddr,
         dav: &Dav,
+        user: &Option<User>,
     ) -> Result<Response, hyper::Error> {
         let method = req.method().clone();
         let uri = req.uri().clone();
 
-        let res = match self.handle(req, dav).await {
+        let res = match self.handle(req, dav, user).await {
             Ok(res) => {
                 let status = res.status().as_u16();
                 info!(r#"{} "{} {}" - {}"#, addr.ip(), method, uri, status,);
@@ -105,7 +128,12 @@ impl WebdavServer {
         Ok(res)
     }
 
-    pub async fn handle(self: Arc<Self>, mut req: Request, dav: &Dav) -> BoxResult<Response> {
+    pub async fn handle(
+        self: Arc<Self>,
+        mut req: Request,
+        dav: &Dav,
+        user: &Option<User>,
+    ) -> BoxResult<Response> {
         let mut res = Response::default();
 
         let req_path = req.uri().path();
@@ -114,7 +142,28 @@ impl WebdavServer {
 
         let head_only = method == Method::HEAD;
 
-        let path = match self.extract_path(req_path, &dav.directory) {
+        let storage = storage::for_dav(dav);
+        let storage = storage.as_ref();
+
+        if let Some(trash_path) = req_path.strip_prefix(TRASH_URL) {
+            self.handle_trash(trash_path, &method, &mut res, dav, &Acl::new(dav, user))
+                .await?;
+            return Ok(res);
+        }
+
+        if let Some(upload_path) = req_path.strip_prefix(UPLOADS_URL) {
+            // Uploads are put together in the local directory of the dav
+            if !storage.is_local() {
+                status_not_found(&mut res);
+                return Ok(res);
+            }
+            let id = upload_path.trim_matches('/').to_owned();
+            self.handle_uploads(&id, req, &mut res, dav, &Acl::new(dav, user))
+                .await?;
+            return Ok(res);
+        }
+
+        let path = match self.extract_path(req_path, dav) {
             Some(v) => v,
             None => {
                 status_forbid(&mut res);
@@ -126,17 +175,37 @@ impl WebdavServer {
 
         let query = req.uri().query().unwrap_or_default();
 
-        let (is_miss, is_dir, is_file, size) = match fs::metadata(path).await.ok() {
-            Some(meta) => (false, meta.is_dir(), meta.is_file(), meta.len()),
+        let (is_miss, is_dir, is_file, size) = match storage.metadata(path).await.ok() {
+            Some(meta) => (false, meta.is_dir, !meta.is_dir, meta.len),
             None => (true, false, false, 0),
         };
 
-        let allow_upload = dav.writable;
-        let allow_delete = dav.writable;
-        let allow_search = true;
-        let key = dav.key;
+        let acl = Acl::new(dav, user);
+        let permission = if is_dir {
+            acl.dir_permission_fs(path, Path::new(&dav.directory))
+        } else {
+            acl.permission_fs(path, Path::new(&dav.directory))
+        };
+        let allow_upload = permission >= Some(Permission::Write);
+        let allow_delete = permission >= Some(Permission::Delete);
+        // Walking the directory tree is only done on the local file system
+        let allow_search = storage.is_local();
+        let names = NameCipher::new(dav);
+        let ctx = DavContext {
+            dav,
+            storage,
+            acl: &acl,
+            names: &names,
+        };
 
-        if !dav.allow_symlinks
+        // Every method needs at least read access on the wanted path
+        if permission.is_none() && method != Method::OPTIONS {
+            status_forbid(&mut res);
+            return Ok(res);
+        }
+
+        if storage.is_local()
+            && !dav.allow_symlinks
             && !is_miss
             && !self
                 .is_root_contained(path, Path::new(&dav.directory))
@@ -146,45 +215,80 @@ impl WebdavServer {
             return Ok(res);
         }
 
+        // Paths whose content may change, to keep the search index in sync
+        let changed = match method.as_str() {
+            "PUT" | "PATCH" | "DELETE" | "MKCOL" | "POST" => vec![path.to_path_buf()],
+            "MOVE" => std::iter::once(path.to_path_buf())
+                .chain(self.extract_dest(headers, dav))
+                .collect(),
+            "COPY" => self.extract_dest(headers, dav).into_iter().collect(),
+            _ => vec![],
+        };
+        // The deletions and the restored versions are not tracked by the quota reservations
+        let untracked = matches!(method.as_str(), "DELETE" | "MOVE" | "POST");
+
         match method {
             Method::GET | Method::HEAD => {
                 if is_dir {
-                    if query == "zip" {
-                        self.handle_zip_dir(path, head_only, &mut res, key).await?;
-                    } else if allow_search && query.starts_with("q=") {
-                        let q = decode_uri(&query[2..]).unwrap_or_default();
-                        self.handle_query_dir(
-                            path,
-                            &q,
-                            &mut res,
-                            &dav.directory,
-                            dav.allow_symlinks,
-                            key,
-                        )
-                        .await?;
+                    if query == "zip" && storage.is_local() {
+                        self.handle_zip_dir(&ctx, path, head_only, &mut res).await?;
+                    } else if allow_search && SearchQuery::is_search(query) {
+                        match SearchQuery::parse(query) {
+                            Ok(search) => {
+                                self.handle_search_dir(&ctx, path, &search, &mut res)
+                                    .await?
+                            }
+                            Err(e) => {
+                                status_bad_request(&mut res);
+                                *res.body_mut() = Body::from(e);
+                            }
+                        }
                     }
+                } else if is_file && storage.is_local() && query.starts_with("thumbnail=") {
+                    self.handle_thumbnail(path, query, head_only, &mut res, dav, &names)
+                        .await?;
+                } else if is_file
+                    && dav.versioning.is_some()
+                    && (query == "versions" || query.starts_with("version="))
+                {
+                    self.handle_versions(&ctx, path, query, &method, headers, &mut res)
+                        .await?;
                 } else if is_file {
-                    self.handle_send_file(path, headers, head_only, &mut res, key)
+                    self.handle_send_file(&ctx, path, headers, head_only, &mut res)
                         .await?;
                 } else {
                     status_not_found(&mut res);
                 }
             }
+            Method::POST => {
+                // Restoring a version replaces the current content
+                if !is_file || dav.versioning.is_none() || !query.starts_with("version=") {
+                    status_method_not_allowed(&mut res);
+                } else if !allow_delete {
+                    status_forbid(&mut res);
+                } else {
+                    self.handle_versions(&ctx, path, query, &method, headers, &mut res)
+                        .await?;
+                }
+            }
             Method::OPTIONS => {
                 set_webdav_headers(&mut res);
             }
             Method::PUT => {
                 if !allow_upload || (!allow_delete && is_file && size > 0) {
                     status_forbid(&mut res);
+                } else if headers.contains_key(CONTENT_RANGE) {
+                    self.handle_partial_update(&ctx, path, req, &mut res)
+                        .await?;
                 } else {
-                    self.handle_upload(path, req, &mut res, key).await?;
+                    self.handle_upload(&ctx, path, req, &mut res).await?;
                 }
             }
             Method::DELETE => {
                 if !allow_delete {
                     status_forbid(&mut res);
                 } else if !is_miss {
-                    self.handle_delete(path, is_dir, &mut res).await?
+                    self.handle_delete(storage, path, &mut res, dav).await?
                 } else {
                     status_not_found(&mut res);
                 }
@@ -192,28 +296,30 @@ impl WebdavServer {
             method => match method.as_str() {
                 "PROPFIND" => {
                     if is_dir {
-                        self.handle_propfind_dir(
-                            path,
-                            headers,
-                            &mut res,
-                            &dav.directory,
-                            dav.allow_symlinks,
-                            key,
-                        )
-                        .await?;
+                        self.handle_propfind_dir(&ctx, path, headers, &mut res)
+                            .await?;
                     } else if is_file {
-                        self.handle_propfind_file(
-                            path,
-                            &mut res,
-                            &dav.directory,
-                            dav.allow_symlinks,
-                            key,
-                        )
-                        .await?;
+                        self.handle_propfind_file(&ctx, path, &mut res).await?;
                     } else {
                         status_not_found(&mut res);
                     }
                 }
+                "PATCH" => {
+                    if !allow_upload || (!allow_delete && size > 0) {
+                        status_forbid(&mut res);
+                    } else if !is_file {
+                        status_not_found(&mut res);
+                    } else if headers.get(CONTENT_TYPE)
+                        != Some(&HeaderValue::from_static(
+                            "application/x-sabredav-partialupdate",
+                        ))
+                    {
+                        *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                    } else {
+                        self.handle_partial_update(&ctx, path, req, &mut res)
+                            .await?;
+                    }
+                }
                 "PROPPATCH" => {
                     if is_file {
                         self.handle_proppatch(req_path, &mut res).await?;
@@ -230,26 +336,24 @@ impl WebdavServer {
                         *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
                         *res.body_mut() = Body::from("Unsupported Media Type");
                     } else {
-                        self.handle_mkcol(path, &mut res).await?;
+                        self.handle_mkcol(storage, path, &mut res).await?;
                     }
                 }
                 "COPY" => {
-                    if !allow_upload {
-                        status_forbid(&mut res);
-                    } else if is_miss {
+                    if is_miss {
                         status_not_found(&mut res);
                     } else {
-                        self.handle_copymove(path, req, method, &mut res, &dav.directory)
+                        self.handle_copymove(&ctx, path, req, method, &mut res)
                             .await?
                     }
                 }
                 "MOVE" => {
-                    if !allow_upload || !allow_delete {
+                    if !allow_delete {
                         status_forbid(&mut res);
                     } else if is_miss {
                         status_not_found(&mut res);
                     } else {
-                        self.handle_copymove(path, req, method, &mut res, &dav.directory)
+                        self.handle_copymove(&ctx, path, req, method, &mut res)
                             .await?
                     }
                 }
@@ -271,95 +375,825 @@ impl WebdavServer {
                 _ => {
                     status_method_not_allowed(&mut res);
                 }
-            },
+            },
+        }
+        if !changed.is_empty() && res.status().is_success() && storage.is_local() {
+            reindex(dav, &changed).await;
+        }
+        if untracked && res.status().is_success() && dav.quota.is_some() {
+            quota::invalidate(Path::new(&dav.directory));
+        }
+        Ok(res)
+    }
+
+    async fn handle_upload(
+        &self,
+        ctx: &DavContext<'_>,
+        path: &Path,
+        mut req: Request,
+        res: &mut Response,
+    ) -> BoxResult<()> {
+        let (storage, dav) = (ctx.storage, ctx.dav);
+        let key = dav.key;
+
+        let existing = storage.metadata(path).await.ok();
+        if existing.as_ref().map_or(false, |meta| meta.is_dir) {
+            status_forbid(res);
+            return Ok(());
+        }
+        if !preconditions_pass(req.headers(), existing.as_ref()) {
+            *res.status_mut() = StatusCode::PRECONDITION_FAILED;
+            return Ok(());
+        }
+
+        // The size of a capped upload must be announced, the body then being unable to exceed it
+        if let Some(MaxFileSize(max_file_size)) = req.extensions().get::<MaxFileSize>().copied() {
+            match req.headers().typed_get::<ContentLength>() {
+                None => {
+                    *res.status_mut() = StatusCode::LENGTH_REQUIRED;
+                    return Ok(());
+                }
+                Some(length) if length.0 > max_file_size => {
+                    *res.status_mut() = StatusCode::PAYLOAD_TOO_LARGE;
+                    return Ok(());
+                }
+                Some(_) => (),
+            }
+        }
+
+        // Reserve the room of the upload in the quota, all the space left if its size is unknown,
+        // then work out how many bytes can be written
+        let mut limit = None;
+        let mut reservation = None;
+        if let Some(quota) = &dav.quota {
+            let replaced = match existing {
+                Some(_) => quota::usage(path, key.is_some()).await?,
+                None => quota::Usage::default(),
+            };
+            let length = req.headers().typed_get::<ContentLength>();
+            let reserved = quota::reserve(
+                Path::new(&dav.directory),
+                key.is_some(),
+                quota,
+                length.map(|length| length.0.saturating_sub(replaced.bytes)),
+                1 - replaced.files,
+            )
+            .await?;
+            let reserved = match reserved {
+                Some(reserved) => reserved,
+                None => {
+                    status_insufficient_storage(res);
+                    return Ok(());
+                }
+            };
+            if quota.bytes.is_some() {
+                limit = Some(reserved.added.bytes + replaced.bytes);
+            }
+            reservation = Some((reserved, replaced));
+        }
+
+        let body_with_io_error = req
+            .body_mut()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+
+        // Read one byte past the limit to find out if the body exceeds it
+        let body_reader =
+            StreamReader::new(body_with_io_error).take(limit.map_or(u64::MAX, |l| l + 1));
+
+        futures::pin_mut!(body_reader);
+
+        if self
+            .write_file(ctx, path, &mut body_reader, limit, existing.as_ref(), res)
+            .await?
+        {
+            if let Some((reserved, replaced)) = reservation {
+                reserved.settle(quota::usage(path, key.is_some()).await?, replaced);
+            }
+            *res.status_mut() = StatusCode::CREATED;
+        }
+        Ok(())
+    }
+
+    /// Overwrite a part of a file or append to it, from a PUT with a Content-Range header or a SabreDAV partial update PATCH.
+    /// The whole file is rewritten : on encrypted davs, re-encrypting only the touched chunks would reuse the nonce of the file
+    /// for another content, which would break the encryption.
+    async fn handle_partial_update(
+        &self,
+        ctx: &DavContext<'_>,
+        path: &Path,
+        mut req: Request,
+        res: &mut Response,
+    ) -> BoxResult<()> {
+        let (storage, dav) = (ctx.storage, ctx.dav);
+        let key = dav.key;
+
+        let existing = storage.metadata(path).await.ok();
+        if existing.as_ref().map_or(false, |meta| meta.is_dir) {
+            status_forbid(res);
+            return Ok(());
+        }
+        if !preconditions_pass(req.headers(), existing.as_ref()) {
+            *res.status_mut() = StatusCode::PRECONDITION_FAILED;
+            return Ok(());
+        }
+        let size = match &existing {
+            Some(meta) => content_size(storage, path, meta, &key).await?,
+            None => 0,
+        };
+
+        // The length of the body must be known so that the rest of the file can be put after it
+        let length = match req.headers().typed_get::<ContentLength>() {
+            Some(length) => length.0,
+            None => {
+                *res.status_mut() = StatusCode::LENGTH_REQUIRED;
+                return Ok(());
+            }
+        };
+        let start = match parse_update_start(req.headers(), size, length) {
+            Some(start) if start <= size => start,
+            _ => {
+                *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
+                res.headers_mut()
+                    .insert(CONTENT_RANGE, format!("bytes */{}", size).parse()?);
+                return Ok(());
+            }
+        };
+        let end = start + length;
+
+        let reservation = match &dav.quota {
+            Some(quota) => {
+                let reserved = quota::reserve(
+                    Path::new(&dav.directory),
+                    key.is_some(),
+                    quota,
+                    Some(end.saturating_sub(size)),
+                    if existing.is_some() { 0 } else { 1 },
+                )
+                .await?;
+                match reserved {
+                    Some(reserved) => Some(reserved),
+                    None => {
+                        status_insufficient_storage(res);
+                        return Ok(());
+                    }
+                }
+            }
+            None => None,
+        };
+
+        let body_with_io_error = req
+            .body_mut()
+            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+        let mut body_reader = StreamReader::new(body_with_io_error).take(length);
+
+        // The plain files are written over a copy, instead of being streamed again whole
+        let written = if storage.is_local() && key.is_none() {
+            self.write_range(path, &mut body_reader, start, existing.as_ref(), res, dav)
+                .await?
+        } else {
+            let head = read_range(storage, path, 0, start, key).await?;
+            let tail = read_range(storage, path, end, size.saturating_sub(end), key).await?;
+            let mut reader = head.chain(body_reader).chain(tail);
+            self.write_file(ctx, path, &mut reader, None, existing.as_ref(), res)
+                .await?
+        };
+        if written {
+            if let Some(reservation) = reservation {
+                reservation.commit();
+            }
+            status_no_content(res);
+        }
+        Ok(())
+    }
+
+    /// Write the content of a file to a partial file beside it, that replaces it only once complete.
+    /// The other storages replace the file at once, encrypted beforehand on encrypted davs.
+    /// Returns false, having set the response status, if the file could not be written or if the content exceeded the limit.
+    async fn write_file<R>(
+        &self,
+        ctx: &DavContext<'_>,
+        path: &Path,
+        reader: &mut R,
+        limit: Option<u64>,
+        existing: Option<&StorageMetadata>,
+        res: &mut Response,
+    ) -> BoxResult<bool>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        let (storage, dav) = (ctx.storage, ctx.dav);
+        if !storage.is_local() {
+            match dav.key {
+                Some(key) => {
+                    let encrypted = encrypted_stream(reader, key).map_ok(std::io::Cursor::new);
+                    let mut encrypted_reader = StreamReader::new(Box::pin(encrypted));
+                    storage.write(path, &mut encrypted_reader).await?
+                }
+                None => storage.write(path, reader).await?,
+            };
+            if let Some((etag, _)) = extract_cache_headers(&storage.metadata(path).await?) {
+                res.headers_mut().typed_insert(etag);
+            }
+            return Ok(true);
+        }
+
+        ensure_path_parent(path).await?;
+
+        let partial_path = partial_path(path)?;
+        let mut file = match fs::File::create(&partial_path).await {
+            Ok(v) => v,
+            Err(_) => {
+                status_forbid(res);
+                return Ok(false);
+            }
+        };
+
+        let written = if let Some(key) = dav.key {
+            let mut enc_file = EncryptedStreamer::new(file, key);
+            enc_file.copy_from(reader).await
+        } else {
+            io::copy(reader, &mut file).await
+        };
+
+        match written {
+            Ok(written) if limit.map_or(false, |l| written > l) => {
+                fs::remove_file(&partial_path).await?;
+                status_insufficient_storage(res);
+                return Ok(false);
+            }
+            Ok(_) => (),
+            Err(e) => {
+                // The existing content is left untouched
+                fs::remove_file(&partial_path).await?;
+                return Err(e.into());
+            }
+        }
+
+        self.replace_file(path, &partial_path, existing, res, dav)
+            .await?;
+        Ok(true)
+    }
+
+    /// Write a range of a plain local file, in place unless it is linked to stored content or versioned,
+    /// into a copy of it that replaces it only once written otherwise.
+    /// Returns false, having set the response status, if there was no space left to write it.
+    async fn write_range<R>(
+        &self,
+        path: &Path,
+        reader: &mut R,
+        start: u64,
+        existing: Option<&StorageMetadata>,
+        res: &mut Response,
+        dav: &Dav,
+    ) -> BoxResult<bool>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        ensure_path_parent(path).await?;
+
+        // A file which is neither linked to stored content nor kept in a history is written in place
+        if let Some(meta) = existing {
+            let versioned = dav.versioning.is_some() && meta.len > 0;
+            if !versioned && is_unlinked(path).await? {
+                let written = async {
+                    let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+                    file.seek(std::io::SeekFrom::Start(start)).await?;
+                    io::copy(reader, &mut file).await
+                }
+                .await;
+                if let Err(e) = written {
+                    return write_failed(e, res);
+                }
+                if let Some(content_store) = ContentStore::new(dav) {
+                    content_store.store(path).await?;
+                }
+                if let Some((etag, _)) = extract_cache_headers(&LocalStorage.metadata(path).await?)
+                {
+                    res.headers_mut().typed_insert(etag);
+                }
+                return Ok(true);
+            }
+        }
+
+        let partial_path = partial_path(path)?;
+        // A copy rather than the file itself, which is a link to stored content or kept in its history
+        let written = async {
+            match existing {
+                Some(_) => fs::copy(path, &partial_path).await?,
+                None => fs::File::create(&partial_path).await.map(|_| 0)?,
+            };
+            let mut file = fs::OpenOptions::new()
+                .write(true)
+                .open(&partial_path)
+                .await?;
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            io::copy(reader, &mut file).await
+        }
+        .await;
+        if let Err(e) = written {
+            // The existing content is left untouched
+            fs::remove_file(&partial_path).await.ok();
+            return write_failed(e, res);
+        }
+
+        self.replace_file(path, &partial_path, existing, res, dav)
+            .await?;
+        Ok(true)
+    }
+
+    /// Replace a local file by its complete partial file, keeping the replaced content in its history
+    async fn replace_file(
+        &self,
+        path: &Path,
+        partial_path: &Path,
+        existing: Option<&StorageMetadata>,
+        res: &mut Response,
+        dav: &Dav,
+    ) -> BoxResult<()> {
+        if let Some(content_store) = ContentStore::new(dav) {
+            content_store.store(partial_path).await?;
+        }
+
+        // Keep the replaced content in the history of the file
+        if let Some(policy) = &dav.versioning {
+            if existing.map_or(false, |meta| meta.len > 0) {
+                VersionStore::new(dav, policy).put(path).await?;
+            }
+        }
+        fs::rename(partial_path, path).await?;
+
+        if let Some((etag, _)) = extract_cache_headers(&LocalStorage.metadata(path).await?) {
+            res.headers_mut().typed_insert(etag);
+        }
+        Ok(())
+    }
+
+    async fn handle_delete(
+        &self,
+        storage: &dyn Storage,
+        path: &Path,
+        res: &mut Response,
+        dav: &Dav,
+    ) -> BoxResult<()> {
+        if dav.trash.is_some() {
+            TrashBin::new(dav).put(path).await?;
+        } else {
+            storage.remove(path).await?;
+        }
+
+        status_no_content(res);
+        Ok(())
+    }
+
+    async fn handle_trash(
+        &self,
+        trash_path: &str,
+        method: &Method,
+        res: &mut Response,
+        dav: &Dav,
+        acl: &Acl,
+    ) -> BoxResult<()> {
+        let policy = match &dav.trash {
+            Some(policy) => policy,
+            None => {
+                status_not_found(res);
+                return Ok(());
+            }
+        };
+        let bin = TrashBin::new(dav);
+        bin.purge_expired(policy).await?;
+        // The items are trashed with their encrypted paths, the users see and the rules apply to the plain ones
+        let names = NameCipher::new(dav);
+        let plain = |item: TrashedItem| match &names {
+            Some(names) => names
+                .decrypt_path(Path::new(&item.path))
+                .ok()
+                .map(|path| TrashedItem {
+                    path: normalize_path(path),
+                    ..item
+                }),
+            None => Some(item),
+        };
+
+        let id = trash_path.trim_matches('/');
+        if id.is_empty() {
+            let items: Vec<TrashedItem> = bin
+                .list()
+                .await?
+                .into_iter()
+                .filter_map(plain)
+                .filter(|item| acl.allows(&item.path, Permission::Read))
+                .collect();
+            match *method {
+                // List the trashed items
+                Method::GET => {
+                    *res.body_mut() = Body::from(serde_json::to_string(&items)?);
+                }
+                // Empty the trash, as far as the user is allowed to
+                Method::DELETE => {
+                    for item in items
+                        .iter()
+                        .filter(|item| acl.allows(&item.path, Permission::Delete))
+                    {
+                        bin.purge(item).await?;
+                    }
+                    status_no_content(res);
+                }
+                _ => status_method_not_allowed(res),
+            }
+            return Ok(());
+        }
+
+        let (item, plain_path) = match bin.get(id).await {
+            Ok(item) => match plain(item.clone()) {
+                Some(plain_item) if acl.allows(&plain_item.path, Permission::Read) => {
+                    (item, plain_item.path)
+                }
+                _ => {
+                    status_not_found(res);
+                    return Ok(());
+                }
+            },
+            _ => {
+                status_not_found(res);
+                return Ok(());
+            }
+        };
+        match *method {
+            // Restore the item
+            Method::POST => {
+                if !acl.allows(&plain_path, Permission::Write) {
+                    status_forbid(res);
+                    return Ok(());
+                }
+                let mut reservation = None;
+                if let Some(quota) = &dav.quota {
+                    reservation = quota::reserve(
+                        Path::new(&dav.directory),
+                        dav.key.is_some(),
+                        quota,
+                        Some(item.size),
+                        if item.is_dir { 0 } else { 1 },
+                    )
+                    .await?;
+                    if reservation.is_none() {
+                        status_insufficient_storage(res);
+                        return Ok(());
+                    }
+                }
+                match bin.restore(&item).await {
+                    Ok(_) => {
+                        if let Some(reservation) = reservation {
+                            reservation.commit();
+                        }
+                        *res.status_mut() = StatusCode::CREATED;
+                        reindex(dav, &[Path::new(&dav.directory).join(&item.path)]).await;
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                        *res.status_mut() = StatusCode::CONFLICT;
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+            // Purge the item
+            Method::DELETE => {
+                if !acl.allows(&plain_path, Permission::Delete) {
+                    status_forbid(res);
+                    return Ok(());
+                }
+                bin.purge(&item).await?;
+                status_no_content(res);
+            }
+            _ => status_method_not_allowed(res),
+        }
+        Ok(())
+    }
+
+    /// Resumable uploads, following the core tus protocol with the creation and termination extensions
+    async fn handle_uploads(
+        &self,
+        id: &str,
+        mut req: Request,
+        res: &mut Response,
+        dav: &Dav,
+        acl: &Acl,
+    ) -> BoxResult<()> {
+        res.headers_mut()
+            .insert("Tus-Resumable", HeaderValue::from_static(TUS_VERSION));
+        let store = UploadStore::new(dav);
+        let method = req.method().clone();
+
+        if method == Method::OPTIONS {
+            res.headers_mut()
+                .insert("Tus-Version", HeaderValue::from_static(TUS_VERSION));
+            res.headers_mut().insert(
+                "Tus-Extension",
+                HeaderValue::from_static("creation,termination"),
+            );
+            status_no_content(res);
+            return Ok(());
+        }
+
+        if id.is_empty() {
+            // Create an upload
+            if method != Method::POST {
+                status_method_not_allowed(res);
+                return Ok(());
+            }
+            let length = req
+                .headers()
+                .get("Upload-Length")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            let rel_path = req
+                .headers()
+                .get("Upload-Metadata")
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| upload_metadata(v, "path"));
+            let (length, rel_path) = match (length, rel_path) {
+                (Some(length), Some(rel_path)) if rel_path.starts_with('/') => (length, rel_path),
+                _ => {
+                    status_bad_request(res);
+                    return Ok(());
+                }
+            };
+            let path = match self.extract_path(&encode_uri(&rel_path), dav) {
+                Some(path) => path,
+                None => {
+                    status_forbid(res);
+                    return Ok(());
+                }
+            };
+            let existing = fs::metadata(&path).await.ok();
+            if existing.as_ref().map_or(false, |meta| meta.is_dir()) {
+                *res.status_mut() = StatusCode::CONFLICT;
+                return Ok(());
+            }
+            // As for PUT, replacing a file needs the right to delete it
+            let wanted = match existing {
+                Some(meta) if meta.len() > 0 => Permission::Delete,
+                _ => Permission::Write,
+            };
+            if !acl.allows(&rel_path, wanted) {
+                status_forbid(res);
+                return Ok(());
+            }
+            // The room of a resumable upload is taken as soon as it is created
+            if let Some(quota) = &dav.quota {
+                let replaced = match fs::metadata(&path).await {
+                    Ok(_) => quota::usage(&path, dav.key.is_some()).await?,
+                    Err(_) => quota::Usage::default(),
+                };
+                let reserved = quota::reserve(
+                    Path::new(&dav.directory),
+                    dav.key.is_some(),
+                    quota,
+                    Some(length.saturating_sub(replaced.bytes)),
+                    1 - replaced.files,
+                )
+                .await?;
+                match reserved {
+                    Some(reserved) => reserved.commit(),
+                    None => {
+                        status_insufficient_storage(res);
+                        return Ok(());
+                    }
+                }
+            }
+            let upload = store.create(&rel_path, length).await?;
+            if upload.is_complete()
+                && !self
+                    .complete_upload(&path, &store, &upload, dav, acl, res)
+                    .await?
+            {
+                return Ok(());
+            }
+            res.headers_mut().insert(
+                "Location",
+                HeaderValue::from_str(&format!("{}/{}", UPLOADS_URL, upload.id))?,
+            );
+            *res.status_mut() = StatusCode::CREATED;
+            return Ok(());
+        }
+
+        let mut upload = match store.get(id).await {
+            Ok(upload) if acl.allows(&upload.path, Permission::Write) => upload,
+            _ => {
+                status_not_found(res);
+                return Ok(());
+            }
+        };
+        match method {
+            // Tell how much of the upload was received
+            Method::HEAD => {
+                let headers = res.headers_mut();
+                headers.insert("Upload-Offset", HeaderValue::from(upload.offset));
+                headers.insert("Upload-Length", HeaderValue::from(upload.length));
+                headers.insert("Cache-Control", HeaderValue::from_static("no-store"));
+            }
+            // Resume the upload
+            Method::PATCH => {
+                if req.headers().get(CONTENT_TYPE)
+                    != Some(&HeaderValue::from_static("application/offset+octet-stream"))
+                {
+                    *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                    return Ok(());
+                }
+                let offset = req
+                    .headers()
+                    .get("Upload-Offset")
+                    .and_then(|v| v.to_str().ok())
+                    .and_then(|v| v.parse::<u64>().ok());
+                if offset != Some(upload.offset) {
+                    *res.status_mut() = StatusCode::CONFLICT;
+                    return Ok(());
+                }
+                let path = match self.extract_path(&encode_uri(&upload.path), dav) {
+                    Some(path) => path,
+                    None => {
+                        status_forbid(res);
+                        return Ok(());
+                    }
+                };
+
+                let body_with_io_error = req
+                    .body_mut()
+                    .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
+                let body_reader = StreamReader::new(body_with_io_error);
+                futures::pin_mut!(body_reader);
+                store.append(&mut upload, &mut body_reader).await?;
+
+                if upload.is_complete()
+                    && !self
+                        .complete_upload(&path, &store, &upload, dav, acl, res)
+                        .await?
+                {
+                    return Ok(());
+                }
+                res.headers_mut()
+                    .insert("Upload-Offset", HeaderValue::from(upload.offset));
+                status_no_content(res);
+            }
+            // Abort the upload
+            Method::DELETE => {
+                store.delete(&upload).await?;
+                status_no_content(res);
+            }
+            _ => status_method_not_allowed(res),
         }
-        Ok(res)
+        Ok(())
     }
 
-    async fn handle_upload(
+    /// Move a complete upload to its destination, in a single step so that a partial file is never seen.
+    /// Returns false, having dropped the upload and set the response status, if the destination may not be replaced.
+    async fn complete_upload(
         &self,
         path: &Path,
-        mut req: Request,
+        store: &UploadStore,
+        upload: &Upload,
+        dav: &Dav,
+        acl: &Acl,
         res: &mut Response,
-        key: Option<[u8; 32]>,
-    ) -> BoxResult<()> {
+    ) -> BoxResult<bool> {
+        // The destination may have been created since the upload was, replacing it needs the right to delete it
+        let replaced = fs::metadata(path).await.ok();
+        if replaced.is_some_and(|meta| meta.len() > 0)
+            && !acl.allows(&upload.path, Permission::Delete)
+        {
+            store.delete(upload).await?;
+            status_forbid(res);
+            return Ok(false);
+        }
+        let data_path = store.complete(upload).await?;
+        if let Some(content_store) = ContentStore::new(dav) {
+            content_store.store(&data_path).await?;
+        }
+        if let Some(policy) = &dav.versioning {
+            if fs::metadata(path)
+                .await
+                .map_or(false, |meta| meta.is_file() && meta.len() > 0)
+            {
+                VersionStore::new(dav, policy).put(path).await?;
+            }
+        }
         ensure_path_parent(path).await?;
+        fs::rename(data_path, path).await?;
+        reindex(dav, &[path.to_path_buf()]).await;
+        Ok(true)
+    }
 
-        let mut file = match fs::File::create(&path).await {
-            Ok(v) => v,
-            Err(_) => {
-                status_forbid(res);
+    async fn handle_versions(
+        &self,
+        ctx: &DavContext<'_>,
+        path: &Path,
+        query: &str,
+        method: &Method,
+        headers: &HeaderMap<HeaderValue>,
+        res: &mut Response,
+    ) -> BoxResult<()> {
+        let dav = ctx.dav;
+        let versions = VersionStore::new(dav, &dav.versioning.clone().unwrap_or_default());
+        // List the versions of the file
+        if query == "versions" {
+            *res.body_mut() = Body::from(serde_json::to_string(&versions.list(path).await?)?);
+            return Ok(());
+        }
+        let id = decode_uri(&query["version=".len()..]).unwrap_or_default();
+        let version_path = match versions.version_path(path, &id) {
+            Ok(version_path) if fs::metadata(&version_path).await.is_ok() => version_path,
+            _ => {
+                status_not_found(res);
                 return Ok(());
             }
         };
-
-        let body_with_io_error = req
-            .body_mut()
-            .map_err(|err| io::Error::new(io::ErrorKind::Other, err));
-
-        let body_reader = StreamReader::new(body_with_io_error);
-
-        futures::pin_mut!(body_reader);
-
-        if let Some(key) = key {
-            let mut enc_file = EncryptedStreamer::new(file, key);
-            enc_file.copy_from(&mut body_reader).await?;
+        if *method == Method::POST {
+            versions.restore(path, &id).await?;
+            status_no_content(res);
         } else {
-            io::copy(&mut body_reader, &mut file).await?;
+            // The versions are kept on the local file system, whatever the dav storage
+            let local = DavContext {
+                storage: &LocalStorage,
+                ..*ctx
+            };
+            self.handle_send_file(&local, &version_path, headers, *method == Method::HEAD, res)
+                .await?;
         }
-
-        *res.status_mut() = StatusCode::CREATED;
         Ok(())
     }
 
-    async fn handle_delete(&self, path: &Path, is_dir: bool, res: &mut Response) -> BoxResult<()> {
-        match is_dir {
-            true => fs::remove_dir_all(path).await?,
-            false => fs::remove_file(path).await?,
+    async fn handle_thumbnail(
+        &self,
+        path: &Path,
+        query: &str,
+        head_only: bool,
+        res: &mut Response,
+        dav: &Dav,
+        names: &Option<NameCipher>,
+    ) -> BoxResult<()> {
+        let size = match ThumbnailSize::parse(&query["thumbnail=".len()..]) {
+            Some(size) => size,
+            None => {
+                status_bad_request(res);
+                return Ok(());
+            }
+        };
+        let name = plain_name(path, names).ok_or("Failed to decrypt file name")?;
+        let thumbnail = match ThumbnailStore::new(dav).get(path, &name, size).await? {
+            Some(thumbnail) => thumbnail,
+            None => {
+                *res.status_mut() = StatusCode::UNSUPPORTED_MEDIA_TYPE;
+                return Ok(());
+            }
+        };
+        res.headers_mut()
+            .insert(CONTENT_TYPE, HeaderValue::from_static(thumbnail.mime));
+        res.headers_mut()
+            .typed_insert(ContentLength(thumbnail.content.len() as u64));
+        if !head_only {
+            *res.body_mut() = Body::from(thumbnail.content);
         }
-
-        status_no_content(res);
         Ok(())
     }
 
-    async fn handle_query_dir(
+    async fn handle_search_dir(
         &self,
+        ctx: &DavContext<'_>,
         path: &Path,
-        query: &str,
+        search: &SearchQuery,
         res: &mut Response,
-        directory: &str,
-        allow_symlinks: bool,
-        key: Option<[u8; 32]>,
     ) -> BoxResult<()> {
+        let (dav, acl) = (ctx.dav, ctx.acl);
+        // The index is only built for the davs on the local file system
+        let local = DavContext {
+            storage: &LocalStorage,
+            ..*ctx
+        };
+        let directory = Path::new(&dav.directory);
+        let results: Vec<PathBuf> = search::search(dav, path, search)
+            .await?
+            .into_iter()
+            .filter(|result| acl.allows_fs(result, directory, Permission::Read))
+            .collect();
         let mut paths: Vec<PathItem> = vec![];
-        let mut walkdir = WalkDir::new(path);
-        while let Some(entry) = walkdir.next().await {
-            if let Ok(entry) = entry {
-                if !entry
-                    .file_name()
-                    .to_string_lossy()
-                    .to_lowercase()
-                    .contains(&query.to_lowercase())
-                {
-                    continue;
-                }
-                if fs::symlink_metadata(entry.path()).await.is_err() {
-                    continue;
-                }
-                if let Ok(Some(item)) = self
-                    .to_pathitem(
-                        entry.path(),
-                        path.to_path_buf(),
-                        directory,
-                        allow_symlinks,
-                        &key,
-                    )
-                    .await
-                {
-                    paths.push(item);
-                }
+        for result in results
+            .iter()
+            .skip(search.offset)
+            .take(search.limit.unwrap_or(usize::MAX))
+        {
+            // The index may lag behind changes made outside of the dav
+            let meta = match LocalStorage.metadata(result).await {
+                Ok(meta) => meta,
+                Err(_) => continue,
+            };
+            if let Ok(Some(item)) = self
+                .to_pathitem(&local, result.as_path(), &meta, path)
+                .await
+            {
+                paths.push(item);
             }
         }
+        res.headers_mut()
+            .insert("x-total-count", HeaderValue::from(results.len()));
         let j = serde_json::to_string(&paths)?;
         *res.body_mut() = Body::from(j);
         Ok(())
@@ -367,18 +1201,24 @@ impl WebdavServer {
 
     async fn handle_zip_dir(
         &self,
+        ctx: &DavContext<'_>,
         path: &Path,
         head_only: bool,
         res: &mut Response,
-        key: Option<[u8; 32]>,
     ) -> BoxResult<()> {
+        let (directory, key, names) = (ctx.dav.directory.as_str(), ctx.dav.key, ctx.names);
         let (mut writer, reader) = tokio::io::duplex(BUF_SIZE);
-        let filename = get_file_name(path)?;
+        // The dav directory keeps its own name
+        let filename = if path == Path::new(directory) {
+            get_file_name(path)?.to_owned()
+        } else {
+            plain_name(path, names).ok_or("Failed to decrypt file name")?
+        };
         res.headers_mut().insert(
             CONTENT_DISPOSITION,
             HeaderValue::from_str(&format!(
                 "attachment; filename=\"{}.zip\"",
-                encode_uri(filename),
+                encode_uri(&filename),
             ))
             .unwrap(),
         );
@@ -388,8 +1228,11 @@ impl WebdavServer {
             return Ok(());
         }
         let path = path.to_owned();
+        let directory = PathBuf::from(directory);
+        let acl = ctx.acl.clone();
+        let names = names.clone();
         tokio::spawn(async move {
-            if let Err(e) = zip_dir(&mut writer, &path, key).await {
+            if let Err(e) = zip_dir(&mut writer, &path, &directory, &acl, key, &names).await {
                 error!("Failed to zip {}, {}", path.display(), e);
             }
         });
@@ -400,13 +1243,14 @@ impl WebdavServer {
 
     async fn handle_send_file(
         &self,
+        ctx: &DavContext<'_>,
         path: &Path,
         headers: &HeaderMap<HeaderValue>,
         head_only: bool,
         res: &mut Response,
-        key: Option<[u8; 32]>,
     ) -> BoxResult<()> {
-        let (file, meta) = tokio::join!(fs::File::open(path), fs::metadata(path),);
+        let (storage, key, names) = (ctx.storage, ctx.dav.key, ctx.names);
+        let (file, meta) = tokio::join!(storage.open(path), storage.metadata(path));
         let (mut file, meta) = (file?, meta?);
         let mut use_range = true;
         if let Some((etag, last_modified)) = extract_cache_headers(&meta) {
@@ -438,86 +1282,123 @@ impl WebdavServer {
             }
         }
 
-        let range = if use_range {
-            parse_range(headers)
+        let decrypted_size = if key.is_some() {
+            let size = decrypted_size(&mut file, meta.len).await?;
+            file.seek(std::io::SeekFrom::Start(0)).await?;
+            size
         } else {
-            None
+            meta.len
         };
 
-        if let Some(mime) = mime_guess::from_path(&path).first() {
-            res.headers_mut().typed_insert(ContentType::from(mime));
+        let ranges = if use_range {
+            parse_range(headers, decrypted_size)
         } else {
-            res.headers_mut().insert(
-                CONTENT_TYPE,
-                HeaderValue::from_static("application/octet-stream"),
-            );
-        }
+            None
+        };
+
+        let filename = plain_name(path, names).ok_or("Failed to decrypt file name")?;
+        let content_type = mime_guess::from_path(&filename).first().map_or_else(
+            || "application/octet-stream".to_owned(),
+            |mime| mime.to_string(),
+        );
 
-        let filename = get_file_name(path)?;
         res.headers_mut().insert(
             CONTENT_DISPOSITION,
-            HeaderValue::from_str(&format!("inline; filename=\"{}\"", encode_uri(filename),))
+            HeaderValue::from_str(&format!("inline; filename=\"{}\"", encode_uri(&filename),))
                 .unwrap(),
         );
 
         res.headers_mut().typed_insert(AcceptRanges::bytes());
 
-        let encrypted_size = meta.len();
-        let decrypted_size = if key.is_some() {
-            decrypted_size(encrypted_size)
-        } else {
-            encrypted_size
-        };
-
-        if let Some(range) = range {
-            println!("Requesting range: {:?}", range);
-            if range
-                .end
-                .map_or_else(|| range.start < decrypted_size, |v| v >= range.start)
-            {
-                let end = range
-                    .end
-                    .unwrap_or(decrypted_size - 1)
-                    .min(decrypted_size - 1);
-                let part_size = end - range.start + 1;
-                *res.status_mut() = StatusCode::PARTIAL_CONTENT;
-                let content_range = format!("bytes {}-{}/{}", range.start, end, decrypted_size);
+        match ranges.as_deref() {
+            None => {
                 res.headers_mut()
-                    .insert(CONTENT_RANGE, content_range.parse()?);
+                    .insert(CONTENT_TYPE, HeaderValue::from_str(&content_type)?);
                 res.headers_mut()
-                    .insert(CONTENT_LENGTH, format!("{}", part_size).parse()?);
+                    .insert(CONTENT_LENGTH, format!("{}", decrypted_size).parse()?);
                 if head_only {
                     return Ok(());
                 }
-
                 if let Some(key) = key {
                     let encrypted_file = EncryptedStreamer::new(file, key);
-                    *res.body_mut() =
-                        Body::wrap_stream(encrypted_file.into_stream_sized(range.start, part_size));
+                    *res.body_mut() = Body::wrap_stream(encrypted_file.into_stream());
                 } else {
-                    file.seek(std::io::SeekFrom::Start(range.start)).await?;
                     let reader = Streamer::new(file, BUF_SIZE);
-                    *res.body_mut() = Body::wrap_stream(reader.into_stream_sized(part_size));
+                    *res.body_mut() = Body::wrap_stream(reader.into_stream());
                 }
-            } else {
+            }
+            Some([]) => {
                 *res.status_mut() = StatusCode::RANGE_NOT_SATISFIABLE;
                 res.headers_mut().insert(
                     CONTENT_RANGE,
                     format!("bytes */{}", decrypted_size).parse()?,
                 );
             }
-        } else {
-            res.headers_mut()
-                .insert(CONTENT_LENGTH, format!("{}", decrypted_size).parse()?);
-            if head_only {
-                return Ok(());
+            Some([range]) => {
+                let part_size = range.end - range.start + 1;
+                *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+                let content_range =
+                    format!("bytes {}-{}/{}", range.start, range.end, decrypted_size);
+                res.headers_mut()
+                    .insert(CONTENT_TYPE, HeaderValue::from_str(&content_type)?);
+                res.headers_mut()
+                    .insert(CONTENT_RANGE, content_range.parse()?);
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, format!("{}", part_size).parse()?);
+                if head_only {
+                    return Ok(());
+                }
+                *res.body_mut() =
+                    Body::wrap_stream(range_stream(file, range.start, part_size, key).await?);
             }
-            if let Some(key) = key {
-                let encrypted_file = EncryptedStreamer::new(file, key);
-                *res.body_mut() = Body::wrap_stream(encrypted_file.into_stream());
-            } else {
-                let reader = Streamer::new(file, BUF_SIZE);
-                *res.body_mut() = Body::wrap_stream(reader.into_stream());
+            Some(ranges) => {
+                // Several ranges are sent as the parts of a multipart/byteranges body
+                let boundary = Uuid::new_v4().simple().to_string();
+                let part_headers: Vec<String> = ranges
+                    .iter()
+                    .map(|range| {
+                        format!(
+                            "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+                            boundary, content_type, range.start, range.end, decrypted_size
+                        )
+                    })
+                    .collect();
+                let closing = format!("\r\n--{}--\r\n", boundary);
+                let length = ranges
+                    .iter()
+                    .zip(&part_headers)
+                    .map(|(range, part_header)| {
+                        range.end - range.start + 1 + part_header.len() as u64
+                    })
+                    .sum::<u64>()
+                    + closing.len() as u64;
+                *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+                res.headers_mut().insert(
+                    CONTENT_TYPE,
+                    format!("multipart/byteranges; boundary={}", boundary).parse()?,
+                );
+                res.headers_mut()
+                    .insert(CONTENT_LENGTH, format!("{}", length).parse()?);
+                if head_only {
+                    return Ok(());
+                }
+                let mut parts = vec![];
+                for (range, part_header) in ranges.iter().zip(part_headers) {
+                    parts.push(
+                        futures::stream::once(async { Ok(part_header.into_bytes()) }).boxed(),
+                    );
+                    parts.push(
+                        range_stream(
+                            storage.open(path).await?,
+                            range.start,
+                            range.end - range.start + 1,
+                            key,
+                        )
+                        .await?,
+                    );
+                }
+                parts.push(futures::stream::once(async { Ok(closing.into_bytes()) }).boxed());
+                *res.body_mut() = Body::wrap_stream(futures::stream::iter(parts).flatten());
             }
         }
         Ok(())
@@ -525,14 +1406,12 @@ impl WebdavServer {
 
     async fn handle_propfind_dir(
         &self,
+        ctx: &DavContext<'_>,
         path: &Path,
         headers: &HeaderMap<HeaderValue>,
         res: &mut Response,
-        directory: &str,
-        allow_symlinks: bool,
-        key: Option<[u8; 32]>,
     ) -> BoxResult<()> {
-        let base_path = Path::new(directory);
+        let base_path = Path::new(&ctx.dav.directory);
         let depth: u32 = match headers.get("depth") {
             Some(v) => match v.to_str().ok().and_then(|v| v.parse().ok()) {
                 Some(v) => v,
@@ -543,16 +1422,14 @@ impl WebdavServer {
             },
             None => 1,
         };
+        let meta = ctx.storage.metadata(path).await?;
         let mut paths = vec![self
-            .to_pathitem(path, base_path, directory, allow_symlinks, &key)
+            .to_pathitem(ctx, path, &meta, base_path)
             .await?
             .unwrap()];
         info!("Paths : {:?}", paths);
         if depth != 0 {
-            match self
-                .list_dir(path, base_path, directory, allow_symlinks, &key)
-                .await
-            {
+            match self.list_dir(ctx, path, base_path).await {
                 Ok(child) => paths.extend(child),
                 Err(_) => {
                     status_forbid(res);
@@ -560,9 +1437,17 @@ impl WebdavServer {
                 }
             }
         }
+        // Report the quota on the requested collection
+        let quota_props = match &ctx.dav.quota {
+            Some(quota) => {
+                quota.dav_props(&quota::current_usage(base_path, ctx.dav.key.is_some()).await?)
+            }
+            None => String::new(),
+        };
         let output = paths
             .iter()
-            .map(|v| v.to_dav_xml("/"))
+            .enumerate()
+            .map(|(i, v)| v.to_dav_xml("/", if i == 0 { &quota_props } else { "" }))
             .fold(String::new(), |mut acc, v| {
                 acc.push_str(&v);
                 acc
@@ -573,27 +1458,28 @@ impl WebdavServer {
 
     async fn handle_propfind_file(
         &self,
+        ctx: &DavContext<'_>,
         path: &Path,
         res: &mut Response,
-        directory: &str,
-        allow_symlinks: bool,
-        key: Option<[u8; 32]>,
     ) -> BoxResult<()> {
-        let base_path = Path::new(directory);
+        let base_path = Path::new(&ctx.dav.directory);
         let self_uri_prefix = "/";
-        if let Some(pathitem) = self
-            .to_pathitem(path, base_path, directory, allow_symlinks, &key)
-            .await?
-        {
-            res_multistatus(res, &pathitem.to_dav_xml(self_uri_prefix));
+        let meta = ctx.storage.metadata(path).await?;
+        if let Some(pathitem) = self.to_pathitem(ctx, path, &meta, base_path).await? {
+            res_multistatus(res, &pathitem.to_dav_xml(self_uri_prefix, ""));
         } else {
             status_not_found(res);
         }
         Ok(())
     }
 
-    async fn handle_mkcol(&self, path: &Path, res: &mut Response) -> BoxResult<()> {
-        match fs::create_dir(path).await {
+    async fn handle_mkcol(
+        &self,
+        storage: &dyn Storage,
+        path: &Path,
+        res: &mut Response,
+    ) -> BoxResult<()> {
+        match storage.create_dir(path).await {
             Ok(_) => {
                 *res.status_mut() = StatusCode::CREATED;
                 Ok(())
@@ -655,17 +1541,22 @@ impl WebdavServer {
         path.ok().map(|v| v.starts_with(dir)).unwrap_or_default()
     }
 
-    fn extract_dest(&self, headers: &HeaderMap<HeaderValue>, dav_path: &str) -> Option<PathBuf> {
+    fn extract_dest(&self, headers: &HeaderMap<HeaderValue>, dav: &Dav) -> Option<PathBuf> {
         let dest = headers.get("Destination")?.to_str().ok()?;
         let uri: Uri = dest.parse().ok()?;
-        self.extract_path(uri.path(), dav_path)
+        self.extract_path(uri.path(), dav)
     }
 
-    fn extract_path(&self, wanted_path: &str, dav_path: &str) -> Option<PathBuf> {
+    /// File system path of an url path, its names being encrypted on the davs encrypting them
+    fn extract_path(&self, wanted_path: &str, dav: &Dav) -> Option<PathBuf> {
         if wanted_path.contains("..") {
             return None;
         }
         let decoded_path = decode_uri(&wanted_path[1..])?;
+        // The internal state of the dav is only reachable through its dedicated apis
+        if is_internal(Path::new(decoded_path.as_ref())) {
+            return None;
+        }
         let slashes_switched = if cfg!(windows) {
             decoded_path.replace('/', "\\")
         } else {
@@ -675,8 +1566,11 @@ impl WebdavServer {
             Some(path) => path,
             None => return None,
         };
-        let self_path = Path::new(dav_path);
-        Some(self_path.join(&stripped_path))
+        let self_path = Path::new(&dav.directory);
+        match NameCipher::new(dav) {
+            Some(names) => Some(self_path.join(names.encrypt_path(stripped_path).ok()?)),
+            None => Some(self_path.join(&stripped_path)),
+        }
     }
 
     fn strip_path_prefix<'a, P: AsRef<Path>>(&self, path: &'a P) -> Option<&'a Path> {
@@ -686,24 +1580,23 @@ impl WebdavServer {
 
     async fn list_dir(
         &self,
-        entry_path: &Path,
+        ctx: &DavContext<'_>,
+        dir_path: &Path,
         base_path: &Path,
-        directory: &str,
-        allow_symlinks: bool,
-        key: &Option<[u8; 32]>,
     ) -> BoxResult<Vec<PathItem>> {
         let mut paths: Vec<PathItem> = vec![];
-        let mut rd = fs::read_dir(entry_path).await?;
-        while let Ok(Some(entry)) = rd.next_entry().await {
-            let entry_path = entry.path();
+        for entry in ctx.storage.read_dir(dir_path).await? {
+            let entry_path = dir_path.join(&entry.name);
+            let permission = if entry.metadata.is_dir {
+                ctx.acl.dir_permission_fs(&entry_path, base_path)
+            } else {
+                ctx.acl.permission_fs(&entry_path, base_path)
+            };
+            if is_internal(Path::new(&entry.name)) || permission.is_none() {
+                continue;
+            }
             if let Ok(Some(item)) = self
-                .to_pathitem(
-                    entry_path.as_path(),
-                    base_path,
-                    directory,
-                    allow_symlinks,
-                    &key,
-                )
+                .to_pathitem(ctx, entry_path.as_path(), &entry.metadata, base_path)
                 .await
             {
                 paths.push(item);
@@ -714,40 +1607,44 @@ impl WebdavServer {
 
     async fn to_pathitem<P: AsRef<Path>>(
         &self,
+        ctx: &DavContext<'_>,
         path: P,
+        meta: &StorageMetadata,
         base_path: P,
-        directory: &str,
-        allow_symlinks: bool,
-        key: &Option<[u8; 32]>,
     ) -> BoxResult<Option<PathItem>> {
+        let (storage, directory, names) = (ctx.storage, ctx.dav.directory.as_str(), ctx.names);
         let path = path.as_ref();
         let rel_path = path.strip_prefix(&base_path).unwrap();
-        let (meta, meta2) = tokio::join!(fs::metadata(&path), fs::symlink_metadata(&path));
-        let (meta, meta2) = (meta?, meta2?);
-        let is_symlink = meta2.is_symlink();
-        if !allow_symlinks
+        // The entries whose names cannot be decrypted are not part of the dav
+        let rel_path = match names {
+            Some(names) => match names.decrypt_path(rel_path) {
+                Ok(rel_path) => rel_path,
+                Err(_) => return Ok(None),
+            },
+            None => rel_path.to_path_buf(),
+        };
+        let is_symlink = meta.is_symlink;
+        if !ctx.dav.allow_symlinks
             && is_symlink
             && !self.is_root_contained(path, Path::new(directory)).await
         {
             return Ok(None);
         }
-        let is_dir = meta.is_dir();
+        let is_dir = meta.is_dir;
         let path_type = match (is_symlink, is_dir) {
             (true, true) => PathType::SymlinkDir,
             (false, true) => PathType::Dir,
             (true, false) => PathType::SymlinkFile,
             (false, false) => PathType::File,
         };
-        let mtime = to_timestamp(&meta.modified()?);
+        let mtime = to_timestamp(&meta.modified.unwrap_or(SystemTime::UNIX_EPOCH));
         let size = match path_type {
             PathType::Dir | PathType::SymlinkDir => None,
-            PathType::File | PathType::SymlinkFile => Some(if let Some(_key) = key {
-                decrypted_size(meta.len().try_into()?).try_into()?
-            } else {
-                meta.len()
-            }),
+            PathType::File | PathType::SymlinkFile => {
+                Some(content_size(storage, path, meta, &ctx.dav.key).await?)
+            }
         };
-        let name = normalize_path(rel_path);
+        let name = normalize_path(&rel_path);
         Ok(Some(PathItem {
             path_type,
             name,
@@ -758,12 +1655,14 @@ impl WebdavServer {
 
     async fn handle_copymove(
         &self,
+        ctx: &DavContext<'_>,
         path: &Path,
         req: Request,
         method: Method,
         res: &mut Response,
-        dav_path: &str,
     ) -> BoxResult<()> {
+        let (storage, dav, acl) = (ctx.storage, ctx.dav, ctx.acl);
+        let dav_path = dav.directory.as_str();
         // get and check headers.
         let overwrite = req.headers().typed_get::<Overwrite>().map_or(true, |o| o.0);
         let depth = match req.headers().typed_get::<Depth>() {
@@ -776,7 +1675,7 @@ impl WebdavServer {
         };
 
         // decode and validate destination.
-        let dest = match self.extract_dest(req.headers(), dav_path) {
+        let dest = match self.extract_dest(req.headers(), dav) {
             Some(dest) => dest,
             None => {
                 *res.status_mut() = StatusCode::FORBIDDEN;
@@ -784,29 +1683,22 @@ impl WebdavServer {
             }
         };
 
-        // for the destination, check if it's a symlink. If we are going
-        // to remove it first, we want to remove the link, not what it points to.
-        let (dest_is_file, dmeta) = match fs::symlink_metadata(&dest).await {
-            Ok(meta) => {
-                let mut is_file = false;
-                if meta.is_symlink() {
-                    if let Ok(m) = fs::metadata(&dest).await {
-                        is_file = m.is_file();
-                    }
-                }
-                if meta.is_file() {
-                    is_file = true;
-                }
-                (is_file, Ok(meta))
-            }
-            Err(e) => (false, Err(e)),
-        };
+        // for the destination, a link is seen as what it points to, but it is the link that is removed if needed.
+        let dmeta = storage.metadata(&dest).await;
+        let dest_is_file = dmeta.as_ref().map_or(false, |meta| !meta.is_dir);
 
         // check if overwrite is "F"
         let exists = dmeta.is_ok();
 
         // Fail if collection parent does not exist
-        if dest.parent().is_none() || !dest.parent().unwrap().exists() {
+        let parent_exists = match dest.parent() {
+            Some(parent) => storage
+                .metadata(parent)
+                .await
+                .map_or(false, |meta| meta.is_dir),
+            None => false,
+        };
+        if !parent_exists {
             *res.status_mut() = StatusCode::CONFLICT;
             return Ok(());
         }
@@ -816,15 +1708,55 @@ impl WebdavServer {
             return Ok(());
         }
 
+        // replacing an existing destination needs the right to delete it
+        let wanted = if exists {
+            Permission::Delete
+        } else {
+            Permission::Write
+        };
+        if !acl.allows_fs(&dest, Path::new(dav_path), wanted) {
+            status_forbid(res);
+            return Ok(());
+        }
+
+        // a copy must fit in the quota, without accounting for what it may replace
+        let mut reservation = None;
+        if let (Some(quota), "COPY") = (&dav.quota, method.as_str()) {
+            let encrypted = dav.key.is_some();
+            let copied = quota::usage(path, encrypted).await?;
+            reservation = quota::reserve(
+                Path::new(dav_path),
+                encrypted,
+                quota,
+                Some(copied.bytes),
+                copied.files,
+            )
+            .await?;
+            if reservation.is_none() {
+                status_insufficient_storage(res);
+                return Ok(());
+            }
+        }
+
         // check if source == dest
         if path == dest {
             *res.status_mut() = StatusCode::FORBIDDEN;
             return Ok(());
         }
 
-        // see if we need to delete the destination first.
-        if path.is_dir() && overwrite && exists && depth != Depth::Zero && !dest_is_file {
-            if fs::remove_dir_all(&dest).await.is_err() {
+        let source_is_dir = storage.metadata(path).await?.is_dir;
+
+        // see if we need to delete the destination first, keeping it in its history or in the trash if there are some.
+        let versions = dav
+            .versioning
+            .as_ref()
+            .map(|policy| VersionStore::new(dav, policy));
+        if let (true, Some(versions)) = (exists && dest_is_file, &versions) {
+            versions.put(&dest).await?;
+        } else if exists && dav.trash.is_some() {
+            TrashBin::new(dav).put(&dest).await?;
+        } else if source_is_dir && overwrite && exists && depth != Depth::Zero && !dest_is_file {
+            if storage.remove(&dest).await.is_err() {
                 *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
                 return Ok(());
             }
@@ -832,15 +1764,26 @@ impl WebdavServer {
 
         // COPY or MOVE.
         if method.as_str() == "COPY" {
-            self.do_copy(&path, &dest, &dest, !dest_is_file, depth)
+            // the destination names the copy itself, being a directory only if the source is one
+            self.do_copy(ctx, path, &dest, &dest, source_is_dir, depth)
                 .await?;
+            if let Some(reservation) = reservation {
+                reservation.commit();
+            }
+            // What the copy replaced is no longer counted
+            if exists && dav.quota.is_some() {
+                quota::invalidate(Path::new(dav_path));
+            }
             if overwrite && exists {
                 *res.status_mut() = StatusCode::NO_CONTENT;
             } else {
                 *res.status_mut() = StatusCode::CREATED;
             }
         } else {
-            fs::rename(path, &dest).await?;
+            storage.rename(path, &dest).await?;
+            if let (false, Some(versions)) = (source_is_dir, &versions) {
+                versions.rename(path, &dest).await?;
+            }
             *res.status_mut() = StatusCode::CREATED;
         }
         Ok(())
@@ -848,6 +1791,7 @@ impl WebdavServer {
 
     fn do_copy<'a>(
         &'a self,
+        ctx: &'a DavContext<'a>,
         source: &'a Path,
         topdest: &'a Path,
         dest: &'a Path,
@@ -855,6 +1799,9 @@ impl WebdavServer {
         depth: Depth,
     ) -> BoxFuture<'a, Result<(), std::io::Error>> {
         async move {
+            let storage = ctx.storage;
+            // the copies of the files are links to the same content in the davs deduplicating their files
+            let link = ContentStore::new(ctx.dav).is_some();
             // when doing "COPY /a/b /a/b/c make sure we don't recursively
             // copy /a/b/c/ into /a/b/c.
             if source == topdest {
@@ -862,24 +1809,24 @@ impl WebdavServer {
             }
 
             // source must exist.
-            let meta = match fs::metadata(source).await {
+            let meta = match storage.metadata(source).await {
                 Err(e) => return Err(e),
                 Ok(m) => m,
             };
 
             // create dest if directory
             if dest_is_dir {
-                fs::create_dir(dest).await.ok();
+                storage.create_dir(dest).await.ok();
             }
 
             // if it's a file we can overwrite it.
-            if meta.is_file() {
+            if !meta.is_dir {
                 if dest_is_dir {
                     let destfile = dest.join(source.file_name().ok_or(Error::new(
                         io::ErrorKind::Other,
                         "could not extract file name",
                     ))?);
-                    return match fs::copy(source, destfile).await {
+                    return match copy_file(storage, source, &destfile, link).await {
                         Ok(_) => Ok(()),
                         Err(e) => {
                             debug!("do_copy: fs::copy error: {:?}", e);
@@ -887,7 +1834,7 @@ impl WebdavServer {
                         }
                     };
                 } else {
-                    return match fs::copy(source, dest).await {
+                    return match copy_file(storage, source, dest, link).await {
                         Ok(_) => Ok(()),
                         Err(e) => {
                             debug!("do_copy: fs::copy error: {:?}", e);
@@ -902,28 +1849,32 @@ impl WebdavServer {
                 return Ok(());
             }
 
-            let mut entries = match fs::read_dir(source).await {
+            let entries = match storage.read_dir(source).await {
                 Ok(entries) => entries,
                 Err(e) => {
-                    debug!("do_copy: fs::read_dir error: {:?}", e);
+                    debug!("do_copy: read_dir error: {:?}", e);
                     return Err(e);
                 }
             };
 
             let mut retval = Ok(());
-            while let Some(dirent) = entries.next_entry().await? {
-                // NOTE: dirent.metadata() behaves like symlink_metadata()
-                let meta = match dirent.metadata().await {
-                    Ok(meta) => meta,
-                    Err(e) => return Err(e),
-                };
-                let name = dirent.file_name();
+            for dirent in entries {
+                let meta = dirent.metadata;
+                let name = dirent.name;
                 let nsrc = source.clone().join(&name);
                 let ndest = dest.clone().join(&name);
 
+                // do not leak the entries that the user cannot read
+                if !ctx
+                    .acl
+                    .allows_fs(&nsrc, Path::new(&ctx.dav.directory), Permission::Read)
+                {
+                    continue;
+                }
+
                 // recurse
                 if let Err(e) = self
-                    .do_copy(&nsrc, topdest, &ndest, meta.is_dir(), depth)
+                    .do_copy(ctx, &nsrc, topdest, &ndest, meta.is_dir, depth)
                     .await
                 {
                     retval = Err(e);
@@ -960,7 +1911,7 @@ impl PathItem {
         self.path_type == PathType::Dir || self.path_type == PathType::SymlinkDir
     }
 
-    pub fn to_dav_xml(&self, prefix: &str) -> String {
+    pub fn to_dav_xml(&self, prefix: &str, extra_props: &str) -> String {
         let mtime = Utc.timestamp_millis(self.mtime as i64).to_rfc2822();
         let mut href = encode_uri(&format!("{}{}", prefix, &self.name));
         if self.is_dir() && !href.ends_with('/') {
@@ -976,11 +1927,11 @@ impl PathItem {
 <D:displayname>{}</D:displayname>
 <D:getlastmodified>{}</D:getlastmodified>
 <D:resourcetype><D:collection/></D:resourcetype>
-</D:prop>
+{}</D:prop>
 <D:status>HTTP/1.1 200 OK</D:status>
 </D:propstat>
 </D:response>"#,
-                href, displayname, mtime
+                href, displayname, mtime, extra_props
             ),
             PathType::File | PathType::SymlinkFile => format!(
                 r#"<D:response>
@@ -1033,6 +1984,25 @@ fn normalize_path<P: AsRef<Path>>(path: P) -> String {
     }
 }
 
+/// Copy a file as a new file, or with a content store as another link to the same content
+async fn copy_file(
+    storage: &dyn Storage,
+    source: &Path,
+    dest: &Path,
+    link: bool,
+) -> io::Result<()> {
+    if !link {
+        return storage.copy(source, dest).await;
+    }
+    // The destination is removed first, not to write through the contents it may share with the store
+    match fs::remove_file(dest).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+        Err(e) => return Err(e),
+    }
+    fs::hard_link(source, dest).await
+}
+
 async fn ensure_path_parent(path: &Path) -> BoxResult<()> {
     if let Some(parent) = path.parent() {
         if fs::symlink_metadata(parent).await.is_err() {
@@ -1060,7 +2030,10 @@ fn res_multistatus(res: &mut Response, content: &str) {
 async fn zip_dir<W: AsyncWrite + Unpin>(
     writer: &mut W,
     dir: &Path,
+    directory: &Path,
+    acl: &Acl,
     key: Option<[u8; 32]>,
+    names: &Option<NameCipher>,
 ) -> BoxResult<()> {
     let mut writer = ZipFileWriter::new(writer);
     let mut walkdir = WalkDir::new(dir);
@@ -1071,10 +2044,21 @@ async fn zip_dir<W: AsyncWrite + Unpin>(
                 Ok(meta) => meta,
                 Err(_) => continue,
             };
-            if !meta.is_file() {
+            if !meta.is_file()
+                || is_internal(&entry_path)
+                || !acl.allows_fs(&entry_path, directory, Permission::Read)
+            {
                 continue;
             }
-            let filename = match entry_path.strip_prefix(dir).ok().and_then(|v| v.to_str()) {
+            let rel_path = match (entry_path.strip_prefix(dir), names) {
+                (Ok(rel_path), Some(names)) => match names.decrypt_path(rel_path) {
+                    Ok(rel_path) => rel_path,
+                    Err(_) => continue,
+                },
+                (Ok(rel_path), None) => rel_path.to_path_buf(),
+                (Err(_), _) => continue,
+            };
+            let filename = match rel_path.to_str() {
                 Some(v) => v,
                 None => continue,
             };
@@ -1096,10 +2080,10 @@ async fn zip_dir<W: AsyncWrite + Unpin>(
     Ok(())
 }
 
-fn extract_cache_headers(meta: &Metadata) -> Option<(ETag, LastModified)> {
-    let mtime = meta.modified().ok()?;
+fn extract_cache_headers(meta: &StorageMetadata) -> Option<(ETag, LastModified)> {
+    let mtime = meta.modified?;
     let timestamp = to_timestamp(&mtime);
-    let size = meta.len();
+    let size = meta.len;
     let etag = format!(r#""{}-{}""#, timestamp, size)
         .parse::<ETag>()
         .unwrap();
@@ -1110,33 +2094,142 @@ fn extract_cache_headers(meta: &Metadata) -> Option<(ETag, LastModified)> {
 #[derive(Debug)]
 struct RangeValue {
     start: u64,
-    end: Option<u64>,
+    end: u64,
 }
 
-fn parse_range(headers: &HeaderMap<HeaderValue>) -> Option<RangeValue> {
+/// Ranges of a Range header, with their inclusive bounds worked out for a file of the given size.
+/// Gives None if the header is missing or invalid, so that the whole file is sent, and an empty list if no range can be satisfied.
+fn parse_range(headers: &HeaderMap<HeaderValue>, size: u64) -> Option<Vec<RangeValue>> {
     let range_hdr = headers.get(RANGE)?;
     let hdr = range_hdr.to_str().ok()?;
-    let mut sp = hdr.splitn(2, '=');
-    let units = sp.next().unwrap();
-    if units == "bytes" {
-        let range = sp.next()?;
-        let mut sp_range = range.splitn(2, '-');
-        let start: u64 = sp_range.next().unwrap().parse().ok()?;
-        let end: Option<u64> = if let Some(end) = sp_range.next() {
-            if end.is_empty() {
-                None
-            } else {
-                Some(end.parse().ok()?)
+    let specs = hdr.trim().strip_prefix("bytes=")?;
+    let mut ranges = vec![];
+    for spec in specs.split(',') {
+        let range = match spec.trim().split_once('-')? {
+            // Suffix range : the last bytes of the file
+            ("", suffix) => {
+                let suffix: u64 = suffix.parse().ok()?;
+                if suffix == 0 || size == 0 {
+                    continue;
+                }
+                RangeValue {
+                    start: size.saturating_sub(suffix),
+                    end: size - 1,
+                }
+            }
+            (start, end) => {
+                let start: u64 = start.parse().ok()?;
+                let end = if end.is_empty() {
+                    u64::MAX
+                } else {
+                    end.parse().ok()?
+                };
+                if end < start {
+                    return None;
+                }
+                if start >= size {
+                    continue;
+                }
+                RangeValue {
+                    start,
+                    end: end.min(size - 1),
+                }
             }
-        } else {
-            None
         };
-        Some(RangeValue { start, end })
+        ranges.push(range);
+    }
+    // Requests for a lot of ranges are served the whole file, not to be used to amplify the load
+    if ranges.len() > MAX_RANGES {
+        return None;
+    }
+    Some(ranges)
+}
+
+/// Stream a part of a file, decrypted on encrypted davs
+async fn range_stream(
+    mut file: Box<dyn StorageFile>,
+    start: u64,
+    length: u64,
+    key: Option<[u8; 32]>,
+) -> io::Result<BoxStream<'static, Result<Vec<u8>, Error>>> {
+    if let Some(key) = key {
+        let encrypted_file = EncryptedStreamer::new(file, key);
+        Ok(encrypted_file.into_stream_sized(start, length).boxed())
     } else {
-        None
+        file.seek(std::io::SeekFrom::Start(start)).await?;
+        let reader = Streamer::new(file, BUF_SIZE);
+        Ok(reader.into_stream_sized(length).boxed())
+    }
+}
+
+/// Offset at which a partial update starts, from the X-Update-Range header (bytes=start-end, bytes=start-, bytes=-length
+/// counted from the end of the file, or append) or from the Content-Range header
+fn parse_update_start(headers: &HeaderMap<HeaderValue>, size: u64, length: u64) -> Option<u64> {
+    let (first, last) = match headers.get("X-Update-Range") {
+        Some(range) => {
+            let range = range.to_str().ok()?;
+            if range == "append" {
+                return Some(size);
+            }
+            let (first, last) = range.strip_prefix("bytes=")?.split_once('-')?;
+            match (first, last) {
+                ("", last) => return size.checked_sub(last.parse().ok()?),
+                (first, "") => return first.parse().ok(),
+                (first, last) => (first.parse().ok()?, last.parse().ok()?),
+            }
+        }
+        None => headers.typed_get::<ContentRange>()?.bytes_range()?,
+    };
+    // The range must match the body
+    (last.checked_sub(first)? + 1 == length).then_some(first)
+}
+
+/// Reader over a part of a file, decrypted on encrypted davs
+async fn read_range(
+    storage: &dyn Storage,
+    path: &Path,
+    start: u64,
+    length: u64,
+    key: Option<[u8; 32]>,
+) -> io::Result<Box<dyn AsyncRead + Unpin + Send>> {
+    if length == 0 {
+        return Ok(Box::new(io::empty()));
+    }
+    let mut file = storage.open(path).await?;
+    match key {
+        Some(key) => {
+            let stream = EncryptedStreamer::new(file, key)
+                .into_stream_sized(start, length)
+                .map_ok(std::io::Cursor::new);
+            Ok(Box::new(StreamReader::new(stream)))
+        }
+        None => {
+            file.seek(std::io::SeekFrom::Start(start)).await?;
+            Ok(Box::new(file.take(length)))
+        }
     }
 }
 
+/// Whether a local file has no other link, from the content store or another file
+async fn is_unlinked(path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    Ok(fs::metadata(path).await?.nlink() == 1)
+}
+
+/// Answer a write that failed for lack of space as insufficient storage, any other failure being an error
+fn write_failed(e: io::Error, res: &mut Response) -> BoxResult<bool> {
+    if e.kind() == io::ErrorKind::StorageFull {
+        status_insufficient_storage(res);
+        return Ok(false);
+    }
+    Err(e.into())
+}
+
+fn status_bad_request(res: &mut Response) {
+    *res.status_mut() = StatusCode::BAD_REQUEST;
+    *res.body_mut() = Body::from("Bad Request");
+}
+
 fn status_forbid(res: &mut Response) {
     *res.status_mut() = StatusCode::FORBIDDEN;
     *res.body_mut() = Body::from("Forbidden");
@@ -1147,6 +2240,11 @@ fn status_not_found(res: &mut Response) {
     *res.body_mut() = Body::from("Not Found");
 }
 
+fn status_insufficient_storage(res: &mut Response) {
+    *res.status_mut() = StatusCode::INSUFFICIENT_STORAGE;
+    *res.body_mut() = Body::from("Insufficient Storage");
+}
+
 fn status_no_content(res: &mut Response) {
     *res.status_mut() = StatusCode::NO_CONTENT;
 }
@@ -1156,6 +2254,80 @@ fn status_method_not_allowed(res: &mut Response) {
     *res.body_mut() = Body::from("Method not allowed");
 }
 
+/// Get a value of a tus Upload-Metadata header, made of comma separated keys and base64 encoded values
+fn upload_metadata(header: &str, wanted: &str) -> Option<String> {
+    header.split(',').find_map(|pair| {
+        let (key, value) = pair.trim().split_once(' ')?;
+        if key != wanted {
+            return None;
+        }
+        String::from_utf8(Base64::decode_vec(value.trim()).ok()?).ok()
+    })
+}
+
+/// Size of the content of a file, read from its header on encrypted davs
+async fn content_size(
+    storage: &dyn Storage,
+    path: &Path,
+    meta: &StorageMetadata,
+    key: &Option<[u8; 32]>,
+) -> io::Result<u64> {
+    if key.is_none() || meta.len == 0 {
+        return Ok(meta.len);
+    }
+    let header = storage.read_start(path, HEADER_SIZE).await?;
+    decrypted_size(&mut header.as_slice(), meta.len).await
+}
+
+/// Path of the file receiving an upload, hidden in the directory of the destination
+fn partial_path(path: &Path) -> BoxResult<PathBuf> {
+    let file_name = get_file_name(path)?;
+    Ok(path.with_file_name(format!(
+        ".{}.{}.{}",
+        file_name,
+        Uuid::new_v4().simple(),
+        PARTIAL_EXTENSION
+    )))
+}
+
+/// Check the If-Match and If-None-Match headers of a request against the current content of a file
+fn preconditions_pass(headers: &HeaderMap<HeaderValue>, meta: Option<&StorageMetadata>) -> bool {
+    let etag = meta.and_then(extract_cache_headers).map(|(etag, _)| etag);
+    if let Some(if_match) = headers.typed_get::<IfMatch>() {
+        if !etag
+            .as_ref()
+            .map_or(false, |etag| if_match.precondition_passes(etag))
+        {
+            return false;
+        }
+    }
+    if let (Some(if_none_match), Some(etag)) = (headers.typed_get::<IfNoneMatch>(), &etag) {
+        if !if_none_match.precondition_passes(etag) {
+            return false;
+        }
+    }
+    true
+}
+
+/// Keep the search index of a dav in sync with changed paths, the failures only costing stale results
+async fn reindex(dav: &Dav, paths: &[PathBuf]) {
+    if let Err(e) = search::refresh(dav, paths).await {
+        error!(
+            "Failed to update the search index of {}, {}",
+            dav.directory, e
+        );
+    }
+}
+
+/// Name of a file as the users know it, None if it cannot be decrypted
+fn plain_name(path: &Path, names: &Option<NameCipher>) -> Option<String> {
+    let file_name = path.file_name()?.to_str()?;
+    match names {
+        Some(names) => names.decrypt(file_name).ok(),
+        None => Some(file_name.to_owned()),
+    }
+}
+
 fn get_file_name(path: &Path) -> BoxResult<&str> {
     path.file_name()
         .and_then(|v| v.to_str())
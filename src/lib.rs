@@ -1,8 +1,11 @@
 pub mod apps;
+pub mod cli;
 pub mod configuration;
 pub mod davs;
 pub mod logger;
 pub mod mocks;
+pub mod redirects;
 pub mod server;
+pub mod streams;
 pub mod users;
 pub mod utils;
@@ -7,6 +7,7 @@ use rustls_acme::caches::DirCache;
 use rustls_acme::AcmeConfig;
 use std::net::SocketAddr;
 use std::pin::Pin;
+use std::sync::Arc;
 use tokio::signal;
 use tokio::sync::broadcast;
 use tokio_stream::StreamExt;
@@ -16,6 +17,7 @@ use tower::MakeService;
 use vestibule::logger;
 use vestibule::mocks::mock_proxied_server;
 use vestibule::server::Server;
+use vestibule::streams;
 
 pub const CONFIG_FILE: &'static str = "vestibule.yaml";
 
@@ -24,6 +26,13 @@ extern crate log;
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // Offline commands on the dav files, instead of the server
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if !args.is_empty() {
+        let succeeded = vestibule::cli::run(CONFIG_FILE, &args).await?;
+        std::process::exit(if succeeded { 0 } else { 1 });
+    }
+
     logger::init()?;
     info!("Starting server...");
 
@@ -52,20 +61,8 @@ async fn main() -> Result<()> {
                 break;
             };
             let config = vestibule::configuration::load_config(CONFIG_FILE).await?;
-            let mut domains: Vec<String> = config
-                .0
-                .apps
-                .iter()
-                .map(|app| format!("{}.{}", app.host.to_owned(), config.0.hostname))
-                .chain(
-                    config
-                        .0
-                        .davs
-                        .iter()
-                        .map(|dav| format!("{}.{}", dav.host.to_owned(), config.0.hostname)),
-                )
-                .collect();
-            domains.insert(0, config.0.hostname);
+            let domains = config.0.domains();
+            let sni_routes = Arc::new(streams::sni_routes(&config.0));
             let mut state = AcmeConfig::new(domains)
                 .contact_push(format!("mailto:{}", config.0.letsencrypt_email))
                 .cache(DirCache::new("./letsencrypt_cache"))
@@ -101,11 +98,23 @@ async fn main() -> Result<()> {
                     .unwrap()
                     .unwrap();
                 let acceptor = acceptor.clone();
+                let sni_routes = sni_routes.clone();
 
                 let app = app.make_service(&stream).await.unwrap();
                 let mut rx = tx.subscribe();
 
                 tokio::spawn(async move {
+                    let mut stream = stream;
+                    // TLS passthrough : hand the raw connection to the target if the server name is a stream's
+                    if !sni_routes.is_empty() {
+                        if let Some(forward_to) = streams::peek_sni(&mut stream)
+                            .await
+                            .and_then(|sni| sni_routes.get(&sni).cloned())
+                        {
+                            streams::forward_tcp(stream.into_inner(), forward_to).await;
+                            return;
+                        }
+                    }
                     let tls = acceptor.accept(stream.compat()).await.unwrap().compat();
                     match tls.get_ref().get_ref().1.get_alpn_protocol() {
                         Some(_acme_tls_alpn_name) => {
@@ -1,6 +1,20 @@
-use axum::{routing::get, Router};
+use axum::{
+    routing::{any, get},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use headers::{ContentLength, HeaderMapExt, LastModified};
+use hyper::{
+    header::{AUTHORIZATION, RANGE},
+    Body, Method, Request, Response, StatusCode,
+};
+use percent_encoding::percent_decode_str;
+use xml::escape::escape_str_pcdata;
 
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::net::TcpListener;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 
 pub async fn mock_proxied_server(listener: TcpListener) {
     let port = listener.local_addr().unwrap().port();
@@ -13,3 +27,181 @@ pub async fn mock_proxied_server(listener: TcpListener) {
         .await
         .unwrap();
 }
+
+type Objects = Mutex<BTreeMap<String, (Vec<u8>, SystemTime)>>;
+
+/// Stand-in for an S3 compatible object store, keeping the objects in memory whatever the bucket.
+/// Requests must be signed, but the signatures are not checked.
+pub async fn mock_s3_server(listener: TcpListener) {
+    let objects: Arc<Objects> = Arc::new(Mutex::new(BTreeMap::new()));
+    let app = Router::new().fallback(any(move |req: Request<Body>| {
+        let objects = objects.clone();
+        async move { s3_response(&objects, req).await }
+    }));
+
+    axum::Server::from_tcp(listener)
+        .expect("failed to build mock server")
+        .serve(app.into_make_service())
+        .await
+        .unwrap();
+}
+
+async fn s3_response(objects: &Objects, req: Request<Body>) -> Response<Body> {
+    let signed = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .map_or(false, |v| v.starts_with("AWS4-HMAC-SHA256 Credential="));
+    if !signed {
+        return status(StatusCode::FORBIDDEN);
+    }
+    // Requests are path style : /bucket/key
+    let key = object_key(req.uri().path());
+    let query: HashMap<String, String> = req
+        .uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(name, value)| (decode(name), decode(value)))
+        .collect();
+
+    match *req.method() {
+        Method::GET if query.contains_key("list-type") => {
+            let prefix = query.get("prefix").cloned().unwrap_or_default();
+            let delimited = query.contains_key("delimiter");
+            Response::new(Body::from(list_objects(
+                &objects.lock().unwrap(),
+                &prefix,
+                delimited,
+            )))
+        }
+        Method::GET => match objects.lock().unwrap().get(&key) {
+            Some((content, _)) => match req.headers().get(RANGE) {
+                Some(range) => ranged_response(content, range.to_str().unwrap_or_default()),
+                None => Response::new(Body::from(content.clone())),
+            },
+            None => status(StatusCode::NOT_FOUND),
+        },
+        Method::HEAD => match objects.lock().unwrap().get(&key) {
+            Some((content, modified)) => {
+                let mut res = Response::new(Body::empty());
+                res.headers_mut()
+                    .typed_insert(ContentLength(content.len() as u64));
+                res.headers_mut()
+                    .typed_insert(LastModified::from(*modified));
+                res
+            }
+            None => status(StatusCode::NOT_FOUND),
+        },
+        Method::PUT => {
+            let content = match req.headers().get("x-amz-copy-source") {
+                Some(source) => {
+                    let source = object_key(source.to_str().unwrap_or_default());
+                    match objects.lock().unwrap().get(&source) {
+                        Some((content, _)) => content.clone(),
+                        None => return status(StatusCode::NOT_FOUND),
+                    }
+                }
+                None => hyper::body::to_bytes(req.into_body())
+                    .await
+                    .unwrap()
+                    .to_vec(),
+            };
+            objects
+                .lock()
+                .unwrap()
+                .insert(key, (content, SystemTime::now()));
+            status(StatusCode::OK)
+        }
+        Method::DELETE => {
+            objects.lock().unwrap().remove(&key);
+            status(StatusCode::NO_CONTENT)
+        }
+        _ => status(StatusCode::METHOD_NOT_ALLOWED),
+    }
+}
+
+/// Part of an object, for a single range of the bytes=start- or bytes=start-end forms
+fn ranged_response(content: &[u8], range: &str) -> Response<Body> {
+    let bounds = range.strip_prefix("bytes=").and_then(|range| {
+        let (start, end) = range.split_once('-')?;
+        let start: usize = start.parse().ok()?;
+        let end = match end {
+            "" => content.len().checked_sub(1)?,
+            end => end
+                .parse::<usize>()
+                .ok()?
+                .min(content.len().checked_sub(1)?),
+        };
+        (start <= end).then_some((start, end))
+    });
+    match bounds {
+        Some((start, end)) => {
+            let mut res = Response::new(Body::from(content[start..=end].to_vec()));
+            *res.status_mut() = StatusCode::PARTIAL_CONTENT;
+            res
+        }
+        None => status(StatusCode::RANGE_NOT_SATISFIABLE),
+    }
+}
+
+/// Answer to a ListObjectsV2 request, on a single page
+fn list_objects(
+    objects: &BTreeMap<String, (Vec<u8>, SystemTime)>,
+    prefix: &str,
+    delimited: bool,
+) -> String {
+    let mut contents = String::new();
+    let mut prefixes = BTreeSet::new();
+    for (key, (content, modified)) in objects
+        .range(prefix.to_owned()..)
+        .take_while(|(key, _)| key.starts_with(prefix))
+    {
+        if let (true, Some(i)) = (delimited, key[prefix.len()..].find('/')) {
+            prefixes.insert(&key[..prefix.len() + i + 1]);
+            continue;
+        }
+        contents.push_str(&format!(
+            "<Contents><Key>{}</Key><LastModified>{}</LastModified><Size>{}</Size></Contents>",
+            escape_str_pcdata(key),
+            DateTime::<Utc>::from(*modified).to_rfc3339(),
+            content.len()
+        ));
+    }
+    let prefixes: String = prefixes
+        .iter()
+        .map(|prefix| {
+            format!(
+                "<CommonPrefixes><Prefix>{}</Prefix></CommonPrefixes>",
+                escape_str_pcdata(prefix)
+            )
+        })
+        .collect();
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<ListBucketResult xmlns="http://s3.amazonaws.com/doc/2006-03-01/"><Prefix>{}</Prefix><IsTruncated>false</IsTruncated>{}{}</ListBucketResult>"#,
+        escape_str_pcdata(prefix),
+        contents,
+        prefixes
+    )
+}
+
+fn object_key(path: &str) -> String {
+    let path = decode(path);
+    path.trim_start_matches('/')
+        .split_once('/')
+        .map_or("", |(_bucket, key)| key)
+        .to_owned()
+}
+
+fn decode(v: &str) -> String {
+    percent_decode_str(v).decode_utf8_lossy().into_owned()
+}
+
+fn status(status: StatusCode) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::empty())
+        .unwrap()
+}
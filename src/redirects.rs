@@ -0,0 +1,218 @@
+use axum::extract::Path;
+use axum::http::{Request, Response};
+use axum::response::IntoResponse;
+use axum::{Extension, Json};
+use headers::HeaderValue;
+use hyper::header::LOCATION;
+use hyper::Uri;
+use hyper::{Body, StatusCode};
+use log::error;
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::configuration::{Config, ConfigFile, HostType, MainSite};
+use crate::users::User;
+use crate::users::{check_authorization_or_login, Admin};
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Redirect {
+    pub id: usize,
+    pub name: String,
+    pub icon: String,
+    pub color: String,
+    pub host: String,
+    pub target: String,
+    #[serde(default)]
+    pub permanent: bool,
+    #[serde(default)]
+    pub preserve_path: bool,
+    #[serde(default)]
+    pub preserve_query: bool,
+    pub secured: bool,
+    pub roles: Vec<String>,
+}
+
+impl Redirect {
+    /// Work out where to send the client, keeping the path and query of the original request if configured to
+    pub fn location(&self, uri: &Uri) -> String {
+        let mut location = self.target.clone();
+        if self.preserve_path && uri.path() != "/" {
+            location = format!("{}{}", location.trim_end_matches('/'), uri.path());
+        }
+        if self.preserve_query {
+            if let Some(query) = uri.query() {
+                let separator = if location.contains('?') { '&' } else { '?' };
+                location = format!("{}{}{}", location, separator, query);
+            }
+        }
+        location
+    }
+
+    pub fn status(&self) -> StatusCode {
+        if self.permanent {
+            StatusCode::PERMANENT_REDIRECT
+        } else {
+            StatusCode::TEMPORARY_REDIRECT
+        }
+    }
+}
+
+pub async fn redirect_handler(
+    user: Option<User>,
+    Extension(main_site): Extension<MainSite>,
+    redirect: HostType,
+    req: Request<Body>,
+) -> Response<Body> {
+    if let Some(value) = check_authorization_or_login(&redirect, &user, &main_site, &req) {
+        return value;
+    }
+
+    let redirect = match redirect {
+        HostType::Redirect(redirect) => redirect,
+        _ => panic!("Service is not a redirect !"),
+    };
+
+    let location = match HeaderValue::from_str(&redirect.location(req.uri())) {
+        Ok(location) => location,
+        Err(e) => {
+            error!("Redirect location error: {:?}", e);
+            return Response::builder()
+                .status(StatusCode::INTERNAL_SERVER_ERROR)
+                .body(Body::empty())
+                .unwrap();
+        }
+    };
+
+    Response::builder()
+        .status(redirect.status())
+        .header(LOCATION, location)
+        .body(Body::empty())
+        .unwrap()
+}
+
+pub async fn get_redirects(
+    config: Config,
+    _admin: Admin,
+) -> Result<(StatusCode, String), (StatusCode, String)> {
+    // Return all the redirects as Json
+    let encoded = serde_json::to_string(&config.redirects).map_err(|_| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "could not encode redirects".to_owned(),
+        )
+    })?;
+    Ok((StatusCode::OK, encoded))
+}
+
+pub async fn delete_redirect(
+    config_file: Extension<ConfigFile>,
+    mut config: Config,
+    _admin: Admin,
+    Path(redirect_id): Path<(String, usize)>,
+) -> Result<impl IntoResponse, impl IntoResponse> {
+    // Find the redirect
+    if let Some(pos) = config.redirects.iter().position(|r| r.id == redirect_id.1) {
+        // It is an existing redirect, delete it
+        config.redirects.remove(pos);
+    } else {
+        // If the redirect doesn't exist, respond with an error
+        return Err((StatusCode::BAD_REQUEST, "redirect doesn't exist"));
+    }
+
+    config
+        .to_file_or_internal_server_error(&config_file)
+        .await?;
+
+    Ok((StatusCode::OK, "redirect deleted successfully"))
+}
+
+pub async fn add_redirect(
+    config_file: Extension<ConfigFile>,
+    mut config: Config,
+    _admin: Admin,
+    Json(payload): Json<Redirect>,
+) -> Result<(StatusCode, &'static str), (StatusCode, &'static str)> {
+    // The target must be an absolute url, or the redirect will loop on itself
+    match payload.target.parse::<Uri>() {
+        Ok(uri) if uri.scheme().is_some() && uri.host().is_some() => (),
+        _ => return Err((StatusCode::NOT_ACCEPTABLE, "target must be an absolute url")),
+    }
+
+    // Find the redirect
+    if let Some(redirect) = config.redirects.iter_mut().find(|r| r.id == payload.id) {
+        *redirect = payload;
+    } else {
+        config.redirects.push(payload);
+    }
+
+    config
+        .to_file_or_internal_server_error(&config_file)
+        .await?;
+
+    Ok((
+        StatusCode::CREATED,
+        "redirect created or updated successfully",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{StatusCode, Uri};
+
+    use super::Redirect;
+
+    fn redirect(preserve_path: bool, preserve_query: bool) -> Redirect {
+        Redirect {
+            target: "https://wiki.example.com/".to_owned(),
+            preserve_path,
+            preserve_query,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_location_drops_path_and_query() {
+        let uri: Uri = "/some/page?lang=fr".parse().unwrap();
+        assert_eq!(
+            redirect(false, false).location(&uri),
+            "https://wiki.example.com/"
+        );
+    }
+
+    #[test]
+    fn test_location_preserves_path() {
+        let uri: Uri = "/some/page?lang=fr".parse().unwrap();
+        assert_eq!(
+            redirect(true, false).location(&uri),
+            "https://wiki.example.com/some/page"
+        );
+    }
+
+    #[test]
+    fn test_location_preserves_path_and_query() {
+        let uri: Uri = "/some/page?lang=fr".parse().unwrap();
+        assert_eq!(
+            redirect(true, true).location(&uri),
+            "https://wiki.example.com/some/page?lang=fr"
+        );
+    }
+
+    #[test]
+    fn test_location_appends_query_to_existing_one() {
+        let mut redirect = redirect(false, true);
+        redirect.target = "https://wiki.example.com/?from=vestibule".to_owned();
+        let uri: Uri = "/?lang=fr".parse().unwrap();
+        assert_eq!(
+            redirect.location(&uri),
+            "https://wiki.example.com/?from=vestibule&lang=fr"
+        );
+    }
+
+    #[test]
+    fn test_status() {
+        let mut redirect = redirect(false, false);
+        assert_eq!(redirect.status(), StatusCode::TEMPORARY_REDIRECT);
+        redirect.permanent = true;
+        assert_eq!(redirect.status(), StatusCode::PERMANENT_REDIRECT);
+    }
+}
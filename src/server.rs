@@ -13,10 +13,18 @@ use crate::{
     apps::{add_app, delete_app, get_apps, proxy_handler},
     configuration::{load_config, ConfigFile, HostType},
     davs::{
+        migration::start_migration,
         model::{add_dav, delete_dav, get_davs},
+        reencryption::{get_reencryption, resume_jobs, start_reencryption},
+        shares::{
+            add_share, delete_share, delete_user_share, get_shares, get_user_shares, share_handler,
+        },
+        watcher::watch_davs,
         webdav_handler,
     },
-    users::{add_user, delete_user, get_users, list_services, local_auth},
+    redirects::{add_redirect, delete_redirect, get_redirects, redirect_handler},
+    streams::serve_streams,
+    users::{add_user, delete_user, get_users, list_services, local_auth, verify_auth},
 };
 
 pub struct Server {
@@ -28,14 +36,26 @@ impl Server {
     pub async fn build(config_file: &str, tx: Sender<()>) -> Result<Self, anyhow::Error> {
         let config = load_config(config_file).await?;
 
+        // Start the raw tcp/udp forwarders, they stop by themselves on reload
+        serve_streams(&config.0.streams, &tx).await;
+        // Likewise for the watchers of the dav directories
+        watch_davs(&config.0.davs, &tx);
+        // The davs re-encrypted or migrated when the server stopped are served once their files are converted
+        resume_jobs(config_file, &config.0.davs, &tx);
+
         let key = Key::generate();
+        let reload = tx.clone();
         let config_file: ConfigFile = config_file.to_owned();
+        let main_site = config.0.main_site();
 
         async fn website_handler() -> Html<String> {
             Html(format!("Hello world from main server !"))
         }
 
-        let user_router = Router::new().route("/list_services", get(list_services));
+        let user_router = Router::new()
+            .route("/list_services", get(list_services))
+            .route("/shares", get(get_user_shares).post(add_share))
+            .route("/shares/:token", delete(delete_user_share));
 
         let admin_router = Router::new()
             .route("/users", get(get_users).post(add_user))
@@ -43,7 +63,19 @@ impl Server {
             .route("/apps", get(get_apps).post(add_app))
             .route("/apps/:app_id", delete(delete_app))
             .route("/davs", get(get_davs).post(add_dav))
-            .route("/davs/:dav_id", delete(delete_dav));
+            .route("/davs/:dav_id", delete(delete_dav))
+            .route(
+                "/davs/:dav_id/reencryption",
+                get(get_reencryption).post(start_reencryption),
+            )
+            .route(
+                "/davs/:dav_id/migration",
+                get(get_reencryption).post(start_migration),
+            )
+            .route("/redirects", get(get_redirects).post(add_redirect))
+            .route("/redirects/:redirect_id", delete(delete_redirect))
+            .route("/shares", get(get_shares))
+            .route("/shares/:token", delete(delete_share));
 
         let website_router = Router::new()
             .route(
@@ -54,12 +86,16 @@ impl Server {
                 }),
             )
             .route("/auth/local", post(local_auth))
+            .route("/auth/verify", any(verify_auth))
+            .route("/share/:token", any(share_handler))
+            .route("/share/:token/*path", any(share_handler))
             .nest("/api/admin", admin_router)
             .nest("/api/user", user_router)
             .route("/", any(website_handler));
 
         let proxy_router = Router::new().route("/*path", any(proxy_handler));
         let webdav_router = Router::new().route("/*path", any(webdav_handler));
+        let redirect_router = Router::new().route("/*path", any(redirect_handler));
 
         let router = Router::new()
             .route(
@@ -69,6 +105,7 @@ impl Server {
                         match hostype {
                             Some(HostType::App(_)) => proxy_router.oneshot(request).await,
                             Some(HostType::Dav(_)) => webdav_router.oneshot(request).await,
+                            Some(HostType::Redirect(_)) => redirect_router.oneshot(request).await,
                             None => website_router.oneshot(request).await,
                         }
                     },
@@ -78,6 +115,8 @@ impl Server {
                 ServiceBuilder::new()
                     .layer(Extension(key))
                     .layer(Extension(config.1))
+                    .layer(Extension(main_site))
+                    .layer(Extension(reload))
                     .layer(Extension(config_file)), /*.layer(
                                                         CorsLayer::new()
                                                             .allow_origin(config.0.hostname.parse::<HeaderValue>().unwrap())
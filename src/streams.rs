@@ -0,0 +1,386 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::future::poll_fn;
+use hyper::server::conn::AddrStream;
+use log::{error, info};
+use serde::Deserialize;
+use serde::Serialize;
+use tokio::io::ReadBuf;
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::sync::broadcast::{Receiver, Sender};
+
+use crate::configuration::Config;
+
+const BIND_ATTEMPTS: usize = 20;
+const BIND_RETRY_DELAY: Duration = Duration::from_millis(100);
+const PEEK_ATTEMPTS: usize = 50;
+const PEEK_RETRY_DELAY: Duration = Duration::from_millis(10);
+const MAX_CLIENT_HELLO_SIZE: usize = 16384 + 5;
+const UDP_BUF_SIZE: usize = 65536;
+const UDP_SESSION_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Protocol {
+    #[default]
+    Tcp,
+    Udp,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Stream {
+    pub id: usize,
+    pub name: String,
+    #[serde(default)]
+    pub protocol: Protocol,
+    /// Port to listen to, on all interfaces
+    #[serde(default)]
+    pub listen_port: Option<u16>,
+    /// If set, TLS connections to `host`.`hostname` on the main TLS listener are passed through to the target (tcp only)
+    #[serde(default)]
+    pub host: String,
+    pub forward_to: String,
+}
+
+/// Work out the TLS passthrough routes, from the full SNI host name to the forwarding target
+pub fn sni_routes(config: &Config) -> HashMap<String, String> {
+    config
+        .streams
+        .iter()
+        .filter(|s| s.protocol == Protocol::Tcp && !s.host.is_empty())
+        .map(|s| {
+            (
+                format!("{}.{}", s.host, config.hostname),
+                s.forward_to.clone(),
+            )
+        })
+        .collect()
+}
+
+/// Bind the listeners for every stream with a listen port, and forward traffic until a reload is requested
+pub async fn serve_streams(streams: &[Stream], tx: &Sender<()>) {
+    for stream in streams {
+        let port = match stream.listen_port {
+            Some(port) => port,
+            None => continue,
+        };
+        let addr = SocketAddr::from(([0, 0, 0, 0], port));
+        let forward_to = stream.forward_to.clone();
+        let rx = tx.subscribe();
+        match stream.protocol {
+            Protocol::Tcp => match bind_with_retry(|| TcpListener::bind(addr)).await {
+                Ok(listener) => {
+                    info!("Forwarding tcp port {} to {}", port, forward_to);
+                    tokio::spawn(serve_tcp(listener, forward_to, rx));
+                }
+                Err(e) => error!("Could not bind tcp port {}: {}", port, e),
+            },
+            Protocol::Udp => match bind_with_retry(|| UdpSocket::bind(addr)).await {
+                Ok(socket) => {
+                    info!("Forwarding udp port {} to {}", port, forward_to);
+                    tokio::spawn(serve_udp(socket, forward_to, rx));
+                }
+                Err(e) => error!("Could not bind udp port {}: {}", port, e),
+            },
+        }
+    }
+}
+
+// The listeners of the previous configuration may still be closing when reloading, so we retry for a while
+async fn bind_with_retry<T, F, Fut>(bind: F) -> std::io::Result<T>
+where
+    F: Fn() -> Fut,
+    Fut: std::future::Future<Output = std::io::Result<T>>,
+{
+    let mut attempt = 1;
+    loop {
+        match bind().await {
+            Ok(v) => return Ok(v),
+            Err(e) if attempt >= BIND_ATTEMPTS || e.kind() != std::io::ErrorKind::AddrInUse => {
+                return Err(e)
+            }
+            Err(_) => {
+                attempt += 1;
+                tokio::time::sleep(BIND_RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+async fn serve_tcp(listener: TcpListener, forward_to: String, mut rx: Receiver<()>) {
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((inbound, _)) => {
+                    tokio::spawn(forward_tcp(inbound, forward_to.clone()));
+                }
+                Err(e) => error!("Stream accept error: {}", e),
+            },
+            _ = rx.recv() => break,
+        }
+    }
+}
+
+pub async fn forward_tcp(mut inbound: TcpStream, forward_to: String) {
+    let mut outbound = match TcpStream::connect(&forward_to).await {
+        Ok(outbound) => outbound,
+        Err(e) => {
+            error!("Could not connect to {}: {}", forward_to, e);
+            return;
+        }
+    };
+    if let Err(e) = tokio::io::copy_bidirectional(&mut inbound, &mut outbound).await {
+        info!("Stream to {} closed: {}", forward_to, e);
+    }
+}
+
+async fn serve_udp(socket: UdpSocket, forward_to: String, mut rx: Receiver<()>) {
+    let socket = Arc::new(socket);
+    let sessions: Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>> =
+        Arc::new(Mutex::new(HashMap::new()));
+    let mut buf = vec![0; UDP_BUF_SIZE];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => {
+                let (len, client) = match received {
+                    Ok(v) => v,
+                    Err(e) => {
+                        error!("Stream receive error: {}", e);
+                        continue;
+                    }
+                };
+                let upstream = sessions.lock().unwrap().get(&client).cloned();
+                let upstream = match upstream {
+                    Some(upstream) => upstream,
+                    None => match open_udp_session(&forward_to, client, &socket, &sessions).await {
+                        Ok(upstream) => upstream,
+                        Err(e) => {
+                            error!("Could not connect to {}: {}", forward_to, e);
+                            continue;
+                        }
+                    },
+                };
+                if let Err(e) = upstream.send(&buf[..len]).await {
+                    error!("Could not send datagram to {}: {}", forward_to, e);
+                }
+            }
+            _ = rx.recv() => break,
+        }
+    }
+}
+
+// Each client gets its own upstream socket, so that the replies can be routed back to it
+async fn open_udp_session(
+    forward_to: &str,
+    client: SocketAddr,
+    socket: &Arc<UdpSocket>,
+    sessions: &Arc<Mutex<HashMap<SocketAddr, Arc<UdpSocket>>>>,
+) -> std::io::Result<Arc<UdpSocket>> {
+    let upstream = UdpSocket::bind(SocketAddr::from(([0, 0, 0, 0], 0))).await?;
+    upstream.connect(forward_to).await?;
+    let upstream = Arc::new(upstream);
+    sessions.lock().unwrap().insert(client, upstream.clone());
+
+    let (socket, sessions, relay) = (socket.clone(), sessions.clone(), upstream.clone());
+    tokio::spawn(async move {
+        let mut buf = vec![0; UDP_BUF_SIZE];
+        while let Ok(Ok(len)) =
+            tokio::time::timeout(UDP_SESSION_TIMEOUT, relay.recv(&mut buf)).await
+        {
+            if socket.send_to(&buf[..len], client).await.is_err() {
+                break;
+            }
+        }
+        sessions.lock().unwrap().remove(&client);
+    });
+    Ok(upstream)
+}
+
+/// Peek at the TLS client hello of an incoming connection, without consuming it, to get the requested server name
+pub async fn peek_sni(stream: &mut AddrStream) -> Option<String> {
+    let mut buf = vec![0; MAX_CLIENT_HELLO_SIZE];
+    for _ in 0..PEEK_ATTEMPTS {
+        let mut read_buf = ReadBuf::new(&mut buf);
+        let n = poll_fn(|cx| stream.poll_peek(cx, &mut read_buf))
+            .await
+            .ok()?;
+        if n == 0 {
+            return None;
+        }
+        match parse_sni(&buf[..n]) {
+            Sni::Found(name) => return Some(name),
+            Sni::Missing => return None,
+            Sni::Incomplete => tokio::time::sleep(PEEK_RETRY_DELAY).await,
+        }
+    }
+    None
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Sni {
+    Found(String),
+    Missing,
+    Incomplete,
+}
+
+/// Extract the server name indication from a TLS client hello record
+pub fn parse_sni(buf: &[u8]) -> Sni {
+    const HANDSHAKE: u8 = 0x16;
+    const CLIENT_HELLO: u8 = 0x01;
+    const SERVER_NAME_EXTENSION: u16 = 0x0000;
+    const HOST_NAME: u8 = 0x00;
+
+    if buf.is_empty() {
+        return Sni::Incomplete;
+    }
+    if buf[0] != HANDSHAKE {
+        return Sni::Missing;
+    }
+    let mut record = Reader::new(&buf[1..]);
+    let record_len = match record.skip(2).and_then(|r| r.u16()) {
+        Some(len) => len as usize,
+        None => return Sni::Incomplete,
+    };
+    if buf.len() < 5 + record_len {
+        return Sni::Incomplete;
+    }
+
+    let mut hello = Reader::new(&buf[5..5 + record_len]);
+    let parsed = (|| {
+        if hello.u8()? != CLIENT_HELLO {
+            return None;
+        }
+        // handshake length, client version and random
+        hello.skip(3 + 2 + 32)?;
+        let session_id_len = hello.u8()? as usize;
+        hello.skip(session_id_len)?;
+        let cipher_suites_len = hello.u16()? as usize;
+        hello.skip(cipher_suites_len)?;
+        let compression_methods_len = hello.u8()? as usize;
+        hello.skip(compression_methods_len)?;
+        let extensions_len = hello.u16()? as usize;
+        let mut extensions = Reader::new(hello.take(extensions_len)?);
+        while let Some(extension_type) = extensions.u16() {
+            let extension_len = extensions.u16()? as usize;
+            let mut extension = Reader::new(extensions.take(extension_len)?);
+            if extension_type != SERVER_NAME_EXTENSION {
+                continue;
+            }
+            let list_len = extension.u16()? as usize;
+            let mut list = Reader::new(extension.take(list_len)?);
+            while let Some(name_type) = list.u8() {
+                let name_len = list.u16()? as usize;
+                let name = list.take(name_len)?;
+                if name_type == HOST_NAME {
+                    return std::str::from_utf8(name)
+                        .ok()
+                        .map(|name| name.to_ascii_lowercase());
+                }
+            }
+        }
+        None
+    })();
+
+    match parsed {
+        Some(name) => Sni::Found(name),
+        None => Sni::Missing,
+    }
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf }
+    }
+
+    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
+        if self.buf.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.buf.split_at(n);
+        self.buf = rest;
+        Some(taken)
+    }
+
+    fn skip(&mut self, n: usize) -> Option<&mut Self> {
+        self.take(n)?;
+        Some(self)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.take(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.take(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_sni, Sni};
+
+    fn client_hello(server_name: Option<&str>) -> Vec<u8> {
+        let mut extensions = Vec::new();
+        // A supported_versions extension, to check that others are skipped
+        extensions.extend_from_slice(&[0x00, 0x2b, 0x00, 0x03, 0x02, 0x03, 0x04]);
+        if let Some(name) = server_name {
+            let name = name.as_bytes();
+            let list_len = name.len() + 3;
+            extensions.extend_from_slice(&[0x00, 0x00]);
+            extensions.extend_from_slice(&((list_len + 2) as u16).to_be_bytes());
+            extensions.extend_from_slice(&(list_len as u16).to_be_bytes());
+            extensions.push(0x00);
+            extensions.extend_from_slice(&(name.len() as u16).to_be_bytes());
+            extensions.extend_from_slice(name);
+        }
+
+        let mut body = vec![0x03, 0x03];
+        body.extend_from_slice(&[0; 32]);
+        body.push(0); // session id
+        body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]); // cipher suites
+        body.extend_from_slice(&[0x01, 0x00]); // compression methods
+        body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+        body.extend_from_slice(&extensions);
+
+        let mut handshake = vec![0x01];
+        handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+        handshake.extend_from_slice(&body);
+
+        let mut record = vec![0x16, 0x03, 0x01];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.extend_from_slice(&handshake);
+        record
+    }
+
+    #[test]
+    fn test_parse_sni() {
+        assert_eq!(
+            parse_sni(&client_hello(Some("SSH.vestibule.io"))),
+            Sni::Found("ssh.vestibule.io".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_parse_sni_without_server_name() {
+        assert_eq!(parse_sni(&client_hello(None)), Sni::Missing);
+    }
+
+    #[test]
+    fn test_parse_sni_incomplete() {
+        let hello = client_hello(Some("ssh.vestibule.io"));
+        assert_eq!(parse_sni(&hello[..3]), Sni::Incomplete);
+        assert_eq!(parse_sni(&hello[..hello.len() - 1]), Sni::Incomplete);
+    }
+
+    #[test]
+    fn test_parse_sni_not_tls() {
+        assert_eq!(parse_sni(b"GET / HTTP/1.1\r\n"), Sni::Missing);
+    }
+}
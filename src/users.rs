@@ -10,14 +10,22 @@ use axum::Json;
 use axum::extract::FromRequest;
 use axum::extract::Host;
 use axum::extract::Path;
+use axum::extract::Query;
 use axum::extract::RequestParts;
 
 use axum::response::IntoResponse;
 use axum::response::Response;
 use axum_extra::extract::cookie::Cookie;
 use axum_extra::extract::SignedCookieJar;
+use hyper::header::ACCEPT;
+use hyper::header::HOST;
+use hyper::header::LOCATION;
 use hyper::Body;
+use hyper::HeaderMap;
+use hyper::Request;
 use hyper::StatusCode;
+use hyper::Uri;
+use log::info;
 
 use rand::rngs::OsRng;
 use serde::Deserialize;
@@ -28,9 +36,13 @@ use crate::configuration::Config;
 use crate::configuration::ConfigFile;
 use crate::configuration::ConfigMap;
 use crate::configuration::HostType;
+use crate::configuration::MainSite;
 use crate::davs::model::Dav;
+use crate::davs::storage::StorageConfig;
 
 static COOKIE_NAME: &str = "VESTIBULE_AUTH";
+static REMOTE_USER: &str = "Remote-User";
+static REMOTE_GROUPS: &str = "Remote-Groups";
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct User {
@@ -86,6 +98,15 @@ where
 pub struct LocalAuth {
     login: String,
     password: String,
+    /// Where to send the user back after login, must be one of the configured domains
+    #[serde(default)]
+    return_to: Option<String>,
+}
+
+/// Answer to a login, the login page navigating to the return url itself as a fetch would follow a redirect in the background
+#[derive(Serialize)]
+pub struct LoggedIn {
+    return_to: String,
 }
 
 pub async fn local_auth(
@@ -93,7 +114,7 @@ pub async fn local_auth(
     mut config: Config,
     Host(hostname): Host,
     Json(payload): Json<LocalAuth>,
-) -> Result<(SignedCookieJar, StatusCode), StatusCode> {
+) -> Result<Response, StatusCode> {
     // Find the user in configuration
     let mut user = config
         .users
@@ -129,7 +150,113 @@ pub async fn local_auth(
         .http_only(false)
         .finish();
 
-    Ok((jar.add(cookie), StatusCode::OK))
+    let jar = jar.add(cookie);
+
+    // Tell where to send the user back to the service he/she came from, if it is one of ours
+    if let Some(return_to) = payload.return_to {
+        if is_allowed_return_to(&config, &return_to) {
+            return Ok((jar, Json(LoggedIn { return_to })).into_response());
+        }
+        info!(
+            "Ignoring return_to outside of configured domains: {}",
+            return_to
+        );
+    }
+
+    Ok((jar, StatusCode::OK).into_response())
+}
+
+fn is_allowed_return_to(config: &Config, return_to: &str) -> bool {
+    let uri: Uri = match return_to.parse() {
+        Ok(uri) => uri,
+        Err(_) => return false,
+    };
+    matches!(uri.scheme_str(), Some("http") | Some("https"))
+        && uri
+            .host()
+            .is_some_and(|host| config.domains().iter().any(|d| d == host))
+}
+
+fn redirect_to_login(main_site: &MainSite, return_to: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FOUND)
+        .header(
+            LOCATION,
+            format!(
+                "{}/?return_to={}",
+                main_site.0,
+                urlencoding::encode(return_to)
+            ),
+        )
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[derive(Deserialize)]
+pub struct VerifyAuth {
+    /// Comma separated roles allowed to access a service that is not configured in vestibule
+    roles: Option<String>,
+    /// Redirect unlogged users to the login page instead of answering with a 401
+    #[serde(default)]
+    redirect: bool,
+}
+
+/// Forward authentication endpoint for external reverse proxies (nginx auth_request, traefik forwardAuth...)
+pub async fn verify_auth(
+    user: Option<User>,
+    config_map: Extension<std::sync::Arc<ConfigMap>>,
+    Extension(main_site): Extension<MainSite>,
+    Query(query): Query<VerifyAuth>,
+    headers: HeaderMap,
+) -> Response<Body> {
+    let forwarded = |name: &str| headers.get(name).and_then(|v| v.to_str().ok());
+    let host = forwarded("X-Forwarded-Host")
+        .or_else(|| forwarded(HOST.as_str()))
+        .unwrap_or_default();
+    let hostname = host.split(':').next().unwrap_or_default();
+
+    // Work out who is allowed : the given roles, the configured service ones, or any logged user
+    let authorized = if let Some(roles) = &query.roles {
+        let roles: Vec<String> = roles.split(',').map(|r| r.trim().to_owned()).collect();
+        user.as_ref().map(|u| user_has_role(u, &roles))
+    } else if let Some(target) = config_map.get(hostname) {
+        if user.is_none() && target.secured() {
+            None
+        } else {
+            Some(check_authorization(target, &user).is_none())
+        }
+    } else {
+        user.as_ref().map(|_| true)
+    };
+
+    match authorized {
+        Some(true) => {
+            let mut response = Response::builder().status(StatusCode::OK);
+            if let Some(user) = &user {
+                response = response
+                    .header(REMOTE_USER, &user.login)
+                    .header(REMOTE_GROUPS, user.roles.join(","));
+            }
+            response.body(Body::empty()).unwrap()
+        }
+        Some(false) => Response::builder()
+            .status(StatusCode::FORBIDDEN)
+            .body(Body::empty())
+            .unwrap(),
+        None if query.redirect => {
+            let return_to = format!(
+                "{}://{}{}",
+                forwarded("X-Forwarded-Proto").unwrap_or("https"),
+                host,
+                forwarded("X-Forwarded-Uri").unwrap_or("/")
+            );
+            redirect_to_login(&main_site, &return_to)
+        }
+        None => Response::builder()
+            .status(StatusCode::UNAUTHORIZED)
+            .body(Body::empty())
+            .unwrap(),
+    }
 }
 
 pub async fn get_users(
@@ -212,8 +339,19 @@ fn strip_sensitive_data_and_push_to_vec(h: &HostType, apps: &mut Vec<App>, davs:
         HostType::Dav(s) => {
             let mut s = s.clone();
             s.passphrase = "REDACTED".to_owned();
+            // The wrapped key would allow to brute force the passphrase offline
+            s.wrapped_key = None;
+            if let Some(migration) = s.migration.as_mut() {
+                migration.passphrase = "REDACTED".to_owned();
+            }
+            if let Some(StorageConfig::S3(s3)) = s.storage.as_mut() {
+                s3.access_key = "REDACTED".to_owned();
+                s3.secret_key = "REDACTED".to_owned();
+            }
             davs.push(s);
         }
+        // Redirects are plain vanity hosts and are not listed as services
+        HostType::Redirect(_) => (),
     }
 }
 
@@ -251,17 +389,17 @@ fn hash_password(payload: &mut User) -> Result<(), argon2::password_hash::Error>
     Ok(())
 }
 
+fn user_has_role(user: &User, roles: &[String]) -> bool {
+    user.roles.iter().any(|user_role| roles.contains(user_role))
+}
+
 pub fn check_user_has_role_or_forbid(
     user: &Option<User>,
     target: &HostType,
 ) -> Option<Response<Body>> {
     if let Some(user) = user {
-        for user_role in user.roles.iter() {
-            for role in target.roles().iter() {
-                if user_role == role {
-                    return None;
-                }
-            }
+        if user_has_role(user, target.roles()) {
+            return None;
         }
     }
     Some(
@@ -281,6 +419,38 @@ pub fn check_authorization(app: &HostType, user: &Option<User>) -> Option<Respon
     None
 }
 
+/// Same as check_authorization, but browsers without session are sent to the login page instead of getting a bare 403
+pub fn check_authorization_or_login(
+    app: &HostType,
+    user: &Option<User>,
+    main_site: &MainSite,
+    req: &Request<Body>,
+) -> Option<Response<Body>> {
+    let response = check_authorization(app, user)?;
+    if user.is_none() && accepts_html(req.headers()) {
+        if let Some(host) = req.headers().get(HOST).and_then(|h| h.to_str().ok()) {
+            let return_to = format!(
+                "{}://{}{}",
+                main_site.scheme(),
+                host,
+                req.uri()
+                    .path_and_query()
+                    .map(|p| p.as_str())
+                    .unwrap_or("/")
+            );
+            return Some(redirect_to_login(main_site, &return_to));
+        }
+    }
+    Some(response)
+}
+
+fn accepts_html(headers: &HeaderMap) -> bool {
+    headers
+        .get(ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("text/html"))
+}
+
 #[cfg(test)]
 mod check_user_has_role_or_forbid_tests {
     use crate::{
@@ -8,3 +8,14 @@ pub fn random_string(size: usize) -> std::string::String {
         .map(char::from)
         .collect()
 }
+
+/// Replace the content of a file through a temporary file renamed over it, so that it is never read half written
+pub async fn write_atomically(path: &str, contents: &[u8]) -> std::io::Result<()> {
+    let temp = format!("{}.{}.tmp", path, random_string(8));
+    tokio::fs::write(&temp, contents).await?;
+    if let Err(e) = tokio::fs::rename(&temp, path).await {
+        tokio::fs::remove_file(&temp).await.ok();
+        return Err(e);
+    }
+    Ok(())
+}
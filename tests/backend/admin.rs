@@ -458,6 +458,20 @@ async fn davs_api_for_admin_user_test() {
     assert_eq!(response.status(), StatusCode::OK);
     assert!(response.text().await.unwrap().contains(r#""id":201"#));
 
+    // Add a dav enabling a trash on another storage than the local one (must fail)
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/api/admin/davs", app.port))
+        .body(NEW_DAV.replace(
+            r#""passphrase": "ABCD101""#,
+            r#""passphrase": "", "storage": {"type": "memory"}, "trash": {}"#,
+        ))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
     // Remove a dav and assert that it is not here anymore
     let response = app
         .client
@@ -478,3 +492,155 @@ async fn davs_api_for_admin_user_test() {
     assert_eq!(response.status(), StatusCode::OK);
     assert!(!response.text().await.unwrap().contains(r#""id":201"#));
 }
+
+const NEW_REDIRECT: &'static str = r##"
+{
+    "id": 301,
+    "name": "Blog",
+    "icon": "blog",
+    "color": "#030303",
+    "host": "blog",
+    "target": "https://blog.example.com",
+    "permanent": true,
+    "preserve_path": true,
+    "preserve_query": true,
+    "secured": false,
+    "roles": ["USERS"]
+}
+"##;
+
+#[tokio::test]
+async fn redirects_api_for_unlogged_user_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    // Do not log
+
+    // Get the existing redirects (must fail)
+    let response = app
+        .client
+        .get(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Add a redirect (must fail)
+    let response = app
+        .client
+        .post(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .body(NEW_REDIRECT)
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Remove a redirect (must fail)
+    let response = app
+        .client
+        .delete(format!(
+            "http://vestibule.io:{}/api/admin/redirects/1",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn redirects_api_for_admin_user_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    // Log as admin
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/auth/local", app.port))
+        .body(r#"{"login":"admin","password":"password"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Get the existing redirects
+    let response = app
+        .client
+        .get(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().starts_with(r#"[{"id":"#));
+
+    // Add a redirect with a relative target (must fail)
+    let response = app
+        .client
+        .post(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .body(NEW_REDIRECT.replace("https://blog.example.com", "/blog"))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::NOT_ACCEPTABLE);
+
+    // Add a redirect and assert that it is here
+    let response = app
+        .client
+        .post(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .body(NEW_REDIRECT)
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::CREATED);
+    let response = app
+        .client
+        .get(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains(r#""id":301"#));
+
+    // Remove a redirect and assert that it is not here anymore
+    let response = app
+        .client
+        .delete(format!(
+            "http://vestibule.io:{}/api/admin/redirects/301",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app
+        .client
+        .get(format!(
+            "http://vestibule.io:{}/api/admin/redirects",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(!response.text().await.unwrap().contains(r#""id":301"#));
+}
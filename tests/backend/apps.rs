@@ -235,7 +235,10 @@ async fn redirect_test() {
         http_port: app.port,
         apps: apps,
         davs: vec![],
+        redirects: vec![],
+        streams: vec![],
         users: vec![],
+        shares: vec![],
     };
     config.to_file(&filepath).await.unwrap();
     app.client
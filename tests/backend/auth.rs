@@ -0,0 +1,190 @@
+use hyper::header::LOCATION;
+use hyper::StatusCode;
+
+use crate::helpers::{log_as, TestApp};
+
+async fn verify(app: &TestApp, query: &str, host: &str) -> reqwest::Response {
+    app.client
+        .get(format!(
+            "http://vestibule.io:{}/auth/verify{}",
+            app.port, query
+        ))
+        .header("X-Forwarded-Proto", "https")
+        .header("X-Forwarded-Host", host)
+        .header("X-Forwarded-Uri", "/dashboard?tab=1")
+        .send()
+        .await
+        .expect("failed to execute request")
+}
+
+#[tokio::test]
+async fn verify_auth_for_unlogged_user_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    // Do not log
+
+    // Act and Assert : an unsecured service is allowed
+    let response = verify(&app, "", "app1.vestibule.io").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("Remote-User").is_none());
+
+    // Act and Assert : a secured service is not
+    let response = verify(&app, "", "secured-app.vestibule.io").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Act and Assert : neither is an external service
+    let response = verify(&app, "?roles=USERS", "grafana.example.com").await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Act and Assert : the user is sent to the login page if asked to
+    let response = verify(&app, "?redirect=true", "secured-app.vestibule.io").await;
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        &format!(
+            "http://vestibule.io:{}/?return_to=https%3A%2F%2Fsecured-app.vestibule.io%2Fdashboard%3Ftab%3D1",
+            app.port
+        )
+    );
+}
+
+#[tokio::test]
+async fn verify_auth_for_normal_user_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    log_as(&app, "user").await;
+
+    // Act and Assert : a service for admins is forbidden
+    let response = verify(&app, "?redirect=true", "secured-app.vestibule.io").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Act and Assert : an external service for users is allowed, with the identity headers
+    let response = verify(&app, "?roles=ADMINS,USERS", "grafana.example.com").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("Remote-User").unwrap(), "user");
+    assert_eq!(response.headers().get("Remote-Groups").unwrap(), "USERS");
+
+    // Act and Assert : an external service for admins is forbidden
+    let response = verify(&app, "?roles=ADMINS", "grafana.example.com").await;
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Act and Assert : an external service without roles only requires to be logged
+    let response = verify(&app, "", "grafana.example.com").await;
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn verify_auth_for_admin_user_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    log_as(&app, "admin").await;
+
+    // Act and Assert
+    let response = verify(&app, "", "secured-app.vestibule.io:443").await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers().get("Remote-User").unwrap(), "admin");
+    assert_eq!(response.headers().get("Remote-Groups").unwrap(), "ADMINS");
+}
+
+#[tokio::test]
+async fn browser_login_redirect_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+
+    // Act : try to access a secured app as an unlogged browser
+    let response = app
+        .client
+        .get(format!(
+            "http://secured-app.vestibule.io:{}/some/page?q=1",
+            app.port
+        ))
+        .header("Accept", "text/html,application/xhtml+xml")
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that the browser is sent to the login page
+    assert_eq!(response.status(), StatusCode::FOUND);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        &format!(
+            "http://vestibule.io:{port}/?return_to=http%3A%2F%2Fsecured-app.vestibule.io%3A{port}%2Fsome%2Fpage%3Fq%3D1",
+            port = app.port
+        )
+    );
+
+    // Act : do the same for a secured dav
+    let response = app
+        .client
+        .get(format!("http://secured-files.vestibule.io:{}/", app.port))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::FOUND);
+
+    // Act : a logged user without the role still gets a 403
+    log_as(&app, "user").await;
+    let response = app
+        .client
+        .get(format!("http://secured-app.vestibule.io:{}", app.port))
+        .header("Accept", "text/html")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn login_with_return_to_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let return_to = format!("http://secured-app.vestibule.io:{}/some/page", app.port);
+
+    // Act : log with a return url to one of our services
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/auth/local", app.port))
+        .body(format!(
+            r#"{{"login":"admin","password":"password","return_to":"{return_to}"}}"#
+        ))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that the login page is told where to send the user back
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(LOCATION).is_none());
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["return_to"], return_to);
+
+    // Act : log with a return url outside of our domains
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/auth/local", app.port))
+        .body(r#"{"login":"admin","password":"password","return_to":"https://evil.com/vestibule.io"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that the return url is ignored
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get(LOCATION).is_none());
+
+    // Act : log with a wrong password and a valid return url
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/auth/local", app.port))
+        .body(format!(
+            r#"{{"login":"admin","password":"wrong","return_to":"{return_to}"}}"#
+        ))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that the login fails
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
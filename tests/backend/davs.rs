@@ -1,4 +1,4 @@
-use crate::helpers::{encode_uri, TestApp};
+use crate::helpers::{encode_uri, log_as, TestApp};
 use std::io::{self, BufWriter, Write};
 
 use hyper::{header::RANGE, Method};
@@ -213,6 +213,78 @@ async fn get_file_range_limit_cases() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn get_multiple_ranges() -> Result<()> {
+    let app = TestApp::spawn().await;
+
+    for case in ["files1", "files2"] {
+        // Arrange
+        let url = format!(
+            "http://{case}.vestibule.io:{}/multiple_ranges.txt",
+            app.port
+        );
+        app.client
+            .put(&url)
+            .body(b"abcdefghijklmnopqrstuvwxyz".to_vec())
+            .send()
+            .await?;
+
+        // Act : get the last bytes of the file
+        let resp = app
+            .client
+            .get(&url)
+            .header(RANGE, "bytes=-4")
+            .send()
+            .await?;
+
+        // Assert
+        assert_eq!(resp.status(), 206);
+        assert_eq!(
+            resp.headers().get("content-range").unwrap(),
+            "bytes 22-25/26"
+        );
+        assert_eq!(resp.text().await?, "wxyz");
+
+        // Act : get several ranges, one of them being unsatisfiable
+        let resp = app
+            .client
+            .get(&url)
+            .header(RANGE, "bytes=0-2, 30-40, -3")
+            .send()
+            .await?;
+
+        // Assert
+        assert_eq!(resp.status(), 206);
+        let content_type = resp.headers()["content-type"].to_str()?.to_owned();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+        let length: usize = resp.headers()["content-length"].to_str()?.parse()?;
+        let body = resp.text().await?;
+        assert_eq!(body.len(), length);
+        assert_eq!(
+            body,
+            format!(
+                "\r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 0-2/26\r\n\r\nabc\
+                 \r\n--{boundary}\r\nContent-Type: text/plain\r\nContent-Range: bytes 23-25/26\r\n\r\nxyz\
+                 \r\n--{boundary}--\r\n"
+            )
+        );
+
+        // Act and Assert : no range can be satisfied
+        let resp = app
+            .client
+            .get(&url)
+            .header(RANGE, "bytes=30-40, -0")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 416);
+        assert_eq!(resp.headers().get("content-range").unwrap(), "bytes */26");
+    }
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn try_to_hack() -> Result<()> {
     let app = TestApp::spawn().await;
@@ -958,3 +1030,2131 @@ async fn secured_dav_test() {
     // Assert that is possible
     assert!(response.status().is_success());
 }
+
+#[tokio::test]
+async fn acl_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let base_url = format!("http://acl-files.vestibule.io:{}", app.port);
+    log_as(&app, "user").await;
+
+    // Act and Assert : the root only shows the user the directories leading to what is granted
+    std::fs::write(format!("data/{}/dir1/root_file", app.id), "")?;
+    std::fs::create_dir_all(format!("data/{}/dir1/dirc", app.id))?;
+    let resp = propfind(&app, &base_url).send().await?;
+    assert_eq!(resp.status(), 207);
+    let body = resp.text().await?;
+    assert!(body.contains("<D:href>/dira/</D:href>"));
+    assert!(body.contains("<D:href>/dirb/</D:href>"));
+    assert!(!body.contains("root_file"));
+    assert!(!body.contains("dirc"));
+    let resp = app
+        .client
+        .get(format!("{base_url}/root_file"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    let resp = propfind(&app, &format!("{base_url}/dirc")).send().await?;
+    assert_eq!(resp.status(), 403);
+    let resp = mkcol(&app, &format!("{base_url}/dird")).send().await?;
+    assert_eq!(resp.status(), 403);
+    let resp = app.client.get(format!("{base_url}?q=file")).send().await?;
+    assert_eq!(resp.status(), 200);
+    let body = resp.text().await?;
+    assert!(body.contains("dira/file1"));
+    assert!(!body.contains("root_file"));
+
+    // Act and Assert : dira is read only for the user
+    let resp = propfind(&app, &format!("{base_url}/dira")).send().await?;
+    assert_eq!(resp.status(), 207);
+    assert!(resp.text().await?.contains("<D:href>/dira/file1</D:href>"));
+    let resp = app
+        .client
+        .get(format!("{base_url}/dira/file1"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let resp = app
+        .client
+        .put(format!("{base_url}/dira/new_file"))
+        .body(b"abc".to_vec())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    let resp = app
+        .client
+        .delete(format!("{base_url}/dira/file1"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+
+    // Act and Assert : dirb is fully granted to the user
+    let resp = app
+        .client
+        .put(format!("{base_url}/dirb/new_file"))
+        .body(b"abc".to_vec())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app
+        .client
+        .delete(format!("{base_url}/dirb/new_file"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    // Act : start an upload to dropbox, where the user can write but not delete, and create its file meanwhile
+    let resp = app
+        .client
+        .post(format!("{base_url}/.vestibule/uploads"))
+        .header("Upload-Length", 3)
+        .header(
+            "Upload-Metadata",
+            format!("path {}", Base64::encode_string(b"/dropbox/raced")),
+        )
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let location = resp.headers()["Location"].to_str()?.to_owned();
+    let raced = format!("data/{}/dir1/dropbox/raced", app.id);
+    std::fs::create_dir_all(format!("data/{}/dir1/dropbox", app.id))?;
+    std::fs::write(&raced, "other")?;
+    let resp = app
+        .client
+        .patch(format!("{base_url}{location}"))
+        .header("Content-Type", "application/offset+octet-stream")
+        .header("Upload-Offset", 0)
+        .body("abc")
+        .send()
+        .await?;
+
+    // Assert : completing the upload would replace the file, which is refused
+    assert_eq!(resp.status(), 403);
+    assert_eq!(std::fs::read_to_string(&raced)?, "other");
+    let resp = app
+        .client
+        .head(format!("{base_url}{location}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    // Act and Assert : copy destinations are checked too
+    let resp = copy(&app, &format!("{base_url}/dira/file1"))
+        .header("Destination", format!("{base_url}/dirb/file1%20(copy)"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = copy(&app, &format!("{base_url}/dirb/file1"))
+        .header("Destination", format!("{base_url}/dira/file1%20(copy)"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    let resp = mv(&app, &format!("{base_url}/dira/file2"))
+        .header("Destination", format!("{base_url}/dirb/file2%20(moved)"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    let resp = mv(&app, &format!("{base_url}/dirb/file2"))
+        .header("Destination", format!("{base_url}/dira/file2%20(moved)"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+
+    // Act : the user shares dirb
+    let shares_url = format!("http://vestibule.io:{}/api/user/shares", app.port);
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":5,"path":"dirb"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let share: serde_json::Value = resp.json().await?;
+    let token = share["token"].as_str().unwrap().to_owned();
+
+    // Act and Assert : the admin can do everything
+    log_as(&app, "admin").await;
+    let resp = propfind(&app, &base_url).send().await?;
+    assert_eq!(resp.status(), 207);
+    assert!(resp.text().await?.contains("root_file"));
+    let resp = app
+        .client
+        .put(format!("{base_url}/dira/new_file"))
+        .body(b"abc".to_vec())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Act and Assert : the admin permission lets manage the shares of the other users
+    let shares: Vec<serde_json::Value> = app.client.get(&shares_url).send().await?.json().await?;
+    assert!(shares.iter().any(|share| share["token"] == token));
+    let resp = app
+        .client
+        .delete(format!("{shares_url}/{token}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn home_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let base_url = format!("http://homes.vestibule.io:{}", app.port);
+
+    // Act and Assert : an unlogged user has no home
+    let resp = propfind(&app, &base_url).send().await?;
+    assert_eq!(resp.status(), 401);
+
+    // Act : log as user and access the home
+    log_as(&app, "user").await;
+    let resp = propfind(&app, &base_url).send().await?;
+
+    // Assert that the home was created from the skeleton
+    assert_eq!(resp.status(), 207);
+    let body = resp.text().await?;
+    assert!(body.contains("<D:href>/file1</D:href>"));
+    assert!(body.contains("<D:href>/dira-a/</D:href>"));
+    let resp = app
+        .client
+        .put(format!("{base_url}/private_file"))
+        .body(b"abc".to_vec())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    assert!(std::path::Path::new(&format!("data/{}/homes/user/private_file", app.id)).exists());
+
+    // Act : log as admin
+    log_as(&app, "admin").await;
+
+    // Assert that the admin has its own home
+    let resp = app
+        .client
+        .get(format!("{base_url}/private_file"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+    let resp = app.client.get(format!("{base_url}/file1")).send().await?;
+    assert_eq!(resp.status(), 200);
+    Ok(())
+}
+
+#[tokio::test]
+async fn quota_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let base_url = format!("http://homes.vestibule.io:{}", app.port);
+    log_as(&app, "user").await;
+
+    // Act and Assert : a file within the quota can be uploaded
+    let resp = app
+        .client
+        .put(format!("{base_url}/file_60"))
+        .body(vec![0; 60])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Act and Assert : a file exceeding the quota cannot be uploaded
+    let resp = app
+        .client
+        .put(format!("{base_url}/other_file_60"))
+        .body(vec![0; 60])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 507);
+    let resp = app
+        .client
+        .get(format!("{base_url}/other_file_60"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    // Act and Assert : the limit is also enforced for streamed bodies without length
+    let chunks: Vec<Result<_, std::io::Error>> = vec![Ok(vec![0; 30]), Ok(vec![0; 30])];
+    let resp = app
+        .client
+        .put(format!("{base_url}/streamed_file"))
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 507);
+
+    // Act and Assert : an existing file can be replaced if the new content fits
+    let resp = app
+        .client
+        .put(format!("{base_url}/file_60"))
+        .body(vec![0; 90])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Act and Assert : the quota is reported
+    let resp = propfind(&app, &base_url).send().await?;
+    assert_eq!(resp.status(), 207);
+    let body = resp.text().await?;
+    assert!(body.contains("<D:quota-available-bytes>10</D:quota-available-bytes>"));
+    assert!(body.contains("<D:quota-used-bytes>90</D:quota-used-bytes>"));
+
+    // Act and Assert : the number of files is limited (the skeleton holds 4 files)
+    for i in 0..3 {
+        let resp = app
+            .client
+            .put(format!("{base_url}/empty_file_{i}"))
+            .body(vec![])
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+    }
+    let resp = app
+        .client
+        .put(format!("{base_url}/one_too_many"))
+        .body(vec![])
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 507);
+
+    // Act : free the space, then upload concurrently more than the quota allows
+    for name in ["file_60", "empty_file_0", "empty_file_1", "empty_file_2"] {
+        let resp = app
+            .client
+            .delete(format!("{base_url}/{name}"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+    }
+    let uploads = (0..4).map(|i| {
+        app.client
+            .put(format!("{base_url}/concurrent_{i}"))
+            .body(vec![0; 40])
+            .send()
+    });
+    let statuses: Vec<u16> = futures::future::join_all(uploads)
+        .await
+        .into_iter()
+        .map(|resp| resp.map(|resp| resp.status().as_u16()))
+        .collect::<Result<_, _>>()?;
+
+    // Assert : the uploads cannot race past the quota
+    assert_eq!(statuses.iter().filter(|status| **status == 201).count(), 2);
+    assert_eq!(statuses.iter().filter(|status| **status == 507).count(), 2);
+    let body = propfind(&app, &base_url).send().await?.text().await?;
+    assert!(body.contains("<D:quota-used-bytes>80</D:quota-used-bytes>"));
+
+    // Act : create a resumable upload, then terminate it
+    let resp = app
+        .client
+        .post(format!("{base_url}/.vestibule/uploads"))
+        .header("Upload-Length", 20)
+        .header(
+            "Upload-Metadata",
+            format!("path {}", Base64::encode_string(b"/resumable")),
+        )
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let location = resp.headers()["Location"].to_str()?.to_owned();
+    let body = propfind(&app, &base_url).send().await?.text().await?;
+    assert!(body.contains("<D:quota-used-bytes>100</D:quota-used-bytes>"));
+    let resp = app
+        .client
+        .delete(format!("{base_url}{location}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    // Assert : the room taken by the upload is given back
+    let body = propfind(&app, &base_url).send().await?.text().await?;
+    assert!(body.contains("<D:quota-used-bytes>80</D:quota-used-bytes>"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn trash_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let base_url = format!("http://files1.vestibule.io:{}", app.port);
+    let url = format!("{base_url}/file_to_trash.txt");
+    app.client.put(&url).body(b"abc".to_vec()).send().await?;
+
+    // Act : delete the file
+    let resp = app.client.delete(&url).send().await?;
+    assert_eq!(resp.status(), 204);
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    // Assert that it is in the trash, which is hidden from the dav
+    let resp = app
+        .client
+        .get(format!("{base_url}/.vestibule/trash"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let items: serde_json::Value = resp.json().await?;
+    assert_eq!(items[0]["path"], "/file_to_trash.txt");
+    assert_eq!(items[0]["size"], 3);
+    let id = items[0]["id"].as_str().unwrap().to_owned();
+    let resp = propfind(&app, &base_url).send().await?;
+    assert!(!resp.text().await?.contains(".vestibule"));
+    let resp = propfind(&app, &format!("{base_url}/.vestibule"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    let resp = app
+        .client
+        .get(format!("{base_url}/.vestibule/trash/{id}.json"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    // Act : restore the file
+    let resp = app
+        .client
+        .post(format!("{base_url}/.vestibule/trash/{id}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "abc");
+
+    // Act : overwrite the file with a move
+    let other_url = format!("{base_url}/other_file.txt");
+    app.client
+        .put(&other_url)
+        .body(b"def".to_vec())
+        .send()
+        .await?;
+    let resp = mv(&app, &other_url)
+        .header("Destination", &url)
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "def");
+
+    // Assert that the overwritten file is in the trash
+    let resp = app
+        .client
+        .get(format!("{base_url}/.vestibule/trash"))
+        .send()
+        .await?;
+    let items: serde_json::Value = resp.json().await?;
+    assert_eq!(items.as_array().unwrap().len(), 1);
+    assert_eq!(items[0]["path"], "/file_to_trash.txt");
+    let id = items[0]["id"].as_str().unwrap().to_owned();
+
+    // Act and Assert : the item cannot be restored over the new file
+    let resp = app
+        .client
+        .post(format!("{base_url}/.vestibule/trash/{id}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 409);
+
+    // Act : purge the item
+    let resp = app
+        .client
+        .delete(format!("{base_url}/.vestibule/trash/{id}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    // Assert that the trash is empty
+    let resp = app
+        .client
+        .get(format!("{base_url}/.vestibule/trash"))
+        .send()
+        .await?;
+    assert_eq!(resp.text().await?, "[]");
+
+    // Assert that davs without trash have no trash api
+    let resp = app
+        .client
+        .get(format!(
+            "http://files3.vestibule.io:{}/.vestibule/trash",
+            app.port
+        ))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+    Ok(())
+}
+
+#[tokio::test]
+async fn versions_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let url = format!("http://files2.vestibule.io:{}/versioned.txt", app.port);
+
+    // Act : write the file three times
+    for content in ["v1", "v2", "v3"] {
+        let resp = app.client.put(&url).body(content).send().await?;
+        assert_eq!(resp.status(), 201);
+    }
+
+    // Assert that the previous versions are kept
+    let resp = app.client.get(format!("{url}?versions")).send().await?;
+    assert_eq!(resp.status(), 200);
+    let versions: serde_json::Value = resp.json().await?;
+    assert_eq!(versions.as_array().unwrap().len(), 2);
+    assert_eq!(versions[0]["size"], 2);
+    let (v2, v1) = (
+        versions[0]["id"].as_str().unwrap().to_owned(),
+        versions[1]["id"].as_str().unwrap().to_owned(),
+    );
+    let resp = app.client.get(format!("{url}?version={v2}")).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await?, "v2");
+    let resp = app
+        .client
+        .get(format!("{url}?version=unknown"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    // Assert that the versions stay encrypted at rest
+    let mut versions_files = vec![];
+    let mut dirs = vec![std::path::PathBuf::from(format!(
+        "data/{}/dir2/.vestibule/versions",
+        app.id
+    ))];
+    while let Some(dir) = dirs.pop() {
+        for entry in std::fs::read_dir(dir)? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                versions_files.push(entry.path());
+            }
+        }
+    }
+    assert_eq!(versions_files.len(), 2);
+    for file in versions_files {
+        let content = std::fs::read(file)?;
+        assert!(content != b"v1" && content != b"v2");
+    }
+
+    // Act : restore the first version
+    let resp = app
+        .client
+        .post(format!("{url}?version={v1}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    // Assert that the replaced content is in the history
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "v1");
+    let resp = app.client.get(format!("{url}?versions")).send().await?;
+    let versions: serde_json::Value = resp.json().await?;
+    assert_eq!(versions.as_array().unwrap().len(), 2);
+    let v3 = versions[0]["id"].as_str().unwrap().to_owned();
+    let resp = app.client.get(format!("{url}?version={v3}")).send().await?;
+    assert_eq!(resp.text().await?, "v3");
+
+    // Act : overwrite the file with a copy
+    let other_url = format!("http://files2.vestibule.io:{}/other.txt", app.port);
+    app.client.put(&other_url).body("other").send().await?;
+    let resp = copy(&app, &other_url)
+        .header("Destination", &url)
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+
+    // Assert that the number of versions is limited
+    let resp = app.client.get(format!("{url}?versions")).send().await?;
+    let versions: serde_json::Value = resp.json().await?;
+    assert_eq!(versions.as_array().unwrap().len(), 3);
+    let resp = app
+        .client
+        .get(format!(
+            "{url}?version={}",
+            versions[0]["id"].as_str().unwrap()
+        ))
+        .send()
+        .await?;
+    assert_eq!(resp.text().await?, "v1");
+
+    // Act : move the file
+    let moved_url = format!("http://files2.vestibule.io:{}/moved.txt", app.port);
+    let resp = mv(&app, &url)
+        .header("Destination", &moved_url)
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+
+    // Assert that the history follows the file
+    let resp = app
+        .client
+        .get(format!("{moved_url}?versions"))
+        .send()
+        .await?;
+    let versions: serde_json::Value = resp.json().await?;
+    assert_eq!(versions.as_array().unwrap().len(), 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn resumable_upload_dav_test() -> Result<()> {
+    let app = TestApp::spawn().await;
+    resume_upload(&app, "files1", false).await?;
+    resume_upload(&app, "files2", true).await?;
+    Ok(())
+}
+
+async fn resume_upload(app: &TestApp, dav: &str, encrypted: bool) -> Result<()> {
+    // Arrange
+    let uploads_url = format!("http://{dav}.vestibule.io:{}/.vestibule/uploads", app.port);
+    let content: Vec<u8> = (0..2_500_000).map(|i| (i % 251) as u8).collect();
+
+    // Act : create the upload
+    let resp = app
+        .client
+        .post(&uploads_url)
+        .header("Tus-Resumable", "1.0.0")
+        .header("Upload-Length", content.len())
+        .header(
+            "Upload-Metadata",
+            format!("path {}", Base64::encode_string(b"/resumed/file.bin")),
+        )
+        .send()
+        .await?;
+
+    // Assert
+    assert_eq!(resp.status(), 201);
+    let location = resp.headers()["Location"].to_str()?.to_owned();
+    let upload_url = format!("http://{dav}.vestibule.io:{}{location}", app.port);
+
+    // Act : send the first part, as if the connection was lost
+    let resp = app
+        .client
+        .patch(&upload_url)
+        .header("Content-Type", "application/offset+octet-stream")
+        .header("Upload-Offset", 0)
+        .body(content[..1_500_000].to_vec())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    // Assert that the offset is kept, encrypted davs only keeping whole chunks
+    let resp = app.client.head(&upload_url).send().await?;
+    assert_eq!(resp.status(), 200);
+    let offset: usize = resp.headers()["Upload-Offset"].to_str()?.parse()?;
+    assert_eq!(offset, if encrypted { 1_000_000 } else { 1_500_000 });
+    assert_eq!(resp.headers()["Upload-Length"], "2500000");
+    // The file is not there until the upload is complete
+    let file_url = format!("http://{dav}.vestibule.io:{}/resumed/file.bin", app.port);
+    let resp = app.client.get(&file_url).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    // Act : try to resume at a wrong offset
+    let resp = app
+        .client
+        .patch(&upload_url)
+        .header("Content-Type", "application/offset+octet-stream")
+        .header("Upload-Offset", 0)
+        .body(content.clone())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 409);
+
+    // Act : resume the upload
+    let resp = app
+        .client
+        .patch(&upload_url)
+        .header("Content-Type", "application/offset+octet-stream")
+        .header("Upload-Offset", offset)
+        .body(content[offset..].to_vec())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+    assert_eq!(resp.headers()["Upload-Offset"], "2500000");
+
+    // Assert that the file is complete and the upload gone
+    let resp = app.client.get(&file_url).send().await?;
+    assert_eq!(resp.status(), 200);
+    assert!(resp.bytes().await? == content);
+    let resp = app.client.head(&upload_url).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    // Act : create an upload outside the dav, and one that is terminated
+    let resp = app
+        .client
+        .post(&uploads_url)
+        .header("Upload-Length", 1)
+        .header(
+            "Upload-Metadata",
+            format!("path {}", Base64::encode_string(b"/../file.bin")),
+        )
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    let resp = app
+        .client
+        .post(&uploads_url)
+        .header("Upload-Length", 1)
+        .header(
+            "Upload-Metadata",
+            format!("path {}", Base64::encode_string(b"/aborted.bin")),
+        )
+        .send()
+        .await?;
+    let location = resp.headers()["Location"].to_str()?.to_owned();
+    let upload_url = format!("http://{dav}.vestibule.io:{}{location}", app.port);
+    let resp = app.client.delete(&upload_url).send().await?;
+
+    // Assert
+    assert_eq!(resp.status(), 204);
+    let resp = app.client.head(&upload_url).send().await?;
+    assert_eq!(resp.status(), 404);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn atomic_put_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let url = format!("http://files1.vestibule.io:{}/atomic.txt", app.port);
+    let resp = app.client.put(&url).body("original").send().await?;
+    assert_eq!(resp.status(), 201);
+    let etag = resp.headers()["ETag"].to_str()?.to_owned();
+
+    // Act : abort an upload replacing the file
+    let chunks: Vec<Result<_, std::io::Error>> = vec![
+        Ok(vec![0; 30]),
+        Err(std::io::Error::new(std::io::ErrorKind::Other, "aborted")),
+    ];
+    let resp = app
+        .client
+        .put(&url)
+        .body(reqwest::Body::wrap_stream(futures::stream::iter(chunks)))
+        .send()
+        .await;
+
+    // Assert that the original content is preserved, and that nothing is left behind
+    assert!(resp.is_err());
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "original");
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    for entry in std::fs::read_dir(format!("data/{}/dir1", app.id))? {
+        assert!(!entry?
+            .file_name()
+            .to_string_lossy()
+            .ends_with(".vestibule-part"));
+    }
+
+    // Act and Assert : the preconditions are checked
+    let resp = app
+        .client
+        .put(&url)
+        .header("If-None-Match", "*")
+        .body("replaced")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 412);
+    let resp = app
+        .client
+        .put(&url)
+        .header("If-Match", r#""0-0""#)
+        .body("replaced")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 412);
+    let resp = app
+        .client
+        .put(format!(
+            "http://files1.vestibule.io:{}/missing.txt",
+            app.port
+        ))
+        .header("If-Match", "*")
+        .body("created")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 412);
+    let resp = app
+        .client
+        .put(&url)
+        .header("If-Match", &etag)
+        .body("replaced")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app
+        .client
+        .put(format!("http://files1.vestibule.io:{}/new.txt", app.port))
+        .header("If-None-Match", "*")
+        .body("created")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Assert
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "replaced");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn partial_update_dav_test() -> Result<()> {
+    let app = TestApp::spawn().await;
+    partial_update(&app, "files1", "dir1", false).await?;
+    partial_update(&app, "files2", "dir2", true).await?;
+    Ok(())
+}
+
+async fn partial_update(app: &TestApp, dav: &str, dir: &str, encrypted: bool) -> Result<()> {
+    // Arrange : a file spanning several encryption chunks
+    let url = format!("http://{dav}.vestibule.io:{}/patched.bin", app.port);
+    let mut content: Vec<u8> = (0..2_100_000).map(|i| (i % 251) as u8).collect();
+    let resp = app.client.put(&url).body(content.clone()).send().await?;
+    assert_eq!(resp.status(), 201);
+    let nonce = std::fs::read(format!("data/{}/{dir}/patched.bin", app.id))?[..19].to_vec();
+    // A link standing for a reader of the file, or for its content in a content store
+    let linked = format!("data/{}/{dir}/linked.bin", app.id);
+    std::fs::hard_link(format!("data/{}/{dir}/patched.bin", app.id), &linked)?;
+
+    // Act : overwrite bytes across a chunk boundary with a PUT
+    let patch = vec![1; 1000];
+    let resp = app
+        .client
+        .put(&url)
+        .header("Content-Range", "bytes 999500-1000499/*")
+        .body(patch.clone())
+        .send()
+        .await?;
+
+    // Assert
+    assert_eq!(resp.status(), 204);
+    let original = content.clone();
+    content[999_500..1_000_500].copy_from_slice(&patch);
+    let resp = app.client.get(&url).send().await?;
+    assert!(resp.bytes().await? == content);
+    // A linked file is replaced, never written in place
+    if !encrypted {
+        assert!(std::fs::read(&linked)? == original);
+    }
+    std::fs::remove_file(&linked)?;
+    // A file with no other link is written in place
+    #[cfg(unix)]
+    if !encrypted {
+        use std::os::unix::fs::MetadataExt;
+        let unlinked = format!("data/{}/{dir}/unlinked.bin", app.id);
+        std::fs::write(&unlinked, "0123456789")?;
+        let inode = std::fs::metadata(&unlinked)?.ino();
+        let resp = app
+            .client
+            .put(format!(
+                "http://{dav}.vestibule.io:{}/unlinked.bin",
+                app.port
+            ))
+            .header("Content-Range", "bytes 2-4/*")
+            .body("abc")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+        assert_eq!(std::fs::read_to_string(&unlinked)?, "01abc56789");
+        assert_eq!(std::fs::metadata(&unlinked)?.ino(), inode);
+    }
+    if encrypted {
+        // The file is encrypted again with a new nonce
+        let new_nonce = std::fs::read(format!("data/{}/{dir}/patched.bin", app.id))?[..19].to_vec();
+        assert!(new_nonce != nonce);
+    }
+
+    // Act : append and replace the end of the file with SabreDAV partial updates
+    for (range, patch) in [("append", vec![2; 10]), ("bytes=-5", vec![3; 8])] {
+        let resp = app
+            .client
+            .patch(&url)
+            .header("Content-Type", "application/x-sabredav-partialupdate")
+            .header("X-Update-Range", range)
+            .body(patch)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+    }
+
+    // Assert
+    content.extend(vec![2; 5]);
+    content.extend(vec![3; 8]);
+    let resp = app.client.get(&url).send().await?;
+    assert!(resp.bytes().await? == content);
+
+    // Act and Assert : ranges out of the file or not matching the body are refused
+    let resp = app
+        .client
+        .patch(&url)
+        .header("Content-Type", "application/x-sabredav-partialupdate")
+        .header("X-Update-Range", "bytes=5000000-")
+        .body("late")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 416);
+    let resp = app
+        .client
+        .patch(&url)
+        .header("Content-Type", "application/x-sabredav-partialupdate")
+        .header("X-Update-Range", "bytes=0-10")
+        .body("short")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 416);
+    let resp = app
+        .client
+        .patch(&url)
+        .header("X-Update-Range", "bytes=0-4")
+        .body("wrong")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 415);
+    let resp = app.client.get(&url).send().await?;
+    assert!(resp.bytes().await? == content);
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn content_store_dav_test() -> Result<()> {
+    use std::os::unix::fs::MetadataExt;
+
+    let app = TestApp::spawn().await;
+    for (dav, dir) in [("files1", "dir1"), ("files2", "dir2")] {
+        // Arrange
+        let base_url = format!("http://{dav}.vestibule.io:{}", app.port);
+        let inode = |name: &str| {
+            std::fs::metadata(format!("data/{}/{dir}/{name}", app.id))
+                .unwrap()
+                .ino()
+        };
+
+        // Act : upload the same content twice, and copy it
+        for name in ["dedup_a.txt", "dedup_b.txt"] {
+            let resp = app
+                .client
+                .put(format!("{base_url}/{name}"))
+                .body("deduplicated content")
+                .send()
+                .await?;
+            assert_eq!(resp.status(), 201);
+        }
+        let resp = copy(&app, &format!("{base_url}/dedup_a.txt"))
+            .header("Destination", format!("{base_url}/dedup_c.txt"))
+            .send()
+            .await?;
+        assert!(resp.status().is_success());
+
+        // Assert that the content is stored once
+        assert_eq!(inode("dedup_a.txt"), inode("dedup_b.txt"));
+        assert_eq!(inode("dedup_a.txt"), inode("dedup_c.txt"));
+
+        // Act : replace one of the files
+        let resp = app
+            .client
+            .put(format!("{base_url}/dedup_b.txt"))
+            .body("new content")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+
+        // Assert that the other files are untouched
+        assert_ne!(inode("dedup_a.txt"), inode("dedup_b.txt"));
+        for (name, content) in [
+            ("dedup_a.txt", "deduplicated content"),
+            ("dedup_b.txt", "new content"),
+            ("dedup_c.txt", "deduplicated content"),
+        ] {
+            let resp = app.client.get(format!("{base_url}/{name}")).send().await?;
+            assert_eq!(resp.text().await?, content);
+        }
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn chunked_storage_dav_test() -> Result<()> {
+    // Arrange : a content spanning several chunks, that does not repeat itself
+    let app = TestApp::spawn().await;
+    let mut state: u32 = 7;
+    let content: Vec<u8> = (0..6_000_000)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    let chunks = || -> Vec<std::path::PathBuf> {
+        std::fs::read_dir(format!("data/{}/chunks/chunks", app.id))
+            .map(|prefixes| {
+                prefixes
+                    .flat_map(|prefix| std::fs::read_dir(prefix.unwrap().path()).unwrap())
+                    .map(|entry| entry.unwrap().path())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    for (dav, dir) in [
+        ("chunked-files", "chunked"),
+        ("secret-chunked-files", "secret-chunked"),
+    ] {
+        let base_url = format!("http://{dav}.vestibule.io:{}", app.port);
+        let before = chunks();
+
+        // Act
+        let resp = app
+            .client
+            .put(format!("{base_url}/dir/file.bin"))
+            .body(content.clone())
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+
+        // Assert that the dav directory only references the chunks of the file
+        let stored = chunks();
+        assert!(stored.len() > before.len() + 1);
+        let manifest = std::fs::read(format!("data/{}/{dir}/dir/file.bin", app.id))?;
+        assert!(manifest.len() < 4096);
+        let resp = app
+            .client
+            .get(format!("{base_url}/dir/file.bin"))
+            .send()
+            .await?;
+        assert!(resp.bytes().await? == content);
+        let resp = app
+            .client
+            .get(format!("{base_url}/dir/file.bin"))
+            .header(RANGE, "bytes=2999990-3000009")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 206);
+        assert!(resp.bytes().await? == content[2999990..3000010]);
+        let resp = propfind(&app, &format!("{base_url}/dir")).send().await?;
+        assert!(resp
+            .text()
+            .await?
+            .contains("<D:getcontentlength>6000000</D:getcontentlength>"));
+
+        // Assert that the chunks of the encrypted davs are encrypted
+        if dav == "secret-chunked-files" {
+            for chunk in stored.iter().filter(|chunk| !before.contains(chunk)) {
+                assert!(!std::fs::read(chunk)?
+                    .windows(32)
+                    .any(|window| window == &content[1000..1032]));
+            }
+        }
+
+        // Act : copy the file
+        let resp = copy(&app, &format!("{base_url}/dir/file.bin"))
+            .header("Destination", format!("{base_url}/copy.bin"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+
+        // Assert that the copy shares the chunks of the file
+        assert_eq!(chunks().len(), stored.len());
+        let resp = app
+            .client
+            .get(format!("{base_url}/copy.bin"))
+            .send()
+            .await?;
+        assert!(resp.bytes().await? == content);
+
+        // Assert that the same plain content uploaded again is not stored twice
+        if dav == "chunked-files" {
+            let resp = app
+                .client
+                .put(format!("{base_url}/again.bin"))
+                .body(content.clone())
+                .send()
+                .await?;
+            assert_eq!(resp.status(), 201);
+            assert_eq!(chunks().len(), stored.len());
+        }
+
+        // Act : append to the copy
+        let resp = app
+            .client
+            .patch(format!("{base_url}/copy.bin"))
+            .header("Content-Type", "application/x-sabredav-partialupdate")
+            .header("X-Update-Range", "append")
+            .body("appended")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+
+        // Assert that the plain files only differ by their last chunk
+        if dav == "chunked-files" {
+            assert_eq!(chunks().len(), stored.len() + 1);
+        }
+        let resp = app
+            .client
+            .get(format!("{base_url}/copy.bin"))
+            .send()
+            .await?;
+        let mut appended = content.clone();
+        appended.extend(b"appended");
+        assert!(resp.bytes().await? == appended);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn storage_backends_dav_test() -> Result<()> {
+    let app = TestApp::spawn().await;
+    for (dav, dir) in [("memory-files", "memory"), ("s3-files", "s3")] {
+        // Arrange : a content spanning several encryption chunks
+        let base_url = format!("http://{dav}.vestibule.io:{}", app.port);
+        let content: Vec<u8> = (0..2_500_000).map(|i| (i % 251) as u8).collect();
+
+        // Act : put a file in a missing directory
+        let resp = app
+            .client
+            .put(format!("{base_url}/dir/file.bin"))
+            .body(content.clone())
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+        assert!(resp.headers().contains_key("etag"));
+
+        // Assert that the file can be got, whole or by ranges
+        let resp = app
+            .client
+            .get(format!("{base_url}/dir/file.bin"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 200);
+        assert!(resp.bytes().await? == content);
+        let resp = app
+            .client
+            .get(format!("{base_url}/dir/file.bin"))
+            .header(RANGE, "bytes=999990-1000009")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 206);
+        assert!(resp.bytes().await? == content[999990..1000010]);
+        let resp = propfind(&app, &format!("{base_url}/dir")).send().await?;
+        assert_eq!(resp.status(), 207);
+        let body = resp.text().await?;
+        assert!(body.contains("<D:href>/dir/file.bin</D:href>"));
+        assert!(body.contains("<D:getcontentlength>2500000</D:getcontentlength>"));
+
+        // Act and Assert : create directories
+        let resp = mkcol(&app, &format!("{base_url}/other")).send().await?;
+        assert_eq!(resp.status(), 201);
+        let resp = mkcol(&app, &format!("{base_url}/missing/child"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 409);
+
+        // Act : copy the file, append to it and move its directory
+        let resp = copy(&app, &format!("{base_url}/dir/file.bin"))
+            .header("Destination", format!("{base_url}/other/copy.bin"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+        let resp = app
+            .client
+            .patch(format!("{base_url}/dir/file.bin"))
+            .header("Content-Type", "application/x-sabredav-partialupdate")
+            .header("X-Update-Range", "append")
+            .body("appended")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+        let resp = mv(&app, &format!("{base_url}/dir"))
+            .header("Destination", format!("{base_url}/moved"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+
+        // Assert
+        let resp = app
+            .client
+            .get(format!("{base_url}/other/copy.bin"))
+            .send()
+            .await?;
+        assert!(resp.bytes().await? == content);
+        let resp = app
+            .client
+            .get(format!("{base_url}/moved/file.bin"))
+            .send()
+            .await?;
+        let mut appended = content.clone();
+        appended.extend(b"appended");
+        assert!(resp.bytes().await? == appended);
+        let resp = app
+            .client
+            .get(format!("{base_url}/dir/file.bin"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 404);
+
+        // Act and Assert : delete the moved directory
+        let resp = app
+            .client
+            .delete(format!("{base_url}/moved"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 204);
+        let resp = propfind(&app, &format!("{base_url}/moved/file.bin"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 404);
+
+        // Assert that nothing was written in the local directory, where the features relying on it are not available
+        assert!(!std::path::Path::new(&format!("data/{}/{dir}", app.id)).exists());
+        let resp = app
+            .client
+            .post(format!("{base_url}/.vestibule/uploads"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 404);
+    }
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn passphrase_rotation_and_reencryption_dav_test() -> Result<()> {
+    // Arrange
+    let mut app = TestApp::spawn().await;
+    let url = format!("http://files2.vestibule.io:{}/rotated.txt", app.port);
+    for content in ["v1", "v2"] {
+        let resp = app.client.put(&url).body(content).send().await?;
+        assert_eq!(resp.status(), 201);
+    }
+    let path = format!("data/{}/dir2/rotated.txt", app.id);
+    let encrypted = std::fs::read(&path)?;
+
+    // Assert that the legacy key of the dav was wrapped in the configuration
+    let fp = format!("{}.yaml", &app.id);
+    assert!(std::fs::read_to_string(&fp)?.contains("salt:"));
+
+    // Act : change the passphrase
+    log_as(&app, "admin").await;
+    let resp = app
+        .client
+        .get(format!("http://vestibule.io:{}/api/admin/davs", app.port))
+        .send()
+        .await?;
+    let davs: serde_json::Value = resp.json().await?;
+    let mut dav = davs
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|dav| dav["id"] == 2)
+        .unwrap()
+        .clone();
+    dav["passphrase"] = "ABCDEFG".into();
+    let resp = app
+        .client
+        .post(format!("http://vestibule.io:{}/api/admin/davs", app.port))
+        .json(&dav)
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    app.client
+        .get(format!("http://vestibule.io:{}/reload", app.port))
+        .send()
+        .await?;
+    app.is_ready().await;
+
+    // Assert that the files are still readable, and were left as they are
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "v2");
+    assert_eq!(std::fs::read(&path)?, encrypted);
+
+    // Act : re-encrypt the files with a new key, the configuration is reloaded once done
+    log_as(&app, "admin").await;
+    let reencryption_url = format!(
+        "http://vestibule.io:{}/api/admin/davs/2/reencryption",
+        app.port
+    );
+    let resp = app.client.post(&reencryption_url).send().await?;
+    assert_eq!(resp.status(), 202);
+    tokio::time::timeout(std::time::Duration::from_secs(30), app.is_ready())
+        .await
+        .expect("re-encryption did not complete");
+
+    // Assert that the files and their versions were re-encrypted
+    log_as(&app, "admin").await;
+    let resp = app.client.get(&reencryption_url).send().await?;
+    let job: serde_json::Value = resp.json().await?;
+    assert_eq!(job["running"], false);
+    assert_eq!(job["error"], serde_json::Value::Null);
+    assert!(job["reencrypted"].as_u64().unwrap() >= 2);
+    assert_ne!(std::fs::read(&path)?, encrypted);
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "v2");
+    let resp = app.client.get(format!("{url}?versions")).send().await?;
+    let versions: serde_json::Value = resp.json().await?;
+    let v1 = versions[0]["id"].as_str().unwrap().to_owned();
+    let resp = app.client.get(format!("{url}?version={v1}")).send().await?;
+    assert_eq!(resp.text().await?, "v1");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_file_header_dav_test() -> Result<()> {
+    use chacha20poly1305::aead::{stream, NewAead};
+    use chacha20poly1305::XChaCha20Poly1305;
+    use sha2::Sha256;
+
+    // Arrange
+    let app = TestApp::spawn().await;
+    let base_url = format!("http://files2.vestibule.io:{}", app.port);
+
+    // Act : write a file
+    let resp = app
+        .client
+        .put(format!("{base_url}/new.txt"))
+        .body("new content")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Assert that it begins with the header : magic bytes, version, cipher and chunk size
+    let content = std::fs::read(format!("data/{}/dir2/new.txt", app.id))?;
+    assert_eq!(&content[..4], b"VSTB");
+    assert_eq!(content[4], 1);
+    assert_eq!(content[5], 1);
+    assert_eq!(&content[6..10], &1_000_000u32.to_be_bytes());
+
+    // Arrange : a file written before the header, beginning with the nonce, encrypted with the hash of the passphrase
+    let key = Sha256::digest(b"ABCD123");
+    let nonce = [7; 19];
+    let mut legacy = nonce.to_vec();
+    legacy.extend(
+        stream::EncryptorBE32::from_aead(XChaCha20Poly1305::new(&key), nonce.as_ref().into())
+            .encrypt_last(b"legacy content".as_ref())
+            .unwrap(),
+    );
+    std::fs::write(format!("data/{}/dir2/legacy.txt", app.id), legacy)?;
+
+    // Assert that it can still be read, sized and ranged
+    let resp = app
+        .client
+        .get(format!("{base_url}/legacy.txt"))
+        .send()
+        .await?;
+    assert_eq!(resp.text().await?, "legacy content");
+    let resp = propfind(&app, &format!("{base_url}/legacy.txt"))
+        .send()
+        .await?;
+    assert!(resp
+        .text()
+        .await?
+        .contains("<D:getcontentlength>14</D:getcontentlength>"));
+    let resp = app
+        .client
+        .get(format!("{base_url}/legacy.txt"))
+        .header(RANGE, "bytes=7-13")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.text().await?, "content");
+    let resp = propfind(&app, &format!("{base_url}/new.txt"))
+        .send()
+        .await?;
+    assert!(resp
+        .text()
+        .await?
+        .contains("<D:getcontentlength>11</D:getcontentlength>"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn encrypted_names_dav_test() -> Result<()> {
+    // Arrange : add a dav encrypting its file names
+    let mut app = TestApp::spawn().await;
+    log_as(&app, "admin").await;
+    let admin_url = format!("http://vestibule.io:{}/api/admin/davs", app.port);
+    let davs: serde_json::Value = app.client.get(&admin_url).send().await?.json().await?;
+    let mut dav = davs
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|dav| dav["id"] == 2)
+        .unwrap()
+        .clone();
+    dav["id"] = 20.into();
+    dav["host"] = "names-files".into();
+    dav["directory"] = format!("./data/{}/names", app.id).into();
+    dav["versioning"] = serde_json::Value::Null;
+    dav["content_store"] = "".into();
+    dav["trash"] = serde_json::json!({ "retention_days": null });
+    dav["wrapped_key"] = serde_json::Value::Null;
+    dav["encrypt_names"] = true.into();
+    let resp = app.client.post(&admin_url).json(&dav).send().await?;
+    assert_eq!(resp.status(), 201);
+    app.client
+        .get(format!("http://vestibule.io:{}/reload", app.port))
+        .send()
+        .await?;
+    app.is_ready().await;
+    std::fs::create_dir_all(format!("data/{}/names", app.id))?;
+
+    // Act : create a directory and a file
+    let base_url = format!("http://names-files.vestibule.io:{}", app.port);
+    let resp = mkcol(&app, &format!("{base_url}/docs")).send().await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app
+        .client
+        .put(format!("{base_url}/docs/report.txt"))
+        .body("secret content")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Assert that neither the names nor the contents are in clear on disk
+    let mut entries = std::fs::read_dir(format!("data/{}/names", app.id))?;
+    let dir = entries.next().unwrap()?;
+    assert!(entries.next().is_none());
+    assert!(!dir.file_name().to_string_lossy().contains("docs"));
+    let file = std::fs::read_dir(dir.path())?.next().unwrap()?;
+    assert!(!file.file_name().to_string_lossy().contains("report"));
+    assert!(!String::from_utf8_lossy(&std::fs::read(file.path())?).contains("secret"));
+
+    // Assert that the dav works with the plain names
+    let resp = app
+        .client
+        .get(format!("{base_url}/docs/report.txt"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/plain");
+    assert!(resp.headers()["content-disposition"]
+        .to_str()?
+        .contains("report.txt"));
+    assert_eq!(resp.text().await?, "secret content");
+    let resp = propfind(&app, &format!("{base_url}/docs")).send().await?;
+    let body = resp.text().await?;
+    assert!(body.contains("<D:href>/docs/report.txt</D:href>"));
+    assert!(body.contains("<D:getcontentlength>14</D:getcontentlength>"));
+    let resp = app
+        .client
+        .get(format!("{base_url}/?q=REPORT"))
+        .send()
+        .await?;
+    let found: serde_json::Value = resp.json().await?;
+    assert_eq!(found[0]["name"], "docs/report.txt");
+
+    // Act : move then delete the file
+    let resp = mv(&app, &format!("{base_url}/docs/report.txt"))
+        .header("Destination", format!("{base_url}/docs/moved.txt"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app
+        .client
+        .get(format!("{base_url}/docs/moved.txt"))
+        .send()
+        .await?;
+    assert_eq!(resp.text().await?, "secret content");
+    let resp = app
+        .client
+        .delete(format!("{base_url}/docs/moved.txt"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+
+    // Assert that the trash shows the plain path
+    let resp = app
+        .client
+        .get(format!("{base_url}/.vestibule/trash"))
+        .send()
+        .await?;
+    let items: serde_json::Value = resp.json().await?;
+    assert_eq!(items[0]["path"], "/docs/moved.txt");
+
+    // Assert that the names encryption cannot be switched off on the existing dav
+    log_as(&app, "admin").await;
+    dav["encrypt_names"] = false.into();
+    let resp = app.client.post(&admin_url).json(&dav).send().await?;
+    assert_eq!(resp.status(), 400);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn migration_dav_test() -> Result<()> {
+    // Arrange
+    let mut app = TestApp::spawn().await;
+    let url = format!("http://files1.vestibule.io:{}/migrated.txt", app.port);
+    let resp = app.client.put(&url).body("migrated content").send().await?;
+    assert_eq!(resp.status(), 201);
+    let path = format!("data/{}/dir1/migrated.txt", app.id);
+
+    // Assert that the encryption cannot be switched by updating the dav
+    log_as(&app, "admin").await;
+    let admin_url = format!("http://vestibule.io:{}/api/admin/davs", app.port);
+    let davs: serde_json::Value = app.client.get(&admin_url).send().await?.json().await?;
+    let mut dav = davs
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|dav| dav["id"] == 1)
+        .unwrap()
+        .clone();
+    dav["passphrase"] = "MIGRATE1".into();
+    let resp = app.client.post(&admin_url).json(&dav).send().await?;
+    assert_eq!(resp.status(), 409);
+
+    // Act : migrate the dav to encrypted files
+    let migration_url = format!("{admin_url}/1/migration");
+    let resp = app.client.post(&migration_url).send().await?;
+    assert_eq!(resp.status(), 400);
+    let resp = app
+        .client
+        .post(&migration_url)
+        .json(&serde_json::json!({ "passphrase": "MIGRATE1" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 202);
+    tokio::time::timeout(std::time::Duration::from_secs(30), app.is_ready())
+        .await
+        .expect("migration did not complete");
+
+    // Assert that the files are encrypted, and served as before
+    log_as(&app, "admin").await;
+    let resp = app.client.get(&migration_url).send().await?;
+    let job: serde_json::Value = resp.json().await?;
+    assert_eq!(job["running"], false);
+    assert_eq!(job["error"], serde_json::Value::Null);
+    assert_ne!(std::fs::read(&path)?, b"migrated content");
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "migrated content");
+
+    // Act : migrate the dav back to plain files
+    let resp = app
+        .client
+        .post(&migration_url)
+        .json(&serde_json::json!({ "passphrase": "" }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 202);
+    tokio::time::timeout(std::time::Duration::from_secs(30), app.is_ready())
+        .await
+        .expect("migration did not complete");
+
+    // Assert
+    assert_eq!(std::fs::read(&path)?, b"migrated content");
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "migrated content");
+
+    // Act : restart the server with a migration left pending, as after a crash
+    let config_file = format!("{}.yaml", app.id);
+    let mut config = vestibule::configuration::Config::from_file(&config_file).await?;
+    let dav = config.davs.iter_mut().find(|dav| dav.id == 1).unwrap();
+    dav.migration = Some(vestibule::davs::migration::PendingMigration {
+        passphrase: "MIGRATE2".to_owned(),
+        wrapped_key: Some(vestibule::davs::keys::WrappedKey::new(
+            "MIGRATE2",
+            &vestibule::davs::keys::random_key(),
+        )?),
+    });
+    config.to_file(&config_file).await?;
+    app.client
+        .get(format!("http://vestibule.io:{}/reload", app.port))
+        .send()
+        .await?;
+    app.is_ready().await;
+
+    // Assert that the migration is resumed, then the dav served again
+    tokio::time::timeout(std::time::Duration::from_secs(30), app.is_ready())
+        .await
+        .expect("migration was not resumed");
+    assert_ne!(std::fs::read(&path)?, b"migrated content");
+    let resp = app.client.get(&url).send().await?;
+    assert_eq!(resp.text().await?, "migrated content");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn content_search_dav_test() -> Result<()> {
+    let app = TestApp::spawn().await;
+    log_as(&app, "admin").await;
+    content_search(&app, "files1").await?;
+    content_search(&app, "files2").await?;
+    Ok(())
+}
+
+async fn content_search(app: &TestApp, dav: &str) -> Result<()> {
+    // Arrange
+    let base_url = format!("http://{dav}.vestibule.io:{}", app.port);
+    let search = |query: &str| {
+        let url = format!("{base_url}/search?{query}");
+        async move {
+            let resp = app.client.get(url).send().await?;
+            assert_eq!(resp.status(), 200);
+            let total: usize = resp.headers()["X-Total-Count"].to_str()?.parse()?;
+            let items: serde_json::Value = resp.json().await?;
+            let names: Vec<String> = items
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|item| item["name"].as_str().unwrap().to_owned())
+                .collect();
+            Ok::<_, anyhow::Error>((total, names))
+        }
+    };
+    for (path, content) in [
+        ("/search/notes/meeting.md", "Budget review, budget cuts"),
+        ("/search/notes/ideas.txt", "A bigger budget for the garden"),
+        ("/search/budget.csv", "month,amount"),
+        ("/search/holidays.txt", "Sea and mountains"),
+    ] {
+        let resp = app
+            .client
+            .put(format!("{base_url}{path}"))
+            .body(content)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+    }
+
+    // Act and Assert : the names are ranked before the contents, then by term frequency
+    let (total, names) = search("q=budget").await?;
+    assert_eq!(total, 3);
+    assert_eq!(
+        names,
+        vec!["budget.csv", "notes/meeting.md", "notes/ideas.txt"]
+    );
+
+    // Act and Assert : filters and paging
+    let (total, names) = search("q=budget&type=text/plain").await?;
+    assert_eq!((total, names), (1, vec!["notes/ideas.txt".to_owned()]));
+    let (_, names) = search("type=dir").await?;
+    assert_eq!(names, vec!["notes"]);
+    let (_, names) = search("q=budget&max_size=20").await?;
+    assert_eq!(names, vec!["budget.csv"]);
+    let (_, names) = search("q=budget&after=2000-01-01&before=2100-01-01&offset=1&limit=1").await?;
+    assert_eq!(names, vec!["notes/meeting.md"]);
+    let (_, names) = search("q=budget&before=2000-01-01").await?;
+    assert!(names.is_empty());
+    let resp = app
+        .client
+        .get(format!("{base_url}/search?min_size=big"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 400);
+
+    // Act and Assert : the index follows the changes
+    let resp = mv(app, &format!("{base_url}/search/notes/ideas.txt"))
+        .header("Destination", format!("{base_url}/search/garden.txt"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app
+        .client
+        .put(format!("{base_url}/search/holidays.txt"))
+        .body("No budget left")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = app
+        .client
+        .delete(format!("{base_url}/search/notes/meeting.md"))
+        .send()
+        .await?;
+    assert!(resp.status().is_success());
+    let (_, names) = search("q=budget").await?;
+    assert_eq!(names, vec!["budget.csv", "garden.txt", "holidays.txt"]);
+    let (_, names) = search("q=mountains").await?;
+    assert!(names.is_empty());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn out_of_band_changes_dav_test() -> Result<()> {
+    // Arrange : build the search index
+    let app = TestApp::spawn().await;
+    let search_url = format!("http://files1.vestibule.io:{}?q=another", app.port);
+    let resp = app.client.get(&search_url).send().await?;
+    assert_eq!(resp.text().await?, "[]");
+    let mut changes = vestibule::davs::watcher::subscribe();
+
+    // Act : write files directly on disk, as a synchronization tool would
+    std::fs::create_dir_all(format!("data/{}/dir1/synced", app.id))?;
+    std::fs::write(
+        format!("data/{}/dir1/synced/notes.txt", app.id),
+        "written by another tool",
+    )?;
+
+    // Assert that the search sees them
+    let mut found = String::new();
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        found = app.client.get(&search_url).send().await?.text().await?;
+        if found.contains("notes.txt") {
+            break;
+        }
+    }
+    assert!(found.contains("synced/notes.txt"));
+
+    // Assert that the change was told to the subscribers
+    let told = tokio::time::timeout(std::time::Duration::from_secs(5), async {
+        loop {
+            match changes.recv().await {
+                Ok(change)
+                    if change.directory.contains(&app.id)
+                        && change.paths.iter().any(|path| {
+                            path.ends_with("synced/notes.txt") || path.ends_with("synced")
+                        }) =>
+                {
+                    return true
+                }
+                Err(tokio::sync::broadcast::error::RecvError::Closed) => return false,
+                _ => (),
+            }
+        }
+    })
+    .await;
+    assert_eq!(told, Ok(true));
+
+    // Act and Assert : removals are seen too
+    std::fs::remove_dir_all(format!("data/{}/dir1/synced", app.id))?;
+    for _ in 0..50 {
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        found = app.client.get(&search_url).send().await?.text().await?;
+        if found == "[]" {
+            break;
+        }
+    }
+    assert_eq!(found, "[]");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn thumbnails_dav_test() -> Result<()> {
+    let app = TestApp::spawn().await;
+    log_as(&app, "admin").await;
+    thumbnails(&app, "files1", "dir1", false).await?;
+    thumbnails(&app, "files2", "dir2", true).await?;
+    Ok(())
+}
+
+async fn thumbnails(app: &TestApp, dav: &str, dir: &str, encrypted: bool) -> Result<()> {
+    // Arrange
+    let base_url = format!("http://{dav}.vestibule.io:{}", app.port);
+    let mut png = std::io::Cursor::new(vec![]);
+    image::RgbImage::from_pixel(300, 600, image::Rgb([10, 120, 200]))
+        .write_to(&mut png, image::ImageFormat::Png)?;
+    let resp = app
+        .client
+        .put(format!("{base_url}/photos/tall.png"))
+        .body(png.into_inner())
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+
+    // Act
+    let resp = app
+        .client
+        .get(format!("{base_url}/photos/tall.png?thumbnail=128x128"))
+        .send()
+        .await?;
+
+    // Assert
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["Content-Type"], "image/jpeg");
+    let thumbnail = image::load_from_memory(&resp.bytes().await?)?;
+    assert_eq!((thumbnail.width(), thumbnail.height()), (64, 128));
+    let cached: Vec<_> = walk(format!("data/{}/{dir}/.vestibule/thumbnails", app.id))?;
+    assert_eq!(cached.len(), 1);
+    // The cache is encrypted along with the files
+    assert_eq!(
+        std::fs::read(&cached[0])?.starts_with(&[0xFF, 0xD8]),
+        !encrypted
+    );
+
+    // Act and Assert : the thumbnail follows the content
+    let mut png = std::io::Cursor::new(vec![]);
+    image::RgbImage::from_pixel(600, 300, image::Rgb([10, 120, 200]))
+        .write_to(&mut png, image::ImageFormat::Png)?;
+    app.client
+        .put(format!("{base_url}/photos/tall.png"))
+        .body(png.into_inner())
+        .send()
+        .await?;
+    let resp = app
+        .client
+        .get(format!("{base_url}/photos/tall.png?thumbnail=128x128"))
+        .send()
+        .await?;
+    let thumbnail = image::load_from_memory(&resp.bytes().await?)?;
+    assert_eq!((thumbnail.width(), thumbnail.height()), (128, 64));
+
+    // Act and Assert : bad sizes and files that are not images
+    let resp = app
+        .client
+        .get(format!("{base_url}/photos/tall.png?thumbnail=big"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 400);
+    app.client
+        .put(format!("{base_url}/photos/notes.txt"))
+        .body("not an image")
+        .send()
+        .await?;
+    let resp = app
+        .client
+        .get(format!("{base_url}/photos/notes.txt?thumbnail=64x64"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 415);
+
+    Ok(())
+}
+
+fn walk(dir: String) -> Result<Vec<std::path::PathBuf>> {
+    let mut files = vec![];
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(walk(path.to_string_lossy().into_owned())?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+#[tokio::test]
+async fn shares_dav_test() -> Result<()> {
+    // Arrange
+    let app = TestApp::spawn().await;
+    log_as(&app, "admin").await;
+    let base_url = format!("http://files1.vestibule.io:{}", app.port);
+    let shares_url = format!("http://vestibule.io:{}/api/user/shares", app.port);
+    let share_url = format!("http://vestibule.io:{}/share", app.port);
+    for (path, content) in [
+        ("shared/report.txt", "report content"),
+        ("shared/sub/inner.txt", "inner content"),
+    ] {
+        let resp = app
+            .client
+            .put(format!("{base_url}/{path}"))
+            .body(content)
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 201);
+    }
+    let resp = mkcol(&app, &format!("{base_url}/drop")).send().await?;
+    assert_eq!(resp.status(), 201);
+
+    // Act and Assert : the shares are checked on creation
+    for body in [
+        r#"{"dav_id":1,"path":"shared/../dira"}"#,
+        r#"{"dav_id":1,"path":"shared/report.txt","mode":"upload_only"}"#,
+        r#"{"dav_id":1,"path":"shared","expires_at":1000}"#,
+        r#"{"dav_id":42,"path":"shared"}"#,
+    ] {
+        let resp = app
+            .client
+            .post(&shares_url)
+            .body(body)
+            .header("Content-Type", "application/json")
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 400);
+    }
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"missing"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+
+    // Act : share a directory, read only, with a password and a download limit
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"/shared/","password":"secret","max_downloads":2}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let share: serde_json::Value = resp.json().await?;
+    assert_eq!(share["password"], "REDACTED");
+    assert_eq!(share["path"], "shared");
+    let token = share["token"].as_str().unwrap().to_owned();
+
+    // Assert : the visitors need the password
+    let file_url = format!("{share_url}/{token}/report.txt");
+    let resp = app.client.get(&file_url).send().await?;
+    assert_eq!(resp.status(), 401);
+    assert!(resp.headers().contains_key("www-authenticate"));
+    let resp = app
+        .client
+        .get(&file_url)
+        .basic_auth("visitor", Some("wrong"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 401);
+    let resp = app
+        .client
+        .get(&file_url)
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await?, "report content");
+
+    // Assert : the listings point to the share, which cannot be escaped nor written to
+    let resp = propfind(&app, &format!("{share_url}/{token}"))
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 207);
+    let listing = resp.text().await?;
+    assert!(listing.contains(&format!("<D:href>/share/{token}/report.txt</D:href>")));
+    assert!(listing.contains(&format!("<D:href>/share/{token}/sub/</D:href>")));
+    assert!(!listing.contains("<D:href>/shared"));
+    let resp = app
+        .client
+        .get(format!("{share_url}/{token}/%2E%2E/dira/file1"))
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+    let resp = app
+        .client
+        .put(format!("{share_url}/{token}/new.txt"))
+        .basic_auth("visitor", Some("secret"))
+        .body("new")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 405);
+
+    // Assert : the share is gone once downloaded as many times as allowed
+    let resp = app
+        .client
+        .get(format!("{share_url}/{token}/sub/inner.txt"))
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let resp = app
+        .client
+        .get(&file_url)
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 410);
+
+    // Act : share a directory as a file drop
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"drop","mode":"upload_only","max_file_size":10}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let share: serde_json::Value = resp.json().await?;
+    let drop_token = share["token"].as_str().unwrap().to_owned();
+
+    // Assert : files can be dropped, but not replaced nor read
+    let drop_url = format!("{share_url}/{drop_token}/dropped.txt");
+    let resp = app.client.put(&drop_url).body("dropped").send().await?;
+    assert_eq!(resp.status(), 201);
+    assert_eq!(
+        std::fs::read_to_string(format!("data/{}/dir1/drop/dropped.txt", app.id))?,
+        "dropped"
+    );
+    for (i, content) in ["second", "third"].iter().enumerate() {
+        let resp = app.client.put(&drop_url).body(*content).send().await?;
+        assert_eq!(resp.status(), 201);
+        assert_eq!(
+            resp.headers()["location"],
+            format!("/share/{drop_token}/dropped%20%28{}%29.txt", i + 1).as_str()
+        );
+        assert_eq!(
+            std::fs::read_to_string(format!("data/{}/dir1/drop/dropped ({}).txt", app.id, i + 1))?,
+            *content
+        );
+    }
+    assert_eq!(
+        std::fs::read_to_string(format!("data/{}/dir1/drop/dropped.txt", app.id))?,
+        "dropped"
+    );
+    let resp = app
+        .client
+        .put(format!("{share_url}/{drop_token}/big.txt"))
+        .body("more than ten bytes")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 413);
+    assert!(!std::path::Path::new(&format!("data/{}/dir1/drop/big.txt", app.id)).exists());
+    let resp = app
+        .client
+        .put(format!("{share_url}/{drop_token}/streamed.txt"))
+        .body(reqwest::Body::wrap_stream(futures::stream::once(async {
+            Ok::<_, std::io::Error>("streamed")
+        })))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 411);
+    let resp = app
+        .client
+        .put(format!("{share_url}/{drop_token}/a/b/nested.txt"))
+        .body("nested")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 403);
+    assert!(!std::path::Path::new(&format!("data/{}/dir1/drop/a", app.id)).exists());
+    let resp = app.client.get(&drop_url).send().await?;
+    assert_eq!(resp.status(), 405);
+    let resp = propfind(&app, &format!("{share_url}/{drop_token}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 405);
+    let resp = app.client.delete(&drop_url).send().await?;
+    assert_eq!(resp.status(), 405);
+
+    // Assert : the shares are listed by their owner and by the admins
+    let resp = app.client.get(&shares_url).send().await?;
+    assert_eq!(resp.status(), 200);
+    let shares: Vec<serde_json::Value> = resp.json().await?;
+    assert_eq!(shares.len(), 2);
+    assert_eq!(shares[0]["downloads"], 2);
+    assert!(std::path::Path::new(&format!("{}.downloads.yaml", app.id)).exists());
+    let admin_shares_url = format!("http://vestibule.io:{}/api/admin/shares", app.port);
+    let shares: Vec<serde_json::Value> = app
+        .client
+        .get(&admin_shares_url)
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(shares.len(), 2);
+
+    // Act and Assert : the shares are revoked
+    let resp = app
+        .client
+        .delete(format!("{shares_url}/{drop_token}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let resp = app.client.put(&drop_url).body("dropped").send().await?;
+    assert_eq!(resp.status(), 404);
+    let resp = app
+        .client
+        .delete(format!("{admin_shares_url}/{token}"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+    let resp = app.client.get(&shares_url).send().await?;
+    assert_eq!(resp.text().await?, "[]");
+
+    // Act : share a single file, downloadable once
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"shared/report.txt","password":"secret","max_downloads":1}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let share: serde_json::Value = resp.json().await?;
+    let file_url = format!("{share_url}/{}", share["token"].as_str().unwrap());
+
+    // Assert : concurrent downloads cannot exceed the limit
+    let downloads = (0..4).map(|_| {
+        app.client
+            .get(&file_url)
+            .basic_auth("visitor", Some("secret"))
+            .send()
+    });
+    let statuses: Vec<u16> = futures::future::join_all(downloads)
+        .await
+        .into_iter()
+        .map(|resp| resp.map(|resp| resp.status().as_u16()))
+        .collect::<Result<_, _>>()?;
+    assert_eq!(statuses.iter().filter(|status| **status == 200).count(), 1);
+    assert_eq!(statuses.iter().filter(|status| **status == 410).count(), 3);
+
+    // Assert : the visitors are locked out after too many wrong passwords
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"shared/report.txt","password":"secret"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    let share: serde_json::Value = resp.json().await?;
+    let file_url = format!("{share_url}/{}", share["token"].as_str().unwrap());
+    for _ in 0..5 {
+        let resp = app
+            .client
+            .get(&file_url)
+            .basic_auth("visitor", Some("wrong"))
+            .send()
+            .await?;
+        assert_eq!(resp.status(), 401);
+    }
+    let resp = app
+        .client
+        .get(&file_url)
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 429);
+    assert_eq!(resp.headers()["retry-after"], "60");
+    let other_visitor = reqwest::Client::builder()
+        .resolve("vestibule.io", format!("127.0.0.1:{}", app.port).parse()?)
+        .local_address("127.0.0.2".parse::<std::net::IpAddr>()?)
+        .build()?;
+    let resp = other_visitor
+        .get(&file_url)
+        .basic_auth("visitor", Some("secret"))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+
+    // Assert : the passwords are taken as given, surrounding spaces included
+    let resp = app
+        .client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"shared/report.txt","password":" spaced "}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    let share: serde_json::Value = resp.json().await?;
+    let file_url = format!("{share_url}/{}", share["token"].as_str().unwrap());
+    let resp = app
+        .client
+        .get(&file_url)
+        .basic_auth("visitor", Some(" spaced "))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 200);
+
+    // Assert : the unlogged users cannot share
+    let client = reqwest::Client::builder()
+        .resolve("vestibule.io", format!("127.0.0.1:{}", app.port).parse()?)
+        .build()?;
+    let resp = client
+        .post(&shares_url)
+        .body(r#"{"dav_id":1,"path":"shared"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 401);
+
+    Ok(())
+}
@@ -3,8 +3,22 @@ use std::{fs, net::SocketAddr};
 use tokio::sync::broadcast;
 
 use vestibule::{
-    apps::App, configuration::Config, davs::model::Dav, mocks::mock_proxied_server, server::Server,
-    users::User, utils::random_string,
+    apps::App,
+    configuration::Config,
+    davs::{
+        acl::{AclRule, Permission},
+        model::Dav,
+        quota::Quota,
+        storage::{ChunksConfig, S3Config, StorageConfig},
+        trash::TrashPolicy,
+        versions::VersioningPolicy,
+    },
+    mocks::{mock_proxied_server, mock_s3_server},
+    redirects::Redirect,
+    server::Server,
+    streams::{Protocol, Stream},
+    users::User,
+    utils::random_string,
 };
 
 use anyhow::Result;
@@ -13,6 +27,8 @@ pub struct TestApp {
     pub client: Client,
     pub id: String,
     pub port: u16,
+    pub tcp_stream_port: u16,
+    pub udp_stream_port: u16,
     pub server_started: tokio::sync::broadcast::Receiver<()>,
 }
 
@@ -38,11 +54,35 @@ impl TestApp {
         let mock2_listener =
             std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind to random port");
         let mock2_port = mock2_listener.local_addr().unwrap().port();
+        let s3_listener =
+            std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind to random port");
+        let s3_port = s3_listener.local_addr().unwrap().port();
+
+        let udp_echo_socket = tokio::net::UdpSocket::bind("127.0.0.1:0")
+            .await
+            .expect("failed to bind to random port");
+        let udp_echo_port = udp_echo_socket.local_addr().unwrap().port();
+        let tcp_stream_port = free_port();
+        let udp_stream_port = free_port();
 
-        create_apps_file(&id, &main_port, &mock1_port, &mock2_port).await;
+        create_apps_file(
+            &id,
+            &main_port,
+            &mock1_port,
+            &mock2_port,
+            &s3_port,
+            &StreamPorts {
+                tcp_stream_port,
+                udp_stream_port,
+                udp_echo_port,
+            },
+        )
+        .await;
 
         tokio::spawn(mock_proxied_server(mock1_listener));
         tokio::spawn(mock_proxied_server(mock2_listener));
+        tokio::spawn(mock_s3_server(s3_listener));
+        tokio::spawn(mock_udp_echo_server(udp_echo_socket));
 
         let (tx, _) = broadcast::channel(16);
         let fp = format!("{}.yaml", &id);
@@ -81,9 +121,19 @@ impl TestApp {
             .resolve("files2.vestibule.io", main_addr)
             .resolve("files3.vestibule.io", main_addr)
             .resolve("secured-files.vestibule.io", main_addr)
+            .resolve("acl-files.vestibule.io", main_addr)
+            .resolve("homes.vestibule.io", main_addr)
+            .resolve("memory-files.vestibule.io", main_addr)
+            .resolve("s3-files.vestibule.io", main_addr)
+            .resolve("chunked-files.vestibule.io", main_addr)
+            .resolve("secret-chunked-files.vestibule.io", main_addr)
+            .resolve("names-files.vestibule.io", main_addr)
             .resolve("fwdtoredirect.vestibule.io", main_addr)
             .resolve("relativeredirect.vestibule.io", main_addr)
             .resolve("absoluteredirect.vestibule.io", main_addr)
+            .resolve("wiki.vestibule.io", main_addr)
+            .resolve("docs.vestibule.io", main_addr)
+            .resolve("secured-redirect.vestibule.io", main_addr)
             .cookie_store(true)
             .build()
             .unwrap();
@@ -92,6 +142,8 @@ impl TestApp {
             client: client,
             id: id,
             port: main_port,
+            tcp_stream_port,
+            udp_stream_port,
             server_started: server_started,
         };
 
@@ -104,11 +156,25 @@ impl TestApp {
 impl Drop for TestApp {
     fn drop(&mut self) {
         std::fs::remove_file(&format!("{}.yaml", self.id)).ok();
+        std::fs::remove_file(&format!("{}.downloads.yaml", self.id)).ok();
         std::fs::remove_dir_all(&format!("./data/{}", self.id)).ok();
     }
 }
 
-pub async fn create_apps_file(id: &str, main_port: &u16, mock1_port: &u16, mock2_port: &u16) {
+pub struct StreamPorts {
+    pub tcp_stream_port: u16,
+    pub udp_stream_port: u16,
+    pub udp_echo_port: u16,
+}
+
+pub async fn create_apps_file(
+    id: &str,
+    main_port: &u16,
+    mock1_port: &u16,
+    mock2_port: &u16,
+    s3_port: &u16,
+    stream_ports: &StreamPorts,
+) {
     let filepath = format!("{}.yaml", &id);
     let apps = vec![
         App {
@@ -167,7 +233,19 @@ pub async fn create_apps_file(id: &str, main_port: &u16, mock1_port: &u16, mock2
             secured: false,
             allow_symlinks: false,
             roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: Some(TrashPolicy {
+                retention_days: Some(30),
+            }),
+            versioning: None,
+            content_store: format!("./data/{id}/store"),
+            storage: None,
             passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
             key: None,
         },
         Dav {
@@ -181,7 +259,20 @@ pub async fn create_apps_file(id: &str, main_port: &u16, mock1_port: &u16, mock2
             secured: false,
             allow_symlinks: true,
             roles: vec!["ADMINS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: Some(VersioningPolicy {
+                max_versions: Some(3),
+                retention_days: Some(30),
+            }),
+            content_store: format!("./data/{id}/store"),
+            storage: None,
             passphrase: "ABCD123".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
             key: None,
         },
         Dav {
@@ -195,7 +286,17 @@ pub async fn create_apps_file(id: &str, main_port: &u16, mock1_port: &u16, mock2
             secured: false,
             allow_symlinks: true,
             roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: None,
             passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
             key: None,
         },
         Dav {
@@ -209,11 +310,264 @@ pub async fn create_apps_file(id: &str, main_port: &u16, mock1_port: &u16, mock2
             secured: true,
             allow_symlinks: true,
             roles: vec!["ADMINS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: None,
+            passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
+            key: None,
+        },
+        Dav {
+            id: 5,
+            host: "acl-files".to_owned(),
+            directory: format!("./data/{id}/dir1"),
+            writable: true,
+            name: "ACL Files".to_owned(),
+            icon: "file-invoice".to_owned(),
+            color: "#2ce027".to_owned(),
+            secured: true,
+            allow_symlinks: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![
+                AclRule {
+                    path: "/**".to_owned(),
+                    permission: Permission::Admin,
+                    roles: vec!["ADMINS".to_owned()],
+                    users: vec![],
+                },
+                AclRule {
+                    path: "/dira/**".to_owned(),
+                    permission: Permission::Read,
+                    roles: vec!["USERS".to_owned()],
+                    users: vec![],
+                },
+                AclRule {
+                    path: "/dirb/**".to_owned(),
+                    permission: Permission::Delete,
+                    roles: vec![],
+                    users: vec!["user".to_owned()],
+                },
+                AclRule {
+                    path: "/dropbox/**".to_owned(),
+                    permission: Permission::Write,
+                    roles: vec![],
+                    users: vec!["user".to_owned()],
+                },
+            ],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: None,
+            passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
+            key: None,
+        },
+        Dav {
+            id: 6,
+            host: "homes".to_owned(),
+            directory: format!("./data/{id}/homes/{{login}}"),
+            writable: true,
+            name: "Homes".to_owned(),
+            icon: "file-invoice".to_owned(),
+            color: "#2ce027".to_owned(),
+            secured: false,
+            allow_symlinks: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: format!("./data/{id}/dir3/dira"),
+            quota: Some(Quota {
+                bytes: Some(100),
+                files: Some(8),
+            }),
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: None,
+            passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
+            key: None,
+        },
+        Dav {
+            id: 7,
+            host: "memory-files".to_owned(),
+            directory: format!("./data/{id}/memory"),
+            writable: true,
+            name: "Memory Files".to_owned(),
+            icon: "file-invoice".to_owned(),
+            color: "#2ce027".to_owned(),
+            secured: false,
+            allow_symlinks: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: Some(StorageConfig::Memory),
+            passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
+            key: None,
+        },
+        Dav {
+            id: 8,
+            host: "s3-files".to_owned(),
+            directory: format!("./data/{id}/s3"),
+            writable: true,
+            name: "S3 Files".to_owned(),
+            icon: "file-invoice".to_owned(),
+            color: "#2ce027".to_owned(),
+            secured: false,
+            allow_symlinks: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: Some(StorageConfig::S3(S3Config {
+                endpoint: format!("http://localhost:{s3_port}"),
+                bucket: "vestibule".to_owned(),
+                region: "us-east-1".to_owned(),
+                access_key: "access".to_owned(),
+                secret_key: "secret".to_owned(),
+            })),
+            passphrase: "ABCD123".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
+            key: None,
+        },
+        Dav {
+            id: 9,
+            host: "chunked-files".to_owned(),
+            directory: format!("./data/{id}/chunked"),
+            writable: true,
+            name: "Chunked Files".to_owned(),
+            icon: "file-invoice".to_owned(),
+            color: "#2ce027".to_owned(),
+            secured: false,
+            allow_symlinks: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: Some(StorageConfig::Chunks(ChunksConfig {
+                store: format!("./data/{id}/chunks"),
+            })),
             passphrase: "".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
+            key: None,
+        },
+        Dav {
+            id: 10,
+            host: "secret-chunked-files".to_owned(),
+            directory: format!("./data/{id}/secret-chunked"),
+            writable: true,
+            name: "Secret Chunked Files".to_owned(),
+            icon: "file-invoice".to_owned(),
+            color: "#2ce027".to_owned(),
+            secured: false,
+            allow_symlinks: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+            acl: vec![],
+            skeleton: "".to_owned(),
+            quota: None,
+            trash: None,
+            versioning: None,
+            content_store: "".to_owned(),
+            storage: Some(StorageConfig::Chunks(ChunksConfig {
+                store: format!("./data/{id}/chunks"),
+            })),
+            passphrase: "ABCD123".to_owned(),
+            wrapped_key: None,
+            encrypt_names: false,
+            migration: None,
             key: None,
         },
     ];
 
+    let redirects = vec![
+        Redirect {
+            id: 1,
+            name: "Wiki".to_owned(),
+            icon: "book".to_owned(),
+            color: "#030303".to_owned(),
+            host: "wiki".to_owned(),
+            target: "https://wiki.example.com/".to_owned(),
+            permanent: false,
+            preserve_path: true,
+            preserve_query: true,
+            secured: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+        },
+        Redirect {
+            id: 2,
+            name: "Docs".to_owned(),
+            icon: "book".to_owned(),
+            color: "#030303".to_owned(),
+            host: "docs".to_owned(),
+            target: "https://docs.example.com/welcome".to_owned(),
+            permanent: true,
+            preserve_path: false,
+            preserve_query: false,
+            secured: false,
+            roles: vec!["ADMINS".to_owned(), "USERS".to_owned()],
+        },
+        Redirect {
+            id: 3,
+            name: "Secured Redirect".to_owned(),
+            icon: "book".to_owned(),
+            color: "#030303".to_owned(),
+            host: "secured-redirect".to_owned(),
+            target: "https://admin.example.com/".to_owned(),
+            permanent: false,
+            preserve_path: false,
+            preserve_query: false,
+            secured: true,
+            roles: vec!["ADMINS".to_owned()],
+        },
+    ];
+
+    let streams = vec![
+        Stream {
+            id: 1,
+            name: "Tcp stream".to_owned(),
+            protocol: Protocol::Tcp,
+            listen_port: Some(stream_ports.tcp_stream_port),
+            host: "".to_owned(),
+            forward_to: format!("localhost:{mock1_port}"),
+        },
+        Stream {
+            id: 2,
+            name: "Udp stream".to_owned(),
+            protocol: Protocol::Udp,
+            listen_port: Some(stream_ports.udp_stream_port),
+            host: "".to_owned(),
+            forward_to: format!("127.0.0.1:{}", stream_ports.udp_echo_port),
+        },
+    ];
+
     let users = vec![
         User {
             login: "admin".to_owned(),
@@ -235,13 +589,29 @@ pub async fn create_apps_file(id: &str, main_port: &u16, mock1_port: &u16, mock2
         http_port: *main_port,
         apps: apps,
         davs: davs,
+        redirects: redirects,
+        streams: streams,
         users: users,
+        shares: vec![],
     };
 
     // Act
     config.to_file(&filepath).await.unwrap();
 }
 
+fn free_port() -> u16 {
+    let listener =
+        std::net::TcpListener::bind("127.0.0.1:0").expect("failed to bind to random port");
+    listener.local_addr().unwrap().port()
+}
+
+async fn mock_udp_echo_server(socket: tokio::net::UdpSocket) {
+    let mut buf = vec![0; 1024];
+    while let Ok((len, peer)) = socket.recv_from(&mut buf).await {
+        socket.send_to(&buf[..len], peer).await.ok();
+    }
+}
+
 fn create_test_tree(base: &str) -> Result<()> {
     for dir in vec!["dir1", "dir2", "dir3"] {
         fs::create_dir_all(format!("./data/{base}/{dir}/dira"))?;
@@ -269,3 +639,15 @@ pub fn encode_uri(v: &str) -> String {
     let parts: Vec<_> = v.split('/').map(urlencoding::encode).collect();
     parts.join("/")
 }
+
+pub async fn log_as(app: &TestApp, login: &str) {
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/auth/local", app.port))
+        .body(format!(r#"{{"login":"{login}","password":"password"}}"#))
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert_eq!(response.status(), 200);
+}
@@ -3,6 +3,9 @@ extern crate log;
 
 mod admin;
 mod apps;
+mod auth;
 mod davs;
 mod helpers;
+mod redirects;
+mod streams;
 mod user;
@@ -0,0 +1,95 @@
+use hyper::header::LOCATION;
+
+use crate::helpers::TestApp;
+
+#[tokio::test]
+async fn temporary_redirect_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+
+    // Act
+    let response = app
+        .client
+        .get(format!(
+            "http://wiki.vestibule.io:{}/some/page?lang=fr",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that the path and query are kept
+    assert_eq!(response.status(), 307);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        "https://wiki.example.com/some/page?lang=fr"
+    );
+}
+
+#[tokio::test]
+async fn permanent_redirect_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+
+    // Act
+    let response = app
+        .client
+        .get(format!(
+            "http://docs.vestibule.io:{}/some/page?lang=fr",
+            app.port
+        ))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that the path and query are dropped
+    assert_eq!(response.status(), 308);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        "https://docs.example.com/welcome"
+    );
+}
+
+#[tokio::test]
+async fn secured_redirect_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+
+    // Act : try to follow the redirect as unlogged user
+    let response = app
+        .client
+        .get(format!("http://secured-redirect.vestibule.io:{}", app.port))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that is impossible
+    assert_eq!(response.status(), 403);
+    assert!(response.headers().get(LOCATION).is_none());
+
+    // Log as admin
+    let response = app
+        .client
+        .post(format!("http://vestibule.io:{}/auth/local", app.port))
+        .body(r#"{"login":"admin","password":"password"}"#)
+        .header("Content-Type", "application/json")
+        .send()
+        .await
+        .expect("failed to execute request");
+    assert!(response.status().is_success());
+
+    // Act : try to follow the redirect as admin
+    let response = app
+        .client
+        .get(format!("http://secured-redirect.vestibule.io:{}", app.port))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert that is possible
+    assert_eq!(response.status(), 307);
+    assert_eq!(
+        response.headers().get(LOCATION).unwrap(),
+        "https://admin.example.com/"
+    );
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use tokio::net::UdpSocket;
+
+use crate::helpers::TestApp;
+
+#[tokio::test]
+async fn tcp_stream_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+
+    // Act : reach the mock server through the raw tcp forwarder
+    let response = app
+        .client
+        .get(format!("http://127.0.0.1:{}", app.tcp_stream_port))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert
+    assert!(response.status().is_success());
+    assert!(response
+        .text()
+        .await
+        .unwrap()
+        .contains("Hello world from mock server"));
+}
+
+#[tokio::test]
+async fn udp_stream_test() {
+    // Arrange
+    let app = TestApp::spawn().await;
+    let socket = UdpSocket::bind("127.0.0.1:0")
+        .await
+        .expect("failed to bind to random port");
+    socket
+        .connect(format!("127.0.0.1:{}", app.udp_stream_port))
+        .await
+        .expect("failed to connect");
+
+    // Act : send a datagram to the echo server through the udp forwarder
+    socket.send(b"ping").await.expect("failed to send datagram");
+    let mut buf = [0; 16];
+    let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .expect("no answer from udp stream")
+        .expect("failed to receive datagram");
+
+    // Assert
+    assert_eq!(&buf[..len], b"ping");
+}
+
+#[tokio::test]
+async fn stream_reload_test() {
+    // Arrange
+    let mut app = TestApp::spawn().await;
+
+    // Act : reload the configuration, the forwarder must be rebound on the same port
+    app.client
+        .get(format!("http://vestibule.io:{}/reload", app.port))
+        .send()
+        .await
+        .expect("failed to execute request");
+    app.is_ready().await;
+
+    let response = app
+        .client
+        .get(format!("http://127.0.0.1:{}", app.tcp_stream_port))
+        .send()
+        .await
+        .expect("failed to execute request");
+
+    // Assert
+    assert!(response.status().is_success());
+}
@@ -61,4 +61,13 @@ async fn list_services_api_for_normal_user_test() {
     assert!(response_content.contains(r#"login":"REDACTED"#));
     assert!(response_content.contains(r#"password":"REDACTED"#));
     assert!(response_content.contains(r#"passphrase":"REDACTED"#));
+    // Assert that the credentials of the storages are not present
+    assert!(response_content.contains("s3-files"));
+    assert!(!response_content.contains(r#"access_key":"access"#));
+    assert!(!response_content.contains(r#"secret_key":"secret"#));
+    assert!(response_content.contains(r#"secret_key":"REDACTED"#));
+    // Assert that the wrapped keys of the encrypted davs are not present
+    assert!(response_content.contains("files2"));
+    assert!(!response_content.contains(r#""salt":"#));
+    assert!(!response_content.contains(r#""wrapped_key":{"#));
 }